@@ -1,6 +1,19 @@
 use clap::{value_parser, Parser, Subcommand};
+use regex_lite::Regex;
 
-use crate::resolution::Resolution;
+use crate::{
+    auto_record::OnFocusLost,
+    encoder_settings::{Level, Profile, RateControl},
+    mixer::AudioTracks,
+    mux::Muxer,
+    pacer::Pacing,
+    pipeline::{AudioCodec, VideoCodec},
+    privacy::Card,
+    region::Crop,
+    resampler::ProcessorBackend,
+    resolution::{Resolution, ScaleMode},
+    window_target::{parse_handle, parse_title_pattern, WhenMinimized},
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -17,10 +30,102 @@ pub struct Args {
     #[clap(short, long, default_value_t = 60)]
     pub frame_rate: u32,
 
-    /// The resolution you would like to encode at: native, 720p, 1080p, 2160p, or 4320p.
+    /// The video codec to encode with: h264, hevc, or av1. HEVC and AV1 need a hardware encoder that supports them.
+    #[clap(long, default_value_t = VideoCodec::H264)]
+    pub codec: VideoCodec,
+
+    /// How the encoder spends bits: cbr (constant bit rate), vbr (variable bit rate averaging the bit rate), quality (constant quality, see --quality), or cqp (constant quantizer, see --qp). Defaults to whatever the encoder picks.
+    #[clap(long)]
+    pub rate_control: Option<RateControl>,
+
+    /// The quality to encode at with quality rate control, from 0 to 100.
+    #[clap(long)]
+    pub quality: Option<u32>,
+
+    /// The quantizer to encode at with cqp rate control, from 0 (best) to 51, or to 255 for AV1.
+    #[clap(long)]
+    pub qp: Option<u32>,
+
+    /// The number of frames from one keyframe to the next.
+    #[clap(long, value_name = "FRAMES")]
+    pub keyframe_interval: Option<u32>,
+
+    /// The number of B-frames between other frames. Not allowed in the baseline profile or low-latency mode.
+    #[clap(long)]
+    pub b_frames: Option<u32>,
+
+    /// The H.264 profile to encode: baseline, main, or high.
+    #[clap(long)]
+    pub profile: Option<Profile>,
+
+    /// The H.264 level to encode, like 4.1. The resolution, frame rate and bit rate have to fit in it.
+    #[clap(long)]
+    pub level: Option<Level>,
+
+    /// Gets each frame out of the encoder as soon as possible, at some cost in quality.
+    #[clap(long)]
+    pub low_latency: bool,
+
+    /// Reads video encoder settings from a file. The file has a setting on each line, named like the options above, then its value, like "rate-control vbr" or "low-latency true". The bit rate is in Mbps. Options given on the command line win over the file. Lines starting with # are comments.
+    #[clap(long, value_name = "FILE")]
+    pub encoder_settings: Option<String>,
+
+    /// How captured frames are fitted to the frame rate: drop (skip frames that come too soon), cfr (constant frame rate, repeating frames to fill gaps), or vfr (encode every frame as captured).
+    #[clap(long, default_value_t = Pacing::Drop)]
+    pub pacing: Pacing,
+
+    /// Only records part of the display: x,y,width,height in pixels from its top left corner, or window to follow whichever window is in the foreground. Native resolution is the size of the region, or of the display when following windows.
+    #[clap(long)]
+    pub crop: Option<Crop>,
+
+    /// Records the first window whose title matches this regular expression (ignoring case), following it from display to display. Native resolution is the size of the display it starts on.
+    #[clap(long, value_name = "REGEX", value_parser = parse_title_pattern, conflicts_with_all = ["display", "crop", "process", "hwnd"])]
+    pub window_title: Option<Regex>,
+
+    /// Records the first window of this process (for example game.exe), following it from display to display.
+    #[clap(long, value_name = "EXE", conflicts_with_all = ["display", "crop", "hwnd"])]
+    pub process: Option<String>,
+
+    /// Records the window with this handle (in decimal, or hex starting with 0x), following it from display to display.
+    #[clap(long, value_name = "HANDLE", value_parser = parse_handle, conflicts_with_all = ["display", "crop"])]
+    pub hwnd: Option<isize>,
+
+    /// What happens while the recorded window is minimized or closed: blank (keep recording black frames), or pause (leave that time out of the recording).
+    #[clap(long, default_value_t = WhenMinimized::Blank)]
+    pub when_minimized: WhenMinimized,
+
+    /// The resolution you would like to encode at: native, 720p, 1080p, 1440p, 2160p, 4320p, or any WIDTHxHEIGHT. Odd sizes are rounded up to even ones.
     #[clap(short, long, default_value_t = Resolution::Native)]
     pub resolution: Resolution,
 
+    /// Encodes this many pixels high, and as wide as keeps the display's aspect ratio.
+    #[clap(long, conflicts_with = "resolution", value_parser = value_parser!(u32).range(2..))]
+    pub height: Option<u32>,
+
+    /// How the display is fitted to the resolution when their aspect ratios differ: letterbox (black bars), stretch, crop (fill, cutting off the edges), or integer (scale by a whole number, with black bars).
+    #[clap(long, default_value_t = ScaleMode::Letterbox)]
+    pub scale_mode: ScaleMode,
+
+    /// Only records while a window these rules allow is in the foreground. The file has a rule on each line: include or exclude, then title (a regular expression, ignoring case), process or class, then what to match, like "include process game.exe". Exclude rules win, and with no include rules every window that isn't excluded is recorded. Lines starting with # are comments.
+    #[clap(long, value_name = "FILE")]
+    pub rules: Option<String>,
+
+    /// What happens while a window the rules don't allow is in the foreground: pause (leave that time out of the recording), blank (keep recording black frames), or split (pause, then carry on in a new numbered file on the next keyframe).
+    #[clap(long, default_value_t = OnFocusLost::Pause, requires = "rules")]
+    pub on_focus_lost: OnFocusLost,
+
+    /// Hides private windows, like password managers and chat apps, while they're in the foreground. The file has a window on each line: title (a regular expression, ignoring case), process or class, then what to match, like "process KeePass.exe". Lines starting with # are comments.
+    #[clap(long, value_name = "FILE")]
+    pub privacy: Option<String>,
+
+    /// What private windows are replaced with: blur, black, or a color like #202020.
+    #[clap(long, default_value_t = Card::Color([0, 0, 0]), requires = "privacy")]
+    pub privacy_card: Card,
+
+    /// Mutes the audio while a private window is in the foreground too.
+    #[clap(long, requires = "privacy")]
+    pub privacy_mute: bool,
+
     /// The index of the encoder you'd like to use to record (use enum-encoders command for a list of encoders and their indices).
     #[clap(long, default_value_t = 0)]
     pub video_encoder: usize,
@@ -29,6 +134,38 @@ pub struct Args {
     #[clap(long, default_value_t = 0)]
     pub audio_encoder: usize,
 
+    /// The audio codec to encode with: aac, opus, flac, or pcm. Opus needs an encoder to be installed, and only the builtin and fragmented muxers can write codecs other than AAC to MP4 files.
+    #[clap(long, default_value_t = AudioCodec::Aac)]
+    pub audio_codec: AudioCodec,
+
+    /// The audio bit rate in kbps. Only AAC and Opus take one; AAC can be 96, 128, 160 or 192, and Opus anything from 6 to 510. Defaults to one that suits the codec.
+    #[clap(long, value_name = "KBPS")]
+    pub audio_bit_rate: Option<u32>,
+
+    /// Records the default microphone too, mixed in with the desktop audio.
+    #[clap(long)]
+    pub mic: bool,
+
+    /// The microphone volume, from 0 (muted) to 4. 1 leaves it unchanged.
+    #[clap(long, default_value_t = 1.0, value_parser = parse_volume, requires = "mic")]
+    pub mic_volume: f32,
+
+    /// The desktop audio volume, from 0 (muted) to 4. 1 leaves it unchanged.
+    #[clap(long, default_value_t = 1.0, value_parser = parse_volume)]
+    pub desktop_volume: f32,
+
+    /// How the desktop audio and microphone are laid out in the file: mixed (one track), separate (a track each), or both (the mixed track first, then one for each). Tracks are only named by the builtin and fragmented muxers.
+    #[clap(long, default_value_t = AudioTracks::Mixed, requires = "mic")]
+    pub audio_tracks: AudioTracks,
+
+    /// The language of the audio tracks, as a three letter ISO 639-2 code like eng. Only written by the builtin, fragmented, and Matroska muxers.
+    #[clap(long, value_name = "CODE", value_parser = parse_language)]
+    pub audio_language: Option<String>,
+
+    /// What converts captured audio to the format it's mixed in: builtin, or mf (the Media Foundation resampler).
+    #[clap(long, default_value_t = ProcessorBackend::Builtin)]
+    pub audio_processor: ProcessorBackend,
+
     /// Disables the yellow capture border (only available on Windows 11).
     #[clap(long)]
     pub borderless: bool,
@@ -44,8 +181,36 @@ pub struct Args {
     /// Recording immediately starts. End the recording through console input.
     #[clap(long)]
     pub console_mode: bool,
-    
-    /// The output file that will contain the recording.
+
+    /// Keeps only the last N seconds of the recording in memory. Each time the hotkey is pressed (or ENTER in console mode), those seconds are saved to a new numbered file next to the output file.
+    #[clap(long, value_name = "SECONDS")]
+    pub replay: Option<u32>,
+
+    /// Caps the memory used by the replay buffer (in MB). Older footage is dropped first.
+    #[clap(long, requires = "replay")]
+    pub replay_max_mb: Option<usize>,
+
+    /// The muxer that writes MP4 files: mf (Media Foundation), builtin, or fragmented (stays playable if the recording is cut off). Matroska and WebM files are always written by the builtin Matroska muxer.
+    #[clap(long, default_value_t = Muxer::MediaFoundation)]
+    pub muxer: Muxer,
+
+    /// How often the fragmented muxer starts a new fragment (in seconds). At most this much is lost if the recording is cut off.
+    #[clap(long, default_value_t = 2, value_parser = value_parser!(u32).range(1..))]
+    pub fragment_duration: u32,
+
+    /// Starts a new numbered file (recording_0001.mp4, recording_0002.mp4, ...) every N seconds. The split happens on the next keyframe.
+    #[clap(long, value_name = "SECONDS", conflicts_with = "replay", value_parser = value_parser!(u32).range(1..))]
+    pub segment_duration: Option<u32>,
+
+    /// Starts a new numbered file once the current one reaches this size (in MB). The split happens on the next keyframe.
+    #[clap(long, value_name = "MB", conflicts_with = "replay", value_parser = value_parser!(u64).range(1..))]
+    pub segment_size: Option<u64>,
+
+    /// Also writes the raw encoded streams, a WAV of each audio track before it's encoded, and their timestamps to this directory. For telling encoder problems apart from muxer ones.
+    #[clap(long, value_name = "DIR")]
+    pub dump_streams: Option<String>,
+
+    /// The output file that will contain the recording. Its extension picks the container: .mp4, .mkv (Matroska), or .webm (AV1 video and Opus audio only).
     #[clap(default_value = "recording.mp4")]
     pub output_file: String,
 
@@ -54,9 +219,35 @@ pub struct Args {
     pub command: Option<Commands>,
 }
 
+fn parse_volume(value: &str) -> Result<f32, String> {
+    let volume: f32 = value
+        .parse()
+        .map_err(|_| format!("`{}` isn't a number", value))?;
+    if !(0.0..=4.0).contains(&volume) {
+        return Err("Volume must be between 0 and 4.".to_string());
+    }
+    Ok(volume)
+}
+
+fn parse_language(value: &str) -> Result<String, String> {
+    if value.len() != 3 || !value.chars().all(|c| c.is_ascii_lowercase()) {
+        return Err("Languages are three letter ISO 639-2 codes, like eng.".to_string());
+    }
+    Ok(value.to_string())
+}
+
 #[derive(Subcommand, Debug)]
 #[clap(args_conflicts_with_subcommands = true)]
 pub enum Commands {
-    /// Lists the available hardware H264 encoders.
+    /// Lists the available H.264, HEVC and AV1 video encoders, and AAC and Opus audio encoders.
     EnumEncoders,
+    /// Repairs a fragmented recording that was cut off, keeping every complete fragment.
+    Recover {
+        /// The recording to repair. It's truncated in place unless an output file is given.
+        input_file: String,
+
+        /// Writes the repaired recording here instead.
+        #[clap(long)]
+        output: Option<String>,
+    },
 }
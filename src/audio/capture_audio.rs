@@ -5,17 +5,17 @@ use ringbuf::traits::{Consumer, Observer};
 use ringbuf::wrap::caching::Caching;
 use windows::Foundation::TimeSpan;
 use windows::Win32::Foundation::HANDLE;
-use windows::Win32::Media::KernelStreaming::{KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE};
 use windows::Win32::Media::Multimedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
-use windows::Win32::System::Performance::QueryPerformanceFrequency;
 use windows::{
     core::*,
     Win32::{
         Foundation::{ E_FAIL, WAIT_OBJECT_0, WAIT_TIMEOUT},
         Media::Audio::{
-            eConsole, eRender, IAudioCaptureClient, IAudioClient, IMMDeviceEnumerator, MMDeviceEnumerator, 
-            AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK,
-            WAVEFORMATEX, WAVEFORMATEXTENSIBLE,
+            eConsole, eRender, EDataFlow, IAudioCaptureClient, IAudioClient, IMMDeviceEnumerator,
+            MMDeviceEnumerator, AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT,
+            AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR, AUDCLNT_E_UNSUPPORTED_FORMAT,
+            AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+            AUDCLNT_STREAMFLAGS_LOOPBACK, WAVEFORMATEX,
         },
         System::{
             Com::{CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_ALL, COINIT_APARTMENTTHREADED},
@@ -28,17 +28,14 @@ use ringbuf::{HeapRb, producer::Producer, traits::Split};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, AtomicU16, AtomicBool, AtomicI64, Ordering};
 
+use crate::resampler::{PcmFormat, SampleEncoding};
+
 use super::encoding_session::AudioSource;
 
 // Constants used within this module
 const REFTIMES_PER_SEC: i64 = 10000000; // 100ns units per second
 const REFTIMES_PER_MILLISEC: i64 = 10000; // 100ns units per millisecond
 
-// Hard-coded audio format constants
-const HARD_CODED_SAMPLE_RATE: u32 = 48000;
-const HARD_CODED_CHANNELS: u16 = 2;
-const HARD_CODED_BITS_PER_SAMPLE: u16 = 16;  // 32-bit float
-
 pub struct AudioSample {
     pub data: Vec<u8>,
     pub timestamp: TimeSpan,
@@ -91,36 +88,67 @@ pub struct CaptureAudioGenerator {
     sample_rate: Arc<AtomicU32>,
     channels: Arc<AtomicU16>,
     bits_per_sample: Arc<AtomicU16>,
+    channel_mask: Arc<AtomicU32>, // 0 if the device doesn't say
+    float: Arc<AtomicBool>,
     initialized: Arc<AtomicBool>,
-    start_qpc: Arc<AtomicI64>, // Changed to atomic for thread safety
-    qpf_frequency: i64,
+    start_qpc: Arc<AtomicI64>, // QPC time (in 100ns units) the timestamps are relative to
 }
 
-// Helper function to create a WAVEFORMATEXTENSIBLE struct with our hard-coded format
-unsafe fn create_hardcoded_wave_format() -> WAVEFORMATEXTENSIBLE {
-    let bytes_per_sample = (HARD_CODED_BITS_PER_SAMPLE / 8) as u16;
-    let block_align = HARD_CODED_CHANNELS * bytes_per_sample;
-    let avg_bytes_per_sec = HARD_CODED_SAMPLE_RATE * block_align as u32;
-    
-    let mut format = WAVEFORMATEXTENSIBLE::default();
-    format.Format.wFormatTag = WAVE_FORMAT_EXTENSIBLE as u16;
-    format.Format.nChannels = HARD_CODED_CHANNELS;
-    format.Format.nSamplesPerSec = HARD_CODED_SAMPLE_RATE;
-    format.Format.nAvgBytesPerSec = avg_bytes_per_sec;
-    format.Format.nBlockAlign = block_align;
-    format.Format.wBitsPerSample = HARD_CODED_BITS_PER_SAMPLE;
-    format.Format.cbSize = 22; // Size of the WAVEFORMATEXTENSIBLE struct minus the size of WAVEFORMATEX
-    
-    format.Samples.wValidBitsPerSample = HARD_CODED_BITS_PER_SAMPLE;
-    format.dwChannelMask = 3; // SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT
-    
-    // Set the SubFormat to PCM instead of IEEE_FLOAT
-    format.SubFormat = KSDATAFORMAT_SUBTYPE_PCM;
-    
+/// Takes what's worth keeping from a buffer the audio engine handed out,
+/// going by the flags it came with. Silent buffers can hold anything, so
+/// they're zeroed, and a timestamp the engine got wrong is replaced by
+/// where the previous buffer ended. After a discontinuity the timestamp is
+/// still right, so whatever was lost gets filled in with silence downstream.
+pub(super) fn read_buffer(
+    data: &[u8],
+    flags: u32,
+    timestamp: i64,
+    next_timestamp: Option<i64>,
+) -> (Vec<u8>, i64) {
+    if flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0 {
+        println!(
+            "Audio capture glitched at {} ms",
+            timestamp / REFTIMES_PER_MILLISEC
+        );
+    }
+    let data = if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+        vec![0; data.len()]
+    } else {
+        data.to_vec()
+    };
+    let timestamp = match next_timestamp {
+        Some(next_timestamp) if flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32 != 0 => {
+            next_timestamp
+        }
+        _ => timestamp,
+    };
+    (data, timestamp)
+}
+
+/// Reads a wave format handed out by the audio engine.
+pub(super) unsafe fn read_wave_format(format: *const WAVEFORMATEX) -> Result<PcmFormat> {
+    let size = std::mem::size_of::<WAVEFORMATEX>() + (*format).cbSize as usize;
+    let bytes = std::slice::from_raw_parts(format as *const u8, size);
+    PcmFormat::from_wave_format(bytes)
+        .map_err(|error| windows::core::Error::new(AUDCLNT_E_UNSUPPORTED_FORMAT, error.to_string()))
+}
+
+/// The format the audio engine mixes in for the default device, which is
+/// what shared mode capture delivers.
+pub(super) unsafe fn default_mix_format(flow: EDataFlow) -> Result<PcmFormat> {
+    let device_enumerator: IMMDeviceEnumerator =
+        CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+    let device = device_enumerator.GetDefaultAudioEndpoint(flow, eConsole)?;
+    let client: IAudioClient = device.Activate(CLSCTX_ALL, None)?;
+    let mix_format = client.GetMixFormat()?;
+    let format = read_wave_format(mix_format);
+    CoTaskMemFree(Some(mix_format as *const _));
     format
 }
 
-unsafe fn initialize_audio_capture(audio_source: &AudioSource) -> Result<(IAudioClient, IAudioCaptureClient, HANDLE, u32, u16, u16)> {
+unsafe fn initialize_audio_capture(
+    audio_source: &AudioSource,
+) -> Result<(IAudioClient, IAudioCaptureClient, HANDLE, PcmFormat)> {
     // Create device enumerator
     let device_enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
     
@@ -150,22 +178,27 @@ unsafe fn initialize_audio_capture(audio_source: &AudioSource) -> Result<(IAudio
         }
     }
     
-    // Create our hard-coded WAVEFORMATEXTENSIBLE
-    let wave_format_ex = create_hardcoded_wave_format();
-    let wave_format_ptr = &wave_format_ex.Format as *const WAVEFORMATEX;
+    // Capture in whatever format the device mixes in, it's converted later
+    let mix_format = client.GetMixFormat()?;
+    let format = read_wave_format(mix_format);
     
-    // Initialize audio client with our hard-coded format
     let buffer_duration_ms = 10;
     let buffer_duration_100ns = buffer_duration_ms * REFTIMES_PER_MILLISEC;
     
-    client.Initialize(
-        AUDCLNT_SHAREMODE_SHARED,
-        stream_flags,
-        buffer_duration_100ns,
-        0,
-        wave_format_ptr,
-        None,
-    )?;
+    let initialized = match &format {
+        Ok(_) => client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            stream_flags,
+            buffer_duration_100ns,
+            0,
+            mix_format,
+            None,
+        ),
+        Err(_) => Ok(()),
+    };
+    CoTaskMemFree(Some(mix_format as *const _));
+    let format = format?;
+    initialized?;
     
     // Set event handle
     client.SetEventHandle(handle)?;
@@ -175,28 +208,35 @@ unsafe fn initialize_audio_capture(audio_source: &AudioSource) -> Result<(IAudio
     
     // Start audio client
     client.Start()?;
-    
-    println!("Audio capture initialized and started with format: {}Hz, {} channels, {}-bit",
-             HARD_CODED_SAMPLE_RATE, HARD_CODED_CHANNELS, HARD_CODED_BITS_PER_SAMPLE);
-    
-    Ok((client, capture_client, handle, HARD_CODED_SAMPLE_RATE, HARD_CODED_CHANNELS, HARD_CODED_BITS_PER_SAMPLE))
+
+    println!(
+        "Audio capture initialized and started with format: {}Hz, {} channels, {}-bit{}",
+        format.sample_rate,
+        format.channels,
+        format.bits_per_sample(),
+        if format.encoding == SampleEncoding::F32 {
+            " float"
+        } else {
+            ""
+        }
+    );
+
+    Ok((client, capture_client, handle, format))
 }
 
 impl CaptureAudioGenerator {
     pub fn new(audio_source: AudioSource) -> Result<Self> {
-        // Create shared atomic variables with hard-coded values
-        let sample_rate = Arc::new(AtomicU32::new(HARD_CODED_SAMPLE_RATE));
-        let channels = Arc::new(AtomicU16::new(HARD_CODED_CHANNELS));
-        let bits_per_sample = Arc::new(AtomicU16::new(HARD_CODED_BITS_PER_SAMPLE));
+        // Find out what the device delivers up front, so whatever converts
+        // it can be set up before capture starts
+        let format = unsafe { default_mix_format(eRender)? };
+        let sample_rate = Arc::new(AtomicU32::new(format.sample_rate));
+        let channels = Arc::new(AtomicU16::new(format.channels));
+        let bits_per_sample = Arc::new(AtomicU16::new(format.bits_per_sample()));
+        let channel_mask = Arc::new(AtomicU32::new(format.channel_mask.unwrap_or(0)));
+        let float = Arc::new(AtomicBool::new(format.encoding == SampleEncoding::F32));
         let initialized = Arc::new(AtomicBool::new(false));
         let start_qpc = Arc::new(AtomicI64::new(0)); // Store as atomic
         
-        // Get the QPC frequency for timestamp calculations
-        let mut qpf_frequency: i64 = 0;
-        unsafe {
-            QueryPerformanceFrequency(&mut qpf_frequency);
-        }
-        println!("QPC frequency: {}", qpf_frequency);
         
         // Create buffer for AudioSample structs
         // Use a smaller capacity since AudioSample structs are larger
@@ -213,9 +253,10 @@ impl CaptureAudioGenerator {
         let thread_sample_rate = sample_rate.clone();
         let thread_channels = channels.clone();
         let thread_bits_per_sample = bits_per_sample.clone();
+        let thread_channel_mask = channel_mask.clone();
+        let thread_float = float.clone();
         let thread_initialized = initialized.clone();
         let thread_start_qpc = start_qpc.clone();
-        let thread_qpf_frequency = qpf_frequency;
 
         // Create session object
         let session = AudioCaptureSession::new(control_sender);
@@ -231,6 +272,8 @@ impl CaptureAudioGenerator {
                 }
                 
                 let mut running = false;
+                // Where the last packet ended, for packets without a good timestamp
+                let mut next_timestamp = None;
                 let mut audio_client: Option<IAudioClient> = None;
                 let mut audio_capture_client: Option<IAudioCaptureClient> = None;
                 let mut event_handle = None;
@@ -245,16 +288,30 @@ impl CaptureAudioGenerator {
                             if running {
                                 // Update the start_qpc with the new value from StartCapture
                                 thread_start_qpc.store(new_qpc, Ordering::SeqCst);
+                                next_timestamp = None;
                                 println!("Updated start_qpc to: {}", new_qpc);
                                 
                                 if audio_client.is_none() {
                                     // Initialize audio capture using our helper function
                                     match initialize_audio_capture(&thread_audio_source) {
-                                        Ok((client, capture_client, handle, actual_sample_rate, actual_channels, actual_bits_per_sample)) => {
-                                            // Store the actual format info
-                                            thread_sample_rate.store(actual_sample_rate, Ordering::SeqCst);
-                                            thread_channels.store(actual_channels, Ordering::SeqCst);
-                                            thread_bits_per_sample.store(actual_bits_per_sample, Ordering::SeqCst);
+                                        Ok((client, capture_client, handle, format)) => {
+                                            // Report the format the device really delivers, before any of
+                                            // its samples, so they're converted from that if the default
+                                            // device changed since
+                                            thread_sample_rate
+                                                .store(format.sample_rate, Ordering::SeqCst);
+                                            thread_channels
+                                                .store(format.channels, Ordering::SeqCst);
+                                            thread_bits_per_sample
+                                                .store(format.bits_per_sample(), Ordering::SeqCst);
+                                            thread_channel_mask.store(
+                                                format.channel_mask.unwrap_or(0),
+                                                Ordering::SeqCst,
+                                            );
+                                            thread_float.store(
+                                                format.encoding == SampleEncoding::F32,
+                                                Ordering::SeqCst,
+                                            );
                                             thread_initialized.store(true, Ordering::SeqCst);
                                             
                                             audio_client = Some(client);
@@ -303,14 +360,15 @@ impl CaptureAudioGenerator {
                             let mut buffer_data_ptr = std::ptr::null_mut();
                             let mut num_frames_available = 0;
                             let mut flags = 0;
+                            let mut device_position: u64 = 0;
                             let mut qpc_position: u64 = 0;
                             
                             let capture_result = capture_client.GetBuffer(
                                 &mut buffer_data_ptr,
                                 &mut num_frames_available,
                                 &mut flags,
-                                Some(&mut qpc_position),
-                                Some(&mut qpc_position), // Request QPC timestamp
+                                Some(&mut device_position),
+                                Some(&mut qpc_position), // Request QPC timestamp (already in 100ns units)
                             );
                             
                             if capture_result.is_ok() && num_frames_available > 0 {
@@ -334,8 +392,15 @@ impl CaptureAudioGenerator {
                                 
                                 // Calculate timestamp and duration using the dynamically updated start_qpc
                                 let qpc_signed = qpc_position as i64;
-                                let relative_timestamp_hns = ((qpc_signed - current_start_qpc) * REFTIMES_PER_SEC) / thread_qpf_frequency;
+                                let relative_timestamp_hns = qpc_signed - current_start_qpc;
                                 let packet_duration_hns = (num_frames_available as i64 * REFTIMES_PER_SEC) / current_sample_rate as i64;
+                                let (data, relative_timestamp_hns) = read_buffer(
+                                    buffer_slice,
+                                    flags,
+                                    relative_timestamp_hns,
+                                    next_timestamp,
+                                );
+                                next_timestamp = Some(relative_timestamp_hns + packet_duration_hns);
                                 
                                 // Create TimeSpan objects
                                 let timestamp = TimeSpan { Duration: relative_timestamp_hns };
@@ -343,7 +408,7 @@ impl CaptureAudioGenerator {
                                 
                                 // Create an AudioSample and push it to the ring buffer
                                 let audio_sample = AudioSample {
-                                    data: buffer_slice.to_vec(),
+                                    data,
                                     timestamp,
                                     duration,
                                     frames: num_frames_available,
@@ -384,9 +449,10 @@ impl CaptureAudioGenerator {
             sample_rate,
             channels,
             bits_per_sample,
+            channel_mask,
+            float,
             initialized,
             start_qpc,
-            qpf_frequency,
         })
     }
     
@@ -427,11 +493,20 @@ impl CaptureAudioGenerator {
         self.bits_per_sample.load(Ordering::SeqCst)
     }
     
-    // Method to calculate time from QPC value
+    pub fn get_channel_mask(&self) -> Option<u32> {
+        Some(self.channel_mask.load(Ordering::SeqCst)).filter(|&mask| mask != 0)
+    }
+
+    // Whether samples are floats rather than integers
+    pub fn is_float(&self) -> bool {
+        self.float.load(Ordering::SeqCst)
+    }
+
+    // Method to calculate time from a QPC time in 100ns units, as WASAPI reports it
     pub fn qpc_to_time(&self, qpc: i64) -> TimeSpan {
         // Use the current start_qpc value
         let current_start_qpc = self.start_qpc.load(Ordering::SeqCst);
-        let relative_time_hns = ((qpc - current_start_qpc) * REFTIMES_PER_SEC) / self.qpf_frequency;
+        let relative_time_hns = qpc - current_start_qpc;
         TimeSpan { Duration: relative_time_hns }
     }
     
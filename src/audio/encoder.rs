@@ -6,7 +6,34 @@ use windows::{
         },
         Media::MediaFoundation::{
             // Interfaces
-            IMFMediaBuffer, IMFMediaType, IMFSample, IMFTransform, MFAudioFormat_AAC, MFAudioFormat_Float, MFAudioFormat_PCM, MFCreateMediaType, MFCreateMemoryBuffer, MFCreateSample, MFMediaType_Audio, MFVideoInterlace_Progressive, MFT_OUTPUT_DATA_BUFFER, MFT_OUTPUT_STREAM_INFO, MF_E_TRANSFORM_NEED_MORE_INPUT, MF_MT_AAC_AUDIO_PROFILE_LEVEL_INDICATION, MF_MT_AAC_PAYLOAD_TYPE, MF_MT_ALL_SAMPLES_INDEPENDENT, MF_MT_AUDIO_AVG_BYTES_PER_SECOND, MF_MT_AUDIO_BITS_PER_SAMPLE, MF_MT_AUDIO_BLOCK_ALIGNMENT, MF_MT_AUDIO_CHANNEL_MASK, MF_MT_AUDIO_NUM_CHANNELS, MF_MT_AUDIO_SAMPLES_PER_SECOND, MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE, MF_MT_SUBTYPE
+            IMFMediaBuffer,
+            IMFMediaType,
+            IMFSample,
+            IMFTransform,
+            MFAudioFormat_AAC,
+            MFAudioFormat_Float,
+            MFAudioFormat_Opus,
+            MFAudioFormat_PCM,
+            MFCreateMediaType,
+            MFCreateMemoryBuffer,
+            MFCreateSample,
+            MFMediaType_Audio,
+            MFVideoInterlace_Progressive,
+            MFT_OUTPUT_DATA_BUFFER,
+            MFT_OUTPUT_STREAM_INFO,
+            MF_E_TRANSFORM_NEED_MORE_INPUT,
+            MF_MT_AAC_AUDIO_PROFILE_LEVEL_INDICATION,
+            MF_MT_AAC_PAYLOAD_TYPE,
+            MF_MT_ALL_SAMPLES_INDEPENDENT,
+            MF_MT_AUDIO_AVG_BYTES_PER_SECOND,
+            MF_MT_AUDIO_BITS_PER_SAMPLE,
+            MF_MT_AUDIO_BLOCK_ALIGNMENT,
+            MF_MT_AUDIO_CHANNEL_MASK,
+            MF_MT_AUDIO_NUM_CHANNELS,
+            MF_MT_AUDIO_SAMPLES_PER_SECOND,
+            MF_MT_INTERLACE_MODE,
+            MF_MT_MAJOR_TYPE,
+            MF_MT_SUBTYPE,
         },
         System::{
             Com::StructuredStorage::PROPVARIANT,
@@ -30,6 +57,16 @@ impl AudioEncoderInputSample {
     }
 }
 
+impl Timestamped for AudioEncoderInputSample {
+    fn timestamp(&self) -> i64 {
+        self.timestamp.Duration
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp.Duration = timestamp;
+    }
+}
+
 pub struct AudioEncoderOutputSample {
     sample: IMFSample,
 }
@@ -42,25 +79,94 @@ impl AudioEncoderOutputSample {
 
 use std::mem::{ManuallyDrop};
 
-use crate::{media, video::encoder};
+use crate::{
+    encoder_settings::default_aac_bit_rate,
+    media::sample_to_packet,
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{
+        self, flac::FlacEncoder, AudioBuffer, AudioCodec, AudioStreamFormat, Encoder, StreamFormat,
+        Timestamped,
+    },
+    video::encoder,
+};
 
 use super::{encoder_device::AudioEncoderDevice, processor::AudioFormat};
 
+/// Encodes 16-bit PCM with whichever codec the encoder device is for.
 pub struct AudioEncoder {
+    backend: Backend,
+    format: AudioStreamFormat,
+}
+
+enum Backend {
+    /// AAC and Opus, which Media Foundation encodes.
+    Transform(TransformAudioEncoder),
+    Flac(FlacEncoder),
+    /// The samples go in the file as they are.
+    Pcm,
+}
+
+impl AudioEncoder {
+    /// The input has to have the output's rate and channels for FLAC and
+    /// PCM, which don't resample.
+    pub fn new(
+        encoder_device: &AudioEncoderDevice,
+        input_format: AudioFormat,
+        output_format: AudioFormat,
+        bit_rate: u32,
+    ) -> Result<Self> {
+        let codec = encoder_device.codec();
+        let backend = match codec {
+            AudioCodec::Aac | AudioCodec::Opus => Backend::Transform(TransformAudioEncoder::new(
+                encoder_device,
+                input_format,
+                output_format.clone(),
+                bit_rate,
+            )?),
+            AudioCodec::Flac => Backend::Flac(FlacEncoder::new(
+                output_format.sample_rate,
+                output_format.channels,
+            )?),
+            AudioCodec::Pcm => Backend::Pcm,
+        };
+        Ok(Self {
+            backend,
+            format: AudioStreamFormat {
+                codec,
+                sample_rate: output_format.sample_rate,
+                channels: output_format.channels,
+                bit_rate,
+                name: None,
+                language: None,
+            },
+        })
+    }
+
+    /// Names the track this encoder's output ends up in.
+    pub fn set_name(&mut self, name: &str) {
+        self.format.name = Some(name.to_string());
+    }
+
+    pub fn set_language(&mut self, language: &str) {
+        self.format.language = Some(language.to_string());
+    }
+}
+
+/// Encodes with a Media Foundation transform.
+struct TransformAudioEncoder {
     encoder_transform: IMFTransform,
     input_media_type: IMFMediaType,
-    output_media_type: IMFMediaType,
     input_stream_id: u32,
     output_stream_id: u32,
     output_buffer_size: u32,
 }
 
-impl AudioEncoder {
-    pub fn new(
+impl TransformAudioEncoder {
+    fn new(
         encoder_device: &AudioEncoderDevice,
         input_format: AudioFormat,
         output_format: AudioFormat, 
-        bitrate: Option<u32>,
+        bit_rate: u32,
     ) -> Result<Self> {
         // Create the encoder transform using the provided device
         let encoder_transform = encoder_device.create_transform()?;
@@ -68,8 +174,11 @@ impl AudioEncoder {
         // Create Media Types
         let input_media_type = create_audio_media_type(&input_format)?;
         
-        // For output, we need to set encoder-specific attributes for AAC
-        let output_media_type = create_aac_output_media_type(&output_format, bitrate)?;
+        // For output, we need to set encoder-specific attributes for the codec
+        let output_media_type = match encoder_device.codec() {
+            AudioCodec::Opus => create_opus_output_media_type(&output_format, bit_rate)?,
+            _ => create_aac_output_media_type(&output_format, Some(bit_rate))?,
+        };
         
         // Set Media Types on the Transform
         unsafe {
@@ -107,7 +216,6 @@ impl AudioEncoder {
         Ok(Self {
             encoder_transform,
             input_media_type,
-            output_media_type,
             input_stream_id,
             output_stream_id,
             output_buffer_size,
@@ -325,10 +433,55 @@ impl AudioEncoder {
         
         Ok(result_samples)
     }
-    
-    // Provide access to the configured output media type
-    pub fn output_media_type(&self) -> &IMFMediaType {
-        &self.output_media_type
+}
+
+unsafe impl Send for AudioEncoder {}
+impl Encoder for AudioEncoder {
+    type Input = AudioEncoderInputSample;
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::Audio(self.format.clone())
+    }
+
+    fn encode(&mut self, sample: AudioEncoderInputSample) -> pipeline::Result<Vec<EncodedPacket>> {
+        match &mut self.backend {
+            Backend::Transform(transform) => {
+                let output = transform.process_sample(&sample)?;
+                Ok(output
+                    .map(|output| sample_to_packet(output.sample(), StreamKind::Audio))
+                    .transpose()?
+                    .into_iter()
+                    .collect())
+            }
+            Backend::Flac(flac) => flac.encode(AudioBuffer {
+                data: sample.data,
+                frames: sample.frames,
+                timestamp: sample.timestamp.Duration,
+                duration: sample.duration.Duration,
+            }),
+            Backend::Pcm => Ok(vec![EncodedPacket::new(
+                StreamKind::Audio,
+                sample.data,
+                sample.timestamp.Duration,
+                sample.duration.Duration,
+                true,
+            )]),
+        }
+    }
+
+    fn drain(&mut self) -> pipeline::Result<Vec<EncodedPacket>> {
+        match &mut self.backend {
+            Backend::Transform(transform) => {
+                let packets = transform
+                    .drain()?
+                    .iter()
+                    .map(|output| sample_to_packet(output.sample(), StreamKind::Audio))
+                    .collect::<Result<_>>()?;
+                Ok(packets)
+            }
+            Backend::Flac(flac) => flac.drain(),
+            Backend::Pcm => Ok(Vec::new()),
+        }
     }
 }
 
@@ -381,7 +534,7 @@ fn create_audio_media_type(format: &AudioFormat) -> Result<IMFMediaType> {
     }
 }
 
-fn create_aac_output_media_type(
+pub fn create_aac_output_media_type(
     format: &AudioFormat, 
     bitrate: Option<u32>
 ) -> Result<IMFMediaType> {
@@ -404,20 +557,9 @@ fn create_aac_output_media_type(
         media_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
         
         // Calculate and set bitrate
-        let bitrate_value = bitrate.unwrap_or_else(|| {
-            // Default bitrates based on sample rate and channels if not specified
-            // These are reasonable defaults for AAC
-            match (format.sample_rate, format.channels) {
-                (sr, ch) if sr >= 48000 && ch >= 2 => 192000, // High quality stereo
-                (sr, ch) if sr >= 44100 && ch >= 2 => 128000, // CD quality stereo
-                (sr, ch) if sr >= 44100 && ch == 1 => 96000,  // CD quality mono
-                (sr, _) if sr >= 32000 => 80000,              // Medium quality
-                (sr, _) if sr >= 24000 => 64000,              // Lower quality
-                (sr, _) if sr >= 16000 => 48000,              // Voice quality
-                _ => 32000,                                   // Minimum quality
-            }
-        });
-        
+        let bitrate_value =
+            bitrate.unwrap_or_else(|| default_aac_bit_rate(format.sample_rate, format.channels));
+
         // Set bitrate (bytes per second = bits per second / 8)
         media_type.SetUINT32(&MF_MT_AUDIO_AVG_BYTES_PER_SECOND, bitrate_value / 8)?;
         
@@ -447,4 +589,21 @@ fn create_aac_output_media_type(
         
         Ok(media_type)
     }
-}
\ No newline at end of file
+}
+
+/// Creates the Opus media type an Opus encoder produces.
+fn create_opus_output_media_type(format: &AudioFormat, bit_rate: u32) -> Result<IMFMediaType> {
+    unsafe {
+        let media_type = MFCreateMediaType()?;
+        media_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Audio)?;
+        media_type.SetGUID(&MF_MT_SUBTYPE, &MFAudioFormat_Opus)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_SAMPLES_PER_SECOND, format.sample_rate)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_NUM_CHANNELS, format.channels as u32)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_AVG_BYTES_PER_SECOND, bit_rate / 8)?;
+        media_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
+        if let Some(mask) = format.channel_mask {
+            media_type.SetUINT32(&MF_MT_AUDIO_CHANNEL_MASK, mask)?;
+        }
+        Ok(media_type)
+    }
+}
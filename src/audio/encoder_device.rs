@@ -1,38 +1,55 @@
 use windows::{
-    core::{Interface, Result},
-    Win32::Media::MediaFoundation::{
-        IMFActivate, IMFTransform, MFMediaType_Audio, // Changed from Video
-        MFAudioFormat_AAC,                             // Changed from H264
-        MFT_CATEGORY_AUDIO_ENCODER,                  // Changed from Video Encoder
-        MFT_FRIENDLY_NAME_Attribute,
-        MFT_ENUM_FLAG_SORTANDFILTER,
-        MFT_ENUM_FLAG_TRANSCODE_ONLY,
-        MFT_REGISTER_TYPE_INFO,
+    core::{Error, Interface, Result},
+    Win32::{
+        Foundation::E_NOTIMPL,
+        Media::MediaFoundation::{
+            IMFActivate,
+            IMFTransform,
+            MFAudioFormat_AAC, // Changed from H264
+            MFAudioFormat_Opus,
+            MFMediaType_Audio, // Changed from Video
+            MFT_FRIENDLY_NAME_Attribute,
+            MFT_CATEGORY_AUDIO_ENCODER, // Changed from Video Encoder
+            MFT_ENUM_FLAG_SORTANDFILTER,
+            MFT_ENUM_FLAG_TRANSCODE_ONLY,
+            MFT_REGISTER_TYPE_INFO,
+        },
     },
 };
 
 // Assuming these helper functions exist in your crate::media module
 // and are generic enough to work with different MFT categories and attributes.
-use crate::media::{enumerate_mfts, get_string_attribute};
+use crate::{
+    media::{enumerate_mfts, get_string_attribute},
+    pipeline::AudioCodec,
+};
 
 unsafe impl Send for AudioEncoderDevice {}
 unsafe impl Sync for AudioEncoderDevice {}
 #[derive(Clone)]
 pub struct AudioEncoderDevice {
-    source: IMFActivate,
+    codec: AudioCodec,
+    // None for the FLAC and PCM encoders in this crate
+    source: Option<IMFActivate>,
     display_name: String,
 }
 
 impl AudioEncoderDevice {
-    /// Enumerates available hardware AAC audio encoders.
+    /// Enumerates available audio encoders for a codec. AAC and Opus are
+    /// encoded by Media Foundation transforms, and FLAC and PCM in this crate.
     ///
-    /// Note: Hardware *audio* encoders are less common than hardware video encoders.
-    /// This function might return an empty list on many systems.
-    pub fn enumerate() -> Result<Vec<AudioEncoderDevice>> {
-        // Define the desired output type: Audio / AAC
+    /// Note: Windows comes with an AAC encoder, but not an Opus one, so that
+    /// list is usually empty unless one has been installed.
+    pub fn enumerate(codec: AudioCodec) -> Result<Vec<AudioEncoderDevice>> {
+        let subtype = match codec {
+            AudioCodec::Aac => MFAudioFormat_AAC,
+            AudioCodec::Opus => MFAudioFormat_Opus,
+            AudioCodec::Flac | AudioCodec::Pcm => return Ok(vec![Self::builtin(codec)]),
+        };
+        // Define the desired output type: Audio / the codec
         let output_info = MFT_REGISTER_TYPE_INFO {
             guidMajorType: MFMediaType_Audio, // Use Audio type
-            guidSubtype: MFAudioFormat_AAC, // Specify AAC format
+            guidSubtype: subtype,
         };
 
         // Enumerate MFTs in the Audio Encoder category
@@ -42,7 +59,7 @@ impl AudioEncoderDevice {
             &MFT_CATEGORY_AUDIO_ENCODER, // Use Audio Encoder category
             MFT_ENUM_FLAG_TRANSCODE_ONLY | MFT_ENUM_FLAG_SORTANDFILTER,
             None, // No specific input requirement
-            Some(&output_info), // Require AAC output support
+            Some(&output_info), // Require support for the codec
         )?;
 
         let mut encoder_devices = Vec::new();
@@ -57,7 +74,8 @@ impl AudioEncoderDevice {
             };
 
             let encoder_device = AudioEncoderDevice {
-                source: encoder,
+                codec,
+                source: Some(encoder),
                 display_name,
             };
             encoder_devices.push(encoder_device);
@@ -65,6 +83,22 @@ impl AudioEncoderDevice {
         Ok(encoder_devices)
     }
 
+    fn builtin(codec: AudioCodec) -> Self {
+        let display_name = match codec {
+            AudioCodec::Flac => "FLAC Audio Encoder (builtin)",
+            _ => "PCM Audio (builtin)",
+        };
+        AudioEncoderDevice {
+            codec,
+            source: None,
+            display_name: display_name.to_owned(),
+        }
+    }
+
+    pub fn codec(&self) -> AudioCodec {
+        self.codec
+    }
+
     /// Returns the human-readable display name of the audio encoder.
     pub fn display_name(&self) -> &str {
         &self.display_name
@@ -73,6 +107,12 @@ impl AudioEncoderDevice {
     /// Creates and activates the underlying Media Foundation Transform (MFT) for this encoder.
     pub fn create_transform(&self) -> Result<IMFTransform> {
         // Activation process is the same
-        unsafe { self.source.ActivateObject() }
+        match &self.source {
+            Some(source) => unsafe { source.ActivateObject() },
+            None => Err(Error::new(
+                E_NOTIMPL,
+                "The builtin audio encoders aren't Media Foundation transforms.",
+            )),
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,31 +1,31 @@
-use std::{sync::{Arc, Barrier, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
 use windows::{
-    core::{imp::CoTaskMemFree, Interface, Result, HSTRING},
+    core::Result,
     Foundation::TimeSpan,
-    Graphics::SizeInt32,
-    Storage::Streams::IRandomAccessStream,
-    Win32::{
-        Graphics::{
-            Direct3D11::{
-                ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D, 
-                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX, 
-                D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT
-            },
-            Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC},
-            Gdi::HMONITOR,
-        },
-        Media::{Audio::{IAudioClient, IMMDevice}, KernelStreaming::KS_TRUECOLORINFO, MediaFoundation::{
-            IMFMediaType, IMFSample, IMFSinkWriter, MFAudioFormat_AAC, MFAudioFormat_Float, MFAudioFormat_PCM, MFCreateAttributes, MFCreateMFByteStreamOnStreamEx, MFCreateSinkWriterFromURL
-        }},
-        System::{Com::CLSCTX_ALL, Performance::QueryPerformanceFrequency}
-    },
+    Win32::Media::MediaFoundation::{MFAudioFormat_AAC, MFAudioFormat_Float, MFAudioFormat_PCM},
 };
 
-use crate::{audio::capture_audio::{CaptureAudioGenerator}, encoding_session::SampleWriter};
+use crate::{
+    audio::capture_audio::{AudioSample, CaptureAudioGenerator},
+    clock::{Clock, SystemClock},
+    dump::{PcmDump, StreamDump},
+    encoder_settings::AudioEncoderSettings,
+    mixer::{to_i16_bytes, AudioTracks, GapFiller, MixedBlock, TrackMixer},
+    pipeline::{self, SampleSource, SharedSink, StreamSession},
+    privacy::{FocusTimeline, Privacy, PRIVACY_DELAY},
+    resampler::ProcessorBackend,
+};
 
 use super::{
-    capture_audio::{AudioCaptureSession, AudioSample}, capture_microphone::{CaptureMicrophoneGenerator, MicrophoneCaptureSession}, encoder::{AudioEncoder, AudioEncoderInputSample}, encoder_device::AudioEncoderDevice, processor::{AudioFormat, AudioProcessor}
+    capture_audio::AudioCaptureSession,
+    capture_microphone::{CaptureMicrophoneGenerator, MicrophoneCaptureSession},
+    encoder::{AudioEncoder, AudioEncoderInputSample},
+    encoder_device::AudioEncoderDevice,
+    processor::{AudioFormat, AudioProcessor},
 };
 
 #[derive(Clone)]
@@ -34,204 +34,385 @@ pub enum AudioSource {
     ActiveWindow,
 }
 
-pub struct AudioEncodingSession {
+/// Which audio gets recorded, and how loud. A volume of 1.0 leaves the
+/// source unchanged.
+#[derive(Clone, Debug)]
+pub struct AudioSources {
+    pub desktop_volume: f32,
+    /// The volume of the default microphone, if it's recorded.
+    pub microphone_volume: Option<f32>,
+    /// Whether the sources are mixed into one track, each get their own,
+    /// or both.
+    pub tracks: AudioTracks,
+    /// What converts each source to the format it's mixed in.
+    pub processor: ProcessorBackend,
+    /// The ISO 639-2 code every track is tagged with, if any.
+    pub language: Option<String>,
+}
+
+// Sources are numbered in the order they are added to the track mixer
+const DESKTOP_SOURCE: usize = 0;
+const MICROPHONE_SOURCE: usize = 1;
+
+// How long a track goes without audio before it's filled in with silence.
+// Longer than the mixer waits for a source that has fallen behind, so this
+// only kicks in once every source in the track has gone quiet.
+const FILL_AFTER_MS: i64 = 200;
+
+/// The capture side, shared by the sessions encoding each track. Whichever
+/// session polls first hands everything captured so far to the track mixer.
+struct SharedCapture {
+    audio_generator: Option<CaptureAudioGenerator>,
+    microphone_generator: Option<CaptureMicrophoneGenerator>,
     audio_capture_session: Option<AudioCaptureSession>,
     microphone_capture_session: Option<MicrophoneCaptureSession>,
-    stop_signal: Arc<std::sync::atomic::AtomicBool>,
-    start_barrier: Arc<Barrier>,
-    processing_thread: Option<std::thread::JoinHandle<()>>,
+
+    // Converts each source to the mix format, in source order, along with
+    // the format each one converts from
+    processors: Vec<AudioProcessor>,
+    capture_formats: Vec<AudioFormat>,
+    processor_backend: ProcessorBackend,
+    mix_format: AudioFormat,
+    mixer: TrackMixer,
+    // How many sessions have started capturing
+    running: usize,
+
+    // Keep each track continuous from the start, and hold what's ready
+    fillers: Vec<GapFiller>,
+    blocks: Vec<VecDeque<MixedBlock>>,
+    clock: SystemClock,
+    start_time: i64,
+
+    // When private windows were in the foreground, if the audio is muted
+    // while they are
+    private_timeline: Option<Arc<FocusTimeline>>,
+    // Whether to report how far the sources' clocks drifted
+    verbose: bool,
 }
 
 struct SampleGenerator {
-    audio_processor: Option<AudioProcessor>,
-    microphone_processor: Option<AudioProcessor>,
+    capture: Arc<Mutex<SharedCapture>>,
+    track: usize,
+    // When the session was stopped, relative to the start
+    stop_time: Option<i64>,
+    // Where the encoder's input is copied, with --dump-streams
+    dump: Option<PcmDump>,
+}
 
-    audio_generator: Option<CaptureAudioGenerator>,
-    microphone_generator: Option<CaptureMicrophoneGenerator>,
+/// Creates a session for each audio track, capturing desktop audio, and the
+/// microphone if asked to, and encoding it with `settings`. Audio is muted while a
+/// private window is in the foreground if `privacy` asks for it. With a
+/// `dump`, what each track's encoder is given also goes into a WAV.
+pub fn new_audio_sessions(
+    encoder_device: &AudioEncoderDevice,
+    settings: &AudioEncoderSettings,
+    sources: &AudioSources,
+    privacy: Option<Privacy>,
+    dump: Option<&StreamDump>,
+    verbose: bool,
+    sink: SharedSink,
+) -> Result<Vec<StreamSession>> {
+    // Your existing format setup code remains the same
+    let output_format = AudioFormat {
+        sample_rate: 48000,
+        channels: 2,
+        bits_per_sample: 16,
+        channel_mask: Some(0x3),
+        format: MFAudioFormat_AAC,
+    };
 
-    seen_first_time_stamp: bool,
-    first_timestamp: i64,
+    let capture_format = AudioFormat {
+        sample_rate: 48000,
+        channels: 2,
+        bits_per_sample: 16,
+        channel_mask: Some(3),
+        format: MFAudioFormat_PCM,
+    };
 
-    frame_period: i64,
-    next_frame_time: TimeSpan,
-    total_samples_processed: i64,
+    let bit_rate = settings.bit_rate(output_format.sample_rate, output_format.channels);
+    let source_count = 1 + sources.microphone_volume.is_some() as usize;
+    let layout = sources.tracks.layout(source_count);
+    let private_timeline = privacy
+        .filter(|privacy| privacy.mute_audio)
+        .map(|privacy| privacy.timeline);
+    let capture = Arc::new(Mutex::new(SharedCapture::new(
+        AudioSource::Desktop,
+        sources,
+        &output_format,
+        &layout,
+        private_timeline,
+        verbose,
+    )?));
+
+    let mut sessions = Vec::new();
+    for (track, track_sources) in layout.iter().enumerate() {
+        let mut audio_encoder = AudioEncoder::new(
+            encoder_device,
+            capture_format.clone(),
+            output_format.clone(),
+            bit_rate,
+        )?;
+        // Only worth naming once there's more than one track to pick from
+        if layout.len() > 1 {
+            audio_encoder.set_name(track_name(track_sources));
+        }
+        if let Some(language) = &sources.language {
+            audio_encoder.set_language(language);
+        }
+
+        let sample_generator = SampleGenerator {
+            capture: capture.clone(),
+            track,
+            stop_time: None,
+            dump: dump
+                .map(|dump| {
+                    PcmDump::create(
+                        dump,
+                        track,
+                        output_format.sample_rate,
+                        output_format.channels,
+                    )
+                })
+                .transpose()?,
+        };
+        sessions.push(StreamSession::audio(
+            sample_generator,
+            audio_encoder,
+            sink.clone(),
+        )?);
+    }
+    Ok(sessions)
 }
 
-impl AudioEncodingSession {
+fn track_name(sources: &[usize]) -> &'static str {
+    match sources {
+        [DESKTOP_SOURCE] => "Desktop audio",
+        [MICROPHONE_SOURCE] => "Microphone",
+        _ => "Mixed audio",
+    }
+}
+
+unsafe impl Send for SharedCapture {}
+impl SharedCapture {
     pub fn new(
-        encoder_device: &AudioEncoderDevice,
-        bit_rate: u32,
-        sample_writer: Arc<Mutex<SampleWriter>>,
+        audio_source: AudioSource,
+        sources: &AudioSources,
+        output_format: &AudioFormat,
+        layout: &[Vec<usize>],
+        private_timeline: Option<Arc<FocusTimeline>>,
+        verbose: bool,
     ) -> Result<Self> {
-        // Your existing format setup code remains the same
-        let output_format = AudioFormat {
-            sample_rate: 48000,
-            channels: 2,
-            bits_per_sample: 16,
-            channel_mask: Some(0x3),
-            format: MFAudioFormat_AAC,
+        // Everything is mixed as floats in the encoder's rate and layout
+        let mix_format = AudioFormat {
+            bits_per_sample: 32,
+            format: MFAudioFormat_Float,
+            ..output_format.clone()
         };
-    
-        let capture_format = AudioFormat {
-            sample_rate: 48000,
-            channels: 2,
-            bits_per_sample: 16,
-            channel_mask: Some(3),
-            format: MFAudioFormat_PCM,
-        };
-    
-        // Create the sample generator outside the thread as it's still needed
-        let sample_generator = SampleGenerator::new(
-            true,
-            AudioSource::Desktop,
-            false,
-            None,
-            output_format.clone(),
-            None,
-        )?;
-        
-        // Store references to capture sessions
-        let audio_capture_session = sample_generator.audio_capture_session().clone();
-        let microphone_capture_session = sample_generator.microphone_capture_session().clone();
-    
-        // Only wrap the sample generator in a thread-safe wrapper
-        let sample_generator = Arc::new(std::sync::Mutex::new(sample_generator));
-        
-        // Clone for thread
-        let sample_generator_thread = sample_generator.clone();
-        
-        // Capture encoder configuration for the thread
-        let encoder_device_clone = encoder_device.clone();
-        let output_format_clone = output_format.clone();
-        let capture_format_clone = capture_format.clone();
-        
-        // Create a barrier for 2 threads: the main thread and the worker thread
-        let start_barrier = Arc::new(Barrier::new(2));
-        let start_barrier_thread = start_barrier.clone();
-
-        // Use a separate signal for stopping
-        let stop_signal = Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let stop_signal_thread = stop_signal.clone();
-        
-        // Create the processing thread
-        let processing_thread = std::thread::spawn(move || {
-            // Create the audio encoder inside the thread
-            let mut audio_encoder = match AudioEncoder::new(
-                &encoder_device_clone,
-                capture_format_clone,
-                output_format_clone,
-                None
-            ) {
-                Ok(encoder) => encoder,
-                Err(e) => {
-                    eprintln!("Failed to create audio encoder in thread: {:?}", e);
-                    return; // Exit thread if encoder creation fails
-                }
-            };
-            let _ = sample_writer.lock().unwrap().add_audio_stream(audio_encoder.output_media_type());
-            println!("created audio encoder");
-
-            println!("Audio thread waiting on barrier...");
-            start_barrier_thread.wait();
-            println!("Audio thread proceeding past barrier.");
-            
-            while !stop_signal_thread.load(std::sync::atomic::Ordering::Relaxed) {
-                // Try to get the next sample
-                if let Ok(mut generator) = sample_generator_thread.lock() {
-                    match generator.generate() {
-                        Ok(Some(sample)) => {
-                            // Process the sample with the encoder (no mutex needed now)
-                            match audio_encoder.process_sample(&sample) {
-                                Ok(Some(encoded_sample)) => {
-                                    // Write the encoded sample and remove buffers
-                                    {
-                                        let writer = sample_writer.lock().unwrap();
-                                        if let Err(e) = writer.write_audio_sample(encoded_sample.sample()) {
-                                            eprintln!("Error writing audio sample: {:?}", e);
-                                        }
-                                    }
-                                    // Explicitly drop the sample to force COM Release
-                                    drop(encoded_sample);
-                                },
-                                Ok(None) => {
-                                    // No encoded sample was produced, perhaps buffering
-                                    // This is normal for some encoders
-                                },
-                                Err(e) => eprintln!("Error processing audio sample: {:?}", e),
-                            }
-                        },
-                        Ok(None) => {
-                            // No sample available, sleep briefly to avoid busy-waiting
-                            std::thread::sleep(std::time::Duration::from_millis(5));
-                        },
-                        Err(e) => eprintln!("Error generating audio sample: {:?}", e),
-                    }
-                }
-            }
-            
-            // Drain any buffered samples when stopping
-            match audio_encoder.drain() {
-                Ok(encoded_samples) => {
-                    // Write any remaining encoded samples
-                    for encoded_sample in encoded_samples {
-                        // Write the drained encoded sample and remove buffers
-                        {
-                            let writer = sample_writer.lock().unwrap();
-                            if let Err(e) = writer.write_audio_sample(encoded_sample.sample()) {
-                                eprintln!("Error writing drained audio sample: {:?}", e);
-                            }
-                        }
-                        // Explicitly drop the sample to force COM Release
-                        drop(encoded_sample);
-                    }
-                },
-                Err(e) => eprintln!("Error draining audio encoder: {:?}", e),
-            }
-        });
-        
+
+        let audio_generator = CaptureAudioGenerator::new(audio_source)?;
+        let mut capture_formats = vec![capture_format(
+            audio_generator.get_sample_rate(),
+            audio_generator.get_channels(),
+            audio_generator.get_bits_per_sample(),
+            audio_generator.is_float(),
+            audio_generator.get_channel_mask(),
+        )];
+        let mut processors = vec![AudioProcessor::new(
+            sources.processor,
+            capture_formats[DESKTOP_SOURCE].clone(),
+            mix_format.clone(),
+        )?];
+        let mut gains = vec![sources.desktop_volume];
+        let audio_generator = Some(audio_generator);
+
+        let mut microphone_generator = None;
+        if let Some(volume) = sources.microphone_volume {
+            let generator = CaptureMicrophoneGenerator::new()?;
+            capture_formats.push(capture_format(
+                generator.get_sample_rate(),
+                generator.get_channels(),
+                generator.get_bits_per_sample(),
+                generator.is_float(),
+                generator.get_channel_mask(),
+            ));
+            processors.push(AudioProcessor::new(
+                sources.processor,
+                capture_formats[MICROPHONE_SOURCE].clone(),
+                mix_format.clone(),
+            )?);
+            gains.push(volume);
+            microphone_generator = Some(generator);
+        }
+
+        let mix_pcm_format = mix_format.pcm_format()?;
+        let source_formats: Vec<_> = gains
+            .into_iter()
+            .map(|gain| (mix_pcm_format, gain))
+            .collect();
+        let mixer = TrackMixer::new(
+            output_format.sample_rate,
+            output_format.channels,
+            &source_formats,
+            layout,
+        );
+
+        let audio_capture_session = audio_generator.as_ref().map(|gen| gen.session().clone());
+        let microphone_capture_session = microphone_generator
+            .as_ref()
+            .map(|gen: &CaptureMicrophoneGenerator| gen.session().clone());
+
         Ok(Self {
+            audio_generator,
+            microphone_generator,
             audio_capture_session,
             microphone_capture_session,
-            stop_signal, // Store the stop signal
-            start_barrier, // Store the barrier
-            processing_thread: Some(processing_thread),
+
+            processors,
+            capture_formats,
+            processor_backend: sources.processor,
+            mix_format,
+            mixer,
+            running: 0,
+
+            fillers: layout
+                .iter()
+                .map(|_| GapFiller::new(output_format.sample_rate, output_format.channels))
+                .collect(),
+            blocks: layout.iter().map(|_| VecDeque::new()).collect(),
+            clock: SystemClock::new()?,
+            start_time: 0,
+
+            private_timeline,
+            verbose,
         })
     }
 
-    pub fn start(&mut self, start_qpc: i64) -> Result<()> {
-        // Start the capture sessions
-        if let Some(session) = &mut self.audio_capture_session {
-            session.StartCapture(start_qpc)?;
+    /// The time relative to the start of capture, in 100ns units.
+    fn now(&self) -> i64 {
+        self.clock.now() - self.start_time
+    }
+
+    pub fn generate(&mut self, track: usize) -> Result<Option<AudioEncoderInputSample>> {
+        self.capture()?;
+        while let Some(block) = self.mixer.next_block(track) {
+            self.blocks[track].extend(self.fillers[track].fill(block));
         }
-        if let Some(session) = &mut self.microphone_capture_session {
-            session.StartCapture(start_qpc)?;
+        // Loopback delivers nothing while nothing plays, but the track
+        // mustn't stop
+        if self.blocks[track].is_empty() {
+            let silent_until = self.now() - FILL_AFTER_MS * 10_000;
+            self.blocks[track].extend(self.fillers[track].silence_until(silent_until));
         }
+        Ok(self.next_block(track).map(encoder_sample))
+    }
 
-        // Signal the processing thread to start its loop by waiting on the barrier
-        // This call will block until the worker thread also calls wait().
-        println!("Main thread waiting on barrier...");
-        self.start_barrier.wait();
-        println!("Main thread proceeding past barrier.");
+    /// The next block that's ready for a track. When muting private windows,
+    /// blocks are held back until any focus change during them would have
+    /// been reported.
+    fn next_block(&mut self, track: usize) -> Option<MixedBlock> {
+        let Some(timeline) = &self.private_timeline else {
+            return self.blocks[track].pop_front();
+        };
+        let block = self.blocks[track].front()?;
+        if block.timestamp + block.duration > self.now() - PRIVACY_DELAY {
+            return None;
+        }
+        let mut block = self.blocks[track].pop_front()?;
+        timeline.mute(&mut block, self.start_time);
+        Some(block)
+    }
 
-        // Note: The stop_signal remains false here.
+    /// Everything left for a track once capture has stopped, with silence
+    /// up to `stop_time` if it ends before then.
+    pub fn drain(&mut self, track: usize, stop_time: i64) -> Result<Vec<AudioEncoderInputSample>> {
+        self.capture()?;
+        while let Some(block) = self.mixer.flush(track) {
+            self.blocks[track].extend(self.fillers[track].fill(block));
+        }
+        self.blocks[track].extend(self.fillers[track].silence_until(stop_time));
+        let mut blocks: Vec<_> = self.blocks[track].drain(..).collect();
+        if let Some(timeline) = &self.private_timeline {
+            for block in &mut blocks {
+                timeline.mute(block, self.start_time);
+            }
+        }
+        Ok(blocks.into_iter().map(encoder_sample).collect())
+    }
 
+    /// Hands everything captured so far to the mixer.
+    fn capture(&mut self) -> Result<()> {
+        while let Some(generator) = &mut self.audio_generator {
+            let Some(sample) = generator.try_get_audio_sample() else {
+                break;
+            };
+            let format = capture_format(
+                generator.get_sample_rate(),
+                generator.get_channels(),
+                generator.get_bits_per_sample(),
+                generator.is_float(),
+                generator.get_channel_mask(),
+            );
+            self.push(DESKTOP_SOURCE, &format, &sample)?;
+        }
+        while let Some(generator) = &mut self.microphone_generator {
+            let Some(sample) = generator.try_get_audio_sample() else {
+                break;
+            };
+            let format = capture_format(
+                generator.get_sample_rate(),
+                generator.get_channels(),
+                generator.get_bits_per_sample(),
+                generator.is_float(),
+                generator.get_channel_mask(),
+            );
+            self.push(MICROPHONE_SOURCE, &format, &sample)?;
+        }
         Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<()> {
-        // Set the stop signal to true to stop the processing thread's loop
-        self.stop_signal.store(true, std::sync::atomic::Ordering::Relaxed);
-        println!("Stop signal sent.");
+    /// Converts a sample from a source and mixes it in. The capture thread
+    /// reports the format the device really delivers once it starts, which
+    /// isn't the one the converter was made for if the default device
+    /// changed in between, so then the converter is made again.
+    fn push(&mut self, source: usize, format: &AudioFormat, sample: &AudioSample) -> Result<()> {
+        if *format != self.capture_formats[source] {
+            println!(
+                "The audio device changed, now capturing {}Hz with {} channels.",
+                format.sample_rate, format.channels
+            );
+            self.processors[source] = AudioProcessor::new(
+                self.processor_backend,
+                format.clone(),
+                self.mix_format.clone(),
+            )?;
+            self.capture_formats[source] = format.clone();
+        }
+        let data = self.processors[source].process(&sample.data)?;
+        self.mixer.push(source, sample.timestamp.Duration, &data);
+        Ok(())
+    }
 
-        // Wait for the processing thread to finish
-        if let Some(thread) = self.processing_thread.take() {
-            println!("Joining audio processing thread...");
-            if let Err(e) = thread.join() {
-                eprintln!("Error joining processing thread: {:?}", e);
-            }
-            println!("Audio processing thread joined.");
+    fn start(&mut self, start_qpc: i64) -> Result<()> {
+        self.running += 1;
+        if self.running > 1 {
+            return Ok(());
+        }
+        self.start_time = start_qpc;
+        // Start the capture sessions
+        if let Some(session) = &mut self.audio_capture_session {
+            session.StartCapture(start_qpc)?;
         }
+        if let Some(session) = &mut self.microphone_capture_session {
+            session.StartCapture(start_qpc)?;
+        }
+        Ok(())
+    }
 
+    fn stop(&mut self) -> Result<()> {
+        self.running = self.running.saturating_sub(1);
+        if self.running > 0 {
+            return Ok(());
+        }
         // Stop the capture sessions
         if let Some(session) = &mut self.audio_capture_session {
             session.StopCapture()?;
@@ -239,210 +420,95 @@ impl AudioEncodingSession {
         if let Some(session) = &mut self.microphone_capture_session {
             session.StopCapture()?;
         }
-
+        if self.verbose {
+            for (source, name) in [
+                (DESKTOP_SOURCE, "Desktop"),
+                (MICROPHONE_SOURCE, "Microphone"),
+            ] {
+                if let Some(ppm) = self.mixer.drift_ppm(source) {
+                    println!("{} audio clock drift: {:+.0} ppm", name, ppm);
+                }
+            }
+        }
         Ok(())
     }
 }
 
-unsafe impl Send for SampleGenerator {}
-impl SampleGenerator {
-    pub fn new(
-        capture_audio: bool,
-        audio_source: AudioSource,
-        capture_microphone: bool,
-        microphone_device: Option<IMMDevice>,
-        output_format: AudioFormat,
-        quality: Option<u32>,
-    ) -> Result<Self> {
-        // Initialize variables to be used in conditionals
-        let mut audio_generator = None;
-        let mut audio_processor = None;
-        let mut microphone_generator = None;
-        let mut microphone_processor = None;
-
-        if capture_audio {
-            // Create the audio generator
-            audio_generator = Some(CaptureAudioGenerator::new(audio_source)?);
-            // Start capture and wait for initialization with 500ms timeout
-            /*audio_processor = Some(AudioProcessor::new(
-                audio_input_format,
-                output_format.clone(),
-                quality,
-            )?);*/
-        }
-
-        /*if capture_microphone {
-            if let Some(device) = &microphone_device {
-                // Create the microphone generator
-                let mut temp_microphone_generator = CaptureMicrophoneGenerator::new(device.clone())?;
-                
-                // Start capture and wait for initialization with 500ms timeout
-                temp_microphone_generator.start_capture_and_wait(500)?;
-                
-                // Create the audio processor with the microphone format from the generator
-                let microphone_input_format = AudioFormat {
-                    sample_rate: temp_microphone_generator.get_sample_rate(),
-                    channels: temp_microphone_generator.get_channels(),
-                    bits_per_sample: temp_microphone_generator.get_bits_per_sample(),
-                };
-                
-                microphone_generator = Some(temp_microphone_generator);
-                microphone_processor = Some(AudioProcessor::new(
-                    microphone_input_format,
-                    output_format.clone(),
-                    quality,
-                )?);
-            }
-        }*/
+fn encoder_sample(block: MixedBlock) -> AudioEncoderInputSample {
+    AudioEncoderInputSample::new(
+        to_i16_bytes(&block.samples),
+        TimeSpan {
+            Duration: block.timestamp,
+        },
+        TimeSpan {
+            Duration: block.duration,
+        },
+        block.frames,
+    )
+}
 
-        Ok(Self {
-            audio_generator,
-            audio_processor,
-            microphone_generator,
-            microphone_processor,
-
-            seen_first_time_stamp: false,
-            first_timestamp: 0,
-        
-            frame_period: 0,
-            next_frame_time: TimeSpan::default(),
-            total_samples_processed: 0,
-        })
+/// Describes what a capture generator delivers, in whatever format the
+/// device mixes in.
+fn capture_format(
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: u16,
+    float: bool,
+    channel_mask: Option<u32>,
+) -> AudioFormat {
+    AudioFormat {
+        sample_rate,
+        channels,
+        bits_per_sample,
+        channel_mask,
+        format: if float {
+            MFAudioFormat_Float
+        } else {
+            MFAudioFormat_PCM
+        },
     }
+}
 
-    pub fn audio_capture_session(&self) -> Option<AudioCaptureSession> {
-        self.audio_generator.as_ref().map(|gen| gen.session().clone())
-    }
+impl SampleSource for SampleGenerator {
+    type Samples = AudioEncoderInputSample;
 
-    pub fn microphone_capture_session(&self) -> Option<MicrophoneCaptureSession> {
-        self.microphone_generator.as_ref().map(|gen| gen.session().clone())
+    fn start(&mut self, start_qpc: i64) -> pipeline::Result<()> {
+        self.capture.lock().unwrap().start(start_qpc)?;
+        Ok(())
     }
 
-    pub fn generate(&mut self) -> Result<Option<AudioEncoderInputSample>> {
-        // Try to get audio samples from both sources
-        let audio_sample = if let Some(generator) = &mut self.audio_generator {
-            generator.try_get_audio_sample()
-        } else {
-            None
-        };
-        
-        /*let mic_sample = if let Some(generator) = &mut self.microphone_generator {
-            generator.try_get_audio_sample()
-        } else {
-            None
-        };*/
-        
-        // Check if we have both audio and mic samples
-        /*if let (Some(audio), Some(mic)) = (&audio_sample, &mic_sample) {
-            // Both sources available, mix them
-            println!("we have both");
-            return Ok(Some(self.mix_audio_samples(audio.clone(), mic.clone())?));
-        }*/
-        
-        // If we didn't have both, check for individual sources
-        if let Some(audio) = audio_sample {
-            // Only system audio available
-            return Ok(Some(self.convert_to_encoder_input(audio)?));
+    fn next_samples(&mut self) -> pipeline::Result<Option<AudioEncoderInputSample>> {
+        let samples = self.capture.lock().unwrap().generate(self.track)?;
+        if let (Some(dump), Some(samples)) = (&mut self.dump, &samples) {
+            dump.write(
+                &samples.data,
+                samples.timestamp.Duration,
+                samples.duration.Duration,
+            )?;
         }
-        
-        /*if let Some(mic) = mic_sample {
-            println!("we have mic");
-            // Only microphone audio available
-            return Ok(Some(self.convert_to_encoder_input(mic)?));
-        }*/
-        
-        // No samples available
-        Ok(None)
+        Ok(samples)
     }
-    
-    fn stop_capture(&mut self) -> Result<()> {
-        // Stop audio capture if it exists
-        if let Some(generator) = &mut self.audio_generator {
-            generator.stop_capture()?;
-        }
-        
-        // Stop microphone capture if it exists
-        if let Some(generator) = &mut self.microphone_generator {
-            generator.stop_capture()?;
-        }
-        
+
+    fn stop(&mut self) -> pipeline::Result<()> {
+        let mut capture = self.capture.lock().unwrap();
+        self.stop_time = Some(capture.now());
+        capture.stop()?;
         Ok(())
     }
-    
-    // Helper to convert float samples to bytes - still needed for mixing
-    fn float_to_bytes(&self, samples: &[f32]) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(samples.len() * 4);
-        for sample in samples {
-            let sample_bytes = sample.to_le_bytes();
-            bytes.extend_from_slice(&sample_bytes);
-        }
-        bytes
-    }
-    
-    fn mix_audio_samples(
-        &mut self,
-        audio_sample: &AudioSample,
-        mic_sample: &AudioSample
-    ) -> Result<AudioEncoderInputSample> {
-        // Extract audio data from both samples
-        let audio_data = self.extract_float_data_from_audio_sample(&audio_sample);
-        let mic_data = self.extract_float_data_from_audio_sample(&mic_sample);
-        
-        // Create a new buffer for the mixed audio
-        let mix_len = audio_data.len().min(mic_data.len());
-        let mut mixed_data = vec![0.0f32; mix_len];
-        
-        // Apply mixing with level control
-        let audio_level = 0.7;
-        let mic_level = 0.3;
-        
-        // Mix the data
-        for i in 0..mix_len {
-            mixed_data[i] = audio_data[i] * audio_level + mic_data[i] * mic_level;
-        }
-        
-        // Convert mixed float data to bytes
-        let mixed_bytes = self.float_to_bytes(&mixed_data);
-        
-        // Use timestamp and duration from audio_sample for the mixed result
-        // (We could also choose the earlier timestamp if they differ, but
-        // they should be synchronized in most cases)
-        Ok(AudioEncoderInputSample::new(
-            mixed_bytes,
-            audio_sample.timestamp,
-            audio_sample.duration,
-            0
-        ))
-    }
-    
-    // Helper to extract float data from an AudioSample
-    fn extract_float_data_from_audio_sample(&self, sample: &AudioSample) -> Vec<f32> {
-        // Calculate how many float samples we have
-        let bytes_per_sample = 4; // 32-bit float
-        let sample_count = sample.data.len() / bytes_per_sample;
-        
-        // Create a vector to hold the samples
-        let mut samples = vec![0.0f32; sample_count];
-        
-        // Convert the bytes to floats
-        for i in 0..sample_count {
-            let start = i * bytes_per_sample;
-            let mut byte_array = [0u8; 4];
-            byte_array.copy_from_slice(&sample.data[start..start+4]);
-            samples[i] = f32::from_le_bytes(byte_array);
+
+    fn drain(&mut self) -> pipeline::Result<Vec<AudioEncoderInputSample>> {
+        let stop_time = self.stop_time.unwrap_or_default();
+        let samples = self.capture.lock().unwrap().drain(self.track, stop_time)?;
+        if let Some(dump) = &mut self.dump {
+            for samples in &samples {
+                dump.write(
+                    &samples.data,
+                    samples.timestamp.Duration,
+                    samples.duration.Duration,
+                )?;
+            }
+            dump.finish()?;
         }
-        
-        samples
-    }
-    
-    // Helper to convert AudioSample to AudioEncoderInputSample
-    fn convert_to_encoder_input(&self, audio_sample: AudioSample) -> Result<AudioEncoderInputSample> {
-        Ok(AudioEncoderInputSample::new(
-            audio_sample.data,
-            audio_sample.timestamp,
-            audio_sample.duration,
-            0
-        ))
+        Ok(samples)
     }
-    
-}
\ No newline at end of file
+}
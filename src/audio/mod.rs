@@ -3,4 +3,4 @@ pub mod capture_microphone;
 pub mod encoder;
 pub mod encoder_device;
 pub mod encoding_session;
-mod processor;
\ No newline at end of file
+pub mod processor;
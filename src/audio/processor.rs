@@ -4,7 +4,36 @@ use windows::{
         Foundation::{CloseHandle, DECIMAL, HANDLE, S_OK, PROPERTYKEY},
         Media::MediaFoundation::{
             // Interfaces
-            IMFActivate, IMFCollection, IMFMediaBuffer, IMFMediaType, IMFSample, IMFTransform, MFAudioConstriction, MFAudioFormat_Float, MFAudioFormat_PCM, MFCreateMediaType, MFCreateMemoryBuffer, MFCreateSample, MFMediaType_Audio, MFShutdown, MFStartup, MFVideoInterlace_Progressive, MFT_OUTPUT_DATA_BUFFER, MFT_OUTPUT_STREAM_INFO, MF_E_TRANSFORM_NEED_MORE_INPUT, MF_MT_AUDIO_AVG_BYTES_PER_SECOND, MF_MT_AUDIO_BITS_PER_SAMPLE, MF_MT_AUDIO_BLOCK_ALIGNMENT, MF_MT_AUDIO_CHANNEL_MASK, MF_MT_AUDIO_NUM_CHANNELS, MF_MT_AUDIO_SAMPLES_PER_SECOND, MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE, MF_MT_SUBTYPE, MF_VERSION
+            IMFActivate,
+            IMFCollection,
+            IMFMediaBuffer,
+            IMFMediaType,
+            IMFSample,
+            IMFTransform,
+            MFAudioConstriction,
+            MFAudioFormat_Float,
+            MFAudioFormat_PCM,
+            MFCreateMediaType,
+            MFCreateMemoryBuffer,
+            MFCreateSample,
+            MFMediaType_Audio,
+            MFShutdown,
+            MFStartup,
+            MFVideoInterlace_Progressive,
+            MFT_OUTPUT_DATA_BUFFER,
+            MFT_OUTPUT_STREAM_INFO,
+            MF_E_INVALIDMEDIATYPE,
+            MF_E_TRANSFORM_NEED_MORE_INPUT,
+            MF_MT_AUDIO_AVG_BYTES_PER_SECOND,
+            MF_MT_AUDIO_BITS_PER_SAMPLE,
+            MF_MT_AUDIO_BLOCK_ALIGNMENT,
+            MF_MT_AUDIO_CHANNEL_MASK,
+            MF_MT_AUDIO_NUM_CHANNELS,
+            MF_MT_AUDIO_SAMPLES_PER_SECOND,
+            MF_MT_INTERLACE_MODE,
+            MF_MT_MAJOR_TYPE,
+            MF_MT_SUBTYPE,
+            MF_VERSION,
             // MFPKEY_WMRESAMP_CHANNELMTX, // Add if custom matrix needed
             // MFPKEY_WMRESAMP_LOWPASS_BANDWIDTH, // Add if needed
         },
@@ -25,6 +54,8 @@ use windows::{
 
 use std::mem::{ManuallyDrop};
 
+use crate::resampler::{FormatConverter, PcmFormat, ProcessorBackend, SampleEncoding};
+
 // CLSID for the Resampler DSP
 const CLSID_CResamplerMediaObject: GUID = GUID::from_u128(0xf447b69e_1884_4a7e_8055_346f74d6edb3);
 
@@ -48,9 +79,66 @@ impl AudioFormat {
     pub fn avg_bytes_per_second(&self) -> u32 {
         self.sample_rate * self.block_align() as u32
     }
+
+    /// The same format for the builtin converter, if it's PCM it can handle.
+    pub fn pcm_format(&self) -> Result<PcmFormat> {
+        let float = self.format == MFAudioFormat_Float;
+        if !float && self.format != MFAudioFormat_PCM {
+            return Err(windows::core::Error::new(
+                MF_E_INVALIDMEDIATYPE,
+                "Only PCM and float audio can be converted",
+            ));
+        }
+        let encoding = SampleEncoding::from_bits(self.bits_per_sample, float).ok_or_else(|| {
+            windows::core::Error::new(
+                MF_E_INVALIDMEDIATYPE,
+                format!("Unsupported sample size: {} bits", self.bits_per_sample),
+            )
+        })?;
+        Ok(PcmFormat {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            encoding,
+            channel_mask: self.channel_mask,
+        })
+    }
+}
+
+/// Converts PCM audio between formats, with either backend.
+pub enum AudioProcessor {
+    MediaFoundation(ResamplerTransform),
+    Builtin(FormatConverter),
 }
 
-pub struct AudioProcessor {
+impl AudioProcessor {
+    pub fn new(
+        backend: ProcessorBackend,
+        input_format: AudioFormat,
+        output_format: AudioFormat,
+    ) -> Result<Self> {
+        match backend {
+            ProcessorBackend::MediaFoundation => Ok(Self::MediaFoundation(
+                ResamplerTransform::new(input_format, output_format, None)?,
+            )),
+            ProcessorBackend::Builtin => Ok(Self::Builtin(FormatConverter::new(
+                input_format.pcm_format()?,
+                output_format.pcm_format()?,
+            ))),
+        }
+    }
+
+    /// Converts a packet of audio. Both backends hold on to some of the
+    /// input until they have what comes after it.
+    pub fn process(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::MediaFoundation(transform) => transform.process(data),
+            Self::Builtin(converter) => Ok(converter.convert(data)),
+        }
+    }
+}
+
+/// The Windows resampler DSP.
+pub struct ResamplerTransform {
     resampler_transform: IMFTransform,
     input_media_type: IMFMediaType,
     output_media_type: IMFMediaType,
@@ -59,7 +147,7 @@ pub struct AudioProcessor {
     output_buffer_size: u32,
 }
 
-impl AudioProcessor {
+impl ResamplerTransform {
     pub fn new(input_format: AudioFormat, output_format: AudioFormat, quality: Option<u32>) -> Result<Self> {
         // Create the Resampler DSP instance
         let resampler_transform: IMFTransform = unsafe {
@@ -181,6 +269,42 @@ impl AudioProcessor {
             }
 
             // 2. Try to get output
+            self.next_output()
+        }
+    }
+
+    /// Converts a packet of raw audio, returning all the output it completes.
+    pub fn process(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        unsafe {
+            let input_buffer = MFCreateMemoryBuffer(data.len() as u32)?;
+            let mut buffer_data: *mut u8 = std::ptr::null_mut();
+            input_buffer.Lock(&mut buffer_data, None, None)?;
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buffer_data, data.len());
+            input_buffer.SetCurrentLength(data.len() as u32)?;
+            input_buffer.Unlock()?;
+            let input_sample = MFCreateSample()?;
+            input_sample.AddBuffer(&input_buffer)?;
+
+            self.resampler_transform
+                .ProcessInput(self.input_stream_id, &input_sample, 0)?;
+
+            // Keep asking until it wants more input
+            let mut output = Vec::new();
+            while let Some(sample) = self.next_output()? {
+                let buffer = sample.ConvertToContiguousBuffer()?;
+                let mut output_data: *mut u8 = std::ptr::null_mut();
+                let mut length = 0;
+                buffer.Lock(&mut output_data, None, Some(&mut length))?;
+                output.extend_from_slice(std::slice::from_raw_parts(output_data, length as usize));
+                buffer.Unlock()?;
+            }
+            Ok(output)
+        }
+    }
+
+    /// Gets the next output sample, if the transform has one ready.
+    fn next_output(&mut self) -> Result<Option<IMFSample>> {
+        unsafe {
             loop {
                  // Create necessary structures for ProcessOutput
 
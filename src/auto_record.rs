@@ -0,0 +1,438 @@
+//! Records only while the right window is in the foreground. Rules pick the
+//! windows by title, executable or window class, and `AutoRecorder` pauses,
+//! blanks or splits the recording as focus moves on and off them.
+
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use regex_lite::Regex;
+
+use crate::{
+    clock::PausableClock,
+    window_target::{is_process, parse_title_pattern},
+};
+
+/// The window that has just come to the foreground.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FocusedWindow {
+    pub title: String,
+    /// The file name of the executable that owns the window.
+    pub process: String,
+    pub class: String,
+}
+
+/// What a rule looks at.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// A regular expression, ignoring case.
+    Title(Regex),
+    /// An executable name, with or without the `.exe`.
+    Process(String),
+    /// A window class name, ignoring case.
+    Class(String),
+}
+
+impl Matcher {
+    /// Parses `title`, `process` or `class`, then what to match, as in
+    /// `process game.exe`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kind, value) = split_word(text);
+        if value.is_empty() {
+            return Err("expecting title, process or class, then a value".to_owned());
+        }
+        match kind.to_lowercase().as_str() {
+            "title" => Ok(Matcher::Title(parse_title_pattern(value)?)),
+            "process" => Ok(Matcher::Process(value.to_owned())),
+            "class" => Ok(Matcher::Class(value.to_owned())),
+            _ => Err(format!("`{}` isn't title, process or class", kind)),
+        }
+    }
+
+    pub fn matches(&self, window: &FocusedWindow) -> bool {
+        match self {
+            Matcher::Title(pattern) => pattern.is_match(&window.title),
+            Matcher::Process(name) => is_process(&window.process, name),
+            Matcher::Class(class) => window.class.eq_ignore_ascii_case(class),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Rule {
+    Include(Matcher),
+    Exclude(Matcher),
+}
+
+/// Decides which windows are recorded. Exclude rules win over include
+/// rules, and with no include rules every window that isn't excluded is
+/// recorded.
+#[derive(Clone, Debug, Default)]
+pub struct RecordRules {
+    rules: Vec<Rule>,
+}
+
+impl RecordRules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    /// Parses one rule per line: `include` or `exclude`, then `title`,
+    /// `process` or `class`, then what to match, as in
+    /// `exclude title - Private Browsing$`. Blank lines and lines starting
+    /// with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        parse_lines(text, parse_rule).map(Self::new)
+    }
+
+    pub fn should_record(&self, window: &FocusedWindow) -> bool {
+        let mut included = None;
+        for rule in &self.rules {
+            match rule {
+                Rule::Exclude(matcher) if matcher.matches(window) => return false,
+                Rule::Include(matcher) => {
+                    included = Some(included.unwrap_or(false) || matcher.matches(window))
+                }
+                Rule::Exclude(_) => {}
+            }
+        }
+        included.unwrap_or(true)
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let (action, rest) = split_word(line);
+    match action.to_lowercase().as_str() {
+        "include" => Ok(Rule::Include(Matcher::parse(rest)?)),
+        "exclude" => Ok(Rule::Exclude(Matcher::parse(rest)?)),
+        _ => Err(format!("`{}` isn't include or exclude", action)),
+    }
+}
+
+/// Parses each line of a rules file that isn't blank or a comment (starting
+/// with `#`). Errors say which line they're on.
+pub fn parse_lines<T>(
+    text: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    let mut parsed = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        parsed.push(parse(line).map_err(|error| format!("line {}: {}", index + 1, error))?);
+    }
+    Ok(parsed)
+}
+
+/// The first word of `text`, and the rest of it trimmed.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+/// What happens to the recording while a window the rules don't allow is in
+/// the foreground.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OnFocusLost {
+    /// Pauses the recording, leaving the time out of it.
+    Pause,
+    /// Keeps recording black frames.
+    Blank,
+    /// Pauses the recording, and carries on in a new file.
+    Split,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseOnFocusLostError(&'static str);
+
+impl FromStr for OnFocusLost {
+    type Err = ParseOnFocusLostError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pause" => Ok(OnFocusLost::Pause),
+            "blank" => Ok(OnFocusLost::Blank),
+            "split" => Ok(OnFocusLost::Split),
+            _ => Err(ParseOnFocusLostError(
+                "Invalid on-focus-lost value! Expecting: pause, blank, or split.",
+            )),
+        }
+    }
+}
+
+impl Display for OnFocusLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnFocusLost::Pause => write!(f, "pause"),
+            OnFocusLost::Blank => write!(f, "blank"),
+            OnFocusLost::Split => write!(f, "split"),
+        }
+    }
+}
+
+impl Display for ParseOnFocusLostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseOnFocusLostError {}
+
+/// Applies the rules to a running recording as focus changes.
+pub struct AutoRecorder {
+    rules: RecordRules,
+    on_focus_lost: OnFocusLost,
+    clock: PausableClock,
+    // Cleared while frames should be left black
+    recording_window: Arc<AtomicBool>,
+    // Set to have the sink start a new file on the next keyframe
+    split_request: Option<Arc<AtomicBool>>,
+    // Whether the last window to have focus is recorded
+    recording: Option<bool>,
+    // Whether the recording was paused because of that
+    paused: bool,
+}
+
+impl AutoRecorder {
+    /// `clock` must be the one the recording was started with, and
+    /// `split_request` is needed to split.
+    pub fn new(
+        rules: RecordRules,
+        on_focus_lost: OnFocusLost,
+        clock: PausableClock,
+        recording_window: Arc<AtomicBool>,
+        split_request: Option<Arc<AtomicBool>>,
+    ) -> Self {
+        Self {
+            rules,
+            on_focus_lost,
+            clock,
+            recording_window,
+            split_request,
+            recording: None,
+            paused: false,
+        }
+    }
+
+    /// Takes note of a window coming to the foreground. Returns whether it's
+    /// recorded, if that changed.
+    pub fn focus(&mut self, window: &FocusedWindow) -> io::Result<Option<bool>> {
+        let recording = self.rules.should_record(window);
+        // Nothing's been held back yet when the first window is allowed
+        if self.recording.unwrap_or(true) == recording {
+            self.recording = Some(recording);
+            return Ok(None);
+        }
+        self.recording = Some(recording);
+        self.recording_window.store(recording, Ordering::SeqCst);
+
+        if recording {
+            // Leave pauses the user made alone
+            if self.paused && self.clock.is_paused() {
+                self.clock.resume()?;
+            }
+            self.paused = false;
+        } else if self.on_focus_lost != OnFocusLost::Blank {
+            if !self.clock.is_paused() {
+                self.clock.pause()?;
+                self.paused = true;
+            }
+            if self.on_focus_lost == OnFocusLost::Split {
+                if let Some(split_request) = &self.split_request {
+                    split_request.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(Some(recording))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::{AutoRecorder, FocusedWindow, OnFocusLost, RecordRules};
+    use crate::clock::{ManualClock, PausableClock};
+
+    fn window(title: &str, process: &str, class: &str) -> FocusedWindow {
+        FocusedWindow {
+            title: title.to_owned(),
+            process: process.to_owned(),
+            class: class.to_owned(),
+        }
+    }
+
+    fn game() -> FocusedWindow {
+        window("My Game", "Game.exe", "UnityWndClass")
+    }
+
+    fn browser() -> FocusedWindow {
+        window(
+            "News - Mozilla Firefox",
+            "firefox.exe",
+            "MozillaWindowClass",
+        )
+    }
+
+    fn private_browser() -> FocusedWindow {
+        window(
+            "Bank - Mozilla Firefox Private Browsing",
+            "firefox.exe",
+            "MozillaWindowClass",
+        )
+    }
+
+    fn console() -> FocusedWindow {
+        window("Command Prompt", "conhost.exe", "ConsoleWindowClass")
+    }
+
+    const RULES: &str = "
+        # Games and the browser, but not private windows or consoles
+        include process game
+        include class mozillawindowclass
+        exclude title private browsing$
+        exclude class ConsoleWindowClass
+    ";
+
+    #[test]
+    fn includes_and_excludes_windows() {
+        let rules = RecordRules::parse(RULES).unwrap();
+        assert!(rules.should_record(&game()));
+        assert!(rules.should_record(&browser()));
+        assert!(!rules.should_record(&private_browser()));
+        assert!(!rules.should_record(&console()));
+        assert!(!rules.should_record(&window("Notepad", "notepad.exe", "Notepad")));
+
+        // Without include rules, anything not excluded is recorded
+        let rules = RecordRules::parse("exclude process firefox").unwrap();
+        assert!(rules.should_record(&game()));
+        assert!(!rules.should_record(&browser()));
+        assert!(RecordRules::default().should_record(&console()));
+    }
+
+    #[test]
+    fn reports_bad_rules() {
+        let cases = [
+            ("include title", "line 1"),
+            ("\n\ninclude size 100", "line 3"),
+            ("record process game", "line 1"),
+            ("include title (unclosed", "line 1"),
+        ];
+        for (text, line) in cases {
+            let error = RecordRules::parse(text).unwrap_err();
+            assert!(error.starts_with(line), "{text:?}: {error}");
+        }
+    }
+
+    struct Recording {
+        auto_recorder: AutoRecorder,
+        clock: PausableClock,
+        recording_window: Arc<AtomicBool>,
+        split_request: Arc<AtomicBool>,
+    }
+
+    impl Recording {
+        fn new(on_focus_lost: OnFocusLost) -> Self {
+            let clock = PausableClock::new(Arc::new(ManualClock::new(10_000_000)));
+            clock.start();
+            let recording_window = Arc::new(AtomicBool::new(true));
+            let split_request = Arc::new(AtomicBool::new(false));
+            Self {
+                auto_recorder: AutoRecorder::new(
+                    RecordRules::parse(RULES).unwrap(),
+                    on_focus_lost,
+                    clock.clone(),
+                    recording_window.clone(),
+                    Some(split_request.clone()),
+                ),
+                clock,
+                recording_window,
+                split_request,
+            }
+        }
+
+        /// Focuses each window in turn, returning what changed.
+        fn focus(&mut self, windows: &[FocusedWindow]) -> Vec<Option<bool>> {
+            windows
+                .iter()
+                .map(|window| self.auto_recorder.focus(window).unwrap())
+                .collect()
+        }
+
+        fn state(&self) -> (bool, bool, bool) {
+            (
+                self.clock.is_paused(),
+                self.recording_window.load(Ordering::SeqCst),
+                self.split_request.swap(false, Ordering::SeqCst),
+            )
+        }
+    }
+
+    #[test]
+    fn pauses_while_away() {
+        let mut recording = Recording::new(OnFocusLost::Pause);
+        let changes = recording.focus(&[game(), browser(), console(), private_browser()]);
+        assert_eq!(changes, [None, None, Some(false), None]);
+        assert_eq!(recording.state(), (true, false, false));
+        assert_eq!(recording.focus(&[game()]), [Some(true)]);
+        assert_eq!(recording.state(), (false, true, false));
+    }
+
+    #[test]
+    fn blanks_while_away() {
+        let mut recording = Recording::new(OnFocusLost::Blank);
+        // Starting out on a window that isn't recorded
+        assert_eq!(recording.focus(&[console()]), [Some(false)]);
+        assert_eq!(recording.state(), (false, false, false));
+        assert_eq!(recording.focus(&[browser(), game()]), [Some(true), None]);
+        assert_eq!(recording.state(), (false, true, false));
+    }
+
+    #[test]
+    fn splits_while_away() {
+        let mut recording = Recording::new(OnFocusLost::Split);
+        assert_eq!(recording.focus(&[game()]), [None]);
+        for _ in 0..3 {
+            assert_eq!(recording.focus(&[console()]), [Some(false)]);
+            assert_eq!(recording.state(), (true, false, true));
+            assert_eq!(recording.focus(&[game()]), [Some(true)]);
+            assert_eq!(recording.state(), (false, true, false));
+        }
+    }
+
+    #[test]
+    fn leaves_pauses_it_didnt_make() {
+        let mut recording = Recording::new(OnFocusLost::Pause);
+        recording.clock.pause().unwrap();
+        recording.focus(&[console(), game()]);
+        assert!(recording.clock.is_paused());
+
+        // The user resumes while away
+        let mut recording = Recording::new(OnFocusLost::Pause);
+        recording.focus(&[console()]);
+        recording.clock.resume().unwrap();
+        recording.focus(&[game()]);
+        assert!(!recording.clock.is_paused());
+    }
+
+    #[test]
+    fn parses_on_focus_lost() {
+        for on_focus_lost in [OnFocusLost::Pause, OnFocusLost::Blank, OnFocusLost::Split] {
+            assert_eq!(on_focus_lost.to_string().parse(), Ok(on_focus_lost));
+        }
+        assert_eq!("Split".parse(), Ok(OnFocusLost::Split));
+        assert!("stop".parse::<OnFocusLost>().is_err());
+    }
+}
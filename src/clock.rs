@@ -0,0 +1,379 @@
+//! Time sources and the recording timeline. Capture code stamps frames and
+//! audio with the time they were captured at, in 100ns units read from a
+//! `Clock`, and `PausableClock` maps those times onto the timeline of the
+//! output, which doesn't include the time spent paused.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// 100ns units per second, the unit Media Foundation and WASAPI use.
+pub const HNS_PER_SECOND: i64 = 10_000_000;
+
+/// A monotonic time source.
+pub trait Clock: Send + Sync {
+    /// The current time in ticks of `frequency`.
+    fn ticks(&self) -> i64;
+
+    /// Ticks per second.
+    fn frequency(&self) -> i64;
+
+    fn ticks_to_hns(&self, ticks: i64) -> i64 {
+        rescale(ticks, self.frequency(), HNS_PER_SECOND)
+    }
+
+    fn hns_to_ticks(&self, hns: i64) -> i64 {
+        rescale(hns, HNS_PER_SECOND, self.frequency())
+    }
+
+    /// The current time in 100ns units.
+    fn now(&self) -> i64 {
+        self.ticks_to_hns(self.ticks())
+    }
+}
+
+/// A clock shared between a session and its streams.
+pub type SharedClock = Arc<dyn Clock>;
+
+fn rescale(value: i64, from: i64, to: i64) -> i64 {
+    (value as i128 * to as i128 / from as i128) as i64
+}
+
+/// The system's performance counter. WASAPI reports capture times against
+/// the same counter, so audio and video timestamps can be compared.
+#[cfg(windows)]
+pub struct SystemClock {
+    frequency: i64,
+}
+
+#[cfg(windows)]
+impl SystemClock {
+    pub fn new() -> windows::core::Result<Self> {
+        let mut frequency = 0;
+        unsafe { windows::Win32::System::Performance::QueryPerformanceFrequency(&mut frequency)? };
+        Ok(Self { frequency })
+    }
+}
+
+#[cfg(windows)]
+impl Clock for SystemClock {
+    fn ticks(&self) -> i64 {
+        let mut ticks = 0;
+        // Can't fail on anything newer than Windows XP.
+        unsafe {
+            windows::Win32::System::Performance::QueryPerformanceCounter(&mut ticks).unwrap()
+        };
+        ticks
+    }
+
+    fn frequency(&self) -> i64 {
+        self.frequency
+    }
+}
+
+/// The standard library's monotonic clock, in nanoseconds since the clock
+/// was created.
+#[cfg(not(windows))]
+pub struct SystemClock {
+    origin: std::time::Instant,
+}
+
+#[cfg(not(windows))]
+impl SystemClock {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            origin: std::time::Instant::now(),
+        })
+    }
+}
+
+#[cfg(not(windows))]
+impl Clock for SystemClock {
+    fn ticks(&self) -> i64 {
+        self.origin.elapsed().as_nanos() as i64
+    }
+
+    fn frequency(&self) -> i64 {
+        1_000_000_000
+    }
+}
+
+/// A clock that only moves when told to, for driving pacing and sync logic
+/// deterministically.
+// Only the tests drive a clock by hand for now.
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct ManualClock {
+    ticks: AtomicI64,
+    frequency: i64,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl ManualClock {
+    pub fn new(frequency: i64) -> Self {
+        Self {
+            ticks: AtomicI64::new(0),
+            frequency,
+        }
+    }
+
+    pub fn set_ticks(&self, ticks: i64) {
+        self.ticks.store(ticks, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, ticks: i64) {
+        self.ticks.fetch_add(ticks, Ordering::SeqCst);
+    }
+
+    /// Moves the clock forward by a duration in 100ns units.
+    pub fn advance_hns(&self, hns: i64) {
+        self.advance(self.hns_to_ticks(hns));
+    }
+}
+
+impl Clock for ManualClock {
+    fn ticks(&self) -> i64 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+
+    fn frequency(&self) -> i64 {
+        self.frequency
+    }
+}
+
+/// A clock that can be paused and resumed any number of times.
+///
+/// Clones share their state, so the session can pause and resume the clock
+/// while each stream uses its own copy to map timestamps. Because streams
+/// are mapped by capture time rather than by when their data arrives, audio
+/// that is delivered late still lands on the right side of a pause.
+#[derive(Clone)]
+pub struct PausableClock {
+    clock: SharedClock,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    start_time: i64,
+    /// Paused intervals relative to `start_time`, oldest first. Only the
+    /// last one can still be open.
+    pauses: Vec<(i64, Option<i64>)>,
+}
+
+impl State {
+    fn is_paused(&self) -> bool {
+        matches!(self.pauses.last(), Some((_, None)))
+    }
+}
+
+impl PausableClock {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            state: Arc::default(),
+        }
+    }
+
+    /// Starts the timeline at the current time, forgetting any earlier
+    /// pauses. Returns the time read from the clock, in 100ns units, which
+    /// stream timestamps are expected to be relative to.
+    pub fn start(&self) -> i64 {
+        let start_time = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        state.start_time = start_time;
+        state.pauses.clear();
+        start_time
+    }
+
+    pub fn start_time(&self) -> i64 {
+        self.state.lock().unwrap().start_time
+    }
+
+    pub fn pause(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_paused() {
+            return Err(io::Error::other("The recording is already paused."));
+        }
+        let now = self.clock.now() - state.start_time;
+        state.pauses.push((now, None));
+        Ok(())
+    }
+
+    pub fn resume(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.is_paused() {
+            return Err(io::Error::other("The recording isn't paused."));
+        }
+        let now = self.clock.now() - state.start_time;
+        state.pauses.last_mut().unwrap().1 = Some(now);
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().is_paused()
+    }
+
+    /// How far along the recording timeline it is now. While paused, that's
+    /// where the recording picks up again.
+    pub fn elapsed(&self) -> i64 {
+        let state = self.state.lock().unwrap();
+        let now = self.clock.now() - state.start_time;
+        let paused: i64 = state
+            .pauses
+            .iter()
+            .map(|&(pause_start, pause_end)| pause_end.unwrap_or(now) - pause_start)
+            .sum();
+        now - paused
+    }
+
+    /// Maps a timestamp relative to the start time onto the recording
+    /// timeline. Returns `None` if it was captured while paused.
+    pub fn recording_time(&self, timestamp: i64) -> Option<i64> {
+        let state = self.state.lock().unwrap();
+        let mut paused = 0;
+        for &(pause_start, pause_end) in &state.pauses {
+            if timestamp < pause_start {
+                break;
+            }
+            match pause_end {
+                Some(pause_end) if timestamp >= pause_end => paused += pause_end - pause_start,
+                _ => return None,
+            }
+        }
+        Some(timestamp - paused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Clock, ManualClock, PausableClock, HNS_PER_SECOND};
+
+    const MILLISECOND: i64 = 10_000;
+    const SECOND: i64 = 1000 * MILLISECOND;
+    const FRAME: i64 = SECOND / 30;
+    const AUDIO_BUFFER: i64 = 10 * MILLISECOND;
+
+    #[test]
+    fn converts_ticks() {
+        // The ACPI timer frequency some machines report for QPC.
+        let clock = ManualClock::new(3_579_545);
+        clock.set_ticks(3_579_545 * 3);
+        assert_eq!(clock.now(), 3 * SECOND);
+        assert_eq!(clock.hns_to_ticks(2 * SECOND), 2 * 3_579_545);
+        // Partial ticks round down.
+        assert_eq!(clock.ticks_to_hns(1), 2);
+        clock.advance_hns(SECOND);
+        assert_eq!(clock.now(), 4 * SECOND);
+
+        // A month of uptime on a fast counter doesn't overflow.
+        let clock = ManualClock::new(1_000_000_000);
+        let month = 30 * 24 * 3600;
+        clock.set_ticks(month * 1_000_000_000);
+        assert_eq!(clock.now(), month * SECOND);
+    }
+
+    #[test]
+    fn cuts_pauses_out_of_the_timeline() {
+        let time = Arc::new(ManualClock::new(HNS_PER_SECOND));
+        let clock = PausableClock::new(time.clone());
+        time.set_ticks(5 * SECOND);
+        assert_eq!(clock.start(), 5 * SECOND);
+
+        time.set_ticks(6 * SECOND);
+        clock.pause().unwrap();
+        assert!(clock.is_paused());
+        assert!(clock.pause().is_err());
+        time.set_ticks(8 * SECOND);
+        assert_eq!(clock.elapsed(), SECOND);
+        clock.resume().unwrap();
+        assert!(clock.resume().is_err());
+
+        assert_eq!(clock.recording_time(SECOND / 2), Some(SECOND / 2));
+        assert_eq!(clock.recording_time(SECOND - 1), Some(SECOND - 1));
+        assert_eq!(clock.recording_time(SECOND), None);
+        assert_eq!(clock.recording_time(3 * SECOND - 1), None);
+        assert_eq!(clock.recording_time(3 * SECOND), Some(SECOND));
+        assert_eq!(clock.recording_time(4 * SECOND), Some(2 * SECOND));
+
+        // An open pause swallows everything after it.
+        time.set_ticks(10 * SECOND);
+        clock.pause().unwrap();
+        time.set_ticks(12 * SECOND);
+        assert_eq!(clock.elapsed(), 3 * SECOND);
+        assert_eq!(clock.recording_time(5 * SECOND - 1), Some(3 * SECOND - 1));
+        assert_eq!(clock.recording_time(6 * SECOND), None);
+
+        // Restarting forgets the pauses.
+        clock.start();
+        assert!(!clock.is_paused());
+        assert_eq!(clock.recording_time(SECOND), Some(SECOND));
+    }
+
+    /// Plays through a recording on a manual clock, pausing and resuming
+    /// it several times, with audio delivered late the way WASAPI does.
+    #[test]
+    fn streams_stay_in_sync_across_pauses() {
+        let time = Arc::new(ManualClock::new(HNS_PER_SECOND));
+        let clock = PausableClock::new(time.clone());
+        time.set_ticks(123 * SECOND);
+        let start_time = clock.start();
+
+        // Pause and resume times, in milliseconds since the start.
+        let toggles = [1000, 1500, 2210, 4000, 4370, 4380, 5990, 7000];
+        let audio_latency = 30 * MILLISECOND;
+        let end = 8 * SECOND;
+
+        let mut video = Vec::new();
+        let mut audio = Vec::new();
+        let mut next_frame = 0;
+        let mut next_buffer = 0;
+        let mut paused_for = 0;
+        for now in (0..=end).step_by(MILLISECOND as usize) {
+            time.set_ticks(start_time + now);
+            if let Some(index) = toggles.iter().position(|&at| at * MILLISECOND == now) {
+                if index % 2 == 0 {
+                    clock.pause().unwrap();
+                } else {
+                    clock.resume().unwrap();
+                    paused_for += toggles[index] - toggles[index - 1];
+                }
+            }
+            while next_frame <= now {
+                if let Some(timestamp) = clock.recording_time(next_frame) {
+                    video.push(timestamp);
+                }
+                next_frame += FRAME;
+            }
+            while next_buffer + AUDIO_BUFFER + audio_latency <= now {
+                if let Some(timestamp) = clock.recording_time(next_buffer) {
+                    audio.push(timestamp);
+                }
+                next_buffer += AUDIO_BUFFER;
+            }
+        }
+
+        // The audio has no gaps or overlaps, so everything captured while
+        // recording made it in.
+        assert!(audio
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] == AUDIO_BUFFER));
+        // Video never skips more than the frame on either side of a pause.
+        assert!(video
+            .windows(2)
+            .all(|pair| pair[1] > pair[0] && pair[1] - pair[0] < 2 * FRAME));
+
+        // After any number of pauses both streams still cover the same span,
+        // the recorded time.
+        let recorded = end - paused_for * MILLISECOND;
+        let video_end = video.last().unwrap() + FRAME;
+        let audio_end = audio.last().unwrap() + AUDIO_BUFFER + audio_latency;
+        assert!((video_end - recorded).abs() <= FRAME);
+        assert!((audio_end - recorded).abs() <= AUDIO_BUFFER);
+    }
+}
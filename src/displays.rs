@@ -1,14 +1,34 @@
 use windows::Win32::{
     Foundation::{LPARAM, RECT},
-    Graphics::Gdi::{EnumDisplayMonitors, HDC, HMONITOR},
+    Graphics::Gdi::{
+        EnumDisplayMonitors, MonitorFromRect, HDC, HMONITOR, MONITOR_DEFAULTTONEAREST,
+    },
 };
 use windows::core::BOOL;
 
+use crate::region::Region;
+
 pub fn get_display_handle_from_index(index: usize) -> Option<HMONITOR> {
     let displays = enumerate_displays();
     displays.get(index).copied()
 }
 
+/// The display most of a region of the desktop is on, or the one nearest to
+/// it if it's on none of them.
+pub fn get_display_handle_from_region(region: Region) -> HMONITOR {
+    let rect = RECT {
+        left: region.x,
+        top: region.y,
+        right: region
+            .x
+            .saturating_add(region.width.min(i32::MAX as u32) as i32),
+        bottom: region
+            .y
+            .saturating_add(region.height.min(i32::MAX as u32) as i32),
+    };
+    unsafe { MonitorFromRect(&rect, MONITOR_DEFAULTTONEAREST) }
+}
+
 fn enumerate_displays() -> Vec<HMONITOR> {
     unsafe {
         let displays = Box::into_raw(Box::default());
@@ -0,0 +1,323 @@
+//! Keeps captured audio in step with the recording clock. Every audio
+//! device runs off its own crystal, so one that says it runs at 48kHz
+//! delivers a little more or a little less than that per second of the
+//! clock the video is timed by. A few hundred parts per million is common,
+//! which adds up to more than a second over an hour.
+
+use crate::clock::HNS_PER_SECOND;
+
+/// How long a stream is measured for before its rate is trusted.
+const WARM_UP_SECONDS: f64 = 2.0;
+/// Packets further than this from where the stream should be are a break
+/// in the stream, not drift.
+const DISCONTINUITY_SECONDS: f64 = 0.002;
+/// The most any real device is expected to be off by, as a fraction of its
+/// rate. Only used to tell drift from breaks before the rate is measured.
+const MAX_DRIFT: f64 = 0.001;
+/// At most one frame is inserted or dropped for this many frames of input,
+/// so catching up after the warm up doesn't happen all at once.
+const MIN_FRAMES_PER_CORRECTION: f64 = 500.0;
+
+/// Measures how fast a device delivers frames against the timestamps they
+/// come with, by fitting a line through frame counts against time.
+pub struct DriftEstimator {
+    sample_rate: f64,
+    first_timestamp: Option<i64>,
+    /// Frames delivered since the first packet.
+    frames: u64,
+    /// Seconds from the first packet to the last.
+    span: f64,
+
+    // Running least squares fit of frames against seconds
+    count: f64,
+    mean_time: f64,
+    mean_frames: f64,
+    time_deviation: f64,
+    co_deviation: f64,
+}
+
+impl DriftEstimator {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            first_timestamp: None,
+            frames: 0,
+            span: 0.0,
+            count: 0.0,
+            mean_time: 0.0,
+            mean_frames: 0.0,
+            time_deviation: 0.0,
+            co_deviation: 0.0,
+        }
+    }
+
+    /// Records a packet of `frames` frames captured at `timestamp`, in
+    /// 100ns units. If it doesn't carry on from the packets before it, the
+    /// measurement starts over from it and this returns `false`.
+    pub fn update(&mut self, timestamp: i64, frames: usize) -> bool {
+        let Some(first_timestamp) = self.first_timestamp else {
+            self.restart(timestamp, frames);
+            return true;
+        };
+        let time = (timestamp - first_timestamp) as f64 / HNS_PER_SECOND as f64;
+        let (expected, slack) = match self.measured_rate() {
+            Some(rate) => (self.mean_frames + (time - self.mean_time) * rate, 0.0),
+            None => (time * self.sample_rate, time * MAX_DRIFT),
+        };
+        let slack = (DISCONTINUITY_SECONDS + slack) * self.sample_rate;
+        if time < self.span || (self.frames as f64 - expected).abs() > slack {
+            self.restart(timestamp, frames);
+            return false;
+        }
+        self.add_point(time, frames);
+        true
+    }
+
+    /// Frames per second of the clock, once the stream has been measured
+    /// for long enough.
+    pub fn measured_rate(&self) -> Option<f64> {
+        (self.span >= WARM_UP_SECONDS && self.time_deviation > 0.0)
+            .then(|| self.co_deviation / self.time_deviation)
+    }
+
+    /// How much faster than its nominal rate the device runs, in parts per
+    /// million.
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.measured_rate()
+            .map(|rate| (rate / self.sample_rate - 1.0) * 1_000_000.0)
+    }
+
+    /// Frames delivered since the measurement started.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn restart(&mut self, timestamp: i64, frames: usize) {
+        *self = Self {
+            first_timestamp: Some(timestamp),
+            ..Self::new(self.sample_rate as u32)
+        };
+        self.add_point(0.0, frames);
+    }
+
+    /// Adds the frame count at the start of a packet to the fit.
+    fn add_point(&mut self, time: f64, frames: usize) {
+        let frames_before = self.frames as f64;
+        self.count += 1.0;
+        let time_delta = time - self.mean_time;
+        self.mean_time += time_delta / self.count;
+        self.mean_frames += (frames_before - self.mean_frames) / self.count;
+        self.time_deviation += time_delta * (time - self.mean_time);
+        self.co_deviation += time_delta * (frames_before - self.mean_frames);
+        self.span = time;
+        self.frames += frames as u64;
+    }
+}
+
+/// Locks a stream of audio to the clock its timestamps come from, by
+/// inserting or dropping the odd frame.
+///
+/// Once the device's rate is measured, the compensator works out how many
+/// frames the stream should have gained or lost for what has been
+/// delivered so far to take as long at the nominal rate as it did by the
+/// clock, and makes up the difference a frame at a time. A frame is dropped
+/// by merging it with the one before it, and inserted as the average of its
+/// neighbours, which is inaudible at these rates. Because the target covers
+/// everything delivered, including the warm up, the stream doesn't just
+/// stop drifting but gets pulled back to where it started.
+pub struct DriftCompensator {
+    channels: usize,
+    estimator: DriftEstimator,
+    /// Frames inserted less frames dropped since the estimator started.
+    adjusted: i64,
+}
+
+impl DriftCompensator {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            channels: channels as usize,
+            estimator: DriftEstimator::new(sample_rate),
+            adjusted: 0,
+        }
+    }
+
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.estimator.drift_ppm()
+    }
+
+    /// Takes a packet of interleaved samples captured at `timestamp`, and
+    /// returns it with frames inserted or dropped to keep in step.
+    pub fn process(&mut self, timestamp: i64, mut samples: Vec<f32>) -> Vec<f32> {
+        let frames = samples.len() / self.channels;
+        if !self.estimator.update(timestamp, frames) {
+            self.adjusted = 0;
+        }
+        let Some(rate) = self.estimator.measured_rate() else {
+            return samples;
+        };
+
+        let delivered = self.estimator.frames() as f64;
+        let target = delivered * (self.estimator.sample_rate / rate - 1.0);
+        let limit = (frames as f64 / MIN_FRAMES_PER_CORRECTION).ceil() as i64;
+        let corrections = ((target - self.adjusted as f64).round() as i64).clamp(-limit, limit);
+        self.adjusted += adjust(&mut samples, self.channels, corrections);
+        samples
+    }
+}
+
+/// Inserts frames into a packet, or drops them if `count` is negative,
+/// spread evenly across it. Returns how many were inserted.
+fn adjust(samples: &mut Vec<f32>, channels: usize, count: i64) -> i64 {
+    let frames = samples.len() / channels;
+    let count = count.clamp(-(frames as i64 / 2), frames as i64 / 2);
+    let corrections = count.unsigned_abs() as usize;
+    // From the back, so the positions still to do don't move.
+    for index in (0..corrections).rev() {
+        let frame = (2 * index + 1) * frames / (2 * corrections);
+        let frame = frame.max(1);
+        let average: Vec<f32> = (0..channels)
+            .map(|channel| {
+                (samples[(frame - 1) * channels + channel] + samples[frame * channels + channel])
+                    / 2.0
+            })
+            .collect();
+        if count > 0 {
+            samples.splice(frame * channels..frame * channels, average);
+        } else {
+            samples.splice((frame - 1) * channels..(frame + 1) * channels, average);
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adjust, DriftCompensator, DriftEstimator};
+
+    const SECOND: f64 = 10_000_000.0;
+    const PACKET: usize = 480;
+
+    /// A device running `ppm` parts per million fast, delivering a packet
+    /// every 10ms of its own clock, with the timestamps it reports off by
+    /// up to `jitter` seconds. Returns each packet's timestamp and the time
+    /// it really ends at, in 100ns units.
+    fn device(ppm: f64, jitter: f64, seconds: f64) -> Vec<(i64, f64)> {
+        let rate = 48000.0 * (1.0 + ppm / 1_000_000.0);
+        let wobble = [0.0, 0.7, -0.4, 1.0, -1.0, 0.2, -0.8];
+        (0..)
+            .map(|index| {
+                let start = (index * PACKET) as f64 / rate;
+                let noise = wobble[index % wobble.len()] * jitter;
+                let end = ((index + 1) * PACKET) as f64 / rate;
+                (((start + noise) * SECOND).round() as i64, end * SECOND)
+            })
+            .take_while(|&(_, end)| end <= seconds * SECOND)
+            .collect()
+    }
+
+    /// A ramp, so every frame is different and moves can be seen.
+    fn packet(index: usize, channels: usize) -> Vec<f32> {
+        (0..PACKET * channels)
+            .map(|sample| ((index * PACKET + sample / channels) % 1000) as f32 / 1000.0)
+            .collect()
+    }
+
+    #[test]
+    fn measures_drift() {
+        for ppm in [-500.0, -37.0, 0.0, 120.0, 500.0] {
+            let mut estimator = DriftEstimator::new(48000);
+            for (index, (timestamp, _)) in device(ppm, 0.0005, 60.0).into_iter().enumerate() {
+                assert!(estimator.update(timestamp, PACKET));
+                if index == 100 {
+                    // Not measured for long enough yet.
+                    assert_eq!(estimator.drift_ppm(), None);
+                }
+            }
+            let measured = estimator.drift_ppm().unwrap();
+            assert!(
+                (measured - ppm).abs() < 2.0,
+                "{ppm} ppm measured as {measured}"
+            );
+        }
+    }
+
+    #[test]
+    fn locks_streams_to_the_clock() {
+        for ppm in [-500.0, 500.0] {
+            let mut compensator = DriftCompensator::new(48000, 2);
+            let mut output = 0;
+            let mut worst_after_settling: f64 = 0.0;
+            for (index, (timestamp, end)) in device(ppm, 0.0002, 120.0).into_iter().enumerate() {
+                output += compensator.process(timestamp, packet(index, 2)).len() / 2;
+                // How far ahead of the clock the stream ends up, in frames.
+                let offset = output as f64 - end / 10_000_000.0 * 48000.0;
+                // Never enough to look like a break in the stream.
+                assert!(offset.abs() < 96.0, "{ppm} ppm: {offset} frames off");
+                if end > 10.0 * 10_000_000.0 {
+                    worst_after_settling = worst_after_settling.max(offset.abs());
+                }
+            }
+            // Without compensation it'd be 60ms out by the end.
+            assert!(
+                worst_after_settling < 2.0,
+                "{ppm} ppm: {worst_after_settling}"
+            );
+            let measured = compensator.drift_ppm().unwrap();
+            assert!((measured - ppm).abs() < 2.0);
+        }
+    }
+
+    #[test]
+    fn leaves_accurate_streams_alone() {
+        let mut compensator = DriftCompensator::new(48000, 1);
+        for (index, (timestamp, _)) in device(0.0, 0.0, 30.0).into_iter().enumerate() {
+            assert_eq!(
+                compensator.process(timestamp, packet(index, 1)),
+                packet(index, 1)
+            );
+        }
+        assert!(compensator.drift_ppm().unwrap().abs() < 0.001);
+    }
+
+    #[test]
+    fn starts_over_after_a_break() {
+        let mut estimator = DriftEstimator::new(48000);
+        let packets = device(500.0, 0.0, 10.0);
+        for &(timestamp, _) in &packets {
+            assert!(estimator.update(timestamp, PACKET));
+        }
+        assert!(estimator.drift_ppm().is_some());
+
+        // Nothing for a second, like loopback while nothing plays.
+        let resume = packets.last().unwrap().0 + 10_000_000;
+        assert!(!estimator.update(resume, PACKET));
+        assert_eq!(estimator.drift_ppm(), None);
+        assert_eq!(estimator.frames(), PACKET as u64);
+        // Overlaps and going backwards are breaks too.
+        assert!(estimator.update(resume + 100_000, PACKET));
+        assert!(!estimator.update(resume + 150_000, PACKET));
+        assert!(!estimator.update(resume, PACKET));
+    }
+
+    #[test]
+    fn inserts_and_drops_between_neighbours() {
+        let ramp: Vec<f32> = (0..8)
+            .flat_map(|frame| [frame as f32, -frame as f32])
+            .collect();
+
+        let mut inserted = ramp.clone();
+        assert_eq!(adjust(&mut inserted, 2, 1), 1);
+        assert_eq!(inserted.len(), 18);
+        assert_eq!(&inserted[6..12], [3.0, -3.0, 3.5, -3.5, 4.0, -4.0]);
+
+        let mut dropped = ramp.clone();
+        assert_eq!(adjust(&mut dropped, 2, -2), -2);
+        let left: Vec<f32> = dropped.iter().step_by(2).copied().collect();
+        assert_eq!(left, [0.0, 1.5, 3.0, 4.0, 5.5, 7.0]);
+
+        // Never more than half a packet.
+        let mut short = vec![0.0, 1.0, 2.0];
+        assert_eq!(adjust(&mut short, 1, 5), 1);
+        assert_eq!(short.len(), 4);
+    }
+}
@@ -0,0 +1,442 @@
+//! Debugging copies of a recording's streams, for telling whether a broken
+//! file is the encoders' fault or the muxer's. `DumpSink` sits in front of
+//! the real sink and copies each encoded packet into a raw stream that can
+//! be played on its own: Annex B for H.264 and HEVC, OBUs for AV1, ADTS for
+//! AAC and a native FLAC file. Opus and PCM have no such format, so only
+//! their timestamps are kept. `PcmDump` copies the audio a track's generator
+//! hands to its encoder into a WAV.
+//!
+//! Every packet and block is logged to timestamps.csv, in 100ns units.
+//! Packets are on the recording's timeline, with pauses cut out, and PCM
+//! blocks on the capture's, which keeps running while paused.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    mux::aac::{adts_header_len, AudioSpecificConfig},
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{flac, AudioCodec, Result, SharedSink, Sink, StreamFormat, VideoCodec},
+};
+
+const TIMESTAMPS_FILE: &str = "timestamps.csv";
+
+/// The directory the dumps go in, shared by the sink and every track.
+#[derive(Clone)]
+pub struct StreamDump {
+    dir: PathBuf,
+    timestamps: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl StreamDump {
+    /// Creates `dir` if it doesn't exist, and starts the timestamp log.
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut timestamps = BufWriter::new(File::create(dir.join(TIMESTAMPS_FILE))?);
+        writeln!(timestamps, "stream,timestamp,duration,keyframe,bytes")?;
+        Ok(Self {
+            dir,
+            timestamps: Arc::new(Mutex::new(timestamps)),
+        })
+    }
+
+    fn create_file(&self, name: &str) -> Result<BufWriter<File>> {
+        Ok(BufWriter::new(File::create(self.dir.join(name))?))
+    }
+
+    fn log(
+        &self,
+        stream: &str,
+        timestamp: i64,
+        duration: i64,
+        keyframe: bool,
+        bytes: usize,
+    ) -> Result<()> {
+        let mut timestamps = self.timestamps.lock().unwrap();
+        writeln!(
+            timestamps,
+            "{},{},{},{},{}",
+            stream, timestamp, duration, keyframe as u8, bytes
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.timestamps.lock().unwrap().flush()
+    }
+}
+
+struct DumpedStream {
+    kind: StreamKind,
+    number: usize,
+    /// Like "video_0", which the file is named after.
+    name: String,
+    format: StreamFormat,
+    file: Option<BufWriter<File>>,
+}
+
+impl DumpedStream {
+    fn write(&mut self, packet: &EncodedPacket) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        if let StreamFormat::Audio(format) = &self.format {
+            // Media Foundation hands out raw AAC frames
+            if format.codec == AudioCodec::Aac && adts_header_len(&packet.data).is_none() {
+                let config = AudioSpecificConfig::new(format.sample_rate, format.channels);
+                file.write_all(&config.adts_header(packet.data.len())?)?;
+            }
+        }
+        file.write_all(&packet.data)
+    }
+}
+
+/// The extension of the raw stream a format is dumped to, if it has one.
+fn extension(format: &StreamFormat) -> Option<&'static str> {
+    match format {
+        StreamFormat::Video(format) => match format.codec {
+            VideoCodec::H264 => Some("h264"),
+            VideoCodec::Hevc => Some("h265"),
+            VideoCodec::Av1 => Some("obu"),
+            VideoCodec::Raw => None,
+        },
+        StreamFormat::Audio(format) => match format.codec {
+            AudioCodec::Aac => Some("aac"),
+            AudioCodec::Flac => Some("flac"),
+            AudioCodec::Opus | AudioCodec::Pcm => None,
+        },
+    }
+}
+
+/// Copies every packet into the dump before passing it on to `inner`, so
+/// the dump has it even if the inner sink fails.
+pub struct DumpSink {
+    inner: SharedSink,
+    dump: StreamDump,
+    streams: Vec<DumpedStream>,
+}
+
+impl DumpSink {
+    pub fn new(inner: SharedSink, dump: StreamDump) -> Self {
+        Self {
+            inner,
+            dump,
+            streams: Vec::new(),
+        }
+    }
+}
+
+impl Sink for DumpSink {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        let number = self.inner.lock().unwrap().add_stream(format.clone())?;
+        let kind = format.kind();
+        let name = match kind {
+            StreamKind::Video => format!("video_{}", number),
+            StreamKind::Audio => format!("audio_{}", number),
+        };
+        let file = match extension(&format) {
+            Some(extension) => {
+                let mut file = self.dump.create_file(&format!("{}.{}", name, extension))?;
+                if let StreamFormat::Audio(format) = &format {
+                    if format.codec == AudioCodec::Flac {
+                        file.write_all(b"fLaC")?;
+                        file.write_all(&flac::metadata(format.sample_rate, format.channels))?;
+                    }
+                }
+                Some(file)
+            }
+            None => None,
+        };
+        self.streams.push(DumpedStream {
+            kind,
+            number,
+            name,
+            format,
+            file,
+        });
+        Ok(number)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.inner.lock().unwrap().start()
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        let stream = self
+            .streams
+            .iter_mut()
+            .find(|stream| stream.kind == packet.kind && stream.number == packet.stream)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "No stream was added for this packet.",
+                )
+            })?;
+        stream.write(&packet)?;
+        self.dump.log(
+            &stream.name,
+            packet.timestamp,
+            packet.duration,
+            packet.keyframe,
+            packet.data.len(),
+        )?;
+        self.inner.lock().unwrap().write(packet)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        for stream in &mut self.streams {
+            if let Some(file) = &mut stream.file {
+                file.flush()?;
+            }
+        }
+        self.dump.flush()?;
+        self.inner.lock().unwrap().stop()
+    }
+
+    fn add_chapter(&mut self, timestamp: i64, title: &str) -> Result<()> {
+        self.inner.lock().unwrap().add_chapter(timestamp, title)
+    }
+}
+
+/// Writes the 16-bit PCM one audio track is encoded from to a WAV.
+pub struct PcmDump {
+    dump: StreamDump,
+    name: String,
+    file: BufWriter<File>,
+    data_len: u64,
+}
+
+const WAV_HEADER_LEN: u64 = 44;
+
+impl PcmDump {
+    pub fn create(
+        dump: &StreamDump,
+        track: usize,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<Self> {
+        let name = format!("pcm_{}", track);
+        let mut file = dump.create_file(&format!("{}.wav", name))?;
+        let block_align = channels * 2;
+        let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        // The sizes are filled in by `finish`. Until then they're as large
+        // as they can be, which most readers take to mean "read to the end".
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // Integer PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            dump: dump.clone(),
+            name,
+            file,
+            data_len: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8], timestamp: i64, duration: i64) -> Result<()> {
+        self.file.write_all(data)?;
+        self.data_len += data.len() as u64;
+        self.dump
+            .log(&self.name, timestamp, duration, true, data.len())
+    }
+
+    /// Fills in the sizes, once the last block has been written.
+    pub fn finish(&mut self) -> Result<()> {
+        let data_len = self.data_len.min(u32::MAX as u64 - WAV_HEADER_LEN) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(data_len + WAV_HEADER_LEN as u32 - 8).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(WAV_HEADER_LEN - 4))?;
+        self.file.write_all(&data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        self.dump.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{DumpSink, PcmDump, StreamDump};
+    use crate::{
+        mux::aac::strip_adts,
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            synthetic::MemorySink, AudioCodec, AudioStreamFormat, Sink, StreamFormat, VideoCodec,
+            VideoStreamFormat,
+        },
+    };
+
+    /// A directory of its own for each test, emptied when it's done.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "displayrecorder_{}_{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        fn read(&self, name: &str) -> Vec<u8> {
+            std::fs::read(self.0.join(name)).unwrap()
+        }
+
+        fn timestamps(&self) -> Vec<String> {
+            let text = String::from_utf8(self.read("timestamps.csv")).unwrap();
+            text.lines().map(str::to_string).collect()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn audio_format(codec: AudioCodec) -> StreamFormat {
+        StreamFormat::Audio(AudioStreamFormat {
+            codec,
+            sample_rate: 48000,
+            channels: 2,
+            bit_rate: 192000,
+            name: None,
+            language: None,
+        })
+    }
+
+    fn packet(kind: StreamKind, stream: usize, data: &[u8], timestamp: i64) -> EncodedPacket {
+        let mut packet = EncodedPacket::new(kind, data.to_vec(), timestamp, 100, true);
+        packet.stream = stream;
+        packet
+    }
+
+    #[test]
+    fn copies_packets_into_raw_streams() {
+        let dir = TestDir::new("raw_streams");
+        let inner = Arc::new(Mutex::new(MemorySink::new()));
+        let mut sink = DumpSink::new(inner.clone(), StreamDump::create(&dir.0).unwrap());
+        let video = StreamFormat::Video(VideoStreamFormat {
+            codec: VideoCodec::H264,
+            width: 1920,
+            height: 1080,
+            frame_rate: 60,
+            bit_rate: 8_000_000,
+        });
+        assert_eq!(sink.add_stream(video).unwrap(), 0);
+        for codec in [AudioCodec::Aac, AudioCodec::Flac, AudioCodec::Opus] {
+            sink.add_stream(audio_format(codec)).unwrap();
+        }
+        sink.start().unwrap();
+        let packets = [
+            packet(StreamKind::Video, 0, &[0, 0, 0, 1, 0x65, 1], 0),
+            packet(StreamKind::Audio, 0, &[0x21, 0x10], 0),
+            packet(StreamKind::Audio, 1, &[0xff, 0xf8, 1], 0),
+            packet(StreamKind::Audio, 2, &[0xfc, 1, 2], 0),
+            packet(StreamKind::Video, 0, &[0, 0, 0, 1, 0x41, 2], 100),
+            packet(StreamKind::Audio, 0, &[0x21, 0x10, 0x30], 100),
+        ];
+        for packet in packets {
+            sink.write(packet).unwrap();
+        }
+        sink.stop().unwrap();
+
+        // Everything still gets to the inner sink
+        let inner = inner.lock().unwrap();
+        assert_eq!(inner.formats.len(), 4);
+        assert_eq!(inner.packets.len(), 6);
+        assert!(inner.stopped);
+
+        assert_eq!(
+            dir.read("video_0.h264"),
+            [0, 0, 0, 1, 0x65, 1, 0, 0, 0, 1, 0x41, 2]
+        );
+        // Each AAC frame gets an ADTS header
+        let aac = dir.read("audio_0.aac");
+        assert_eq!(aac.len(), 7 + 2 + 7 + 3);
+        assert_eq!(strip_adts(&aac[..9]), [0x21, 0x10]);
+        assert_eq!(strip_adts(&aac[9..]), [0x21, 0x10, 0x30]);
+        let flac = dir.read("audio_1.flac");
+        assert_eq!(&flac[..4], b"fLaC");
+        assert_eq!(flac.len(), 4 + 4 + 34 + 3);
+        // Opus has no raw stream to go in
+        assert!(!dir.0.join("audio_2.opus").exists());
+
+        assert_eq!(
+            dir.timestamps(),
+            [
+                "stream,timestamp,duration,keyframe,bytes",
+                "video_0,0,100,1,6",
+                "audio_0,0,100,1,2",
+                "audio_1,0,100,1,3",
+                "audio_2,0,100,1,3",
+                "video_0,100,100,1,6",
+                "audio_0,100,100,1,3",
+            ]
+        );
+    }
+
+    #[test]
+    fn packets_need_a_stream() {
+        let dir = TestDir::new("unknown_stream");
+        let inner = Arc::new(Mutex::new(MemorySink::new()));
+        let mut sink = DumpSink::new(inner, StreamDump::create(&dir.0).unwrap());
+        sink.add_stream(audio_format(AudioCodec::Aac)).unwrap();
+        sink.start().unwrap();
+        assert!(sink.write(packet(StreamKind::Audio, 1, &[1], 0)).is_err());
+    }
+
+    #[test]
+    fn writes_pcm_to_a_wav() {
+        let dir = TestDir::new("wav");
+        let dump = StreamDump::create(&dir.0).unwrap();
+        let mut pcm = PcmDump::create(&dump, 1, 48000, 2).unwrap();
+        pcm.write(&[1, 0, 2, 0], 0, 2).unwrap();
+        pcm.write(&[3, 0, 4, 0, 5, 0, 6, 0], 2, 4).unwrap();
+        pcm.finish().unwrap();
+
+        let wav = dir.read("pcm_1.wav");
+        assert_eq!(wav.len(), 44 + 12);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], 48u32.to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // Stereo at 48 kHz, 4 bytes a frame
+        assert_eq!(wav[22..24], 2u16.to_le_bytes());
+        assert_eq!(wav[24..28], 48000u32.to_le_bytes());
+        assert_eq!(wav[28..32], 192000u32.to_le_bytes());
+        assert_eq!(wav[32..34], 4u16.to_le_bytes());
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], 12u32.to_le_bytes());
+        assert_eq!(wav[44..], [1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0]);
+
+        assert_eq!(
+            dir.timestamps(),
+            [
+                "stream,timestamp,duration,keyframe,bytes",
+                "pcm_1,0,2,1,4",
+                "pcm_1,2,4,1,8",
+            ]
+        );
+    }
+}
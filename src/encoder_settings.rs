@@ -0,0 +1,957 @@
+//! How the encoders are set up. For video that's rate control, keyframes,
+//! B-frames and the H.264 profile and level, and for audio the codec and bit
+//! rate. Anything left unset is up to the encoder. Settings are checked
+//! here, and turned into the codec API values the encoder is given, so
+//! neither needs an encoder to test.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    auto_record::parse_lines,
+    pipeline::{AudioCodec, VideoCodec},
+};
+
+/// How the encoder decides how many bits each frame gets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RateControl {
+    /// A constant bit rate.
+    Cbr,
+    /// A variable bit rate that averages out to the bit rate.
+    Vbr,
+    /// A constant quality, from 0 to 100.
+    Quality,
+    /// A constant quantizer, from 0 to 51. Lower is better.
+    Cqp,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseRateControlError(&'static str);
+
+impl FromStr for RateControl {
+    type Err = ParseRateControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cbr" => Ok(RateControl::Cbr),
+            "vbr" => Ok(RateControl::Vbr),
+            "quality" => Ok(RateControl::Quality),
+            "cqp" => Ok(RateControl::Cqp),
+            _ => Err(ParseRateControlError(
+                "Invalid rate-control value! Expecting: cbr, vbr, quality, or cqp.",
+            )),
+        }
+    }
+}
+
+impl Display for RateControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            RateControl::Cbr => "cbr",
+            RateControl::Vbr => "vbr",
+            RateControl::Quality => "quality",
+            RateControl::Cqp => "cqp",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseRateControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseRateControlError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+impl Profile {
+    /// The `eAVEncH264VProfile` value, which is the `profile_idc` from the
+    /// spec.
+    pub fn value(self) -> u32 {
+        match self {
+            Profile::Baseline => 66,
+            Profile::Main => 77,
+            Profile::High => 100,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseProfileError(&'static str);
+
+impl FromStr for Profile {
+    type Err = ParseProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "baseline" => Ok(Profile::Baseline),
+            "main" => Ok(Profile::Main),
+            "high" => Ok(Profile::High),
+            _ => Err(ParseProfileError(
+                "Invalid profile value! Expecting: baseline, main, or high.",
+            )),
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Profile::Baseline => "baseline",
+            Profile::Main => "main",
+            Profile::High => "high",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseProfileError {}
+
+/// An H.264 level, like 4.1, which caps the frame size, frame rate and bit
+/// rate.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Level(u32);
+
+/// The limits of each level, from table A-1 of the spec: macroblocks per
+/// second, macroblocks per frame, and the bit rate in kbps for the baseline
+/// and main profiles.
+const LEVELS: [(Level, u32, u32, u32); 19] = [
+    (Level(10), 1_485, 99, 64),
+    (Level(11), 3_000, 396, 192),
+    (Level(12), 6_000, 396, 384),
+    (Level(13), 11_880, 396, 768),
+    (Level(20), 11_880, 396, 2_000),
+    (Level(21), 19_800, 792, 4_000),
+    (Level(22), 20_250, 1_620, 4_000),
+    (Level(30), 40_500, 1_620, 10_000),
+    (Level(31), 108_000, 3_600, 14_000),
+    (Level(32), 216_000, 5_120, 20_000),
+    (Level(40), 245_760, 8_192, 20_000),
+    (Level(41), 245_760, 8_192, 50_000),
+    (Level(42), 522_240, 8_704, 50_000),
+    (Level(50), 589_824, 22_080, 135_000),
+    (Level(51), 983_040, 36_864, 240_000),
+    (Level(52), 2_073_600, 36_864, 240_000),
+    (Level(60), 4_177_920, 139_264, 240_000),
+    (Level(61), 8_355_840, 139_264, 480_000),
+    (Level(62), 16_711_680, 139_264, 800_000),
+];
+
+impl Level {
+    /// The `eAVEncH264VLevel` value, which is the `level_idc` from the
+    /// spec.
+    pub fn value(self) -> u32 {
+        self.0
+    }
+
+    /// The lowest level a video of the given size, frame rate and bit rate
+    /// fits in with the baseline or main profile, or the highest there is.
+    pub fn fitting(width: u32, height: u32, frame_rate: u32, bit_rate: u64) -> Level {
+        let frame_size = width.div_ceil(16) * height.div_ceil(16);
+        LEVELS
+            .iter()
+            .find(|&&(_, max_mbps, max_frame_size, max_bit_rate)| {
+                frame_size <= max_frame_size
+                    && frame_size as u64 * frame_rate as u64 <= max_mbps as u64
+                    && bit_rate <= max_bit_rate as u64 * 1000
+            })
+            .map_or(LEVELS[LEVELS.len() - 1].0, |&(level, ..)| level)
+    }
+
+    fn limits(self) -> (u32, u32, u32) {
+        let &(_, max_mbps, max_frame_size, max_bit_rate) = LEVELS
+            .iter()
+            .find(|(level, ..)| *level == self)
+            .expect("Levels are only made from the table");
+        (max_mbps, max_frame_size, max_bit_rate)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseLevelError(&'static str);
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error =
+            ParseLevelError("Invalid level value! Expecting a level from 1 to 6.2, like 4.1.");
+        let (major, minor) = s.split_once('.').unwrap_or((s, "0"));
+        let (Ok(major), Ok(minor)) = (major.parse::<u32>(), minor.parse::<u32>()) else {
+            return Err(error);
+        };
+        if minor > 9 {
+            return Err(error);
+        }
+        let level = Level(major * 10 + minor);
+        match LEVELS.iter().any(|(known, ..)| *known == level) {
+            true => Ok(level),
+            false => Err(error),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.0 / 10, self.0 % 10)
+    }
+}
+
+impl Display for ParseLevelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseLevelError {}
+
+/// A codec API property the settings are applied with, named after its
+/// `CODECAPI_` GUID.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CodecProperty {
+    /// `AVEncCommonRateControlMode`, an `eAVEncCommonRateControlMode`.
+    RateControlMode,
+    /// `AVEncCommonMeanBitRate`, in bits per second.
+    MeanBitRate,
+    /// `AVEncCommonQuality`, from 0 to 100.
+    Quality,
+    /// `AVEncVideoEncodeQP`, from 0 to 51.
+    Qp,
+    /// `AVEncMPVGOPSize`, in frames.
+    GopSize,
+    /// `AVEncMPVDefaultBPictureCount`.
+    BFrames,
+    /// `AVLowLatencyMode`, 1 for on.
+    LowLatency,
+}
+
+impl Display for CodecProperty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            CodecProperty::RateControlMode => "rate control mode",
+            CodecProperty::MeanBitRate => "bit rate",
+            CodecProperty::Quality => "quality",
+            CodecProperty::Qp => "QP",
+            CodecProperty::GopSize => "keyframe interval",
+            CodecProperty::BFrames => "number of B-frames",
+            CodecProperty::LowLatency => "low-latency mode",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+// eAVEncCommonRateControlMode values
+const RATE_CONTROL_CBR: u32 = 0;
+const RATE_CONTROL_UNCONSTRAINED_VBR: u32 = 2;
+const RATE_CONTROL_QUALITY: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoEncoderSettings {
+    pub codec: VideoCodec,
+    /// In bits per second. Ignored when encoding for a quality or QP.
+    pub bit_rate: u32,
+    pub rate_control: Option<RateControl>,
+    /// From 0 to 100, only for `RateControl::Quality`.
+    pub quality: Option<u32>,
+    /// From 0 to 51, or 255 for AV1, and needed for `RateControl::Cqp`.
+    pub qp: Option<u32>,
+    /// Frames from one keyframe to the next.
+    pub keyframe_interval: Option<u32>,
+    /// Not for AV1, which doesn't have them.
+    pub b_frames: Option<u32>,
+    /// Only for H.264.
+    pub profile: Option<Profile>,
+    /// Only for H.264.
+    pub level: Option<Level>,
+    /// Trades quality for getting each frame out as soon as it's in.
+    pub low_latency: bool,
+}
+
+impl VideoEncoderSettings {
+    /// H.264 with everything up to the encoder, at the given bit rate.
+    pub fn new(bit_rate: u32) -> Self {
+        Self {
+            codec: VideoCodec::H264,
+            bit_rate,
+            rate_control: None,
+            quality: None,
+            qp: None,
+            keyframe_interval: None,
+            b_frames: None,
+            profile: None,
+            level: None,
+            low_latency: false,
+        }
+    }
+
+    /// Checks the settings make sense together.
+    pub fn validate(&self) -> Result<(), String> {
+        let b_frames = self.b_frames.unwrap_or(0);
+        if self.quality.is_some() && self.rate_control != Some(RateControl::Quality) {
+            return Err("A quality can only be set with quality rate control.".to_owned());
+        }
+        if self.qp.is_some() && self.rate_control != Some(RateControl::Cqp) {
+            return Err("A QP can only be set with cqp rate control.".to_owned());
+        }
+        if self.rate_control == Some(RateControl::Cqp) && self.qp.is_none() {
+            return Err("Rate control cqp needs a QP.".to_owned());
+        }
+        if self.quality.is_some_and(|quality| quality > 100) {
+            return Err("The quality has to be from 0 to 100.".to_owned());
+        }
+        let max_qp = match self.codec {
+            VideoCodec::Av1 => 255,
+            _ => 51,
+        };
+        if self.qp.is_some_and(|qp| qp > max_qp) {
+            return Err(format!(
+                "The QP has to be from 0 to {} for {}.",
+                max_qp,
+                self.codec.to_string().to_uppercase()
+            ));
+        }
+        if self.codec != VideoCodec::H264 && (self.profile.is_some() || self.level.is_some()) {
+            return Err("Profiles and levels can only be set for H.264.".to_owned());
+        }
+        if self.codec == VideoCodec::Av1 && b_frames > 0 {
+            return Err("AV1 doesn't have B-frames.".to_owned());
+        }
+        if self.keyframe_interval == Some(0) {
+            return Err("The keyframe interval has to be at least one frame.".to_owned());
+        }
+        if b_frames > 0 && self.profile == Some(Profile::Baseline) {
+            return Err("B-frames aren't allowed in the baseline profile.".to_owned());
+        }
+        if b_frames > 0 && self.low_latency {
+            return Err("B-frames can't be used in low-latency mode.".to_owned());
+        }
+        if self
+            .keyframe_interval
+            .is_some_and(|interval| b_frames >= interval)
+        {
+            return Err(
+                "There have to be fewer B-frames than frames between keyframes.".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks a video of the given size and frame rate fits in the level,
+    /// if there is one. Levels are only set for H.264.
+    pub fn check_level(&self, width: u32, height: u32, frame_rate: u32) -> Result<(), String> {
+        let Some(level) = self.level else {
+            return Ok(());
+        };
+        let (max_mbps, max_frame_size, max_bit_rate) = level.limits();
+        let frame_size = width.div_ceil(16) * height.div_ceil(16);
+        if frame_size > max_frame_size {
+            return Err(format!(
+                "{}x{} is too big for level {}.",
+                width, height, level
+            ));
+        }
+        if frame_size as u64 * frame_rate as u64 > max_mbps as u64 {
+            return Err(format!(
+                "{}x{} at {} fps is too much for level {}.",
+                width, height, frame_rate, level
+            ));
+        }
+        // The high profile is allowed a quarter more
+        let max_bit_rate = match self.profile {
+            Some(Profile::High) => max_bit_rate as u64 * 1250,
+            _ => max_bit_rate as u64 * 1000,
+        };
+        let uses_bit_rate = matches!(
+            self.rate_control,
+            None | Some(RateControl::Cbr | RateControl::Vbr)
+        );
+        if uses_bit_rate && self.bit_rate as u64 > max_bit_rate {
+            return Err(format!(
+                "{} kbps is too much for level {}, which allows {} kbps.",
+                self.bit_rate / 1000,
+                level,
+                max_bit_rate / 1000
+            ));
+        }
+        Ok(())
+    }
+
+    /// The codec API values that apply the settings, in the order they
+    /// should be set.
+    pub fn codec_values(&self) -> Vec<(CodecProperty, u32)> {
+        let mut values = Vec::new();
+        match self.rate_control {
+            Some(RateControl::Cbr) => {
+                values.push((CodecProperty::RateControlMode, RATE_CONTROL_CBR));
+                values.push((CodecProperty::MeanBitRate, self.bit_rate));
+            }
+            Some(RateControl::Vbr) => {
+                values.push((
+                    CodecProperty::RateControlMode,
+                    RATE_CONTROL_UNCONSTRAINED_VBR,
+                ));
+                values.push((CodecProperty::MeanBitRate, self.bit_rate));
+            }
+            Some(RateControl::Quality) => {
+                values.push((CodecProperty::RateControlMode, RATE_CONTROL_QUALITY));
+                if let Some(quality) = self.quality {
+                    values.push((CodecProperty::Quality, quality));
+                }
+            }
+            // A QP replaces the quality in quality mode
+            Some(RateControl::Cqp) => {
+                values.push((CodecProperty::RateControlMode, RATE_CONTROL_QUALITY));
+                if let Some(qp) = self.qp {
+                    values.push((CodecProperty::Qp, qp));
+                }
+            }
+            None => {}
+        }
+        if let Some(interval) = self.keyframe_interval {
+            values.push((CodecProperty::GopSize, interval));
+        }
+        if let Some(b_frames) = self.b_frames {
+            values.push((CodecProperty::BFrames, b_frames));
+        }
+        if self.low_latency {
+            values.push((CodecProperty::LowLatency, 1));
+        }
+        values
+    }
+
+    pub fn apply(&mut self, setting: VideoSetting) {
+        match setting {
+            VideoSetting::Codec(codec) => self.codec = codec,
+            VideoSetting::BitRate(bit_rate) => self.bit_rate = bit_rate,
+            VideoSetting::RateControl(rate_control) => self.rate_control = Some(rate_control),
+            VideoSetting::Quality(quality) => self.quality = Some(quality),
+            VideoSetting::Qp(qp) => self.qp = Some(qp),
+            VideoSetting::KeyframeInterval(interval) => self.keyframe_interval = Some(interval),
+            VideoSetting::BFrames(b_frames) => self.b_frames = Some(b_frames),
+            VideoSetting::Profile(profile) => self.profile = Some(profile),
+            VideoSetting::Level(level) => self.level = Some(level),
+            VideoSetting::LowLatency(low_latency) => self.low_latency = low_latency,
+        }
+    }
+}
+
+/// One line of a video encoder settings file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VideoSetting {
+    Codec(VideoCodec),
+    /// In bits per second.
+    BitRate(u32),
+    RateControl(RateControl),
+    Quality(u32),
+    Qp(u32),
+    KeyframeInterval(u32),
+    BFrames(u32),
+    Profile(Profile),
+    Level(Level),
+    LowLatency(bool),
+}
+
+impl VideoSetting {
+    /// Parses a setting named like its command line option, then its value,
+    /// as in `rate-control vbr` or `level 4.1`. The bit rate is in Mbps like
+    /// the option, and `low-latency` takes true or false.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let (name, value) = match line.split_once(char::is_whitespace) {
+            Some((name, value)) => (name, value.trim()),
+            None => (line, ""),
+        };
+        Ok(match name.to_lowercase().as_str() {
+            "codec" => VideoSetting::Codec(parse_value(value)?),
+            "bit-rate" => VideoSetting::BitRate(
+                parse_value::<u32>(value)?
+                    .checked_mul(1_000_000)
+                    .ok_or("the bit rate is too high")?,
+            ),
+            "rate-control" => VideoSetting::RateControl(parse_value(value)?),
+            "quality" => VideoSetting::Quality(parse_value(value)?),
+            "qp" => VideoSetting::Qp(parse_value(value)?),
+            "keyframe-interval" => VideoSetting::KeyframeInterval(parse_value(value)?),
+            "b-frames" => VideoSetting::BFrames(parse_value(value)?),
+            "profile" => VideoSetting::Profile(parse_value(value)?),
+            "level" => VideoSetting::Level(parse_value(value)?),
+            "low-latency" => VideoSetting::LowLatency(parse_value(value)?),
+            _ => return Err(format!("`{}` isn't a video encoder setting", name)),
+        })
+    }
+
+    /// Parses one setting per line. Blank lines and lines starting with `#`
+    /// are skipped.
+    pub fn parse_file(text: &str) -> Result<Vec<Self>, String> {
+        parse_lines(text, Self::parse)
+    }
+
+    /// The ID of the command line option for the same setting.
+    #[cfg(windows)]
+    pub fn option(self) -> &'static str {
+        match self {
+            VideoSetting::Codec(_) => "codec",
+            VideoSetting::BitRate(_) => "bit_rate",
+            VideoSetting::RateControl(_) => "rate_control",
+            VideoSetting::Quality(_) => "quality",
+            VideoSetting::Qp(_) => "qp",
+            VideoSetting::KeyframeInterval(_) => "keyframe_interval",
+            VideoSetting::BFrames(_) => "b_frames",
+            VideoSetting::Profile(_) => "profile",
+            VideoSetting::Level(_) => "level",
+            VideoSetting::LowLatency(_) => "low_latency",
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|error: T::Err| error.to_string())
+}
+
+// The only rates the Media Foundation AAC encoder takes
+const AAC_BIT_RATES: [u32; 4] = [96_000, 128_000, 160_000, 192_000];
+const OPUS_BIT_RATES: std::ops::RangeInclusive<u32> = 6_000..=510_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioEncoderSettings {
+    pub codec: AudioCodec,
+    /// In bits per second. Only AAC and Opus take one.
+    pub bit_rate: Option<u32>,
+}
+
+impl AudioEncoderSettings {
+    /// The codec at whatever bit rate suits it.
+    pub fn new(codec: AudioCodec) -> Self {
+        Self {
+            codec,
+            bit_rate: None,
+        }
+    }
+
+    /// Checks the codec can be encoded at the bit rate.
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(bit_rate) = self.bit_rate else {
+            return Ok(());
+        };
+        match self.codec {
+            AudioCodec::Aac if !AAC_BIT_RATES.contains(&bit_rate) => {
+                Err("AAC can only be encoded at 96, 128, 160 or 192 kbps.".to_owned())
+            }
+            AudioCodec::Opus if !OPUS_BIT_RATES.contains(&bit_rate) => {
+                Err("Opus can only be encoded at 6 to 510 kbps.".to_owned())
+            }
+            AudioCodec::Flac | AudioCodec::Pcm => Err(format!(
+                "{} is lossless, so it can't be given a bit rate.",
+                self.codec.to_string().to_uppercase()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The bit rate to encode audio of the given rate and channels at. For
+    /// FLAC and PCM that's the rate of the 16-bit samples, which FLAC never
+    /// needs more than.
+    pub fn bit_rate(&self, sample_rate: u32, channels: u16) -> u32 {
+        match self.codec {
+            AudioCodec::Aac => self
+                .bit_rate
+                .unwrap_or_else(|| default_aac_bit_rate(sample_rate, channels)),
+            AudioCodec::Opus => self
+                .bit_rate
+                .unwrap_or((channels as u32 * 64_000).min(*OPUS_BIT_RATES.end())),
+            AudioCodec::Flac | AudioCodec::Pcm => sample_rate * channels as u32 * 16,
+        }
+    }
+}
+
+/// The AAC bit rate for audio of the given rate and channels, when none is
+/// asked for.
+pub fn default_aac_bit_rate(sample_rate: u32, channels: u16) -> u32 {
+    match (sample_rate, channels) {
+        (sr, ch) if sr >= 48000 && ch >= 2 => 192000, // High quality stereo
+        (sr, ch) if sr >= 44100 && ch >= 2 => 128000, // CD quality stereo
+        (sr, ch) if sr >= 44100 && ch == 1 => 96000,  // CD quality mono
+        (sr, _) if sr >= 32000 => 80000,              // Medium quality
+        (sr, _) if sr >= 24000 => 64000,              // Lower quality
+        (sr, _) if sr >= 16000 => 48000,              // Voice quality
+        _ => 32000,                                   // Minimum quality
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AudioEncoderSettings, CodecProperty, Level, Profile, RateControl, VideoEncoderSettings,
+        VideoSetting,
+    };
+    use crate::pipeline::{AudioCodec, VideoCodec};
+
+    #[test]
+    fn parses_rate_control() {
+        for rate_control in [
+            RateControl::Cbr,
+            RateControl::Vbr,
+            RateControl::Quality,
+            RateControl::Cqp,
+        ] {
+            assert_eq!(rate_control.to_string().parse(), Ok(rate_control));
+        }
+        assert_eq!("CBR".parse(), Ok(RateControl::Cbr));
+        assert!("abr".parse::<RateControl>().is_err());
+    }
+
+    #[test]
+    fn parses_profile() {
+        for profile in [Profile::Baseline, Profile::Main, Profile::High] {
+            assert_eq!(profile.to_string().parse(), Ok(profile));
+        }
+        assert!("high10".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn parses_level() {
+        assert_eq!("4.1".parse::<Level>().map(Level::value), Ok(41));
+        assert_eq!("5".parse::<Level>().map(Level::value), Ok(50));
+        assert_eq!("3.0".parse::<Level>().map(Level::value), Ok(30));
+        assert_eq!("6.2".parse::<Level>().unwrap().to_string(), "6.2");
+        for invalid in ["", "4.3", "7", "1.10", "4.1.1", "high"] {
+            assert!(invalid.parse::<Level>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn reads_settings_files() {
+        let text = "\
+# Streaming
+codec hevc
+bit-rate 6
+Rate-Control vbr
+
+keyframe-interval 120
+b-frames 2
+level 5.1
+low-latency true
+";
+        let mut settings = VideoEncoderSettings::new(18_000_000);
+        for setting in VideoSetting::parse_file(text).unwrap() {
+            settings.apply(setting);
+        }
+        assert_eq!(
+            settings,
+            VideoEncoderSettings {
+                codec: VideoCodec::Hevc,
+                rate_control: Some(RateControl::Vbr),
+                keyframe_interval: Some(120),
+                b_frames: Some(2),
+                level: Some("5.1".parse().unwrap()),
+                low_latency: true,
+                ..VideoEncoderSettings::new(6_000_000)
+            }
+        );
+
+        let error = VideoSetting::parse_file("qp 20\nprofile high10\n").unwrap_err();
+        assert!(error.starts_with("line 2: "), "{error}");
+        for invalid in [
+            "crf 20",
+            "qp",
+            "quality high",
+            "low-latency yes",
+            "bit-rate 5000",
+        ] {
+            assert!(VideoSetting::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn finds_fitting_level() {
+        assert_eq!(Level::fitting(1280, 720, 30, 14_000_000).to_string(), "3.1");
+        assert_eq!(Level::fitting(1280, 720, 30, 20_000_000).to_string(), "3.2");
+        assert_eq!(
+            Level::fitting(1920, 1080, 30, 20_000_000).to_string(),
+            "4.0"
+        );
+        assert_eq!(
+            Level::fitting(1920, 1080, 30, 50_000_000).to_string(),
+            "4.1"
+        );
+        assert_eq!(
+            Level::fitting(1920, 1080, 60, 20_000_000).to_string(),
+            "4.2"
+        );
+        assert_eq!(Level::fitting(7680, 4320, 240, 0).to_string(), "6.2");
+        assert_eq!(
+            Level::fitting(1920, 1080, 60, 2_000_000_000).to_string(),
+            "6.2"
+        );
+    }
+
+    #[test]
+    fn validates_combinations() {
+        let settings = VideoEncoderSettings::new(18_000_000);
+        assert_eq!(settings.validate(), Ok(()));
+
+        let invalid = [
+            VideoEncoderSettings {
+                profile: Some(Profile::Baseline),
+                b_frames: Some(2),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                low_latency: true,
+                b_frames: Some(1),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                keyframe_interval: Some(2),
+                b_frames: Some(2),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                keyframe_interval: Some(0),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                rate_control: Some(RateControl::Cqp),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                rate_control: Some(RateControl::Cqp),
+                qp: Some(52),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                rate_control: Some(RateControl::Quality),
+                quality: Some(101),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                rate_control: Some(RateControl::Cbr),
+                quality: Some(70),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                qp: Some(23),
+                ..settings.clone()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?}", settings);
+        }
+
+        // No B-frames is fine anywhere, and B-frames are fine in main
+        let valid = [
+            VideoEncoderSettings {
+                profile: Some(Profile::Baseline),
+                b_frames: Some(0),
+                low_latency: true,
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                profile: Some(Profile::Main),
+                b_frames: Some(2),
+                keyframe_interval: Some(120),
+                ..settings.clone()
+            },
+            VideoEncoderSettings {
+                rate_control: Some(RateControl::Quality),
+                ..settings.clone()
+            },
+        ];
+        for settings in valid {
+            assert_eq!(settings.validate(), Ok(()), "{:?}", settings);
+        }
+    }
+
+    #[test]
+    fn validates_codecs() {
+        let hevc = VideoEncoderSettings {
+            codec: VideoCodec::Hevc,
+            ..VideoEncoderSettings::new(18_000_000)
+        };
+        let av1 = VideoEncoderSettings {
+            codec: VideoCodec::Av1,
+            ..hevc.clone()
+        };
+        let cqp = |settings: &VideoEncoderSettings, qp| VideoEncoderSettings {
+            rate_control: Some(RateControl::Cqp),
+            qp: Some(qp),
+            ..settings.clone()
+        };
+        assert_eq!(cqp(&av1, 200).validate(), Ok(()));
+        assert!(cqp(&av1, 256).validate().is_err());
+        assert!(cqp(&hevc, 52).validate().is_err());
+        let b_frames = |settings: &VideoEncoderSettings| VideoEncoderSettings {
+            b_frames: Some(2),
+            ..settings.clone()
+        };
+        assert_eq!(b_frames(&hevc).validate(), Ok(()));
+        assert!(b_frames(&av1).validate().is_err());
+        for settings in [hevc, av1] {
+            let profile = VideoEncoderSettings {
+                profile: Some(Profile::High),
+                ..settings.clone()
+            };
+            assert!(profile.validate().is_err());
+            let level = VideoEncoderSettings {
+                level: Some("5.1".parse().unwrap()),
+                ..settings
+            };
+            assert!(level.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn checks_levels() {
+        let settings = |level: &str, bit_rate: u32| VideoEncoderSettings {
+            level: Some(level.parse().unwrap()),
+            ..VideoEncoderSettings::new(bit_rate)
+        };
+        // 1080p is 8160 macroblocks, since 1080 rounds up to 1088
+        assert_eq!(
+            settings("4.1", 20_000_000).check_level(1920, 1080, 30),
+            Ok(())
+        );
+        assert!(settings("4.1", 20_000_000)
+            .check_level(1920, 1080, 60)
+            .is_err());
+        assert_eq!(
+            settings("4.2", 20_000_000).check_level(1920, 1080, 60),
+            Ok(())
+        );
+        assert!(settings("3.1", 10_000_000)
+            .check_level(1920, 1080, 30)
+            .is_err());
+        assert!(settings("5.1", 20_000_000)
+            .check_level(3840, 2160, 60)
+            .is_err());
+        assert_eq!(
+            settings("5.2", 20_000_000).check_level(3840, 2160, 60),
+            Ok(())
+        );
+
+        // Level 4 allows 20 Mbps, or 25 in the high profile
+        assert!(settings("4", 24_000_000)
+            .check_level(1280, 720, 30)
+            .is_err());
+        let high = VideoEncoderSettings {
+            profile: Some(Profile::High),
+            ..settings("4", 24_000_000)
+        };
+        assert_eq!(high.check_level(1280, 720, 30), Ok(()));
+        let quality = VideoEncoderSettings {
+            rate_control: Some(RateControl::Quality),
+            ..settings("4", 100_000_000)
+        };
+        assert_eq!(quality.check_level(1280, 720, 30), Ok(()));
+
+        assert_eq!(
+            VideoEncoderSettings::new(u32::MAX).check_level(8192, 8192, 240),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn maps_to_codec_api() {
+        let settings = VideoEncoderSettings::new(8_000_000);
+        assert_eq!(settings.codec_values(), []);
+
+        let cbr = VideoEncoderSettings {
+            rate_control: Some(RateControl::Cbr),
+            keyframe_interval: Some(60),
+            b_frames: Some(0),
+            low_latency: true,
+            ..settings.clone()
+        };
+        assert_eq!(
+            cbr.codec_values(),
+            [
+                (CodecProperty::RateControlMode, 0),
+                (CodecProperty::MeanBitRate, 8_000_000),
+                (CodecProperty::GopSize, 60),
+                (CodecProperty::BFrames, 0),
+                (CodecProperty::LowLatency, 1),
+            ]
+        );
+        let vbr = VideoEncoderSettings {
+            rate_control: Some(RateControl::Vbr),
+            ..settings.clone()
+        };
+        assert_eq!(
+            vbr.codec_values(),
+            [
+                (CodecProperty::RateControlMode, 2),
+                (CodecProperty::MeanBitRate, 8_000_000)
+            ]
+        );
+        let quality = VideoEncoderSettings {
+            rate_control: Some(RateControl::Quality),
+            quality: Some(70),
+            ..settings.clone()
+        };
+        assert_eq!(
+            quality.codec_values(),
+            [
+                (CodecProperty::RateControlMode, 3),
+                (CodecProperty::Quality, 70)
+            ]
+        );
+        let cqp = VideoEncoderSettings {
+            rate_control: Some(RateControl::Cqp),
+            qp: Some(23),
+            ..settings.clone()
+        };
+        assert_eq!(
+            cqp.codec_values(),
+            [(CodecProperty::RateControlMode, 3), (CodecProperty::Qp, 23)]
+        );
+
+        assert_eq!(Profile::Baseline.value(), 66);
+        assert_eq!(Profile::Main.value(), 77);
+        assert_eq!(Profile::High.value(), 100);
+    }
+
+    #[test]
+    fn validates_audio_bit_rates() {
+        let settings = |codec, kbps: u32| AudioEncoderSettings {
+            codec,
+            bit_rate: Some(kbps * 1000),
+        };
+        assert_eq!(settings(AudioCodec::Aac, 160).validate(), Ok(()));
+        assert!(settings(AudioCodec::Aac, 100).validate().is_err());
+        assert_eq!(settings(AudioCodec::Opus, 100).validate(), Ok(()));
+        assert!(settings(AudioCodec::Opus, 600).validate().is_err());
+        assert_eq!(
+            settings(AudioCodec::Flac, 128).validate(),
+            Err("FLAC is lossless, so it can't be given a bit rate.".to_owned())
+        );
+        assert!(settings(AudioCodec::Pcm, 128).validate().is_err());
+        for codec in [AudioCodec::Flac, AudioCodec::Pcm] {
+            assert_eq!(AudioEncoderSettings::new(codec).validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn picks_audio_bit_rates() {
+        let bit_rate = |codec| AudioEncoderSettings::new(codec).bit_rate(48000, 2);
+        assert_eq!(bit_rate(AudioCodec::Aac), 192_000);
+        assert_eq!(bit_rate(AudioCodec::Opus), 128_000);
+        assert_eq!(bit_rate(AudioCodec::Flac), 1_536_000);
+        assert_eq!(bit_rate(AudioCodec::Pcm), 1_536_000);
+        let asked = AudioEncoderSettings {
+            codec: AudioCodec::Opus,
+            bit_rate: Some(96_000),
+        };
+        assert_eq!(asked.bit_rate(48000, 2), 96_000);
+    }
+}
@@ -1,195 +1,275 @@
-use std::sync::{Arc, Mutex};
-use windows::{
-    core::{Result, HSTRING},
-    Foundation::TimeSpan,
-    Graphics::SizeInt32,
-    Storage::Streams::IRandomAccessStream,
-    Win32::{
-        Graphics::{
-            Direct3D11::ID3D11Device,
-            Gdi::HMONITOR,
-        },
-        Media::MediaFoundation::{
-            IMFMediaType, IMFSample, IMFSinkWriter, MF_SINK_WRITER_DISABLE_THROTTLING,
-            MFCreateAttributes, MFCreateMFByteStreamOnStreamEx, MFCreateSinkWriterFromURL
-        }, System::Performance::QueryPerformanceCounter,
-    },
+use crate::{
+    clock::{PausableClock, SharedClock},
+    pipeline::{Result, SharedSink, StreamSession},
 };
 
+#[cfg(windows)]
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+
+#[cfg(windows)]
+use windows::Win32::Graphics::{Direct3D11::ID3D11Device, Gdi::HMONITOR};
+
+#[cfg(windows)]
 use crate::{
-    audio::encoder_device::AudioEncoderDevice,
-    audio::encoding_session::AudioEncodingSession,
-    video::encoder_device::VideoEncoderDevice,
-    video::encoding_session::VideoEncodingSession,
+    audio::{
+        encoder_device::AudioEncoderDevice,
+        encoding_session::{new_audio_sessions, AudioSources},
+    },
+    clock::SystemClock,
+    dump::{DumpSink, StreamDump},
+    encoder_settings::{AudioEncoderSettings, VideoEncoderSettings},
+    pacer::Pacing,
+    privacy::Privacy,
+    region::Crop,
+    resolution::{Resolution, ScaleMode},
+    video::{encoder_device::VideoEncoderDevice, encoding_session::new_video_session},
+    window_target::WindowCapture,
 };
 
+/// Records a video stream and any number of audio streams into a shared
+/// sink.
 pub struct MediaEncodingSession {
-    video_session: VideoEncodingSession,
-    audio_session: AudioEncodingSession,
-    sample_writer: Arc<Mutex<SampleWriter>>,
+    video_session: StreamSession,
+    audio_sessions: Vec<StreamSession>,
+    sink: SharedSink,
+    clock: PausableClock,
 }
 
-pub struct SampleWriter {
-    _stream: IRandomAccessStream,
-    sink_writer: IMFSinkWriter,
-    video_stream_index: Option<u32>,
-    audio_stream_index: Option<u32>,
-}
-
-unsafe impl Send for SampleWriter {}
-unsafe impl Sync for SampleWriter {}
-impl SampleWriter {
-    pub fn new(stream: IRandomAccessStream) -> Result<Self> {
-        let attributes = unsafe {
-            let mut attributes = None;
-            MFCreateAttributes(&mut attributes, 1)?;
-            let attributes = attributes.unwrap();
-            
-            // Set the disable throttling attribute to TRUE
-            attributes.SetUINT32(&MF_SINK_WRITER_DISABLE_THROTTLING, 1)?;
-            
-            attributes
-        };
-        
-        let sink_writer = unsafe {
-            let byte_stream = MFCreateMFByteStreamOnStreamEx(&stream)?;
-            MFCreateSinkWriterFromURL(&HSTRING::from(".mp4"), &byte_stream, &attributes)?
-        };
+impl MediaEncodingSession {
+    /// All streams must already have been added to `sink`. `clock` is read
+    /// on start to pick the time the streams are relative to, and whenever
+    /// the recording is paused or resumed.
+    // Recording on Windows goes through `with_clock`
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn from_streams(
+        video_session: StreamSession,
+        audio_sessions: Vec<StreamSession>,
+        sink: SharedSink,
+        clock: SharedClock,
+    ) -> Self {
+        Self::with_clock(
+            video_session,
+            audio_sessions,
+            sink,
+            PausableClock::new(clock),
+        )
+    }
 
-        Ok(Self {
-            _stream: stream,
-            sink_writer,
-            video_stream_index: None,
-            audio_stream_index: None,
-        })
+    /// Like `from_streams`, for streams that hold on to a clone of the
+    /// pausable clock to pause the recording themselves.
+    pub fn with_clock(
+        video_session: StreamSession,
+        audio_sessions: Vec<StreamSession>,
+        sink: SharedSink,
+        clock: PausableClock,
+    ) -> Self {
+        Self {
+            video_session,
+            audio_sessions,
+            sink,
+            clock,
+        }
     }
 
-    pub fn add_video_stream(&mut self, media_type: &IMFMediaType) -> Result<u32> {
-        let stream_index = unsafe { self.sink_writer.AddStream(media_type)? };
-        self.video_stream_index = Some(stream_index);
-        Ok(stream_index)
+    pub fn start(&mut self) -> Result<()> {
+        // Start the sink first
+        self.sink.lock().unwrap().start()?;
+
+        let start_time = self.clock.start();
+        println!("Obtained start time: {}", start_time);
+
+        // Start all encoding sessions
+        for audio_session in &mut self.audio_sessions {
+            audio_session.start(self.clock.clone())?;
+        }
+        self.video_session.start(self.clock.clone())?;
+
+        Ok(())
     }
 
-    pub fn add_audio_stream(&mut self, media_type: &IMFMediaType) -> Result<u32> {
-        let stream_index = unsafe { self.sink_writer.AddStream(media_type)? };
-        self.audio_stream_index = Some(stream_index);
-        Ok(stream_index)
+    /// Stops adding to the recording. Capture keeps running, but nothing is
+    /// encoded until `resume` is called, and the time in between is left
+    /// out of the recording.
+    #[cfg(windows)]
+    pub fn pause(&mut self) -> Result<()> {
+        self.clock.pause()
     }
 
-    pub fn set_input_media_type(&self, stream_index: u32, media_type: &IMFMediaType) -> Result<()> {
-        let empty_attributes = unsafe {
-            let mut attributes = None;
-            MFCreateAttributes(&mut attributes, 0)?;
-            attributes.unwrap()
-        };
-        unsafe {
-            self.sink_writer.SetInputMediaType(stream_index, media_type, &empty_attributes)
-        }
+    #[cfg(windows)]
+    pub fn resume(&mut self) -> Result<()> {
+        self.clock.resume()
     }
 
-    pub fn start(&self) -> Result<()> {
-        unsafe { self.sink_writer.BeginWriting() }
+    #[cfg(windows)]
+    pub fn is_paused(&self) -> bool {
+        self.clock.is_paused()
     }
 
-    pub fn stop(&self) -> Result<()> {
-        unsafe { self.sink_writer.Finalize() }
+    /// Starts a chapter at the current point in the recording.
+    pub fn add_chapter(&mut self, title: &str) -> Result<()> {
+        let timestamp = self.clock.elapsed();
+        self.sink.lock().unwrap().add_chapter(timestamp, title)
     }
 
-    /// Write a video sample to the sink writer and release its buffers to avoid leaks.
-    pub fn write_video_sample(&self, sample: &IMFSample) -> Result<()> {
-        if let Some(stream_index) = self.video_stream_index {
-            unsafe {
-                // Write the sample to the sink writer
-                self.sink_writer.WriteSample(stream_index, sample)?;
-                // Remove all buffers from the sample to free associated memory
-                sample.RemoveAllBuffers()?;
-            }
-            Ok(())
-        } else {
-            Err(windows::core::Error::from_win32())
-        }
+    /// The clock the recording is paused with, for pausing it from
+    /// elsewhere.
+    #[cfg(windows)]
+    pub fn clock(&self) -> PausableClock {
+        self.clock.clone()
     }
 
-    /// Write an audio sample to the sink writer and release its buffers to avoid leaks.
-    pub fn write_audio_sample(&self, sample: &IMFSample) -> Result<()> {
-        if let Some(stream_index) = self.audio_stream_index {
-            unsafe {
-                // Write the sample to the sink writer
-                self.sink_writer.WriteSample(stream_index, sample)?;
-                // Remove all buffers from the sample to free associated memory
-                sample.RemoveAllBuffers()?;
-            }
-            Ok(())
-        } else {
-            Err(windows::core::Error::from_win32())
+    pub fn stop(&mut self) -> Result<()> {
+        // Stop all encoding sessions first
+        self.video_session.stop()?;
+        for audio_session in &mut self.audio_sessions {
+            audio_session.stop()?;
         }
+
+        // Finally stop the sink
+        self.sink.lock().unwrap().stop()?;
+
+        Ok(())
     }
 }
 
+#[cfg(windows)]
 impl MediaEncodingSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         d3d_device: ID3D11Device,
         monitor_handle: HMONITOR,
         video_encoder_device: &VideoEncoderDevice,
         audio_encoder_device: &AudioEncoderDevice,
-        resolution: SizeInt32,
-        video_bit_rate: u32,
-        audio_bit_rate: u32,
+        crop: Option<Crop>,
+        window: Option<WindowCapture>,
+        recording_window: Arc<AtomicBool>,
+        privacy: Option<Privacy>,
+        resolution: Resolution,
+        scale_mode: ScaleMode,
+        video: &VideoEncoderSettings,
+        audio_settings: &AudioEncoderSettings,
         frame_rate: u32,
-        stream: IRandomAccessStream,
-    ) -> Result<Self> {
-        // Create the shared sink writer
-        let sample_writer = Arc::new(Mutex::new(SampleWriter::new(stream)?));
-        
-        // Create video session with shared sink writer
-        let video_session = VideoEncodingSession::new(
-            d3d_device.clone(),
+        pacing: Pacing,
+        audio: &AudioSources,
+        dump: Option<&StreamDump>,
+        verbose: bool,
+        sink: SharedSink,
+    ) -> windows::core::Result<Self> {
+        let clock: SharedClock = Arc::new(SystemClock::new()?);
+        // Everything the encoders put out is copied on its way into the sink
+        let sink: SharedSink = match dump {
+            Some(dump) => Arc::new(Mutex::new(DumpSink::new(sink, dump.clone()))),
+            None => sink,
+        };
+        let recording_clock = PausableClock::new(clock.clone());
+
+        // Create video session with shared sink
+        let video_session = new_video_session(
+            clock.clone(),
+            recording_clock.clone(),
+            d3d_device,
             monitor_handle,
             video_encoder_device,
+            crop,
+            window,
+            recording_window,
+            privacy.clone(),
             resolution,
-            video_bit_rate,
+            scale_mode,
+            video,
             frame_rate,
-            sample_writer.clone(),
+            pacing,
+            verbose,
+            sink.clone(),
         )?;
         println!("created video encoder");
-        
-        // Create audio session with shared sink writer
-        let audio_session = AudioEncodingSession::new(
+
+        // Create an audio session for each track with shared sink
+        let audio_sessions = new_audio_sessions(
             audio_encoder_device,
-            audio_bit_rate,
-            sample_writer.clone(),
+            audio_settings,
+            audio,
+            privacy,
+            dump,
+            verbose,
+            sink.clone(),
         )?;
-        
-        Ok(Self {
+        println!("created audio encoders");
+
+        Ok(Self::with_clock(
             video_session,
-            audio_session,
-            sample_writer,
-        })
+            audio_sessions,
+            sink,
+            recording_clock,
+        ))
     }
-    
-    pub fn start(&mut self) -> Result<()> {
-        // Start the sink writer first
-        self.sample_writer.lock().unwrap().start()?;
-
-        let mut start_qpc = 0;
-        unsafe { QueryPerformanceCounter(&mut start_qpc)? };
-        println!("Obtained start QPC: {}", start_qpc);
-        
-        // Start both encoding sessions
-        self.audio_session.start(start_qpc)?;
-        self.video_session.start(start_qpc)?;
-        
-        Ok(())
-    }
-    
-    pub fn stop(&mut self) -> Result<()> {
-        // Stop both encoding sessions first
-        self.video_session.stop()?;
-        self.audio_session.stop()?;
-        
-        // Finally stop the sink writer
-        self.sample_writer.lock().unwrap().stop()?;
-        
-        Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::MediaEncodingSession;
+    use crate::{
+        clock::SystemClock,
+        packet::StreamKind,
+        pipeline::{
+            synthetic::{MemorySink, PassthroughEncoder, SineWaveSource, TestPatternSource},
+            StreamSession,
+        },
+    };
+
+    #[test]
+    fn records_synthetic_streams() {
+        let clock = Arc::new(SystemClock::new().unwrap());
+        let sink = Arc::new(Mutex::new(MemorySink::new()));
+        let video_session = StreamSession::video(
+            TestPatternSource::new(clock.clone(), 64, 48, 60),
+            PassthroughEncoder::video(64, 48, 60, 30),
+            sink.clone(),
+        )
+        .unwrap();
+        let audio_session = StreamSession::audio(
+            SineWaveSource::new(clock.clone(), 48000, 2, 440.0, 480),
+            PassthroughEncoder::audio(48000, 2),
+            sink.clone(),
+        )
+        .unwrap();
+        let mut session = MediaEncodingSession::from_streams(
+            video_session,
+            vec![audio_session],
+            sink.clone(),
+            clock,
+        );
+
+        session.start().unwrap();
+        std::thread::sleep(Duration::from_millis(250));
+        session.add_chapter("Ending").unwrap();
+        session.stop().unwrap();
+
+        let sink = sink.lock().unwrap();
+        assert!(sink.started && sink.stopped);
+        assert_eq!(sink.formats.len(), 2);
+
+        let video: Vec<_> = sink.packets_of(StreamKind::Video).collect();
+        assert!(video.len() >= 5);
+        assert!(video[0].keyframe);
+        assert!(video
+            .windows(2)
+            .all(|pair| pair[0].timestamp < pair[1].timestamp));
+
+        let audio: Vec<_> = sink.packets_of(StreamKind::Audio).collect();
+        assert!(audio.len() >= 5);
+        assert!(audio
+            .windows(2)
+            .all(|pair| pair[0].end_time() == pair[1].timestamp));
+
+        assert_eq!(sink.chapters.len(), 1);
+        let (timestamp, title) = &sink.chapters[0];
+        assert!(*timestamp >= 2_500_000);
+        assert_eq!(title, "Ending");
     }
-}
\ No newline at end of file
+}
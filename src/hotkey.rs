@@ -21,6 +21,10 @@ impl HotKey {
         }
         Ok(Self { id })
     }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
 }
 
 impl Drop for HotKey {
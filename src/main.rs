@@ -1,34 +1,109 @@
+#[cfg(windows)]
 mod args;
+#[cfg(windows)]
+mod audio;
+#[cfg(any(windows, test))]
+mod auto_record;
+#[cfg(any(windows, test))]
+mod clock;
+#[cfg(windows)]
 mod d3d;
+#[cfg(windows)]
 mod displays;
+#[cfg(any(windows, test))]
+mod drift;
+#[cfg(any(windows, test))]
+mod dump;
+#[cfg(any(windows, test))]
+mod encoder_settings;
+#[cfg(any(windows, test))]
+mod encoding_session;
+#[cfg(windows)]
 mod hotkey;
+#[cfg(windows)]
 mod media;
+#[cfg(any(windows, test))]
+mod mixer;
+#[cfg(any(windows, test))]
+mod mux;
+#[cfg(any(windows, test))]
+mod pacer;
+#[cfg(any(windows, test))]
+mod packet;
+#[cfg(any(windows, test))]
+mod pipeline;
+#[cfg(any(windows, test))]
+mod privacy;
+#[cfg(any(windows, test))]
+mod region;
+#[cfg(any(windows, test))]
+mod replay;
+#[cfg(any(windows, test))]
+mod resampler;
+#[cfg(any(windows, test))]
 mod resolution;
+#[cfg(windows)]
+mod sample_writer;
+#[cfg(any(windows, test))]
+mod segment;
+#[cfg(windows)]
 mod video;
+#[cfg(windows)]
 mod window_detector;
-mod audio;
-mod encoding_session;
+#[cfg(any(windows, test))]
+mod window_target;
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool};
+#[cfg(windows)]
+use std::sync::atomic::AtomicBool;
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
 
-use std::{path::Path, time::Duration};
+#[cfg(any(windows, test))]
+use std::path::{Path, PathBuf};
+#[cfg(windows)]
+use std::time::Duration;
 
+#[cfg(windows)]
 use args::Args;
-use audio::encoder_device::AudioEncoderDevice;
-use encoding_session::MediaEncodingSession;
-use clap::Parser;
+#[cfg(windows)]
+use audio::{encoder_device::AudioEncoderDevice, encoding_session::AudioSources};
+#[cfg(windows)]
+use auto_record::{AutoRecorder, OnFocusLost, RecordRules};
+#[cfg(windows)]
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+#[cfg(windows)]
 use d3d::set_multithread_protected;
+#[cfg(windows)]
+use dump::StreamDump;
+#[cfg(windows)]
+use encoding_session::MediaEncodingSession;
+#[cfg(windows)]
 use hotkey::HotKey;
+#[cfg(any(windows, test))]
+use mux::Container;
+#[cfg(windows)]
+use mux::{scan_fragments, FragmentedMp4Writer, MatroskaWriter, Mp4Writer, Muxer};
+#[cfg(windows)]
+use pipeline::{AudioCodec, SharedSink, VideoCodec};
+#[cfg(windows)]
+use privacy::{Card, FocusTimeline, Privacy, PrivacyList};
+#[cfg(windows)]
+use replay::ReplaySink;
+#[cfg(windows)]
+use sample_writer::SampleWriter;
+#[cfg(windows)]
+use segment::{SegmentLimits, SegmentedSink};
+#[cfg(windows)]
+use window_detector::WindowChangeDetector;
+#[cfg(windows)]
 use windows::{
-    core::{h, Result, RuntimeName, HSTRING},
-    Foundation::Metadata::ApiInformation,
-    Graphics::SizeInt32,
+    core::{Result, HSTRING},
+    Foundation::TimeSpan,
     Storage::{
         CreationCollisionOption, FileAccessMode, StorageFolder, Streams::IRandomAccessStream,
     },
     Win32::{
-        Foundation::{HWND, MAX_PATH},
+        Foundation::MAX_PATH,
         Graphics::{Direct3D11::ID3D11Device, Gdi::HMONITOR},
         Media::MediaFoundation::{MFStartup, MFSTARTUP_FULL},
         Storage::FileSystem::GetFullPathNameW,
@@ -38,32 +113,89 @@ use windows::{
             WinRT::{RoInitialize, RO_INIT_MULTITHREADED},
         },
         UI::{
+            HiDpi::{SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2},
             Input::KeyboardAndMouse::{MOD_CONTROL, MOD_SHIFT},
             WindowsAndMessaging::{DispatchMessageW, GetMessageW, MSG, WM_HOTKEY},
         },
     },
 };
 
+#[cfg(windows)]
 use crate::{
-    d3d::create_d3d_device, displays::get_display_handle_from_index, media::MF_VERSION,
-    resolution::Resolution, video::encoder_device::VideoEncoderDevice,
+    clock::HNS_PER_SECOND,
+    d3d::create_d3d_device,
+    displays::{get_display_handle_from_index, get_display_handle_from_region},
+    encoder_settings::{AudioEncoderSettings, VideoEncoderSettings, VideoSetting},
+    media::MF_VERSION,
+    pacer::Pacing,
+    region::Crop,
+    resolution::{Resolution, ScaleMode},
+    video::{encoder_device::VideoEncoderDevice, window::DesktopWindows},
+    window_target::{WindowCapture, WindowTarget},
 };
 
+/// Settings for instant-replay mode, where only the most recent part of the
+/// recording is kept and saved on demand.
+#[cfg(windows)]
+struct ReplaySettings {
+    duration: TimeSpan,
+    max_bytes: Option<usize>,
+}
+
+/// Records only while a window the rules allow is in the foreground.
+#[cfg(windows)]
+struct AutoRecordSettings {
+    rules: RecordRules,
+    on_focus_lost: OnFocusLost,
+}
+
+/// Hides private windows while they're in the foreground.
+#[cfg(windows)]
+struct PrivacySettings {
+    list: PrivacyList,
+    card: Card,
+    mute_audio: bool,
+}
+
+/// How the recording is written to disk.
+#[cfg(windows)]
+#[derive(Copy, Clone)]
+struct OutputSettings {
+    muxer: Muxer,
+    fragment_duration: TimeSpan,
+    segment: Option<SegmentLimits>,
+}
+
+#[cfg(windows)]
 #[allow(clippy::too_many_arguments)]
 fn run(
     display_index: usize,
     output_path: &str,
-    bit_rate: u32,
+    video: VideoEncoderSettings,
     frame_rate: u32,
+    pacing: Pacing,
+    crop: Option<Crop>,
+    window: Option<WindowCapture>,
     resolution: Resolution,
+    scale_mode: ScaleMode,
     video_encoder_index: usize,
     audio_encoder_index: usize,
+    audio_settings: AudioEncoderSettings,
+    audio: AudioSources,
+    output: OutputSettings,
+    dump: Option<StreamDump>,
+    replay: Option<ReplaySettings>,
+    auto_record: Option<AutoRecordSettings>,
+    privacy: Option<PrivacySettings>,
     verbose: bool,
     wait_for_debugger: bool,
     console_mode: bool,
 ) -> Result<()> {
     unsafe {
         RoInitialize(RO_INIT_MULTITHREADED)?;
+        // Work in physical pixels, so window bounds line up with captured
+        // frames. Fails harmlessly if something already picked a mode.
+        let _ = SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2);
     }
     unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
 
@@ -89,22 +221,34 @@ fn run(
         );
     }
 
-    // TODO: get display handle by window (game) rather than index
-    let monitor_handle = get_display_handle_from_index(display_index)
-        .expect("The provided display index was out of bounds!");
+    // Recording a window starts on whichever display it's on
+    let monitor_handle = match &window {
+        Some(window) => {
+            let Some(found) = window.target.find(&DesktopWindows) else {
+                exit_with_error(&format!("Couldn't find {}!", window.target));
+            };
+            if verbose {
+                println!("Found window \"{}\" ({}).", found.title, found.process);
+            }
+            get_display_handle_from_region(found.bounds)
+        }
+        None => get_display_handle_from_index(display_index)
+            .expect("The provided display index was out of bounds!"),
+    };
 
     let d3d_device = create_d3d_device()?;
 
     let _ = set_multithread_protected(&d3d_device, true)?;
 
-    // TODO: move this stuff to a method and automatically get resolution
-    let resolution = resolution
-        .get_size()
-        .expect("Resolution must be specified when not using Graphics Capture.");
-    let bit_rate = bit_rate * 1000000;
-    let video_encoder_devices = VideoEncoderDevice::enumerate()?;
+    let mut video_encoder_devices = VideoEncoderDevice::enumerate(video.codec)?;
     if video_encoder_devices.is_empty() {
-        exit_with_error("No hardware H264 encoders found!");
+        if video.codec != VideoCodec::H264 {
+            let codec = video.codec.to_string().to_uppercase();
+            exit_with_error(&format!("No hardware {} encoders found!", codec));
+        }
+        println!("No hardware H264 encoders found, using the software encoder.");
+        println!("It doesn't compress, so the bit rate is ignored and the file can get very big.");
+        video_encoder_devices.push(VideoEncoderDevice::software());
     }
     if verbose {
         println!("Encoders ({}):", video_encoder_devices.len());
@@ -120,9 +264,10 @@ fn run(
     if verbose {
         println!("Using: {}", video_encoder_device.display_name());
     }
-    let audio_encoder_devices = AudioEncoderDevice::enumerate()?;
+    let audio_encoder_devices = AudioEncoderDevice::enumerate(audio_settings.codec)?;
     if audio_encoder_devices.is_empty() {
-        exit_with_error("No AAC encoders found!");
+        let codec = audio_settings.codec.to_string().to_uppercase();
+        exit_with_error(&format!("No {} encoders found!", codec));
     }
     if verbose {
         println!("Encoders ({}):", audio_encoder_devices.len());
@@ -139,51 +284,154 @@ fn run(
         println!("Using: {}", audio_encoder_device.display_name());
     }
     
-    // Create our file
-    let path = unsafe {
-        let mut new_path = vec![0u16; MAX_PATH as usize];
-        let length = GetFullPathNameW(&HSTRING::from(output_path), Some(&mut new_path), None);
-        new_path.resize(length as usize, 0);
-        String::from_utf16(&new_path).unwrap()
+    // Create our file, unless we're only keeping a replay buffer
+    let replay_sink = replay.as_ref().map(|replay| {
+        Arc::new(Mutex::new(ReplaySink::new(
+            replay.duration.Duration,
+            replay.max_bytes,
+        )))
+    });
+    // Splitting when focus is lost needs numbered files, like segments do
+    let split_on_focus_lost = auto_record
+        .as_ref()
+        .is_some_and(|auto_record| auto_record.on_focus_lost == OnFocusLost::Split);
+    let mut split_request = None;
+    let sink: SharedSink = match &replay_sink {
+        Some(replay_sink) => replay_sink.clone(),
+        None => match output
+            .segment
+            .or(split_on_focus_lost.then(SegmentLimits::default))
+        {
+            Some(limits) => {
+                let output_path = output_path.to_owned();
+                let segmented_sink = SegmentedSink::new(
+                    limits,
+                    Box::new(move |index| {
+                        let segment_path = numbered_path(&output_path, index);
+                        Ok(create_file_sink(output, segment_path.to_str().unwrap())?)
+                    }),
+                );
+                split_request = Some(segmented_sink.split_request());
+                Arc::new(Mutex::new(segmented_sink))
+            }
+            None => create_file_sink(output, output_path)?,
+        },
     };
-    let path = Path::new(&path);
-    let parent_folder_path = path.parent().unwrap();
-    let parent_folder = StorageFolder::GetFolderFromPathAsync(&HSTRING::from(
-        parent_folder_path.as_os_str().to_str().unwrap(),
-    ))?
-    .get()?;
-    let file_name = path.file_name().unwrap();
-    let file = parent_folder
-        .CreateFileAsync(
-            &HSTRING::from(file_name.to_str().unwrap()),
-            CreationCollisionOption::ReplaceExisting,
-        )?
-        .get()?;
 
+    // Cleared by the auto-record rules while frames should be left black
     let is_recording_window = Arc::new(AtomicBool::new(true));
-    let hook = window_detector::start_window_change_detector(is_recording_window.clone());
+    // Private windows are watched for from before the recording starts, so
+    // whatever is in the foreground to begin with is known
+    let (privacy, _privacy_detector) = match privacy {
+        Some(settings) => {
+            let timeline = Arc::new(FocusTimeline::default());
+            let detector = start_privacy_detector(settings.list, timeline.clone());
+            let privacy = Privacy {
+                timeline,
+                card: settings.card,
+                mute_audio: settings.mute_audio,
+            };
+            (Some(privacy), Some(detector))
+        }
+        None => (None, None),
+    };
+    // Starts the session, and applies the rules for as long as the
+    // detector is kept
+    let start = |session: &mut MediaEncodingSession| -> Result<Option<WindowChangeDetector>> {
+        session.start()?;
+        Ok(auto_record.as_ref().map(|auto_record| {
+            start_auto_record(auto_record, session, &is_recording_window, &split_request)
+        }))
+    };
 
     // Start the recording
     {
-        let stream = file.OpenAsync(FileAccessMode::ReadWrite)?.get()?;
         // d3d_device created earlier
         let mut session = create_encoding_session(
             d3d_device,
             monitor_handle,
             video_encoder_device,
             audio_encoder_device,
+            crop,
+            window,
+            is_recording_window.clone(),
+            privacy,
             resolution,
-            bit_rate,
+            scale_mode,
+            &video,
+            &audio_settings,
             frame_rate,
-            stream,
+            pacing,
+            &audio,
+            dump.as_ref(),
+            verbose,
+            sink,
         )?;
-        if !console_mode {
+        if let Some(replay_sink) = &replay_sink {
+            let _detector = start(&mut session)?;
+            let mut clip_index = 0;
+            let mut save_replay = || -> Result<()> {
+                // Only hold the sink while copying, the encoders write to it
+                let replay = replay_sink.lock().unwrap().snapshot();
+                let Some(replay) = replay else {
+                    println!("Nothing to save yet...");
+                    return Ok(());
+                };
+                clip_index += 1;
+                let clip_path = numbered_path(output_path, clip_index);
+                let clip_writer = create_file_sink(output, clip_path.to_str().unwrap())?;
+                replay.save(&mut *clip_writer.lock().unwrap())?;
+                println!("Saved replay to \"{}\".", clip_path.display());
+                Ok(())
+            };
+            if !console_mode {
+                let hot_keys = [
+                    HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x52 /* R */)?,
+                    HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x51 /* Q */)?,
+                ];
+                println!("Press SHIFT+CTRL+R to save a replay, SHIFT+CTRL+Q to stop...");
+                pump_messages(&hot_keys, |index| -> Result<bool> {
+                    Ok(if index == 0 {
+                        save_replay()?;
+                        false
+                    } else {
+                        true
+                    })
+                })?;
+            } else {
+                println!("Press ENTER to save a replay, type q and press ENTER to stop...");
+                let mut line = String::new();
+                while std::io::stdin().read_line(&mut line).unwrap() > 0 && line.trim() != "q" {
+                    save_replay()?;
+                    line.clear();
+                }
+            }
+            println!("Stopping recording...");
+        } else if !console_mode {
+            let hot_keys = [
+                HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x52 /* R */)?,
+                HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x50 /* P */)?,
+                HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x4D /* M */)?,
+            ];
+            println!("Press SHIFT+CTRL+R to start/stop the recording, SHIFT+CTRL+P to pause/resume it, SHIFT+CTRL+M to mark a chapter...");
             let mut is_recording = false;
-            pump_messages(|| -> Result<bool> {
-                Ok(if !is_recording {
+            let mut _detector = None;
+            let mut chapters = 0;
+            pump_messages(&hot_keys, |index| -> Result<bool> {
+                Ok(if index == 1 {
+                    if is_recording {
+                        toggle_pause(&mut session)?;
+                    }
+                    false
+                } else if index == 2 {
+                    if is_recording {
+                        add_chapter(&mut session, &mut chapters)?;
+                    }
+                    false
+                } else if !is_recording {
                     is_recording = true;
                     println!("Starting recording...");
-                    session.start()?;
+                    _detector = start(&mut session)?;
                     false
                 } else {
                     true
@@ -191,8 +439,18 @@ fn run(
             })?;
             println!("Stopping recording...");
         } else {
-            session.start()?;
-            pause();
+            let _detector = start(&mut session)?;
+            println!("Press ENTER to stop recording, type p and press ENTER to pause/resume it, or m to mark a chapter...");
+            let mut line = String::new();
+            let mut chapters = 0;
+            while std::io::stdin().read_line(&mut line).unwrap() > 0 {
+                match line.trim() {
+                    "p" => toggle_pause(&mut session)?,
+                    "m" => add_chapter(&mut session, &mut chapters)?,
+                    _ => break,
+                }
+                line.clear();
+            }
         }
         session.stop()?;
     }
@@ -200,6 +458,7 @@ fn run(
     Ok(())
 }
 
+#[cfg(windows)]
 fn main() {
     // Handle /?
     let args: Vec<_> = std::env::args().collect();
@@ -208,11 +467,17 @@ fn main() {
         std::process::exit(0);
     }
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
 
     if let Some(command) = args.command {
         match command {
             args::Commands::EnumEncoders => enum_encoders().unwrap(),
+            args::Commands::Recover { input_file, output } => {
+                if let Err(error) = recover(&input_file, output.as_deref()) {
+                    exit_with_error(&format!("Couldn't recover \"{}\": {}", input_file, error));
+                }
+            }
         }
         return;
     }
@@ -222,25 +487,173 @@ fn main() {
     let verbose = args.verbose;
     let wait_for_debugger = args.wait_for_debugger;
     let console_mode = args.console_mode;
-    let bit_rate: u32 = args.bit_rate;
+    let mut video = VideoEncoderSettings {
+        codec: args.codec,
+        rate_control: args.rate_control,
+        quality: args.quality,
+        qp: args.qp,
+        keyframe_interval: args.keyframe_interval,
+        b_frames: args.b_frames,
+        profile: args.profile,
+        level: args.level,
+        low_latency: args.low_latency,
+        ..VideoEncoderSettings::new(args.bit_rate * 1000000)
+    };
+    if let Some(path) = &args.encoder_settings {
+        let settings = std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| VideoSetting::parse_file(&text));
+        match settings {
+            Ok(settings) => {
+                // Options given on the command line win over the file
+                for setting in settings {
+                    if matches.value_source(setting.option()) != Some(ValueSource::CommandLine) {
+                        video.apply(setting);
+                    }
+                }
+            }
+            Err(error) => exit_with_error(&format!(
+                "Couldn't read encoder settings from \"{}\": {}",
+                path, error
+            )),
+        }
+    }
     let frame_rate: u32 = args.frame_rate;
-    let resolution: Resolution = args.resolution;
+    let pacing: Pacing = args.pacing;
+    let resolution: Resolution = args
+        .height
+        .map(Resolution::Height)
+        .unwrap_or(args.resolution);
+    let scale_mode: ScaleMode = args.scale_mode;
+    let crop: Option<Crop> = args.crop;
+    let window_target = match (args.window_title, args.process, args.hwnd) {
+        (Some(pattern), _, _) => Some(WindowTarget::Title(pattern)),
+        (_, Some(process), _) => Some(WindowTarget::Process(process)),
+        (_, _, Some(handle)) => Some(WindowTarget::Handle(handle)),
+        _ => None,
+    };
+    let window = window_target.map(|target| WindowCapture {
+        target,
+        when_minimized: args.when_minimized,
+    });
     let video_encoder_index: usize = args.video_encoder;
     let audio_encoder_index: usize = args.audio_encoder;
+    let audio_settings = AudioEncoderSettings {
+        bit_rate: args.audio_bit_rate.map(|kbps| kbps * 1000),
+        ..AudioEncoderSettings::new(args.audio_codec)
+    };
+    let audio = AudioSources {
+        desktop_volume: args.desktop_volume,
+        microphone_volume: args.mic.then_some(args.mic_volume),
+        tracks: args.audio_tracks,
+        processor: args.audio_processor,
+        language: args.audio_language,
+    };
+    let output = OutputSettings {
+        muxer: args.muxer,
+        fragment_duration: TimeSpan {
+            Duration: args.fragment_duration as i64 * HNS_PER_SECOND,
+        },
+        segment: (args.segment_duration.is_some() || args.segment_size.is_some()).then(|| {
+            SegmentLimits {
+                max_duration: args
+                    .segment_duration
+                    .map(|seconds| seconds as i64 * HNS_PER_SECOND),
+                max_bytes: args.segment_size.map(|megabytes| megabytes * 1024 * 1024),
+            }
+        }),
+    };
+    let auto_record = args.rules.as_ref().map(|path| {
+        let rules = std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| RecordRules::parse(&text));
+        match rules {
+            Ok(rules) => AutoRecordSettings {
+                rules,
+                on_focus_lost: args.on_focus_lost,
+            },
+            Err(error) => {
+                exit_with_error(&format!("Couldn't read rules from \"{}\": {}", path, error))
+            }
+        }
+    });
+    let privacy = args.privacy.as_ref().map(|path| {
+        let list = std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| PrivacyList::parse(&text));
+        match list {
+            Ok(list) => PrivacySettings {
+                list,
+                card: args.privacy_card,
+                mute_audio: args.privacy_mute,
+            },
+            Err(error) => exit_with_error(&format!(
+                "Couldn't read private windows from \"{}\": {}",
+                path, error
+            )),
+        }
+    });
+    let dump = args
+        .dump_streams
+        .as_ref()
+        .map(|dir| match StreamDump::create(dir) {
+            Ok(dump) => dump,
+            Err(error) => {
+                exit_with_error(&format!("Couldn't dump streams to \"{}\": {}", dir, error))
+            }
+        });
+    let replay = args.replay.map(|seconds| ReplaySettings {
+        duration: TimeSpan {
+            Duration: seconds as i64 * HNS_PER_SECOND,
+        },
+        max_bytes: args.replay_max_mb.map(|megabytes| megabytes * 1024 * 1024),
+    });
 
     // Validate some of the params
     if !validate_path(output_path) {
         exit_with_error("Invalid path specified!");
     }
+    if let Err(error) = video.validate() {
+        exit_with_error(&error);
+    }
+    if let Err(error) = audio_settings.validate() {
+        exit_with_error(&error);
+    }
+    let container = Container::from_path(output_path);
+    if container == Some(Container::Mp4)
+        && args.muxer == Muxer::MediaFoundation
+        && audio_settings.codec != AudioCodec::Aac
+    {
+        exit_with_error("The Media Foundation muxer can only write AAC audio!");
+    }
+    if container == Some(Container::WebM)
+        && (video.codec != VideoCodec::Av1 || audio_settings.codec != AudioCodec::Opus)
+    {
+        exit_with_error("WebM files can only hold AV1 video and Opus audio!");
+    }
+    if replay.is_some() && args.rules.is_some() && args.on_focus_lost == OnFocusLost::Split {
+        exit_with_error("Replays can't be split when focus is lost!");
+    }
 
     let result = run(
         monitor_index,
         output_path,
-        bit_rate,
+        video,
         frame_rate,
+        pacing,
+        crop,
+        window,
         resolution,
+        scale_mode,
         video_encoder_index,
         audio_encoder_index,
+        audio_settings,
+        audio,
+        output,
+        dump,
+        replay,
+        auto_record,
+        privacy,
         verbose | wait_for_debugger,
         wait_for_debugger,
         console_mode,
@@ -252,62 +665,171 @@ fn main() {
     }
 }
 
-fn pause() {
-    println!("Press ENTER to stop recording...");
-    std::io::Read::read(&mut std::io::stdin(), &mut [0]).unwrap();
+#[cfg(not(windows))]
+fn main() {
+    exit_with_error("Recording is only supported on Windows.");
 }
 
-fn enum_encoders() -> Result<()> {
-    // Enumerate video encoders
-    let video_encoder_devices = VideoEncoderDevice::enumerate()?;
-    if video_encoder_devices.is_empty() {
-        println!("No hardware H264 encoders found!");
+#[cfg(windows)]
+fn toggle_pause(session: &mut MediaEncodingSession) -> Result<()> {
+    if session.is_paused() {
+        session.resume()?;
+        println!("Resumed recording.");
     } else {
-        println!("Video Encoders ({}):", video_encoder_devices.len());
+        session.pause()?;
+        println!("Paused recording.");
+    }
+    Ok(())
+}
+
+/// Marks the start of the next numbered chapter. Only Matroska files keep
+/// them.
+#[cfg(windows)]
+fn add_chapter(session: &mut MediaEncodingSession, chapters: &mut usize) -> Result<()> {
+    *chapters += 1;
+    let title = format!("Chapter {}", chapters);
+    session.add_chapter(&title)?;
+    println!("Marked \"{}\".", title);
+    Ok(())
+}
+
+/// Applies the auto-record rules to a recording that has just started, for
+/// as long as the detector returned is kept.
+#[cfg(windows)]
+fn start_auto_record(
+    settings: &AutoRecordSettings,
+    session: &MediaEncodingSession,
+    is_recording_window: &Arc<AtomicBool>,
+    split_request: &Option<Arc<AtomicBool>>,
+) -> WindowChangeDetector {
+    let mut auto_recorder = AutoRecorder::new(
+        settings.rules.clone(),
+        settings.on_focus_lost,
+        session.clock(),
+        is_recording_window.clone(),
+        split_request.clone(),
+    );
+    window_detector::start_window_change_detector(move |window, _| {
+        match auto_recorder.focus(&window) {
+            Ok(Some(true)) => println!("Recording \"{}\" ({}).", window.title, window.process),
+            Ok(Some(false)) => println!("Not recording \"{}\" ({}).", window.title, window.process),
+            Ok(None) => {}
+            Err(error) => println!("Couldn't follow focus to \"{}\": {}", window.title, error),
+        }
+    })
+}
+
+/// Keeps track of when private windows are in the foreground, for as long
+/// as the detector returned is kept.
+#[cfg(windows)]
+fn start_privacy_detector(list: PrivacyList, timeline: Arc<FocusTimeline>) -> WindowChangeDetector {
+    let mut was_private = false;
+    window_detector::start_window_change_detector(move |window, time| {
+        let private = list.is_private(&window);
+        timeline.record(time, private);
+        if private != was_private {
+            was_private = private;
+            if private {
+                println!("Hiding a private window.");
+            } else {
+                println!("Showing the recording again.");
+            }
+        }
+    })
+}
+
+#[cfg(windows)]
+fn enum_encoders() -> Result<()> {
+    // Enumerate video encoders, for each codec
+    let h264_encoder_devices = VideoEncoderDevice::enumerate(VideoCodec::H264)?;
+    if h264_encoder_devices.is_empty() {
+        println!("No hardware H264 encoders found! Recordings will be encoded in software.");
+    }
+    for codec in [VideoCodec::H264, VideoCodec::Hevc, VideoCodec::Av1] {
+        let video_encoder_devices = VideoEncoderDevice::enumerate(codec)?;
+        if video_encoder_devices.is_empty() {
+            continue;
+        }
+        println!(
+            "{} Video Encoders ({}):",
+            codec.to_string().to_uppercase(),
+            video_encoder_devices.len()
+        );
         for (i, encoder_device) in video_encoder_devices.iter().enumerate() {
             println!("  {} - {}", i, encoder_device.display_name());
         }
     }
     
-    // Enumerate audio encoders
-    let audio_encoder_devices = AudioEncoderDevice::enumerate()?;
+    // Enumerate audio encoders, for each codec. FLAC and PCM are always
+    // there, so only the ones from Media Foundation are worth listing
+    let audio_encoder_devices = AudioEncoderDevice::enumerate(AudioCodec::Aac)?;
     if audio_encoder_devices.is_empty() {
         println!("No hardware AAC audio encoders found!");
-    } else {
-        println!("Audio Encoders ({}):", audio_encoder_devices.len());
+    }
+    for codec in [AudioCodec::Aac, AudioCodec::Opus] {
+        let audio_encoder_devices = AudioEncoderDevice::enumerate(codec)?;
+        if audio_encoder_devices.is_empty() {
+            continue;
+        }
+        println!(
+            "{} Audio Encoders ({}):",
+            codec.to_string().to_uppercase(),
+            audio_encoder_devices.len()
+        );
         for (i, encoder_device) in audio_encoder_devices.iter().enumerate() {
             println!("  {} - {}", i, encoder_device.display_name());
         }
     }
     
     // If both types of encoders are missing, exit with an error
-    if video_encoder_devices.is_empty() && audio_encoder_devices.is_empty() {
+    if h264_encoder_devices.is_empty() && audio_encoder_devices.is_empty() {
         exit_with_error("No hardware encoders found!");
     }
     
     Ok(())
 }
 
+#[cfg(windows)]
+#[allow(clippy::too_many_arguments)]
 fn create_encoding_session(
     d3d_device: ID3D11Device,
     monitor_handle: HMONITOR,
     video_encoder_device: &VideoEncoderDevice,
     audio_encoder_device: &AudioEncoderDevice,
-    resolution: SizeInt32,
-    bit_rate: u32,
+    crop: Option<Crop>,
+    window: Option<WindowCapture>,
+    recording_window: Arc<AtomicBool>,
+    privacy: Option<Privacy>,
+    resolution: Resolution,
+    scale_mode: ScaleMode,
+    video: &VideoEncoderSettings,
+    audio_settings: &AudioEncoderSettings,
     frame_rate: u32,
-    stream: IRandomAccessStream,
+    pacing: Pacing,
+    audio: &AudioSources,
+    dump: Option<&StreamDump>,
+    verbose: bool,
+    sink: SharedSink,
 ) -> Result<MediaEncodingSession> {
     let result = MediaEncodingSession::new(
         d3d_device,
         monitor_handle,
         video_encoder_device,
         audio_encoder_device,
+        crop,
+        window,
+        recording_window,
+        privacy,
         resolution,
-        bit_rate,
-        80,
+        scale_mode,
+        video,
+        audio_settings,
         frame_rate,
-        stream,
+        pacing,
+        audio,
+        dump,
+        verbose,
+        sink,
     );
     if result.is_err() {
         println!("Error during encoder setup, try another set of encoding settings.");
@@ -315,17 +837,103 @@ fn create_encoding_session(
     result
 }
 
-fn validate_path<P: AsRef<Path>>(path: P) -> bool {
+#[cfg(windows)]
+fn create_file_sink(output: OutputSettings, output_path: &str) -> Result<SharedSink> {
+    let open_seekable = || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_path)
+    };
+    // The muxer only picks how MP4 files are written
+    Ok(match (Container::from_path(output_path), output.muxer) {
+        (Some(Container::Matroska), _) => {
+            Arc::new(Mutex::new(MatroskaWriter::new(open_seekable()?)))
+        }
+        (Some(Container::WebM), _) => Arc::new(Mutex::new(MatroskaWriter::webm(open_seekable()?))),
+        (_, Muxer::MediaFoundation) => Arc::new(Mutex::new(SampleWriter::new(
+            create_file_stream(output_path)?,
+        )?)),
+        (_, Muxer::Builtin) => Arc::new(Mutex::new(Mp4Writer::new(open_seekable()?))),
+        (_, Muxer::Fragmented) => {
+            let file = std::fs::File::create(output_path)?;
+            Arc::new(Mutex::new(FragmentedMp4Writer::new(
+                file,
+                output.fragment_duration.Duration,
+            )))
+        }
+    })
+}
+
+/// Cuts a fragmented recording back to its last complete fragment.
+#[cfg(windows)]
+fn recover(input_path: &str, output_path: Option<&str>) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(output_path.is_none())
+        .open(input_path)?;
+    let scan = scan_fragments(&mut file)?;
+    println!(
+        "Found {} complete fragments, dropping {} trailing bytes.",
+        scan.fragments,
+        scan.file_len - scan.complete_len
+    );
+    match output_path {
+        Some(output_path) => {
+            std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(0))?;
+            let mut output = std::fs::File::create(output_path)?;
+            std::io::copy(
+                &mut std::io::Read::take(&mut file, scan.complete_len),
+                &mut output,
+            )?;
+        }
+        None => file.set_len(scan.complete_len)?,
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn create_file_stream(output_path: &str) -> Result<IRandomAccessStream> {
+    let path = unsafe {
+        let mut new_path = vec![0u16; MAX_PATH as usize];
+        let length = GetFullPathNameW(&HSTRING::from(output_path), Some(&mut new_path), None);
+        new_path.resize(length as usize, 0);
+        String::from_utf16(&new_path).unwrap()
+    };
+    let path = Path::new(&path);
+    let parent_folder_path = path.parent().unwrap();
+    let parent_folder = StorageFolder::GetFolderFromPathAsync(&HSTRING::from(
+        parent_folder_path.as_os_str().to_str().unwrap(),
+    ))?
+    .get()?;
+    let file_name = path.file_name().unwrap();
+    let file = parent_folder
+        .CreateFileAsync(
+            &HSTRING::from(file_name.to_str().unwrap()),
+            CreationCollisionOption::ReplaceExisting,
+        )?
+        .get()?;
+    file.OpenAsync(FileAccessMode::ReadWrite)?.get()
+}
+
+/// Turns "recording.mp4" into "recording_0001.mp4" for an index of 1.
+#[cfg(any(windows, test))]
+fn numbered_path<P: AsRef<Path>>(path: P, index: usize) -> PathBuf {
     let path = path.as_ref();
-    let mut valid = true;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{}_{:04}", stem, index);
     if let Some(extension) = path.extension() {
-        if extension != "mp4" {
-            valid = false;
-        }
-    } else {
-        valid = false;
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
     }
-    valid
+    path.with_file_name(file_name)
+}
+
+#[cfg(any(windows, test))]
+fn validate_path<P: AsRef<Path>>(path: P) -> bool {
+    Container::from_path(path).is_some()
 }
 
 fn exit_with_error(message: &str) -> ! {
@@ -334,14 +942,25 @@ fn exit_with_error(message: &str) -> ! {
 }
 
 
-fn pump_messages<F: FnMut() -> Result<bool>>(mut hot_key_callback: F) -> Result<()> {
-    let _hot_key = HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x52 /* R */)?;
-    println!("Press SHIFT+CTRL+R to start/stop the recording...");
+/// Dispatches messages until the callback returns true. The callback is
+/// given the index into `hot_keys` of the hotkey that was pressed.
+#[cfg(windows)]
+fn pump_messages<F: FnMut(usize) -> Result<bool>>(
+    hot_keys: &[HotKey],
+    mut hot_key_callback: F,
+) -> Result<()> {
     unsafe {
         let mut message = MSG::default();
         while GetMessageW(&mut message, None, 0, 0).into() {
-            if message.message == WM_HOTKEY && hot_key_callback()? {
-                break;
+            if message.message == WM_HOTKEY {
+                if let Some(index) = hot_keys
+                    .iter()
+                    .position(|hot_key| hot_key.id() as usize == message.wParam.0)
+                {
+                    if hot_key_callback(index)? {
+                        break;
+                    }
+                }
             }
             DispatchMessageW(&message);
         }
@@ -351,7 +970,9 @@ fn pump_messages<F: FnMut() -> Result<bool>>(mut hot_key_callback: F) -> Result<
 
 #[cfg(test)]
 mod tests {
-    use crate::validate_path;
+    use std::path::Path;
+
+    use crate::{numbered_path, validate_path};
 
     #[test]
     fn path_parsing_test() {
@@ -359,6 +980,8 @@ mod tests {
         assert!(validate_path("somedir/something.mp4"));
         assert!(validate_path("somedir\\something.mp4"));
         assert!(validate_path("../something.mp4"));
+        assert!(validate_path("something.mkv"));
+        assert!(validate_path("somedir/something.webm"));
 
         assert!(!validate_path("."));
         assert!(!validate_path("*"));
@@ -367,4 +990,17 @@ mod tests {
         assert!(!validate_path("mp4"));
         assert!(!validate_path("something.avi"));
     }
+
+    #[test]
+    fn numbered_path_test() {
+        assert_eq!(
+            numbered_path("recording.mp4", 1),
+            Path::new("recording_0001.mp4")
+        );
+        assert_eq!(
+            numbered_path("somedir/clip.mp4", 12),
+            Path::new("somedir/clip_0012.mp4")
+        );
+        assert_eq!(numbered_path("clip", 3), Path::new("clip_0003"));
+    }
 }
@@ -1,11 +1,14 @@
 use windows::{
     core::{Array, Result, GUID},
     Win32::Media::MediaFoundation::{
-        IMFActivate, IMFAttributes, MFTEnumEx, MFT_ENUM_FLAG, MFT_REGISTER_TYPE_INFO,
+        IMFActivate, IMFAttributes, IMFSample, MFCreateMemoryBuffer, MFCreateSample,
+        MFSampleExtension_CleanPoint, MFTEnumEx, MFT_ENUM_FLAG, MFT_REGISTER_TYPE_INFO,
         MF_E_ATTRIBUTENOTFOUND,
     },
 };
 
+use crate::packet::{EncodedPacket, StreamKind};
+
 pub fn enumerate_mfts(
     category: &GUID,
     flags: MFT_ENUM_FLAG,
@@ -96,3 +99,45 @@ pub unsafe fn MFSetAttributeRatio(
 ) -> Result<()> {
     MFSetAttribute2UINT32asUINT64(attributes, key, numerator, denominator)
 }
+
+/// Copies the contents of an encoded `IMFSample` into an `EncodedPacket`.
+pub fn sample_to_packet(sample: &IMFSample, kind: StreamKind) -> Result<EncodedPacket> {
+    unsafe {
+        let buffer = sample.ConvertToContiguousBuffer()?;
+        let mut data_ptr = std::ptr::null_mut();
+        let mut length = 0;
+        buffer.Lock(&mut data_ptr, None, Some(&mut length))?;
+        let data = std::slice::from_raw_parts(data_ptr, length as usize).to_vec();
+        buffer.Unlock()?;
+
+        let timestamp = sample.GetSampleTime()?;
+        let duration = sample.GetSampleDuration().unwrap_or(0);
+        // Audio samples are all independent, the encoder only marks video.
+        let keyframe = match kind {
+            StreamKind::Video => sample.GetUINT32(&MFSampleExtension_CleanPoint).unwrap_or(0) != 0,
+            StreamKind::Audio => true,
+        };
+        Ok(EncodedPacket::new(
+            kind, data, timestamp, duration, keyframe,
+        ))
+    }
+}
+
+/// Wraps an `EncodedPacket` in a new `IMFSample` backed by system memory.
+pub fn packet_to_sample(packet: &EncodedPacket) -> Result<IMFSample> {
+    unsafe {
+        let buffer = MFCreateMemoryBuffer(packet.data.len() as u32)?;
+        let mut data_ptr = std::ptr::null_mut();
+        buffer.Lock(&mut data_ptr, None, None)?;
+        std::ptr::copy_nonoverlapping(packet.data.as_ptr(), data_ptr, packet.data.len());
+        buffer.Unlock()?;
+        buffer.SetCurrentLength(packet.data.len() as u32)?;
+
+        let sample = MFCreateSample()?;
+        sample.AddBuffer(&buffer)?;
+        sample.SetSampleTime(packet.timestamp)?;
+        sample.SetSampleDuration(packet.duration)?;
+        sample.SetUINT32(&MFSampleExtension_CleanPoint, packet.keyframe as u32)?;
+        Ok(sample)
+    }
+}
@@ -0,0 +1,889 @@
+//! Mixes any number of captured audio streams into one. Each input is
+//! converted to the mix format as it arrives, placed on a shared timeline by
+//! its timestamps, and the inputs are summed with their own gain in blocks
+//! of a fixed length.
+
+use std::{collections::VecDeque, fmt::Display, str::FromStr};
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    drift::DriftCompensator,
+    resampler::{FormatConverter, PcmFormat, SampleEncoding},
+};
+
+/// Output blocks are 10ms long.
+const BLOCKS_PER_SECOND: u32 = 100;
+/// How far one input may run ahead of another before the mixer stops
+/// waiting for the one that fell behind. Loopback capture delivers nothing
+/// at all while nothing is playing, so this is also how much the mix lags
+/// behind the other inputs in that case.
+const MAX_LATENCY_MS: i64 = 100;
+/// Timestamps within this distance of where the previous packet ended are
+/// treated as a continuation of it, so timestamp jitter doesn't turn into
+/// clicks.
+const CONTIGUOUS_TOLERANCE_MS: i64 = 2;
+/// Mixed samples above this level are compressed instead of clipped.
+const CLIP_KNEE: f32 = 0.8;
+
+/// A block of mixed audio, as interleaved floats in the mix format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixedBlock {
+    pub samples: Vec<f32>,
+    pub frames: u32,
+    /// In 100ns units.
+    pub timestamp: i64,
+    /// In 100ns units.
+    pub duration: i64,
+}
+
+/// Identifies an input of a `Mixer`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InputId(usize);
+
+/// Brings an input's audio to the mix format, and keeps it in step with
+/// its timestamps if its clock runs fast or slow.
+struct InputConverter {
+    converter: FormatConverter,
+    drift: DriftCompensator,
+}
+
+impl InputConverter {
+    fn new(format: PcmFormat, mix_format: PcmFormat) -> Self {
+        Self {
+            converter: FormatConverter::new(format, mix_format),
+            drift: DriftCompensator::new(mix_format.sample_rate, mix_format.channels),
+        }
+    }
+
+    fn convert(&mut self, timestamp: i64, data: &[u8]) -> Vec<f32> {
+        let converted = self.converter.convert_to_f32(data);
+        self.drift.process(timestamp, converted)
+    }
+}
+
+struct Input {
+    gain: f32,
+    muted: bool,
+    /// Converted audio that hasn't been mixed yet.
+    pending: VecDeque<f32>,
+    /// The mix frame the front of `pending` belongs at, once anything has
+    /// arrived.
+    start: Option<i64>,
+}
+
+impl Input {
+    fn end(&self, channels: usize) -> Option<i64> {
+        self.start
+            .map(|start| start + (self.pending.len() / channels) as i64)
+    }
+
+    /// Drops everything before `frame`.
+    fn discard_before(&mut self, frame: i64, channels: usize) {
+        if let Some(start) = self.start {
+            if start < frame {
+                let frames = ((frame - start) as usize).min(self.pending.len() / channels);
+                self.pending.drain(..frames * channels);
+                self.start = Some(frame);
+            }
+        }
+    }
+}
+
+/// Sums audio from several inputs into blocks of interleaved floats.
+///
+/// A block is only mixed once every input has delivered audio for all of
+/// it, unless one of them is more than `MAX_LATENCY_MS` behind the others,
+/// in which case whatever it is missing is treated as silence. Audio that
+/// arrives for a part of the timeline that has already been mixed is
+/// dropped.
+pub struct Mixer {
+    sample_rate: u32,
+    channels: u16,
+    inputs: Vec<Input>,
+    /// The next frame to mix, once anything has arrived.
+    position: Option<i64>,
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            inputs: Vec::new(),
+            position: None,
+        }
+    }
+
+    pub fn add_input(&mut self, gain: f32) -> InputId {
+        self.inputs.push(Input {
+            gain,
+            muted: false,
+            pending: VecDeque::new(),
+            start: None,
+        });
+        InputId(self.inputs.len() - 1)
+    }
+
+    // Gains are only set up front from the command line for now.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn set_gain(&mut self, input: InputId, gain: f32) {
+        self.inputs[input.0].gain = gain;
+    }
+
+    // Nothing mutes at runtime yet.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn set_muted(&mut self, input: InputId, muted: bool) {
+        self.inputs[input.0].muted = muted;
+    }
+
+    /// Adds a packet of interleaved floats in the mix format, captured at
+    /// `timestamp` (in 100ns units).
+    pub fn push(&mut self, input: InputId, timestamp: i64, mut samples: Vec<f32>) {
+        let channels = self.channels as usize;
+        let tolerance = self.ms_to_frames(CONTIGUOUS_TOLERANCE_MS);
+        let frame = self.time_to_frame(timestamp);
+        let input = &mut self.inputs[input.0];
+
+        match input.end(channels) {
+            None => input.start = Some(frame),
+            Some(end) if frame > end + tolerance => {
+                // A gap, fill it with silence.
+                let gap = (frame - end) as usize * channels;
+                input.pending.extend(std::iter::repeat_n(0.0, gap));
+            }
+            Some(end) if frame < end - tolerance => {
+                // Overlaps what we already have, keep the earlier audio.
+                let overlap = ((end - frame) as usize * channels).min(samples.len());
+                samples.drain(..overlap);
+            }
+            Some(_) => {}
+        }
+        input.pending.extend(samples);
+
+        let position = *self.position.get_or_insert(frame);
+        input.discard_before(position, channels);
+    }
+
+    /// Mixes the next block, if enough audio has arrived for it.
+    pub fn next_block(&mut self) -> Option<MixedBlock> {
+        self.mix_block(false)
+    }
+
+    /// Mixes the next block as long as any input has audio for it, treating
+    /// whatever the others are missing as silence. For getting out what's
+    /// left once capture has stopped.
+    pub fn flush(&mut self) -> Option<MixedBlock> {
+        self.mix_block(true)
+    }
+
+    fn mix_block(&mut self, flush: bool) -> Option<MixedBlock> {
+        let channels = self.channels as usize;
+        let position = self.position?;
+        let block_frames = (self.sample_rate / BLOCKS_PER_SECOND) as i64;
+        let end = position + block_frames;
+
+        let ends: Vec<Option<i64>> = self
+            .inputs
+            .iter()
+            .map(|input| input.end(channels))
+            .collect();
+        let latest = ends.iter().flatten().max().copied()?;
+        let complete = ends
+            .iter()
+            .all(|input_end| input_end.is_some_and(|input_end| input_end >= end));
+        if flush {
+            if latest <= position {
+                return None;
+            }
+        } else if !complete && latest - end < self.ms_to_frames(MAX_LATENCY_MS) {
+            return None;
+        }
+
+        let mut samples = vec![0.0; block_frames as usize * channels];
+        for input in &mut self.inputs {
+            let Some(start) = input.start else {
+                continue;
+            };
+            if !input.muted && input.gain != 0.0 {
+                // The input may start partway into the block.
+                let offset = ((start - position).max(0) as usize * channels).min(samples.len());
+                for (mixed, sample) in samples[offset..].iter_mut().zip(&input.pending) {
+                    *mixed += sample * input.gain;
+                }
+            }
+            input.discard_before(end, channels);
+        }
+        for sample in &mut samples {
+            *sample = soft_clip(*sample);
+        }
+
+        self.position = Some(end);
+        let timestamp = self.frame_to_time(position);
+        Some(MixedBlock {
+            samples,
+            frames: block_frames as u32,
+            timestamp,
+            duration: self.frame_to_time(end) - timestamp,
+        })
+    }
+
+    fn time_to_frame(&self, time: i64) -> i64 {
+        (time * self.sample_rate as i64 + HNS_PER_SECOND / 2).div_euclid(HNS_PER_SECOND)
+    }
+
+    fn frame_to_time(&self, frame: i64) -> i64 {
+        (frame * HNS_PER_SECOND).div_euclid(self.sample_rate as i64)
+    }
+
+    fn ms_to_frames(&self, ms: i64) -> i64 {
+        ms * self.sample_rate as i64 / 1000
+    }
+}
+
+/// Interleaved floats at the mix rate, with the usual layout for the number
+/// of channels.
+fn mix_format(sample_rate: u32, channels: u16) -> PcmFormat {
+    PcmFormat {
+        sample_rate,
+        channels,
+        encoding: SampleEncoding::F32,
+        channel_mask: None,
+    }
+}
+
+/// Which tracks the recorded audio sources end up on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioTracks {
+    /// One track with every source mixed together.
+    Mixed,
+    /// A track for each source.
+    Separate,
+    /// The mixed track first, for players that only play one, followed by a
+    /// track for each source.
+    Both,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseAudioTracksError(&'static str);
+
+impl FromStr for AudioTracks {
+    type Err = ParseAudioTracksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mixed" => Ok(AudioTracks::Mixed),
+            "separate" => Ok(AudioTracks::Separate),
+            "both" => Ok(AudioTracks::Both),
+            _ => Err(ParseAudioTracksError(
+                "Invalid audio tracks value! Expecting: mixed, separate, or both.",
+            )),
+        }
+    }
+}
+
+impl Display for AudioTracks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            AudioTracks::Mixed => "mixed",
+            AudioTracks::Separate => "separate",
+            AudioTracks::Both => "both",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseAudioTracksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseAudioTracksError {}
+
+impl AudioTracks {
+    /// The sources that go into each track, in track order. With a single
+    /// source every choice comes down to the same one track.
+    pub fn layout(self, sources: usize) -> Vec<Vec<usize>> {
+        let mixed: Vec<usize> = (0..sources).collect();
+        if sources <= 1 {
+            return vec![mixed];
+        }
+        let separate = (0..sources).map(|source| vec![source]);
+        match self {
+            AudioTracks::Mixed => vec![mixed],
+            AudioTracks::Separate => separate.collect(),
+            AudioTracks::Both => std::iter::once(mixed).chain(separate).collect(),
+        }
+    }
+}
+
+/// Mixes the same sources into several tracks, each with its own `Mixer`.
+/// Each source is converted once and the result goes to every track it's
+/// in, so frames inserted or dropped for drift are the same in all of them
+/// and the tracks stay sample-aligned.
+pub struct TrackMixer {
+    sources: Vec<InputConverter>,
+    /// Each track's mixer, and which of its inputs each source feeds.
+    tracks: Vec<(Mixer, Vec<(usize, InputId)>)>,
+}
+
+impl TrackMixer {
+    /// `sources` are the format and gain of each source, and `layout` lists
+    /// the sources that go into each track.
+    pub fn new(
+        sample_rate: u32,
+        channels: u16,
+        sources: &[(PcmFormat, f32)],
+        layout: &[Vec<usize>],
+    ) -> Self {
+        let tracks = layout
+            .iter()
+            .map(|track_sources| {
+                let mut mixer = Mixer::new(sample_rate, channels);
+                let inputs = track_sources
+                    .iter()
+                    .map(|&source| (source, mixer.add_input(sources[source].1)))
+                    .collect();
+                (mixer, inputs)
+            })
+            .collect();
+        let mix_format = mix_format(sample_rate, channels);
+        Self {
+            sources: sources
+                .iter()
+                .map(|&(format, _)| InputConverter::new(format, mix_format))
+                .collect(),
+            tracks,
+        }
+    }
+
+    /// Adds a packet from a source to every track it goes into.
+    pub fn push(&mut self, source: usize, timestamp: i64, data: &[u8]) {
+        let samples = self.sources[source].convert(timestamp, data);
+        for (mixer, inputs) in &mut self.tracks {
+            for &(input_source, input) in inputs.iter() {
+                if input_source == source {
+                    mixer.push(input, timestamp, samples.clone());
+                }
+            }
+        }
+    }
+
+    pub fn next_block(&mut self, track: usize) -> Option<MixedBlock> {
+        self.tracks[track].0.next_block()
+    }
+
+    #[cfg(windows)]
+    pub fn flush(&mut self, track: usize) -> Option<MixedBlock> {
+        self.tracks[track].0.flush()
+    }
+
+    /// How far a source's clock is off from the timestamps it comes with,
+    /// in parts per million, once that has been measured.
+    #[cfg(windows)]
+    pub fn drift_ppm(&self, source: usize) -> Option<f64> {
+        self.sources[source].drift.drift_ppm()
+    }
+}
+
+/// Keeps a track continuous from the start of the recording. Blocks are
+/// passed through in order, with silence in front of any that start later
+/// than where the track has got to, and whatever part of a block comes
+/// before that is cut off. Loopback capture delivers nothing at all while
+/// nothing is playing, so `silence_until` is also used to keep the track
+/// going when no blocks are coming.
+pub struct GapFiller {
+    sample_rate: u32,
+    channels: u16,
+    /// Frames handed out so far, silence included, so also the frame the
+    /// next block has to start at.
+    total_samples_processed: i64,
+}
+
+impl GapFiller {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            total_samples_processed: 0,
+        }
+    }
+
+    /// Where the next block has to start, in 100ns units.
+    pub fn next_timestamp(&self) -> i64 {
+        self.frame_to_time(self.total_samples_processed)
+    }
+
+    /// Returns `block`, with silence in front of it if it starts after the
+    /// track has got to, or with the part before that cut off. Nothing is
+    /// returned if all of it is.
+    pub fn fill(&mut self, mut block: MixedBlock) -> Vec<MixedBlock> {
+        let channels = self.channels as usize;
+        let start = self.time_to_frame(block.timestamp);
+        let mut blocks = self.silence_until(block.timestamp);
+
+        let overlap = self.total_samples_processed - start;
+        if overlap >= block.frames as i64 {
+            return blocks;
+        }
+        if overlap > 0 {
+            block.samples.drain(..overlap as usize * channels);
+            block.frames -= overlap as u32;
+        }
+        block.timestamp = self.next_timestamp();
+        self.total_samples_processed += block.frames as i64;
+        block.duration = self.next_timestamp() - block.timestamp;
+        blocks.push(block);
+        blocks
+    }
+
+    /// Blocks of silence from where the track has got to up to `timestamp`.
+    pub fn silence_until(&mut self, timestamp: i64) -> Vec<MixedBlock> {
+        let end = self.time_to_frame(timestamp);
+        let block_frames = (self.sample_rate / BLOCKS_PER_SECOND) as i64;
+        let mut blocks = Vec::new();
+        while self.total_samples_processed < end {
+            let frames = (end - self.total_samples_processed).min(block_frames);
+            let timestamp = self.next_timestamp();
+            self.total_samples_processed += frames;
+            blocks.push(MixedBlock {
+                samples: vec![0.0; frames as usize * self.channels as usize],
+                frames: frames as u32,
+                timestamp,
+                duration: self.next_timestamp() - timestamp,
+            });
+        }
+        blocks
+    }
+
+    fn time_to_frame(&self, time: i64) -> i64 {
+        (time * self.sample_rate as i64 + HNS_PER_SECOND / 2).div_euclid(HNS_PER_SECOND)
+    }
+
+    fn frame_to_time(&self, frame: i64) -> i64 {
+        (frame * HNS_PER_SECOND).div_euclid(self.sample_rate as i64)
+    }
+}
+
+/// Passes samples through unchanged up to `CLIP_KNEE`, and smoothly
+/// compresses anything louder so it never goes past full scale.
+pub fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - CLIP_KNEE;
+    (CLIP_KNEE + headroom * ((magnitude - CLIP_KNEE) / headroom).tanh()).copysign(sample)
+}
+
+/// Converts mixed samples to 16-bit little endian PCM for the encoder.
+pub fn to_i16_bytes(samples: &[f32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use crate::resampler::encode;
+
+    use super::{
+        mix_format, soft_clip, to_i16_bytes, AudioTracks, GapFiller, InputConverter, InputId,
+        MixedBlock, Mixer, PcmFormat, SampleEncoding, TrackMixer, CLIP_KNEE, MAX_LATENCY_MS,
+    };
+
+    const MILLISECOND: i64 = 10_000;
+    const STEREO_I16: PcmFormat = PcmFormat {
+        sample_rate: 48000,
+        channels: 2,
+        encoding: SampleEncoding::I16,
+        channel_mask: None,
+    };
+    const STEREO_F32: PcmFormat = PcmFormat {
+        sample_rate: 48000,
+        channels: 2,
+        encoding: SampleEncoding::F32,
+        channel_mask: None,
+    };
+
+    /// A sine tone, as interleaved floats with the same value on every
+    /// channel.
+    fn tone(format: PcmFormat, frequency: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let phase = 2.0 * PI * frequency * frame as f32 / format.sample_rate as f32;
+                std::iter::repeat_n(phase.sin() * amplitude, format.channels as usize)
+            })
+            .collect()
+    }
+
+    /// Feeds a whole signal to an input in packets of varying size, with
+    /// timestamps starting at `offset`, converting it to the mix format on
+    /// the way.
+    fn feed(mixer: &mut Mixer, input: InputId, format: PcmFormat, samples: &[f32], offset: i64) {
+        let mut converter =
+            InputConverter::new(format, mix_format(mixer.sample_rate, mixer.channels));
+        let channels = format.channels as usize;
+        let sizes = [441, 480, 512, 300, 480];
+        let mut frame = 0;
+        for size in sizes.iter().cycle() {
+            if frame * channels >= samples.len() {
+                break;
+            }
+            let end = ((frame + size) * channels).min(samples.len());
+            let timestamp = offset + frame as i64 * 10_000_000 / format.sample_rate as i64;
+            let data = encode(&samples[frame * channels..end], format.encoding);
+            mixer.push(input, timestamp, converter.convert(timestamp, &data));
+            frame += size;
+        }
+    }
+
+    fn drain(mixer: &mut Mixer) -> Vec<f32> {
+        let mut timestamp = None;
+        let mut samples = Vec::new();
+        while let Some(block) = mixer.next_block() {
+            // Blocks follow on from each other.
+            if let Some(timestamp) = timestamp {
+                assert_eq!(block.timestamp, timestamp);
+            }
+            timestamp = Some(block.timestamp + block.duration);
+            samples.extend(block.samples);
+        }
+        samples
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len());
+        for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+            assert!(
+                (actual - expected).abs() <= tolerance,
+                "sample {index}: {actual} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn mixes_tones_with_gain() {
+        let mut mixer = Mixer::new(48000, 2);
+        let desktop = mixer.add_input(0.5);
+        let microphone = mixer.add_input(1.5);
+        let a = tone(STEREO_I16, 440.0, 0.5, 48000);
+        let b = tone(STEREO_F32, 1000.0, 0.25, 48000);
+        feed(&mut mixer, desktop, STEREO_I16, &a, 0);
+        feed(&mut mixer, microphone, STEREO_F32, &b, 0);
+
+        let mixed = drain(&mut mixer);
+        let expected: Vec<f32> = a.iter().zip(&b).map(|(a, b)| a * 0.5 + b * 1.5).collect();
+        // Everything stays below the knee, so the sum is exact up to the
+        // 16-bit rounding of the desktop tone.
+        assert!(expected.iter().all(|sample| sample.abs() <= CLIP_KNEE));
+        assert_close(&mixed, &expected, 1.0 / 32768.0);
+    }
+
+    #[test]
+    fn aligns_inputs_by_timestamp() {
+        let mut mixer = Mixer::new(48000, 2);
+        let desktop = mixer.add_input(1.0);
+        let microphone = mixer.add_input(1.0);
+        let a = tone(STEREO_F32, 440.0, 0.25, 24000);
+        let b = tone(STEREO_F32, 300.0, 0.25, 19200);
+        // The microphone starts 100ms in, but its packets arrive first.
+        feed(&mut mixer, microphone, STEREO_F32, &b, 100 * MILLISECOND);
+        feed(&mut mixer, desktop, STEREO_F32, &a, 0);
+
+        // Nothing is mixed before the microphone's first packet, since
+        // that's what started the timeline.
+        let mixed = drain(&mut mixer);
+        let expected: Vec<f32> = a[4800 * 2..].iter().zip(&b).map(|(a, b)| a + b).collect();
+        assert_close(&mixed, &expected, 1e-6);
+    }
+
+    #[test]
+    fn fills_gaps_and_drops_overlaps() {
+        let mut mixer = Mixer::new(48000, 2);
+        let input = mixer.add_input(1.0);
+        let one = vec![1.0; 480 * 2];
+        mixer.push(input, 0, one.clone());
+        // 10ms missing.
+        mixer.push(input, 20 * MILLISECOND, one.clone());
+        // Starts 5ms before the last packet ended.
+        let half = vec![0.5; 480 * 2];
+        mixer.push(input, 25 * MILLISECOND, half.clone());
+
+        let mixed: Vec<f32> = drain(&mut mixer).chunks(2).map(|frame| frame[0]).collect();
+        assert_eq!(mixed.len(), 480 * 3);
+        assert!(mixed[..480].iter().all(|&sample| sample == soft_clip(1.0)));
+        assert_eq!(mixed[480..960], [0.0; 480]);
+        assert!(mixed[960..].iter().all(|&sample| sample == soft_clip(1.0)));
+
+        // What was left of the last packet carries on into the next one.
+        let more = vec![0.5; 720 * 2];
+        mixer.push(input, 35 * MILLISECOND, more.clone());
+        let mixed = drain(&mut mixer);
+        assert_eq!(mixed.len(), 480 * 2 * 2);
+        assert!(mixed.iter().all(|&sample| sample == 0.5));
+    }
+
+    #[test]
+    fn muted_inputs_are_silent() {
+        let mut mixer = Mixer::new(48000, 2);
+        let desktop = mixer.add_input(1.0);
+        let microphone = mixer.add_input(1.0);
+        mixer.set_muted(microphone, true);
+        let a = tone(STEREO_F32, 440.0, 0.25, 4800);
+        let b = tone(STEREO_F32, 1000.0, 0.25, 4800);
+        feed(&mut mixer, desktop, STEREO_F32, &a, 0);
+        feed(&mut mixer, microphone, STEREO_F32, &b, 0);
+        assert_close(&drain(&mut mixer), &a, 0.0);
+
+        mixer.set_muted(microphone, false);
+        mixer.set_gain(desktop, 0.0);
+        feed(&mut mixer, desktop, STEREO_F32, &a, 100 * MILLISECOND);
+        feed(&mut mixer, microphone, STEREO_F32, &b, 100 * MILLISECOND);
+        assert_close(&drain(&mut mixer), &b, 0.0);
+    }
+
+    #[test]
+    fn soft_clipper_never_exceeds_full_scale() {
+        let mut mixer = Mixer::new(48000, 2);
+        let desktop = mixer.add_input(1.0);
+        let microphone = mixer.add_input(1.0);
+        let loud = tone(STEREO_F32, 440.0, 1.0, 4800);
+        feed(&mut mixer, desktop, STEREO_F32, &loud, 0);
+        feed(&mut mixer, microphone, STEREO_F32, &loud, 0);
+
+        let mixed = drain(&mut mixer);
+        assert!(mixed.iter().all(|sample| sample.abs() < 1.0));
+        // The peaks are compressed, not flattened.
+        let peak = mixed
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.99);
+
+        // Quiet parts are left alone, and the curve is smooth at the knee.
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-CLIP_KNEE), -CLIP_KNEE);
+        assert!((soft_clip(CLIP_KNEE + 1e-3) - (CLIP_KNEE + 1e-3)).abs() < 1e-5);
+        assert!(soft_clip(10.0) <= 1.0 && soft_clip(-10.0) >= -1.0);
+        assert!(soft_clip(1.5) > soft_clip(1.2));
+        assert_eq!(to_i16_bytes(&[1.0, -1.0]), [0xff, 0x7f, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn does_not_wait_forever_for_a_silent_input() {
+        let mut mixer = Mixer::new(48000, 2);
+        let desktop = mixer.add_input(1.0);
+        let microphone = mixer.add_input(1.0);
+        let b = tone(STEREO_F32, 1000.0, 0.25, 48000);
+        feed(&mut mixer, microphone, STEREO_F32, &b, 0);
+
+        // The desktop never delivers anything, but the microphone still
+        // comes through, up to the latency limit.
+        let mixed = drain(&mut mixer);
+        let frames = 48000 - (MAX_LATENCY_MS as usize * 48);
+        assert_close(&mixed, &b[..frames * 2], 0.0);
+
+        // When the desktop catches up it joins in where the mix is.
+        let a = tone(STEREO_F32, 440.0, 0.25, 48000);
+        feed(&mut mixer, desktop, STEREO_F32, &a, 0);
+        let mixed = drain(&mut mixer);
+        let expected: Vec<f32> = a[frames * 2..]
+            .iter()
+            .zip(&b[frames * 2..])
+            .map(|(a, b)| a + b)
+            .collect();
+        assert_close(&mixed, &expected, 1e-6);
+    }
+
+    #[test]
+    fn lays_out_tracks() {
+        assert_eq!(AudioTracks::Mixed.layout(2), [vec![0, 1]]);
+        assert_eq!(AudioTracks::Separate.layout(2), [vec![0], vec![1]]);
+        assert_eq!(AudioTracks::Both.layout(2), [vec![0, 1], vec![0], vec![1]]);
+        // Nothing to split with only one source.
+        for tracks in [AudioTracks::Mixed, AudioTracks::Separate, AudioTracks::Both] {
+            assert_eq!(tracks.layout(1), [vec![0]]);
+            assert_eq!(tracks.to_string().parse(), Ok(tracks));
+        }
+        assert!("all".parse::<AudioTracks>().is_err());
+    }
+
+    #[test]
+    fn mixes_each_track_from_its_own_sources() {
+        let sources = [(STEREO_I16, 1.0), (STEREO_F32, 0.5)];
+        let mut mixer = TrackMixer::new(48000, 2, &sources, &AudioTracks::Both.layout(2));
+        let a = tone(STEREO_I16, 440.0, 0.25, 9600);
+        let b = tone(STEREO_F32, 1000.0, 0.25, 9600);
+        mixer.push(0, 0, &encode(&a, SampleEncoding::I16));
+        mixer.push(1, 0, &encode(&b, SampleEncoding::F32));
+
+        let mut tracks = vec![Vec::new(); 3];
+        for (index, track) in tracks.iter_mut().enumerate() {
+            while let Some(block) = mixer.next_block(index) {
+                track.extend(block.samples);
+            }
+        }
+        let mixed: Vec<f32> = a.iter().zip(&b).map(|(a, b)| a + b * 0.5).collect();
+        let b_only: Vec<f32> = b.iter().map(|b| b * 0.5).collect();
+        assert_close(&tracks[0], &mixed, 1.0 / 32768.0);
+        assert_close(&tracks[1], &a, 1.0 / 32768.0);
+        assert_close(&tracks[2], &b_only, 0.0);
+    }
+
+    #[test]
+    fn keeps_tracks_aligned_when_a_source_drifts() {
+        let sources = [(STEREO_F32, 1.0), (STEREO_F32, 1.0)];
+        let mut mixer = TrackMixer::new(48000, 2, &sources, &AudioTracks::Both.layout(2));
+        // The desktop delivers a ramp from a clock running 500ppm fast,
+        // and the microphone silence on time.
+        let packet = 480;
+        let mut tracks = vec![Vec::new(); 2];
+        for index in 0..1000 {
+            let frames = index * packet;
+            let ramp: Vec<f32> = (frames..frames + packet)
+                .flat_map(|frame| [(frame % 1000) as f32 / 1000.0; 2])
+                .collect();
+            let on_time = frames as i64 * 10_000_000 / 48000;
+            let fast = (on_time as f64 * (1.0 - 500e-6)) as i64;
+            mixer.push(0, fast, &encode(&ramp, SampleEncoding::F32));
+            mixer.push(
+                1,
+                on_time,
+                &encode(&vec![0.0; packet * 2], SampleEncoding::F32),
+            );
+            for (index, track) in tracks.iter_mut().enumerate() {
+                while let Some(block) = mixer.next_block(index) {
+                    track.extend(block.samples);
+                }
+            }
+        }
+        assert!(mixer.sources[0].drift.drift_ppm().is_some());
+        // The mix only has the desktop in it, and the same frames were
+        // inserted or dropped for it as for the desktop's own track
+        let length = tracks[0].len().min(tracks[1].len());
+        assert!(length > 9 * 48000);
+        assert_eq!(tracks[0][..length], tracks[1][..length]);
+    }
+
+    #[test]
+    fn converts_inputs_to_the_mix_format() {
+        let mono = PcmFormat {
+            sample_rate: 44100,
+            channels: 1,
+            encoding: SampleEncoding::I16,
+            channel_mask: None,
+        };
+        let mut mixer = Mixer::new(48000, 2);
+        let input = mixer.add_input(1.0);
+        feed(&mut mixer, input, mono, &tone(mono, 1000.0, 0.5, 44100), 0);
+        let mixed = drain(&mut mixer);
+
+        // A second at 44.1kHz becomes a second at 48kHz, give or take the
+        // last block, with the tone on both channels.
+        let frames = mixed.len() / 2;
+        assert!((47500..=48000).contains(&frames), "{frames} frames");
+        assert!(mixed.chunks(2).all(|frame| frame[0] == frame[1]));
+        // Still a 1kHz tone at the same level.
+        let left: Vec<f32> = mixed.chunks(2).map(|frame| frame[0]).collect();
+        let crossings = left
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!(
+            (crossings as i64 - frames as i64 / 48).abs() <= 1,
+            "{crossings} crossings"
+        );
+        let peak = left
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn flushes_what_is_left() {
+        let mut mixer = Mixer::new(48000, 2);
+        let first = mixer.add_input(1.0);
+        mixer.add_input(1.0);
+        feed(&mut mixer, first, STEREO_F32, &vec![0.5; 4800], 0);
+        // Still waiting on the second input.
+        assert_eq!(mixer.next_block(), None);
+
+        let mut frames = 0;
+        while let Some(block) = mixer.flush() {
+            assert!(block.samples.iter().all(|&sample| sample == 0.5));
+            frames += block.frames;
+        }
+        assert_eq!(frames, 2400);
+    }
+
+    /// A block of `duration_ms` where every sample is `value`.
+    fn block(start_ms: i64, duration_ms: i64, value: f32) -> MixedBlock {
+        let frames = duration_ms as u32 * 48;
+        MixedBlock {
+            samples: vec![value; frames as usize * 2],
+            frames,
+            timestamp: start_ms * MILLISECOND,
+            duration: duration_ms * MILLISECOND,
+        }
+    }
+
+    #[test]
+    fn gap_filler_keeps_tracks_continuous() {
+        let mut filler = GapFiller::new(48000, 2);
+        let mut output = Vec::new();
+        let packets = [
+            // Nothing played for the first 30ms.
+            block(30, 10, 1.0),
+            block(40, 10, 1.0),
+            // Then nothing for another 30ms.
+            block(80, 10, 2.0),
+            // Overlapping the end of the last one.
+            block(85, 10, 3.0),
+            // Entirely before where the track has got to.
+            block(60, 10, 4.0),
+        ];
+        for packet in packets {
+            output.extend(filler.fill(packet));
+        }
+        // Nothing at all for a while, until the recording stops.
+        output.extend(filler.silence_until(123 * MILLISECOND));
+
+        let mut timestamp = 0;
+        for block in &output {
+            assert_eq!(block.timestamp, timestamp);
+            assert_eq!(block.samples.len(), block.frames as usize * 2);
+            assert!(block.frames <= 480);
+            timestamp += block.duration;
+        }
+        assert_eq!(timestamp, 123 * MILLISECOND);
+        assert_eq!(filler.next_timestamp(), timestamp);
+
+        let samples: Vec<f32> = output
+            .iter()
+            .flat_map(|block| block.samples.iter().step_by(2).copied())
+            .collect();
+        assert_eq!(samples.len(), 123 * 48);
+        let value_at = |ms: usize| samples[ms * 48];
+        let expected = [
+            (0, 0.0),
+            (29, 0.0),
+            (30, 1.0),
+            (49, 1.0),
+            (50, 0.0),
+            (79, 0.0),
+            (80, 2.0),
+            (89, 2.0),
+            (90, 3.0),
+            (94, 3.0),
+            (95, 0.0),
+            (122, 0.0),
+        ];
+        for (ms, value) in expected {
+            assert_eq!(value_at(ms), value, "at {ms}ms");
+        }
+    }
+}
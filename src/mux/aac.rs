@@ -0,0 +1,255 @@
+//! AAC stream configuration: the AudioSpecificConfig that decoders need and
+//! the ADTS headers some encoders put in front of every frame.
+
+use std::io;
+
+use super::bmff::PutBytes;
+
+pub const OBJECT_TYPE_AAC_LC: u8 = 2;
+
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+pub fn sampling_frequency_index(sample_rate: u32) -> Option<u8> {
+    SAMPLE_RATES
+        .iter()
+        .position(|&rate| rate == sample_rate)
+        .map(|index| index as u8)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AudioSpecificConfig {
+    pub object_type: u8,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioSpecificConfig {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            object_type: OBJECT_TYPE_AAC_LC,
+            sample_rate,
+            channels,
+        }
+    }
+
+    fn channel_config(self) -> io::Result<u16> {
+        // Configuration 7 is 7.1, and there's no configuration for 7 channels.
+        match self.channels {
+            1..=6 => Ok(self.channels),
+            8 => Ok(7),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AAC needs 1 to 6 or 8 channels.",
+            )),
+        }
+    }
+
+    pub fn to_bytes(self) -> io::Result<Vec<u8>> {
+        let channel_config = self.channel_config()?;
+        let mut bits: u64 = self.object_type as u64;
+        let mut length = 5;
+        match sampling_frequency_index(self.sample_rate) {
+            Some(index) => {
+                bits = (bits << 4) | index as u64;
+                length += 4;
+            }
+            None => {
+                bits = (bits << 28) | (0xf << 24) | self.sample_rate as u64 & 0xff_ffff;
+                length += 28;
+            }
+        }
+        bits = (bits << 4) | channel_config as u64;
+        length += 4;
+        // frameLengthFlag, dependsOnCoreCoder and extensionFlag.
+        bits <<= 3;
+        length += 3;
+
+        let padding = (8 - length % 8) % 8;
+        bits <<= padding;
+        let bytes = (length + padding) / 8;
+        Ok(bits.to_be_bytes()[8 - bytes..].to_vec())
+    }
+
+    /// Builds the ADTS header for a frame with a payload of `payload_len`
+    /// bytes, which is what makes a run of raw frames playable on its own.
+    pub fn adts_header(self, payload_len: usize) -> io::Result<Vec<u8>> {
+        let channel_config = self.channel_config()?;
+        let index = sampling_frequency_index(self.sample_rate).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ADTS can only describe the standard AAC sample rates.",
+            )
+        })?;
+        // The frame length includes the header, and has 13 bits.
+        let frame_len = payload_len + 7;
+        if frame_len >= 1 << 13 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The frame is too long for an ADTS header.",
+            ));
+        }
+        let profile = self.object_type - 1;
+        Ok(vec![
+            // Sync word, MPEG-4, no CRC
+            0xff,
+            0xf1,
+            profile << 6 | index << 2 | (channel_config >> 2) as u8,
+            ((channel_config & 3) << 6) as u8 | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            ((frame_len & 7) << 5) as u8 | 0x1f,
+            // The rest of the buffer fullness, which is all ones for a
+            // variable bit rate, and one raw data block
+            0xfc,
+        ])
+    }
+
+    #[cfg(test)]
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < 2 {
+            return Err(invalid_data("AudioSpecificConfig is too short."));
+        }
+        let object_type = data[0] >> 3;
+        let index = ((data[0] & 0x07) << 1) | (data[1] >> 7);
+        let (sample_rate, channel_bits) = if index == 0xf {
+            if data.len() < 5 {
+                return Err(invalid_data("AudioSpecificConfig is too short."));
+            }
+            let value = u64::from_be_bytes([0, 0, 0, data[0], data[1], data[2], data[3], data[4]]);
+            ((value >> 7) as u32 & 0xff_ffff, (value >> 3) as u8 & 0xf)
+        } else {
+            let sample_rate = *SAMPLE_RATES
+                .get(index as usize)
+                .ok_or_else(|| invalid_data("Reserved sampling frequency index."))?;
+            (sample_rate, (data[1] >> 3) & 0xf)
+        };
+        let channels = match channel_bits {
+            7 => 8,
+            channels => channels as u16,
+        };
+        Ok(Self {
+            object_type,
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+/// Returns the length of the ADTS header at the start of `frame`, if it has
+/// one.
+pub fn adts_header_len(frame: &[u8]) -> Option<usize> {
+    if frame.len() < 7 || frame[0] != 0xff || frame[1] & 0xf6 != 0xf0 {
+        return None;
+    }
+    let protection_absent = frame[1] & 1 == 1;
+    Some(if protection_absent { 7 } else { 9 })
+}
+
+/// Strips the ADTS header from a frame, leaving the raw payload.
+pub fn strip_adts(frame: &[u8]) -> &[u8] {
+    match adts_header_len(frame) {
+        Some(length) if length <= frame.len() => &frame[length..],
+        _ => frame,
+    }
+}
+
+/// Builds the ES descriptor that goes in an esds box for an AAC stream.
+pub fn elementary_stream_descriptor(config: &[u8], bit_rate: u32) -> Vec<u8> {
+    let mut decoder_config = Vec::new();
+    // MPEG-4 audio, audio stream.
+    decoder_config.put_u8(0x40);
+    decoder_config.put_u8(0x15);
+    decoder_config.put_u24(0);
+    decoder_config.put_u32(bit_rate);
+    decoder_config.put_u32(bit_rate);
+    put_descriptor(&mut decoder_config, 0x05, config);
+
+    let mut es = Vec::new();
+    // The ES ID is always zero in MP4 files.
+    es.put_u16(0);
+    es.put_u8(0);
+    put_descriptor(&mut es, 0x04, &decoder_config);
+    // SL config with the predefined MP4 layout.
+    put_descriptor(&mut es, 0x06, &[0x02]);
+
+    let mut out = Vec::new();
+    put_descriptor(&mut out, 0x03, &es);
+    out
+}
+
+fn put_descriptor(out: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    out.put_u8(tag);
+    let length = body.len() as u32;
+    out.put_u8(0x80 | ((length >> 21) & 0x7f) as u8);
+    out.put_u8(0x80 | ((length >> 14) & 0x7f) as u8);
+    out.put_u8(0x80 | ((length >> 7) & 0x7f) as u8);
+    out.put_u8((length & 0x7f) as u8);
+    out.put_bytes(body);
+}
+
+#[cfg(test)]
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adts_header_len, elementary_stream_descriptor, strip_adts, AudioSpecificConfig};
+
+    #[test]
+    fn standard_rates_use_two_bytes() {
+        let config = AudioSpecificConfig::new(48000, 2);
+        let bytes = config.to_bytes().unwrap();
+        assert_eq!(bytes, [0x11, 0x90]);
+        assert_eq!(AudioSpecificConfig::parse(&bytes).unwrap(), config);
+
+        let bytes = AudioSpecificConfig::new(44100, 1).to_bytes().unwrap();
+        assert_eq!(bytes, [0x12, 0x08]);
+    }
+
+    #[test]
+    fn other_rates_are_escaped() {
+        let config = AudioSpecificConfig::new(37800, 2);
+        let bytes = config.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 5);
+        assert_eq!(AudioSpecificConfig::parse(&bytes).unwrap(), config);
+        assert!(AudioSpecificConfig::new(48000, 0).to_bytes().is_err());
+    }
+
+    #[test]
+    fn strips_adts_headers() {
+        let frame = [0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc, 0xaa, 0xbb];
+        assert_eq!(strip_adts(&frame), [0xaa, 0xbb]);
+        assert_eq!(strip_adts(&frame[7..]), [0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn builds_adts_headers() {
+        let header = AudioSpecificConfig::new(44100, 2).adts_header(2).unwrap();
+        assert_eq!(header, [0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc]);
+        assert_eq!(adts_header_len(&header), Some(7));
+
+        let header = AudioSpecificConfig::new(48000, 8)
+            .adts_header(1000)
+            .unwrap();
+        // 7.1, and a frame of 1007 bytes
+        assert_eq!(header, [0xff, 0xf1, 0x4d, 0xc0, 0x7d, 0xff, 0xfc]);
+        assert!(AudioSpecificConfig::new(37800, 2).adts_header(2).is_err());
+        assert!(AudioSpecificConfig::new(48000, 2)
+            .adts_header(8200)
+            .is_err());
+    }
+
+    #[test]
+    fn descriptor_carries_config() {
+        let esds = elementary_stream_descriptor(&[0x11, 0x90], 192000);
+        assert_eq!(esds[0], 0x03);
+        assert_eq!(esds.len(), 5 + 3 + 5 + 13 + 5 + 2 + 5 + 1);
+        assert_eq!(&esds[esds.len() - 8..esds.len() - 6], &[0x11, 0x90]);
+        assert_eq!(
+            &esds[esds.len() - 6..],
+            &[0x06, 0x80, 0x80, 0x80, 0x01, 0x02]
+        );
+    }
+}
@@ -0,0 +1,464 @@
+//! AV1 bitstream handling: splitting temporal units into OBUs, reading the
+//! sequence header and building the av1C configuration record.
+
+use std::io;
+
+use super::{bmff::PutBytes, h264::BitReader};
+
+pub const OBU_SEQUENCE_HEADER: u8 = 1;
+pub const OBU_TEMPORAL_DELIMITER: u8 = 2;
+pub const OBU_PADDING: u8 = 15;
+
+/// An open bitstream unit, as found in a temporal unit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Obu<'a> {
+    pub obu_type: u8,
+    /// The OBU header, with the extension if there is one.
+    pub header: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl Obu<'_> {
+    /// The OBU with a size field, which is how samples have to store it.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.header.len() + 8 + self.payload.len());
+        out.put_u8(self.header[0] | 0x02);
+        out.put_bytes(&self.header[1..]);
+        put_leb128(&mut out, self.payload.len() as u64);
+        out.put_bytes(self.payload);
+        out
+    }
+}
+
+/// Splits a temporal unit in the low overhead bitstream format into OBUs.
+/// One without a size field runs to the end of the data.
+pub fn split_obus(mut data: &[u8]) -> io::Result<Vec<Obu<'_>>> {
+    let mut obus = Vec::new();
+    while !data.is_empty() {
+        let first = data[0];
+        if first & 0x80 != 0 {
+            return Err(invalid_data("The OBU's forbidden bit is set."));
+        }
+        let header_length = 1 + ((first >> 2) & 1) as usize;
+        if data.len() < header_length {
+            return Err(invalid_data("Truncated OBU header."));
+        }
+        let (header, rest) = data.split_at(header_length);
+        let (payload, rest) = if first & 0x02 != 0 {
+            let (size, length) = read_leb128(rest)?;
+            let rest = &rest[length..];
+            if (rest.len() as u64) < size {
+                return Err(invalid_data("OBU runs past the end of the sample."));
+            }
+            rest.split_at(size as usize)
+        } else {
+            (rest, &rest[rest.len()..])
+        };
+        obus.push(Obu {
+            obu_type: (first >> 3) & 0x0f,
+            header,
+            payload,
+        });
+        data = rest;
+    }
+    Ok(obus)
+}
+
+/// Reads an unsigned LEB128 number, returning it with how many bytes it
+/// took.
+pub fn read_leb128(data: &[u8]) -> io::Result<(u64, usize)> {
+    let mut value = 0;
+    for (index, &byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (index * 7);
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+    Err(invalid_data("Truncated OBU size."))
+}
+
+pub fn put_leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.put_u8(byte);
+            return;
+        }
+        out.put_u8(byte | 0x80);
+    }
+}
+
+/// The fields of a sequence header the container cares about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SequenceHeader {
+    pub seq_profile: u8,
+    pub seq_level_idx: u8,
+    pub seq_tier: u8,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    /// The largest frame size, which is the video's size for a recording.
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl SequenceHeader {
+    /// Parses the payload of a sequence header OBU. Only the first
+    /// operating point is kept.
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut reader = BitReader::new(payload);
+        let seq_profile = reader.bits(3)? as u8;
+        if seq_profile > 2 {
+            return Err(invalid_data("Unknown AV1 profile."));
+        }
+        // still_picture
+        reader.bit()?;
+        let reduced_still_picture_header = reader.bit()?;
+        let (seq_level_idx, seq_tier) = if reduced_still_picture_header {
+            (reader.bits(5)? as u8, 0)
+        } else {
+            let timing_info_present = reader.bit()?;
+            let mut decoder_model_info_present = false;
+            let mut buffer_delay_length = 0;
+            if timing_info_present {
+                // num_units_in_display_tick and time_scale
+                reader.bits(32)?;
+                reader.bits(32)?;
+                if reader.bit()? {
+                    read_uvlc(&mut reader)?;
+                }
+                decoder_model_info_present = reader.bit()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = reader.bits(5)? + 1;
+                    // num_units_in_decoding_tick,
+                    // buffer_removal_time_length_minus_1 and
+                    // frame_presentation_time_length_minus_1
+                    reader.bits(32)?;
+                    reader.bits(10)?;
+                }
+            }
+            let initial_display_delay_present = reader.bit()?;
+            let operating_points = reader.bits(5)? + 1;
+            let mut first = None;
+            for _ in 0..operating_points {
+                // operating_point_idc
+                reader.bits(12)?;
+                let level = reader.bits(5)? as u8;
+                let tier = if level > 7 { reader.bit()? as u8 } else { 0 };
+                if decoder_model_info_present && reader.bit()? {
+                    // decoder_buffer_delay, encoder_buffer_delay and
+                    // low_delay_mode_flag
+                    reader.bits(buffer_delay_length)?;
+                    reader.bits(buffer_delay_length)?;
+                    reader.bit()?;
+                }
+                if initial_display_delay_present && reader.bit()? {
+                    reader.bits(4)?;
+                }
+                first.get_or_insert((level, tier));
+            }
+            first.expect("There's at least one operating point")
+        };
+
+        let width_bits = reader.bits(4)? + 1;
+        let height_bits = reader.bits(4)? + 1;
+        let max_width = reader.bits(width_bits)? + 1;
+        let max_height = reader.bits(height_bits)? + 1;
+        if !reduced_still_picture_header && reader.bit()? {
+            // delta_frame_id_length_minus_2 and
+            // additional_frame_id_length_minus_1
+            reader.bits(7)?;
+        }
+        // use_128x128_superblock, enable_filter_intra and
+        // enable_intra_edge_filter
+        reader.bits(3)?;
+        if !reduced_still_picture_header {
+            // enable_interintra_compound, enable_masked_compound,
+            // enable_warped_motion and enable_dual_filter
+            reader.bits(4)?;
+            let enable_order_hint = reader.bit()?;
+            if enable_order_hint {
+                // enable_jnt_comp and enable_ref_frame_mvs
+                reader.bits(2)?;
+            }
+            let force_screen_content_tools = if reader.bit()? {
+                2
+            } else {
+                reader.bit()? as u32
+            };
+            if force_screen_content_tools > 0 && !reader.bit()? {
+                // seq_force_integer_mv
+                reader.bit()?;
+            }
+            if enable_order_hint {
+                // order_hint_bits_minus_1
+                reader.bits(3)?;
+            }
+        }
+        // enable_superres, enable_cdef and enable_restoration
+        reader.bits(3)?;
+
+        // color_config
+        let high_bitdepth = reader.bit()?;
+        let twelve_bit = seq_profile == 2 && high_bitdepth && reader.bit()?;
+        let monochrome = seq_profile != 1 && reader.bit()?;
+        let (color_primaries, transfer_characteristics, matrix_coefficients) = if reader.bit()? {
+            (reader.bits(8)?, reader.bits(8)?, reader.bits(8)?)
+        } else {
+            (2, 2, 2)
+        };
+        let (subsampling_x, subsampling_y) = if monochrome {
+            // color_range
+            reader.bit()?;
+            (true, true)
+        } else if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0
+        {
+            // sRGB is always full range 4:4:4
+            (false, false)
+        } else {
+            // color_range
+            reader.bit()?;
+            match seq_profile {
+                0 => (true, true),
+                1 => (false, false),
+                _ if twelve_bit => {
+                    let x = reader.bit()?;
+                    (x, x && reader.bit()?)
+                }
+                _ => (true, false),
+            }
+        };
+        let chroma_sample_position = if subsampling_x && subsampling_y && !monochrome {
+            reader.bits(2)? as u8
+        } else {
+            0
+        };
+
+        Ok(Self {
+            seq_profile,
+            seq_level_idx,
+            seq_tier,
+            high_bitdepth,
+            twelve_bit,
+            monochrome,
+            chroma_subsampling_x: subsampling_x,
+            chroma_subsampling_y: subsampling_y,
+            chroma_sample_position,
+            max_width,
+            max_height,
+        })
+    }
+}
+
+fn read_uvlc(reader: &mut BitReader) -> io::Result<u32> {
+    let mut leading_zeros = 0;
+    while !reader.bit()? {
+        leading_zeros += 1;
+    }
+    if leading_zeros >= 32 {
+        return Ok(u32::MAX);
+    }
+    Ok(((1u64 << leading_zeros) - 1 + reader.bits(leading_zeros)? as u64) as u32)
+}
+
+/// Builds the body of an av1C box from a sequence header OBU, which is
+/// included as the only config OBU.
+pub fn av1_decoder_configuration(sequence_header: &[u8]) -> io::Result<Vec<u8>> {
+    let obu = split_obus(sequence_header)?
+        .into_iter()
+        .find(|obu| obu.obu_type == OBU_SEQUENCE_HEADER)
+        .ok_or_else(|| invalid_data("No sequence header was found in the stream."))?;
+    let parsed = SequenceHeader::parse(obu.payload)?;
+
+    let mut out = Vec::new();
+    // marker and version
+    out.put_u8(0x81);
+    out.put_u8((parsed.seq_profile << 5) | parsed.seq_level_idx);
+    out.put_u8(
+        (parsed.seq_tier << 7)
+            | ((parsed.high_bitdepth as u8) << 6)
+            | ((parsed.twelve_bit as u8) << 5)
+            | ((parsed.monochrome as u8) << 4)
+            | ((parsed.chroma_subsampling_x as u8) << 3)
+            | ((parsed.chroma_subsampling_y as u8) << 2)
+            | parsed.chroma_sample_position,
+    );
+    // No initial_presentation_delay.
+    out.put_u8(0);
+    out.put_bytes(&obu.to_bytes());
+    Ok(out)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Builds sequence headers for tests, without a real encoder around.
+#[cfg(test)]
+pub mod test_util {
+    use super::{put_leb128, OBU_SEQUENCE_HEADER};
+    use crate::mux::h264::BitWriter;
+
+    pub fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut obu = vec![(obu_type << 3) | 0x02];
+        put_leb128(&mut obu, payload.len() as u64);
+        obu.extend_from_slice(payload);
+        obu
+    }
+
+    /// A sequence header OBU for a 4:2:0 stream of the given size, with
+    /// timing info and a second operating point to skip over.
+    pub fn sequence_header(profile: u32, level: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.bits(profile, 3);
+        writer.bits(0, 2);
+        // timing_info_present_flag, with an equal picture interval
+        writer.bit(true);
+        writer.bits(1, 32);
+        writer.bits(60, 32);
+        writer.bit(true);
+        writer.bits(0b1, 1);
+        // decoder_model_info_present_flag
+        writer.bit(true);
+        writer.bits(4, 5);
+        writer.bits(1, 32);
+        writer.bits(0, 10);
+        // initial_display_delay_present_flag
+        writer.bit(true);
+        writer.bits(1, 5);
+        for level in [level, 0] {
+            writer.bits(0, 12);
+            writer.bits(level, 5);
+            if level > 7 {
+                writer.bit(true);
+            }
+            writer.bit(true);
+            writer.bits(0, 5);
+            writer.bits(0, 5);
+            writer.bit(false);
+            writer.bit(true);
+            writer.bits(9, 4);
+        }
+        writer.bits(15, 4);
+        writer.bits(15, 4);
+        writer.bits(width - 1, 16);
+        writer.bits(height - 1, 16);
+        // frame_id_numbers_present_flag
+        writer.bit(false);
+        writer.bits(0b011, 3);
+        writer.bits(0b0011, 4);
+        // enable_order_hint, with enable_jnt_comp and enable_ref_frame_mvs
+        writer.bit(true);
+        writer.bits(0b11, 2);
+        // seq_choose_screen_content_tools, then seq_choose_integer_mv
+        writer.bit(true);
+        writer.bit(true);
+        writer.bits(6, 3);
+        writer.bits(0b011, 3);
+        // high_bitdepth for 10 bits in the main profile
+        writer.bit(profile == 0);
+        // mono_chrome
+        if profile != 1 {
+            writer.bit(false);
+        }
+        // BT.709 color description and limited range
+        writer.bit(true);
+        writer.bits(1, 8);
+        writer.bits(1, 8);
+        writer.bits(1, 8);
+        writer.bit(false);
+        if profile == 0 {
+            // chroma_sample_position is colocated
+            writer.bits(2, 2);
+        }
+        // separate_uv_delta_q
+        writer.bit(false);
+        obu(OBU_SEQUENCE_HEADER, &writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        av1_decoder_configuration, put_leb128, read_leb128, split_obus, test_util, SequenceHeader,
+        OBU_PADDING, OBU_SEQUENCE_HEADER, OBU_TEMPORAL_DELIMITER,
+    };
+
+    #[test]
+    fn round_trips_leb128() {
+        for value in [0, 1, 127, 128, 300, 1 << 20, u32::MAX as u64] {
+            let mut out = Vec::new();
+            put_leb128(&mut out, value);
+            assert_eq!(read_leb128(&out).unwrap(), (value, out.len()));
+        }
+        assert_eq!(read_leb128(&[0xe5, 0x8e, 0x26]).unwrap(), (624485, 3));
+        assert!(read_leb128(&[0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn splits_temporal_units() {
+        let sequence_header = test_util::sequence_header(0, 8, 1920, 1080);
+        let mut data = test_util::obu(OBU_TEMPORAL_DELIMITER, &[]);
+        data.extend_from_slice(&sequence_header);
+        data.extend_from_slice(&test_util::obu(OBU_PADDING, &[0; 200]));
+        // A frame OBU with an extension and no size field
+        data.extend_from_slice(&[(6 << 3) | 0x04, 0x20, 1, 2, 3]);
+        let obus = split_obus(&data).unwrap();
+        let types: Vec<u8> = obus.iter().map(|obu| obu.obu_type).collect();
+        assert_eq!(
+            types,
+            [OBU_TEMPORAL_DELIMITER, OBU_SEQUENCE_HEADER, OBU_PADDING, 6]
+        );
+        assert_eq!(obus[1].to_bytes(), sequence_header);
+        assert_eq!(obus[2].payload.len(), 200);
+        assert_eq!(obus[3].header, &[(6 << 3) | 0x04, 0x20]);
+        assert_eq!(obus[3].payload, &[1, 2, 3]);
+        assert_eq!(obus[3].to_bytes(), [(6 << 3) | 0x06, 0x20, 3, 1, 2, 3]);
+
+        assert!(split_obus(&[(6 << 3) | 0x02, 5, 1]).is_err());
+        assert!(split_obus(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn parses_main_profile_sequence_header() {
+        let obu = test_util::sequence_header(0, 13, 3840, 2160);
+        let obus = split_obus(&obu).unwrap();
+        let header = SequenceHeader::parse(obus[0].payload).unwrap();
+        assert_eq!(header.seq_profile, 0);
+        assert_eq!((header.seq_level_idx, header.seq_tier), (13, 1));
+        assert!(header.high_bitdepth && !header.twelve_bit && !header.monochrome);
+        assert!(header.chroma_subsampling_x && header.chroma_subsampling_y);
+        assert_eq!(header.chroma_sample_position, 2);
+        assert_eq!((header.max_width, header.max_height), (3840, 2160));
+    }
+
+    #[test]
+    fn parses_high_profile_sequence_header() {
+        let obu = test_util::sequence_header(1, 5, 1280, 720);
+        let obus = split_obus(&obu).unwrap();
+        let header = SequenceHeader::parse(obus[0].payload).unwrap();
+        assert_eq!(
+            (header.seq_profile, header.seq_level_idx, header.seq_tier),
+            (1, 5, 0)
+        );
+        assert!(!header.high_bitdepth && !header.monochrome);
+        assert!(!header.chroma_subsampling_x && !header.chroma_subsampling_y);
+        assert_eq!((header.max_width, header.max_height), (1280, 720));
+        assert!(SequenceHeader::parse(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn builds_av1c_with_the_sequence_header() {
+        let sequence_header = test_util::sequence_header(0, 12, 2560, 1440);
+        let config = av1_decoder_configuration(&sequence_header).unwrap();
+        assert_eq!(&config[..4], &[0x81, 12, 0xce, 0]);
+        assert_eq!(&config[4..], &sequence_header[..]);
+
+        let delimiter = test_util::obu(OBU_TEMPORAL_DELIMITER, &[]);
+        assert!(av1_decoder_configuration(&delimiter).is_err());
+    }
+}
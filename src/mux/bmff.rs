@@ -0,0 +1,237 @@
+//! Helpers for reading and writing ISO base media file format boxes.
+
+/// Big endian writes into a byte buffer.
+pub trait PutBytes {
+    fn put_u8(&mut self, value: u8);
+    fn put_u16(&mut self, value: u16);
+    fn put_u24(&mut self, value: u32);
+    fn put_u32(&mut self, value: u32);
+    fn put_u64(&mut self, value: u64);
+    fn put_bytes(&mut self, bytes: &[u8]);
+    fn put_zeros(&mut self, count: usize);
+}
+
+impl PutBytes for Vec<u8> {
+    fn put_u8(&mut self, value: u8) {
+        self.push(value);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u24(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes()[1..]);
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+
+    fn put_zeros(&mut self, count: usize) {
+        self.resize(self.len() + count, 0);
+    }
+}
+
+/// Appends a box to `out`, with the body written by `body`.
+pub fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.put_u32(0);
+    out.put_bytes(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a box that starts with a version and flags.
+pub fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.put_u8(version);
+        out.put_u24(flags);
+        body(out);
+    })
+}
+
+/// The header of a box, as found at the start of `data`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BoxHeader {
+    pub kind: [u8; 4],
+    pub header_len: usize,
+    /// The size of the whole box including its header, or None if the box
+    /// extends to the end of the file.
+    pub size: Option<u64>,
+}
+
+impl BoxHeader {
+    /// Returns None if `data` is too short to hold the header.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 {
+            return None;
+        }
+        let size = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let kind = data[4..8].try_into().unwrap();
+        match size {
+            0 => Some(Self {
+                kind,
+                header_len: 8,
+                size: None,
+            }),
+            1 => {
+                if data.len() < 16 {
+                    return None;
+                }
+                Some(Self {
+                    kind,
+                    header_len: 16,
+                    size: Some(u64::from_be_bytes(data[8..16].try_into().unwrap())),
+                })
+            }
+            size => Some(Self {
+                kind,
+                header_len: 8,
+                size: Some(size as u64),
+            }),
+        }
+    }
+}
+
+/// A box found in memory by `boxes`.
+#[derive(Copy, Clone, Debug)]
+pub struct Mp4Box<'a> {
+    pub kind: [u8; 4],
+    /// Offset of the box from the start of the slice it was found in. Only
+    /// the tests need it so far.
+    #[allow(dead_code)]
+    pub offset: usize,
+    pub body: &'a [u8],
+}
+
+/// Iterates over the boxes in `data`, stopping at the first one that is
+/// malformed or cut off.
+pub fn boxes(data: &[u8]) -> impl Iterator<Item = Mp4Box<'_>> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = BoxHeader::parse(&data[offset..])?;
+        let size = header
+            .size
+            .map(|size| size as usize)
+            .unwrap_or(data.len() - offset);
+        if size < header.header_len || offset + size > data.len() {
+            return None;
+        }
+        let found = Mp4Box {
+            kind: header.kind,
+            offset,
+            body: &data[offset + header.header_len..offset + size],
+        };
+        offset += size;
+        Some(found)
+    })
+}
+
+/// Finds a box by following `path` down from the top level of `data`.
+pub fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<Mp4Box<'a>> {
+    let (first, rest) = path.split_first()?;
+    let found = boxes(data).find(|found| &found.kind == *first)?;
+    if rest.is_empty() {
+        Some(found)
+    } else {
+        find_box(found.body, rest)
+    }
+}
+
+/// Big endian reads from a byte slice, for parsing box bodies.
+#[cfg(test)]
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+#[cfg(test)]
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn bytes(&mut self, count: usize) -> &'a [u8] {
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        bytes
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.bytes(8).try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{boxes, find_box, write_box, write_full_box, BoxHeader, PutBytes};
+
+    #[test]
+    fn nested_boxes_round_trip() {
+        let mut data = Vec::new();
+        write_box(&mut data, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| out.put_u32(1000));
+            write_box(out, b"trak", |out| {
+                write_box(out, b"tkhd", |out| out.put_u16(7));
+            });
+        });
+        write_box(&mut data, b"free", |_| {});
+
+        let kinds: Vec<_> = boxes(&data).map(|found| found.kind).collect();
+        assert_eq!(kinds, [*b"moov", *b"free"]);
+        let mvhd = find_box(&data, &[b"moov", b"mvhd"]).unwrap();
+        assert_eq!(mvhd.body, [0, 0, 0, 0, 0, 0, 0x03, 0xe8]);
+        let tkhd = find_box(&data, &[b"moov", b"trak", b"tkhd"]).unwrap();
+        assert_eq!(tkhd.body, [0, 7]);
+    }
+
+    #[test]
+    fn parses_large_and_open_ended_sizes() {
+        let mut data = Vec::new();
+        data.put_u32(1);
+        data.put_bytes(b"mdat");
+        data.put_u64(20);
+        data.put_u32(0xdeadbeef);
+        let header = BoxHeader::parse(&data).unwrap();
+        assert_eq!(header.header_len, 16);
+        assert_eq!(header.size, Some(20));
+        assert_eq!(boxes(&data).next().unwrap().body, [0xde, 0xad, 0xbe, 0xef]);
+
+        data[3] = 0;
+        assert_eq!(BoxHeader::parse(&data).unwrap().size, None);
+        assert!(BoxHeader::parse(&data[..6]).is_none());
+    }
+
+    #[test]
+    fn truncated_box_ends_iteration() {
+        let mut data = Vec::new();
+        write_box(&mut data, b"ftyp", |out| out.put_bytes(b"isom"));
+        write_box(&mut data, b"mdat", |out| out.put_zeros(32));
+        data.truncate(data.len() - 1);
+        assert_eq!(boxes(&data).count(), 1);
+    }
+}
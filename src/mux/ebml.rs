@@ -0,0 +1,268 @@
+//! Helpers for reading and writing EBML, the binary format Matroska and WebM
+//! files are made of. An element is an ID, a size and a body, where IDs and
+//! sizes are variable length integers whose first byte says how long they
+//! are.
+
+/// The size of an element whose end isn't known yet, as 8 bytes.
+pub const UNKNOWN_SIZE: u64 = (1 << 56) - 1;
+
+/// The body of a Void element is ignored, so it holds space for later.
+pub const VOID: u32 = 0xec;
+
+/// Writes EBML elements into a byte buffer.
+pub trait PutElements {
+    /// IDs are stored with their length marker, so they're written as is.
+    fn put_id(&mut self, id: u32);
+    /// Writes a size in as few bytes as it fits in.
+    fn put_size(&mut self, size: u64);
+    /// Writes a size in 8 bytes, so it can be overwritten with any other.
+    fn put_size_8(&mut self, size: u64);
+    fn put_uint(&mut self, id: u32, value: u64);
+    fn put_float(&mut self, id: u32, value: f64);
+    fn put_string(&mut self, id: u32, value: &str);
+    fn put_binary(&mut self, id: u32, value: &[u8]);
+    /// Fills `len` bytes, which must be at least 2, with a Void element.
+    fn put_void(&mut self, len: usize);
+}
+
+impl PutElements for Vec<u8> {
+    fn put_id(&mut self, id: u32) {
+        let len = 4 - id.leading_zeros() as usize / 8;
+        self.extend_from_slice(&id.to_be_bytes()[4 - len..]);
+    }
+
+    fn put_size(&mut self, size: u64) {
+        // All ones is reserved for unknown sizes, so each length holds one
+        // less than it could.
+        let len = (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+        let marked = size | 1 << (7 * len);
+        self.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+    }
+
+    fn put_size_8(&mut self, size: u64) {
+        self.extend_from_slice(&(size | 1 << 56).to_be_bytes());
+    }
+
+    fn put_uint(&mut self, id: u32, value: u64) {
+        let len = (8 - value.leading_zeros() as usize / 8).max(1);
+        self.put_id(id);
+        self.put_size(len as u64);
+        self.extend_from_slice(&value.to_be_bytes()[8 - len..]);
+    }
+
+    fn put_float(&mut self, id: u32, value: f64) {
+        self.put_id(id);
+        self.put_size(8);
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_string(&mut self, id: u32, value: &str) {
+        self.put_binary(id, value.as_bytes());
+    }
+
+    fn put_binary(&mut self, id: u32, value: &[u8]) {
+        self.put_id(id);
+        self.put_size(value.len() as u64);
+        self.extend_from_slice(value);
+    }
+
+    fn put_void(&mut self, len: usize) {
+        self.put_id(VOID);
+        // A 1 byte size covers up to 126 bytes of body, anything more takes 8.
+        if len - 2 < 127 {
+            self.put_size(len as u64 - 2);
+            self.resize(self.len() + len - 2, 0);
+        } else {
+            self.put_size_8(len as u64 - 9);
+            self.resize(self.len() + len - 9, 0);
+        }
+    }
+}
+
+/// Appends an element to `out`, with the body written by `body`.
+pub fn write_element(out: &mut Vec<u8>, id: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let mut contents = Vec::new();
+    body(&mut contents);
+    out.put_id(id);
+    out.put_size(contents.len() as u64);
+    out.extend_from_slice(&contents);
+}
+
+/// Reads a variable length integer from the start of `data`, returning it
+/// with the length marker still set, and its length.
+#[cfg(test)]
+fn read_marked(data: &[u8]) -> Option<(u64, usize)> {
+    let len = data.first()?.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len {
+        return None;
+    }
+    let value = data[..len]
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u64);
+    Some((value, len))
+}
+
+/// Reads a size, which is None if it's unknown.
+#[cfg(test)]
+pub fn read_size(data: &[u8]) -> Option<(Option<u64>, usize)> {
+    let (marked, len) = read_marked(data)?;
+    let size = marked & !(1 << (7 * len));
+    let unknown = size == (1 << (7 * len)) - 1;
+    Some(((!unknown).then_some(size), len))
+}
+
+/// An element found in memory by `elements`.
+#[cfg(test)]
+#[derive(Copy, Clone, Debug)]
+pub struct Element<'a> {
+    pub id: u32,
+    /// Offset of the element from the start of the slice it was found in.
+    pub offset: usize,
+    pub body: &'a [u8],
+}
+
+/// Iterates over the elements in `data`, stopping at the first one that is
+/// malformed or cut off. An element of unknown size takes up the rest of
+/// `data`, so this only suits files where those come last, like the
+/// segment and the cluster being written.
+#[cfg(test)]
+pub fn elements(data: &[u8]) -> impl Iterator<Item = Element<'_>> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let (id, id_len) = read_marked(&data[offset..])?;
+        let (size, size_len) = read_size(&data[offset + id_len..])?;
+        let start = offset + id_len + size_len;
+        let end = match size {
+            Some(size) => start.checked_add(size as usize)?,
+            None => data.len(),
+        };
+        if end > data.len() {
+            return None;
+        }
+        let found = Element {
+            id: id as u32,
+            offset,
+            body: &data[start..end],
+        };
+        offset = end;
+        Some(found)
+    })
+}
+
+/// Finds an element by following `path` down from the top level of `data`.
+#[cfg(test)]
+pub fn find_element<'a>(data: &'a [u8], path: &[u32]) -> Option<Element<'a>> {
+    let (first, rest) = path.split_first()?;
+    let found = elements(data).find(|found| found.id == *first)?;
+    if rest.is_empty() {
+        Some(found)
+    } else {
+        find_element(found.body, rest)
+    }
+}
+
+#[cfg(test)]
+pub fn read_uint(body: &[u8]) -> u64 {
+    body.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+#[cfg(test)]
+pub fn read_float(body: &[u8]) -> f64 {
+    match body.len() {
+        4 => f32::from_be_bytes(body.try_into().unwrap()) as f64,
+        _ => f64::from_be_bytes(body.try_into().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        elements, find_element, read_float, read_size, read_uint, write_element, PutElements,
+        UNKNOWN_SIZE, VOID,
+    };
+
+    #[test]
+    fn sizes_use_as_few_bytes_as_they_can() {
+        for (size, len) in [
+            (0, 1),
+            (126, 1),
+            (127, 2),
+            (16382, 2),
+            (16383, 3),
+            (1 << 40, 6),
+        ] {
+            let mut data = Vec::new();
+            data.put_size(size);
+            assert_eq!(data.len(), len, "{}", size);
+            assert_eq!(read_size(&data), Some((Some(size), len)));
+        }
+        let mut data = Vec::new();
+        data.put_size_8(5);
+        assert_eq!(data, [1, 0, 0, 0, 0, 0, 0, 5]);
+        data.clear();
+        data.put_size_8(UNKNOWN_SIZE);
+        assert_eq!(read_size(&data), Some((None, 8)));
+        assert_eq!(read_size(&[0xff]), Some((None, 1)));
+        assert_eq!(read_size(&[0]), None);
+    }
+
+    #[test]
+    fn nested_elements_round_trip() {
+        let mut data = Vec::new();
+        write_element(&mut data, 0x1a45dfa3, |out| {
+            out.put_uint(0x4286, 1);
+            out.put_string(0x4282, "matroska");
+        });
+        write_element(&mut data, 0x1654ae6b, |out| {
+            write_element(out, 0xae, |out| {
+                out.put_uint(0xd7, 0x1234);
+                out.put_float(0xb5, 48000.0);
+                out.put_binary(0x63a2, &[1, 2, 3]);
+            });
+        });
+        assert_eq!(&data[..4], [0x1a, 0x45, 0xdf, 0xa3]);
+
+        let ids: Vec<_> = elements(&data).map(|found| found.id).collect();
+        assert_eq!(ids, [0x1a45dfa3, 0x1654ae6b]);
+        let doc_type = find_element(&data, &[0x1a45dfa3, 0x4282]).unwrap();
+        assert_eq!(doc_type.body, b"matroska");
+        let number = find_element(&data, &[0x1654ae6b, 0xae, 0xd7]).unwrap();
+        assert_eq!(number.body, [0x12, 0x34]);
+        assert_eq!(read_uint(number.body), 0x1234);
+        let rate = find_element(&data, &[0x1654ae6b, 0xae, 0xb5]).unwrap();
+        assert_eq!(read_float(rate.body), 48000.0);
+        let zero = {
+            let mut data = Vec::new();
+            data.put_uint(0xd7, 0);
+            data
+        };
+        assert_eq!(zero, [0xd7, 0x81, 0]);
+    }
+
+    #[test]
+    fn voids_fill_exactly() {
+        for len in [2, 3, 128, 129, 130, 1000] {
+            let mut data = Vec::new();
+            data.put_void(len);
+            assert_eq!(data.len(), len);
+            let void = elements(&data).next().unwrap();
+            assert_eq!(void.id, VOID);
+            let header_len = if len <= 128 { 2 } else { 9 };
+            assert_eq!(void.body.len(), len - header_len);
+        }
+    }
+
+    #[test]
+    fn unknown_sizes_take_the_rest() {
+        let mut data = Vec::new();
+        data.put_id(0x18538067);
+        data.put_size_8(UNKNOWN_SIZE);
+        data.put_uint(0xe7, 7);
+        // Cut off in the middle of an element.
+        data.extend_from_slice(&[0xa3, 0x85, 1, 2]);
+        let segment = elements(&data).next().unwrap();
+        assert_eq!(segment.body.len(), 7);
+        let children: Vec<_> = elements(segment.body).map(|found| found.id).collect();
+        assert_eq!(children, [0xe7]);
+    }
+}
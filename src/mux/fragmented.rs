@@ -0,0 +1,645 @@
+//! Fragmented MP4 output. An init segment describing the tracks is followed
+//! by a moof and mdat for every few seconds of media, so a file that's cut
+//! off part way through still plays up to its last complete fragment.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{Result, Sink, StreamFormat},
+};
+
+use super::{
+    bmff::{find_box, write_box, write_full_box, BoxHeader, PutBytes},
+    mp4::{
+        add_track, build_trak, find_track, put_file_type, put_movie_header, rescale, Sample, State,
+        Timing, Track,
+    },
+};
+
+/// The sample doesn't depend on any other.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// The sample depends on others and isn't a sync sample.
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// Writes a fragmented MP4 file with one track per stream.
+pub struct FragmentedMp4Writer<W> {
+    out: W,
+    tracks: Vec<Track>,
+    /// The media data of each track's pending samples, which have offsets
+    /// relative to it.
+    pending: Vec<Vec<u8>>,
+    fragment_duration: i64,
+    sequence_number: u32,
+    wrote_init: bool,
+    state: State,
+}
+
+impl<W: Write + Send> FragmentedMp4Writer<W> {
+    /// Starts a new fragment at the first keyframe after `fragment_duration`
+    /// (in 100ns units) has passed.
+    pub fn new(out: W, fragment_duration: i64) -> Self {
+        Self {
+            out,
+            tracks: Vec::new(),
+            pending: Vec::new(),
+            fragment_duration,
+            sequence_number: 1,
+            wrote_init: false,
+            state: State::Idle,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// The track fragments are cut on: video if there is any, so every
+    /// fragment starts with a keyframe.
+    fn cut_track(&self) -> usize {
+        self.tracks
+            .iter()
+            .position(|track| track.kind() == StreamKind::Video)
+            .unwrap_or(0)
+    }
+
+    fn write_init_segment(&mut self) -> Result<()> {
+        let mut traks = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            let (trak, _) = build_trak(track, index as u32 + 1, None)?;
+            traks.extend_from_slice(&trak);
+        }
+
+        let mut init = Vec::new();
+        put_file_type(&mut init, b"iso6", &self.tracks);
+        write_box(&mut init, b"moov", |out| {
+            put_movie_header(out, 0, self.tracks.len() as u32 + 1);
+            out.put_bytes(&traks);
+            write_box(out, b"mvex", |out| {
+                for track_id in 1..=self.tracks.len() as u32 {
+                    write_full_box(out, b"trex", 0, 0, |out| {
+                        out.put_u32(track_id);
+                        out.put_u32(1);
+                        out.put_zeros(12);
+                    });
+                }
+            });
+        });
+        self.out.write_all(&init)?;
+        Ok(())
+    }
+
+    /// Writes the pending samples as a fragment. `end_time` is when the cut
+    /// track's last sample ends, if the sample after it has arrived.
+    fn write_fragment(&mut self, end_time: Option<i64>) -> Result<()> {
+        if self.tracks.iter().all(|track| track.samples.is_empty()) {
+            return Ok(());
+        }
+        if !self.wrote_init {
+            self.write_init_segment()?;
+            self.wrote_init = true;
+        }
+
+        let cut_track = self.cut_track();
+        let timings: Vec<Option<Timing>> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| {
+                (!track.samples.is_empty())
+                    .then(|| Timing::new(track, end_time.filter(|_| index == cut_track)))
+            })
+            .collect();
+
+        // The data offsets depend on the size of the moof, which doesn't
+        // depend on their values.
+        let moof_len = self.build_moof(&timings, &vec![0; self.tracks.len()]).len();
+        let mdat_len: usize = self.pending.iter().map(Vec::len).sum();
+        let mdat_header_len = if mdat_len + 8 > u32::MAX as usize {
+            16
+        } else {
+            8
+        };
+        let mut data_offsets = Vec::with_capacity(self.tracks.len());
+        let mut offset = moof_len + mdat_header_len;
+        for data in &self.pending {
+            data_offsets.push(offset as u32);
+            offset += data.len();
+        }
+
+        let mut fragment = self.build_moof(&timings, &data_offsets);
+        fragment.reserve(mdat_header_len + mdat_len);
+        if mdat_header_len == 16 {
+            fragment.put_u32(1);
+            fragment.put_bytes(b"mdat");
+            fragment.put_u64((mdat_len + 16) as u64);
+        } else {
+            fragment.put_u32((mdat_len + 8) as u32);
+            fragment.put_bytes(b"mdat");
+        }
+        for data in &mut self.pending {
+            fragment.put_bytes(data);
+            data.clear();
+        }
+        for track in &mut self.tracks {
+            track.samples.clear();
+        }
+
+        // The whole fragment goes out in one write, so a crash leaves at most
+        // one incomplete fragment at the end of the file.
+        self.out.write_all(&fragment)?;
+        self.out.flush()?;
+        self.sequence_number += 1;
+        Ok(())
+    }
+
+    fn build_moof(&self, timings: &[Option<Timing>], data_offsets: &[u32]) -> Vec<u8> {
+        let mut moof = Vec::new();
+        write_box(&mut moof, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| out.put_u32(self.sequence_number));
+            for (index, (track, timing)) in self.tracks.iter().zip(timings).enumerate() {
+                let Some(timing) = timing else {
+                    continue;
+                };
+                let base_decode_time =
+                    rescale(timing.start_time, HNS_PER_SECOND, track.timescale() as i64).max(0);
+                write_box(out, b"traf", |out| {
+                    // The data offsets are relative to the moof.
+                    write_full_box(out, b"tfhd", 0, 0x020000, |out| {
+                        out.put_u32(index as u32 + 1);
+                    });
+                    write_full_box(out, b"tfdt", 1, 0, |out| {
+                        out.put_u64(base_decode_time as u64);
+                    });
+                    put_track_run(out, &track.samples, timing, data_offsets[index]);
+                });
+            }
+        });
+        moof
+    }
+}
+
+fn put_track_run(out: &mut Vec<u8>, samples: &[Sample], timing: &Timing, data_offset: u32) {
+    // Data offset, and a duration, size and flags for each sample.
+    let mut flags = 0x000001 | 0x000100 | 0x000200 | 0x000400;
+    let mut version = 0;
+    if timing.composition_offsets.is_some() {
+        flags |= 0x000800;
+        version = 1;
+    }
+    write_full_box(out, b"trun", version, flags, |out| {
+        out.put_u32(samples.len() as u32);
+        out.put_u32(data_offset);
+        for (index, sample) in samples.iter().enumerate() {
+            out.put_u32(timing.decode_deltas[index]);
+            out.put_u32(sample.size);
+            out.put_u32(if sample.keyframe {
+                SYNC_SAMPLE_FLAGS
+            } else {
+                NON_SYNC_SAMPLE_FLAGS
+            });
+            if let Some(offsets) = &timing.composition_offsets {
+                // Signed, so there's no need for an edit list to undo the shift.
+                let offset = offsets[index] as i64 - timing.composition_start;
+                out.put_u32(offset as i32 as u32);
+            }
+        }
+    });
+}
+
+impl<W: Write + Send> Sink for FragmentedMp4Writer<W> {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("Streams must be added before starting."));
+        }
+        let number = add_track(&mut self.tracks, format)?;
+        self.pending.push(Vec::new());
+        Ok(number)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("The MP4 writer was already started."));
+        }
+        self.state = State::Writing;
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other("The MP4 writer isn't accepting packets."));
+        }
+        let index = find_track(&self.tracks, &packet)?;
+        let data = self.tracks[index].sample_data(&packet)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        if index == self.cut_track() && packet.keyframe {
+            let fragment_start = self.tracks[index]
+                .samples
+                .first()
+                .map(|sample| sample.timestamp);
+            if fragment_start
+                .is_some_and(|start| packet.timestamp - start >= self.fragment_duration)
+            {
+                self.write_fragment(Some(packet.timestamp))?;
+            }
+        }
+
+        let pending = &mut self.pending[index];
+        self.tracks[index].samples.push(Sample {
+            offset: pending.len() as u64,
+            size: data.len() as u32,
+            timestamp: packet.timestamp,
+            duration: packet.duration,
+            keyframe: packet.keyframe,
+        });
+        pending.extend_from_slice(&data);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other("The MP4 writer isn't running."));
+        }
+        self.state = State::Finished;
+        self.write_fragment(None)
+    }
+}
+
+/// What `scan_fragments` found in a fragmented MP4 file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FragmentScan {
+    pub fragments: usize,
+    /// The length of the file up to the end of its last complete fragment.
+    pub complete_len: u64,
+    pub file_len: u64,
+}
+
+/// Walks the top level boxes of a fragmented MP4 file to find where the
+/// complete fragments end. Anything after that was cut off and can be
+/// dropped to repair the file.
+pub fn scan_fragments<R: Read + Seek>(input: &mut R) -> Result<FragmentScan> {
+    let file_len = input.seek(SeekFrom::End(0))?;
+    let mut position = 0;
+    match next_box(input, position, file_len)? {
+        Some((header, end)) if &header.kind == b"ftyp" => position = end,
+        _ => return Err(invalid_data("This isn't an MP4 file.")),
+    }
+    let moov_start = position;
+    let moov_end = match next_box(input, position, file_len)? {
+        Some((header, end)) if &header.kind == b"moov" => end,
+        _ => {
+            return Err(invalid_data(
+                "The file was cut off before any media was written.",
+            ))
+        }
+    };
+    let mut moov = vec![0; (moov_end - moov_start) as usize];
+    input.seek(SeekFrom::Start(moov_start))?;
+    input.read_exact(&mut moov)?;
+    if find_box(&moov, &[b"moov", b"mvex"]).is_none() {
+        return Err(invalid_data(
+            "The file isn't fragmented, so it can't be recovered.",
+        ));
+    }
+
+    let mut scan = FragmentScan {
+        fragments: 0,
+        complete_len: moov_end,
+        file_len,
+    };
+    let mut pending_moof = false;
+    position = moov_end;
+    while let Some((header, end)) = next_box(input, position, file_len)? {
+        match &header.kind {
+            b"moof" if !pending_moof => pending_moof = true,
+            b"mdat" if pending_moof => {
+                pending_moof = false;
+                scan.fragments += 1;
+                scan.complete_len = end;
+            }
+            // Boxes that can sit between fragments.
+            b"free" | b"skip" | b"styp" | b"sidx" | b"mfra" if !pending_moof => {
+                scan.complete_len = end;
+            }
+            _ => break,
+        }
+        position = end;
+    }
+    Ok(scan)
+}
+
+/// Reads the header of the box at `position`, returning it with the
+/// position the box ends at. Returns None if the box is cut off.
+fn next_box<R: Read + Seek>(
+    input: &mut R,
+    position: u64,
+    file_len: u64,
+) -> Result<Option<(BoxHeader, u64)>> {
+    let mut header = [0; 16];
+    let available = (file_len - position).min(16) as usize;
+    input.seek(SeekFrom::Start(position))?;
+    input.read_exact(&mut header[..available])?;
+    let Some(parsed) = BoxHeader::parse(&header[..available]) else {
+        return Ok(None);
+    };
+    match parsed.size {
+        Some(size) if size >= parsed.header_len as u64 && size <= file_len - position => {
+            Ok(Some((parsed, position + size)))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{scan_fragments, FragmentedMp4Writer, SYNC_SAMPLE_FLAGS};
+    use crate::{
+        mux::{
+            bmff::{boxes, find_box, ByteReader},
+            h264::test_util,
+            mp4::Mp4Writer,
+        },
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            AudioCodec, AudioStreamFormat, Sink, StreamFormat, VideoCodec, VideoStreamFormat,
+        },
+    };
+
+    const SECOND: i64 = 10_000_000;
+    const FRAGMENT: i64 = SECOND / 2;
+    const FRAME: i64 = SECOND / 30;
+    const AAC_FRAME: i64 = 1024 * SECOND / 48000;
+
+    fn formats() -> [StreamFormat; 2] {
+        [
+            StreamFormat::Video(VideoStreamFormat {
+                codec: VideoCodec::H264,
+                width: 640,
+                height: 360,
+                frame_rate: 30,
+                bit_rate: 2_000_000,
+            }),
+            StreamFormat::Audio(AudioStreamFormat {
+                codec: AudioCodec::Aac,
+                sample_rate: 48000,
+                channels: 2,
+                bit_rate: 128_000,
+                name: None,
+                language: None,
+            }),
+        ]
+    }
+
+    fn video_frame(index: usize, keyframe: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        if keyframe {
+            for unit in [test_util::sps(100, 640, 360), test_util::pps()] {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend(unit);
+            }
+        }
+        data.extend_from_slice(&[0, 0, 0, 1, if keyframe { 0x65 } else { 0x41 }]);
+        // Keep the payload free of anything that looks like a start code.
+        data.extend_from_slice(&(index as u32 | 0x8080_8080).to_be_bytes());
+        data
+    }
+
+    /// Three and a bit seconds of 30fps video with a keyframe every second,
+    /// interleaved with AAC frames.
+    fn packets() -> Vec<EncodedPacket> {
+        let mut packets: Vec<_> = (0..100)
+            .map(|index| {
+                let keyframe = index % 30 == 0;
+                EncodedPacket::new(
+                    StreamKind::Video,
+                    video_frame(index, keyframe),
+                    index as i64 * FRAME,
+                    FRAME,
+                    keyframe,
+                )
+            })
+            .collect();
+        packets.extend((0..150).map(|index| {
+            EncodedPacket::new(
+                StreamKind::Audio,
+                vec![index as u8; 6],
+                (index as i64 * 1024 * SECOND + 24000) / 48000,
+                AAC_FRAME,
+                true,
+            )
+        }));
+        packets.sort_by_key(|packet| packet.timestamp);
+        packets
+    }
+
+    fn write_file(fragment_duration: i64) -> Vec<u8> {
+        let mut writer = FragmentedMp4Writer::new(Vec::new(), fragment_duration);
+        for format in formats() {
+            writer.add_stream(format).unwrap();
+        }
+        writer.start().unwrap();
+        for packet in packets() {
+            writer.write(packet).unwrap();
+        }
+        writer.stop().unwrap();
+        writer.into_inner()
+    }
+
+    struct TrackRun {
+        track_id: u32,
+        base_decode_time: u64,
+        durations: Vec<u32>,
+        flags: Vec<u32>,
+        samples: Vec<Vec<u8>>,
+    }
+
+    /// Reads every track run back out of the file.
+    fn track_runs(file: &[u8]) -> Vec<Vec<TrackRun>> {
+        let top: Vec<_> = boxes(file).collect();
+        let mut fragments = Vec::new();
+        for (moof, mdat) in top
+            .iter()
+            .zip(top.iter().skip(1))
+            .filter(|(moof, _)| &moof.kind == b"moof")
+        {
+            assert_eq!(&mdat.kind, b"mdat");
+            let mut runs = Vec::new();
+            for traf in boxes(moof.body).filter(|found| &found.kind == b"traf") {
+                let tfhd = find_box(traf.body, &[b"tfhd"]).unwrap();
+                let tfdt = find_box(traf.body, &[b"tfdt"]).unwrap();
+                let trun = find_box(traf.body, &[b"trun"]).unwrap();
+                let mut reader = ByteReader::new(trun.body);
+                let trun_flags = reader.u32() & 0xffffff;
+                let count = reader.u32();
+                let mut offset = moof.offset + reader.u32() as usize;
+                let mut run = TrackRun {
+                    track_id: u32::from_be_bytes(tfhd.body[4..8].try_into().unwrap()),
+                    base_decode_time: ByteReader::new(&tfdt.body[4..]).u64(),
+                    durations: Vec::new(),
+                    flags: Vec::new(),
+                    samples: Vec::new(),
+                };
+                for _ in 0..count {
+                    run.durations.push(reader.u32());
+                    let size = reader.u32() as usize;
+                    run.flags.push(reader.u32());
+                    if trun_flags & 0x800 != 0 {
+                        reader.u32();
+                    }
+                    run.samples.push(file[offset..offset + size].to_vec());
+                    offset += size;
+                }
+                runs.push(run);
+            }
+            fragments.push(runs);
+        }
+        fragments
+    }
+
+    #[test]
+    fn writes_init_segment_then_fragments() {
+        let file = write_file(FRAGMENT);
+        let kinds: Vec<_> = boxes(&file).map(|found| found.kind).collect();
+        assert_eq!(&kinds[..2], [*b"ftyp", *b"moov"]);
+        assert_eq!(kinds.len(), 2 + 4 * 2);
+        assert!(kinds[2..]
+            .chunks(2)
+            .all(|pair| pair == [*b"moof", *b"mdat"]));
+
+        let mvex = find_box(&file, &[b"moov", b"mvex"]).unwrap();
+        assert_eq!(boxes(mvex.body).count(), 2);
+        let stsz = find_box(
+            &file,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsz"],
+        )
+        .unwrap();
+        assert_eq!(stsz.body, [0; 12]);
+        assert!(find_box(
+            &file,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn fragments_hold_every_sample_once() {
+        let file = write_file(FRAGMENT);
+        let fragments = track_runs(&file);
+        let expected: Vec<_> = packets();
+
+        let video: Vec<_> = fragments
+            .iter()
+            .flatten()
+            .filter(|run| run.track_id == 1)
+            .flat_map(|run| run.samples.clone())
+            .collect();
+        let expected_video: Vec<_> = expected
+            .iter()
+            .filter(|packet| packet.kind == StreamKind::Video)
+            .map(|packet| {
+                let slice = &packet.data[packet.data.len() - 5..];
+                let mut sample = (slice.len() as u32).to_be_bytes().to_vec();
+                sample.extend_from_slice(slice);
+                sample
+            })
+            .collect();
+        assert_eq!(video, expected_video);
+
+        let audio: Vec<_> = fragments
+            .iter()
+            .flatten()
+            .filter(|run| run.track_id == 2)
+            .flat_map(|run| run.samples.clone())
+            .collect();
+        let expected_audio: Vec<_> = expected
+            .iter()
+            .filter(|packet| packet.kind == StreamKind::Audio)
+            .map(|packet| packet.data.clone())
+            .collect();
+        assert_eq!(audio, expected_audio);
+    }
+
+    #[test]
+    fn video_fragments_start_on_keyframes_and_are_contiguous() {
+        let file = write_file(FRAGMENT);
+        let mut next_decode_time = 0;
+        for fragment in track_runs(&file) {
+            let video = fragment.iter().find(|run| run.track_id == 1).unwrap();
+            assert_eq!(video.flags[0], SYNC_SAMPLE_FLAGS);
+            assert!(video.flags[1..]
+                .iter()
+                .all(|&flags| flags != SYNC_SAMPLE_FLAGS));
+            assert_eq!(video.base_decode_time, next_decode_time);
+            next_decode_time += video.durations.iter().map(|&d| d as u64).sum::<u64>();
+            assert!(video.durations.iter().all(|&duration| duration == 3000));
+        }
+        assert_eq!(next_decode_time, 100 * 3000);
+    }
+
+    #[test]
+    fn scan_finds_the_last_complete_fragment() {
+        let file = write_file(FRAGMENT);
+        let scan = scan_fragments(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(scan.fragments, 4);
+        assert_eq!(scan.complete_len, file.len() as u64);
+
+        let fragment_ends: Vec<u64> = boxes(&file)
+            .filter(|found| &found.kind == b"mdat")
+            .map(|found| (found.offset + 8 + found.body.len()) as u64)
+            .collect();
+        let moov = find_box(&file, &[b"moov"]).unwrap();
+        let init_len = (moov.offset + 8 + moov.body.len()) as u64;
+        for cut in (init_len..file.len() as u64).step_by(97) {
+            let truncated = &file[..cut as usize];
+            let scan = scan_fragments(&mut Cursor::new(truncated)).unwrap();
+            let complete = fragment_ends.iter().filter(|&&end| end <= cut).count();
+            assert_eq!(scan.fragments, complete);
+            let expected_len = fragment_ends[..complete]
+                .last()
+                .copied()
+                .unwrap_or(init_len);
+            assert_eq!(scan.complete_len, expected_len);
+            assert_eq!(scan.file_len, cut);
+
+            // What's left is a complete file in its own right.
+            let repaired = &file[..scan.complete_len as usize];
+            let kinds: Vec<_> = boxes(repaired).map(|found| found.kind).collect();
+            assert_eq!(kinds.len(), 2 + complete * 2);
+        }
+    }
+
+    #[test]
+    fn scan_rejects_files_it_cannot_repair() {
+        let file = write_file(FRAGMENT);
+        let moov = find_box(&file, &[b"moov"]).unwrap();
+        assert!(scan_fragments(&mut Cursor::new(&file[..moov.offset + 20])).is_err());
+        assert!(scan_fragments(&mut Cursor::new(&file[..4])).is_err());
+
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()));
+        writer.add_stream(formats()[1].clone()).unwrap();
+        writer.start().unwrap();
+        writer
+            .write(EncodedPacket::new(
+                StreamKind::Audio,
+                vec![1, 2],
+                0,
+                AAC_FRAME,
+                true,
+            ))
+            .unwrap();
+        writer.stop().unwrap();
+        let progressive = writer.into_inner().into_inner();
+        assert!(scan_fragments(&mut Cursor::new(&progressive)).is_err());
+    }
+}
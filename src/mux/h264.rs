@@ -0,0 +1,532 @@
+//! H.264 bitstream handling: splitting access units into NAL units, reading
+//! and writing bits, reading the sequence parameter set and building the
+//! avcC configuration record.
+
+use std::io;
+
+use super::bmff::PutBytes;
+
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map(|header| header & 0x1f).unwrap_or(0)
+}
+
+/// Whether the access unit uses start codes rather than length prefixes.
+pub fn is_annex_b(data: &[u8]) -> bool {
+    data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1])
+}
+
+/// Splits an Annex B access unit on its start codes.
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 2 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                units.push(trim_trailing_zeros(&data[start..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(trim_trailing_zeros(&data[start..]));
+    }
+    units.retain(|unit| !unit.is_empty());
+    units
+}
+
+fn trim_trailing_zeros(mut unit: &[u8]) -> &[u8] {
+    while let [rest @ .., 0] = unit {
+        unit = rest;
+    }
+    unit
+}
+
+/// Splits an access unit made of 4-byte big endian length prefixes.
+pub fn split_avcc(mut data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut units = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(invalid_data("Truncated NAL unit length."));
+        }
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        if data.len() - 4 < length {
+            return Err(invalid_data("NAL unit runs past the end of the sample."));
+        }
+        units.push(&data[4..4 + length]);
+        data = &data[4 + length..];
+    }
+    Ok(units)
+}
+
+/// Splits an access unit in either format.
+pub fn split_nal_units(data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    if is_annex_b(data) {
+        Ok(split_annex_b(data))
+    } else {
+        split_avcc(data)
+    }
+}
+
+/// Removes emulation prevention bytes, giving the raw payload of a NAL unit.
+pub fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Reads bits most significant first, including the exp-Golomb codes used
+/// throughout the parameter sets.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn bit(&mut self) -> io::Result<bool> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or_else(|| invalid_data("Ran out of bits while parsing."))?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    pub fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    pub fn ue(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(invalid_data("Exp-Golomb code is too long."));
+            }
+        }
+        Ok(((1u64 << leading_zeros) - 1 + self.bits(leading_zeros)? as u64) as u32)
+    }
+
+    pub fn se(&mut self) -> io::Result<i32> {
+        let value = self.ue()? as i64;
+        Ok(if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            -(value / 2) as i32
+        })
+    }
+
+    /// Skips to the next byte boundary.
+    #[cfg(test)]
+    pub fn align(&mut self) {
+        self.position = self.position.next_multiple_of(8);
+    }
+
+    #[cfg(test)]
+    pub fn bits_left(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+}
+
+/// The fields of a sequence parameter set the container cares about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Sps {
+    /// Parses a complete SPS NAL unit, header byte included.
+    pub fn parse(nal: &[u8]) -> io::Result<Self> {
+        if nal_type(nal) != NAL_SPS {
+            return Err(invalid_data("Not a sequence parameter set."));
+        }
+        let rbsp = unescape_rbsp(&nal[1..]);
+        let mut reader = BitReader::new(&rbsp);
+        let profile_idc = reader.bits(8)? as u8;
+        let constraint_flags = reader.bits(8)? as u8;
+        let level_idc = reader.bits(8)? as u8;
+        let _seq_parameter_set_id = reader.ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if has_chroma_info(profile_idc) {
+            chroma_format_idc = reader.ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.bit()?;
+            }
+            bit_depth_luma = reader.ue()? + 8;
+            bit_depth_chroma = reader.ue()? + 8;
+            let _qpprime_y_zero_transform_bypass = reader.bit()?;
+            if reader.bit()? {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..lists {
+                    if reader.bit()? {
+                        skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let _log2_max_frame_num = reader.ue()?;
+        match reader.ue()? {
+            0 => {
+                let _log2_max_pic_order_cnt_lsb = reader.ue()?;
+            }
+            1 => {
+                let _delta_pic_order_always_zero = reader.bit()?;
+                let _offset_for_non_ref_pic = reader.se()?;
+                let _offset_for_top_to_bottom_field = reader.se()?;
+                for _ in 0..reader.ue()? {
+                    let _offset_for_ref_frame = reader.se()?;
+                }
+            }
+            _ => {}
+        }
+        let _max_num_ref_frames = reader.ue()?;
+        let _gaps_in_frame_num_allowed = reader.bit()?;
+        let width_in_mbs = reader.ue()? + 1;
+        let height_in_map_units = reader.ue()? + 1;
+        let frame_mbs_only = reader.bit()?;
+        if !frame_mbs_only {
+            let _mb_adaptive_frame_field = reader.bit()?;
+        }
+        let _direct_8x8_inference = reader.bit()?;
+
+        let field_factor = if frame_mbs_only { 1 } else { 2 };
+        let mut width = width_in_mbs * 16;
+        let mut height = height_in_map_units * 16 * field_factor;
+        if reader.bit()? {
+            let (crop_unit_x, crop_unit_y) = if separate_colour_plane || chroma_format_idc == 0 {
+                (1, field_factor)
+            } else {
+                let sub_width = if chroma_format_idc == 3 { 1 } else { 2 };
+                let sub_height = if chroma_format_idc == 1 { 2 } else { 1 };
+                (sub_width, sub_height * field_factor)
+            };
+            let left = reader.ue()?;
+            let right = reader.ue()?;
+            let top = reader.ue()?;
+            let bottom = reader.ue()?;
+            width = width
+                .checked_sub(crop_unit_x * (left + right))
+                .ok_or_else(|| invalid_data("Cropping is larger than the picture."))?;
+            height = height
+                .checked_sub(crop_unit_y * (top + bottom))
+                .ok_or_else(|| invalid_data("Cropping is larger than the picture."))?;
+        }
+
+        Ok(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            width,
+            height,
+        })
+    }
+}
+
+fn has_chroma_info(profile_idc: u8) -> bool {
+    matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    )
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> io::Result<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta = reader.se()?;
+            next_scale = (last_scale + delta + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the body of an avcC box from the stream's parameter sets.
+pub fn avc_decoder_configuration(sps: &[Vec<u8>], pps: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let first = sps
+        .first()
+        .ok_or_else(|| invalid_data("No sequence parameter set was found in the stream."))?;
+    if pps.is_empty() {
+        return Err(invalid_data(
+            "No picture parameter set was found in the stream.",
+        ));
+    }
+    let parsed = Sps::parse(first)?;
+
+    let mut out = Vec::new();
+    out.put_u8(1);
+    out.put_u8(parsed.profile_idc);
+    out.put_u8(parsed.constraint_flags);
+    out.put_u8(parsed.level_idc);
+    // Four byte NAL unit lengths.
+    out.put_u8(0xfc | 3);
+    out.put_u8(0xe0 | sps.len() as u8);
+    for unit in sps {
+        out.put_u16(unit.len() as u16);
+        out.put_bytes(unit);
+    }
+    out.put_u8(pps.len() as u8);
+    for unit in pps {
+        out.put_u16(unit.len() as u16);
+        out.put_bytes(unit);
+    }
+    if has_chroma_info(parsed.profile_idc) {
+        out.put_u8(0xfc | parsed.chroma_format_idc as u8);
+        out.put_u8(0xf8 | (parsed.bit_depth_luma - 8) as u8);
+        out.put_u8(0xf8 | (parsed.bit_depth_chroma - 8) as u8);
+        out.put_u8(0);
+    }
+    Ok(out)
+}
+
+/// Writes bits most significant first, the counterpart of `BitReader`.
+#[derive(Default)]
+pub struct BitWriter {
+    pub data: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn bit(&mut self, bit: bool) {
+        if self.bits.is_multiple_of(8) {
+            self.data.push(0);
+        }
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    pub fn bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.bit((value >> i) & 1 == 1);
+        }
+    }
+
+    pub fn ue(&mut self, value: u32) {
+        let coded = value as u64 + 1;
+        let length = 64 - coded.leading_zeros();
+        self.bits(0, length - 1);
+        self.bits(coded as u32, length);
+    }
+
+    /// Pads with zeros to a byte boundary.
+    pub fn align(&mut self) {
+        self.bits = self.bits.next_multiple_of(8);
+    }
+
+    /// Writes whole bytes, which have to start on a byte boundary.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        assert!(self.bits.is_multiple_of(8), "Bytes have to be aligned");
+        self.data.extend_from_slice(bytes);
+        self.bits += bytes.len() * 8;
+    }
+
+    /// Adds the stop bit and pads to a byte boundary.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.bit(true);
+        self.data
+    }
+}
+
+/// Inserts emulation prevention bytes.
+pub fn escape_rbsp(rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rbsp.len());
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Builds parameter sets for tests, without a real encoder around.
+#[cfg(test)]
+pub mod test_util {
+    pub use super::{escape_rbsp, BitWriter};
+
+    /// An SPS for a progressive 4:2:0 stream of the given size.
+    pub fn sps(profile_idc: u8, width: u32, height: u32) -> Vec<u8> {
+        let width_in_mbs = width.div_ceil(16);
+        let height_in_mbs = height.div_ceil(16);
+        let mut writer = BitWriter::default();
+        writer.bits(profile_idc as u32, 8);
+        writer.bits(0, 8);
+        writer.bits(40, 8);
+        writer.ue(0);
+        if super::has_chroma_info(profile_idc) {
+            writer.ue(1);
+            writer.ue(0);
+            writer.ue(0);
+            writer.bit(false);
+            writer.bit(false);
+        }
+        writer.ue(0);
+        writer.ue(2);
+        writer.ue(1);
+        writer.bit(false);
+        writer.ue(width_in_mbs - 1);
+        writer.ue(height_in_mbs - 1);
+        writer.bit(true);
+        writer.bit(true);
+        let crop_right = width_in_mbs * 16 - width;
+        let crop_bottom = height_in_mbs * 16 - height;
+        writer.bit(crop_right != 0 || crop_bottom != 0);
+        if crop_right != 0 || crop_bottom != 0 {
+            writer.ue(0);
+            writer.ue(crop_right / 2);
+            writer.ue(0);
+            writer.ue(crop_bottom / 2);
+        }
+        writer.bit(false);
+        let mut nal = vec![0x67];
+        nal.extend(escape_rbsp(&writer.finish()));
+        nal
+    }
+
+    pub fn pps() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.ue(0);
+        writer.ue(0);
+        writer.bit(false);
+        writer.bit(false);
+        writer.ue(0);
+        let mut nal = vec![0x68];
+        nal.extend(escape_rbsp(&writer.finish()));
+        nal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        avc_decoder_configuration, split_annex_b, split_avcc, split_nal_units, test_util,
+        unescape_rbsp, BitReader, Sps, NAL_SPS,
+    };
+
+    #[test]
+    fn splits_annex_b_with_mixed_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4, 5,
+        ];
+        let units = split_annex_b(&data);
+        assert_eq!(units, [&[0x67, 1, 2][..], &[0x68, 3], &[0x65, 4, 5]]);
+        assert_eq!(split_nal_units(&data).unwrap(), units);
+    }
+
+    #[test]
+    fn splits_length_prefixed_units() {
+        let data = [0, 0, 0, 2, 0x41, 9, 0, 0, 0, 1, 0x06];
+        assert_eq!(split_nal_units(&data).unwrap(), [&[0x41, 9][..], &[0x06]]);
+        assert!(split_avcc(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn removes_emulation_prevention() {
+        assert_eq!(
+            unescape_rbsp(&[0, 0, 3, 1, 0, 0, 3, 0, 3]),
+            [0, 0, 1, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn reads_exp_golomb_codes() {
+        // 1, 010, 011, 00100, 00101
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_0000];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.ue().unwrap(), 0);
+        assert_eq!(reader.ue().unwrap(), 1);
+        assert_eq!(reader.ue().unwrap(), 2);
+        assert_eq!(reader.se().unwrap(), 2);
+        assert_eq!(reader.se().unwrap(), -2);
+    }
+
+    #[test]
+    fn parses_cropped_high_profile_sps() {
+        let nal = test_util::sps(100, 1920, 1080);
+        let sps = Sps::parse(&nal).unwrap();
+        assert_eq!(sps.profile_idc, 100);
+        assert_eq!(sps.level_idc, 40);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+    }
+
+    #[test]
+    fn parses_baseline_sps() {
+        let sps = Sps::parse(&test_util::sps(66, 1280, 720)).unwrap();
+        assert_eq!(sps.profile_idc, 66);
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert!(Sps::parse(&test_util::pps()).is_err());
+    }
+
+    #[test]
+    fn builds_avcc_with_high_profile_extension() {
+        let sps = test_util::sps(100, 640, 360);
+        let pps = test_util::pps();
+        let config =
+            avc_decoder_configuration(std::slice::from_ref(&sps), std::slice::from_ref(&pps))
+                .unwrap();
+        assert_eq!(&config[..4], &[1, 100, 0, 40]);
+        assert_eq!(config[4], 0xff);
+        assert_eq!(config[5], 0xe1);
+        let sps_len = u16::from_be_bytes([config[6], config[7]]) as usize;
+        assert_eq!(&config[8..8 + sps_len], &sps[..]);
+        assert_eq!(config[8] & 0x1f, NAL_SPS);
+        let rest = &config[8 + sps_len..];
+        assert_eq!(rest[0], 1);
+        assert_eq!(&rest[3..3 + pps.len()], &pps[..]);
+        assert_eq!(&rest[3 + pps.len()..], &[0xfd, 0xf8, 0xf8, 0]);
+
+        assert!(avc_decoder_configuration(&[], &[pps]).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! HEVC bitstream handling: reading the sequence parameter set and building
+//! the hvcC configuration record. Access units are split into NAL units the
+//! same way as H.264 ones, only the NAL unit header differs.
+
+use std::io;
+
+use super::{
+    bmff::PutBytes,
+    h264::{unescape_rbsp, BitReader},
+};
+
+pub const NAL_VPS: u8 = 32;
+pub const NAL_SPS: u8 = 33;
+pub const NAL_PPS: u8 = 34;
+pub const NAL_AUD: u8 = 35;
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map(|header| (header >> 1) & 0x3f).unwrap_or(0)
+}
+
+/// The fields of a sequence parameter set the container cares about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sps {
+    pub max_sub_layers: u8,
+    pub temporal_id_nesting: bool,
+    pub profile_space: u8,
+    pub tier: u8,
+    pub profile_idc: u8,
+    pub profile_compatibility_flags: u32,
+    /// The 48 bits of general constraint flags.
+    pub constraint_flags: u64,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Sps {
+    /// Parses a complete SPS NAL unit, header included.
+    pub fn parse(nal: &[u8]) -> io::Result<Self> {
+        if nal_type(nal) != NAL_SPS || nal.len() < 2 {
+            return Err(invalid_data("Not an HEVC sequence parameter set."));
+        }
+        let rbsp = unescape_rbsp(&nal[2..]);
+        let mut reader = BitReader::new(&rbsp);
+        // sps_video_parameter_set_id
+        reader.bits(4)?;
+        let max_sub_layers_minus1 = reader.bits(3)? as usize;
+        let temporal_id_nesting = reader.bit()?;
+
+        // profile_tier_level
+        let profile_space = reader.bits(2)? as u8;
+        let tier = reader.bit()? as u8;
+        let profile_idc = reader.bits(5)? as u8;
+        let profile_compatibility_flags = reader.bits(32)?;
+        let constraint_flags = ((reader.bits(32)? as u64) << 16) | reader.bits(16)? as u64;
+        let level_idc = reader.bits(8)? as u8;
+        let mut sub_layers = Vec::with_capacity(max_sub_layers_minus1);
+        for _ in 0..max_sub_layers_minus1 {
+            sub_layers.push((reader.bit()?, reader.bit()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            for _ in max_sub_layers_minus1..8 {
+                reader.bits(2)?;
+            }
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                reader.bits(32)?;
+                reader.bits(32)?;
+                reader.bits(24)?;
+            }
+            if level_present {
+                reader.bits(8)?;
+            }
+        }
+
+        // sps_seq_parameter_set_id
+        reader.ue()?;
+        let chroma_format_idc = reader.ue()?;
+        let separate_colour_plane = chroma_format_idc == 3 && reader.bit()?;
+        let mut width = reader.ue()?;
+        let mut height = reader.ue()?;
+        if reader.bit()? {
+            let (sub_width, sub_height) = match chroma_format_idc {
+                _ if separate_colour_plane => (1, 1),
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let left = reader.ue()?;
+            let right = reader.ue()?;
+            let top = reader.ue()?;
+            let bottom = reader.ue()?;
+            width = width
+                .checked_sub(sub_width * (left + right))
+                .ok_or_else(|| invalid_data("Cropping is larger than the picture."))?;
+            height = height
+                .checked_sub(sub_height * (top + bottom))
+                .ok_or_else(|| invalid_data("Cropping is larger than the picture."))?;
+        }
+        let bit_depth_luma = reader.ue()? + 8;
+        let bit_depth_chroma = reader.ue()? + 8;
+
+        Ok(Self {
+            max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+            temporal_id_nesting,
+            profile_space,
+            tier,
+            profile_idc,
+            profile_compatibility_flags,
+            constraint_flags,
+            level_idc,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            width,
+            height,
+        })
+    }
+}
+
+/// Builds the body of an hvcC box from the stream's parameter sets.
+pub fn hevc_decoder_configuration(
+    vps: &[Vec<u8>],
+    sps: &[Vec<u8>],
+    pps: &[Vec<u8>],
+) -> io::Result<Vec<u8>> {
+    let first = sps
+        .first()
+        .ok_or_else(|| invalid_data("No sequence parameter set was found in the stream."))?;
+    if vps.is_empty() {
+        return Err(invalid_data(
+            "No video parameter set was found in the stream.",
+        ));
+    }
+    if pps.is_empty() {
+        return Err(invalid_data(
+            "No picture parameter set was found in the stream.",
+        ));
+    }
+    let parsed = Sps::parse(first)?;
+
+    let mut out = Vec::new();
+    out.put_u8(1);
+    out.put_u8((parsed.profile_space << 6) | (parsed.tier << 5) | parsed.profile_idc);
+    out.put_u32(parsed.profile_compatibility_flags);
+    out.put_u16((parsed.constraint_flags >> 32) as u16);
+    out.put_u32(parsed.constraint_flags as u32);
+    out.put_u8(parsed.level_idc);
+    // No min_spatial_segmentation_idc or parallelismType.
+    out.put_u16(0xf000);
+    out.put_u8(0xfc);
+    out.put_u8(0xfc | parsed.chroma_format_idc as u8);
+    out.put_u8(0xf8 | (parsed.bit_depth_luma - 8) as u8);
+    out.put_u8(0xf8 | (parsed.bit_depth_chroma - 8) as u8);
+    // No avgFrameRate or constantFrameRate, and four byte NAL unit lengths.
+    out.put_u16(0);
+    out.put_u8((parsed.max_sub_layers << 3) | ((parsed.temporal_id_nesting as u8) << 2) | 3);
+    out.put_u8(3);
+    for (nal_type, units) in [(NAL_VPS, vps), (NAL_SPS, sps), (NAL_PPS, pps)] {
+        // Every parameter set is here rather than in the samples.
+        out.put_u8(0x80 | nal_type);
+        out.put_u16(units.len() as u16);
+        for unit in units {
+            out.put_u16(unit.len() as u16);
+            out.put_bytes(unit);
+        }
+    }
+    Ok(out)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Builds parameter sets for tests, without a real encoder around.
+#[cfg(test)]
+pub mod test_util {
+    use crate::mux::h264::{escape_rbsp, BitWriter};
+
+    fn nal_unit(nal_type: u8, writer: BitWriter) -> Vec<u8> {
+        let mut nal = vec![nal_type << 1, 1];
+        nal.extend(escape_rbsp(&writer.finish()));
+        nal
+    }
+
+    fn profile_tier_level(writer: &mut BitWriter, profile_idc: u32, sub_layers: u32) {
+        writer.bits(0, 2);
+        writer.bit(false);
+        writer.bits(profile_idc, 5);
+        writer.bits(0x6000_0000 >> (profile_idc - 1), 32);
+        // progressive_source_flag and frame_only_constraint_flag
+        writer.bits(0b1001, 4);
+        writer.bits(0, 32);
+        writer.bits(0, 12);
+        writer.bits(123, 8);
+        for _ in 1..sub_layers {
+            // Only sub_layer_level_present_flag
+            writer.bits(0b01, 2);
+        }
+        if sub_layers > 1 {
+            for _ in sub_layers - 1..8 {
+                writer.bits(0, 2);
+            }
+        }
+        for _ in 1..sub_layers {
+            writer.bits(90, 8);
+        }
+    }
+
+    /// A VPS the SPS can point at. Nothing reads it but decoders.
+    pub fn vps() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.bits(0, 4);
+        writer.bits(0b11, 2);
+        writer.bits(0, 6);
+        writer.bits(0, 3);
+        writer.bit(true);
+        writer.bits(0xffff, 16);
+        profile_tier_level(&mut writer, 1, 1);
+        nal_unit(super::NAL_VPS, writer)
+    }
+
+    /// An SPS for a 4:2:0 stream of the given size, coded in 16x16 blocks.
+    pub fn sps(profile_idc: u32, width: u32, height: u32, sub_layers: u32) -> Vec<u8> {
+        let coded_width = width.next_multiple_of(16);
+        let coded_height = height.next_multiple_of(16);
+        let mut writer = BitWriter::default();
+        writer.bits(0, 4);
+        writer.bits(sub_layers - 1, 3);
+        writer.bit(true);
+        profile_tier_level(&mut writer, profile_idc, sub_layers);
+        writer.ue(0);
+        writer.ue(1);
+        writer.ue(coded_width);
+        writer.ue(coded_height);
+        let cropped = coded_width != width || coded_height != height;
+        writer.bit(cropped);
+        if cropped {
+            writer.ue(0);
+            writer.ue((coded_width - width) / 2);
+            writer.ue(0);
+            writer.ue((coded_height - height) / 2);
+        }
+        // Main 10 is 10 bits, anything else 8
+        let bit_depth = if profile_idc == 2 { 2 } else { 0 };
+        writer.ue(bit_depth);
+        writer.ue(bit_depth);
+        nal_unit(super::NAL_SPS, writer)
+    }
+
+    pub fn pps() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.ue(0);
+        writer.ue(0);
+        writer.bits(0, 7);
+        nal_unit(super::NAL_PPS, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hevc_decoder_configuration, nal_type, test_util, Sps, NAL_PPS, NAL_SPS, NAL_VPS};
+    use crate::mux::h264::split_annex_b;
+
+    #[test]
+    fn reads_two_byte_nal_headers() {
+        let data = [
+            0, 0, 0, 1, 0x40, 1, 0xc, 0, 0, 1, 0x42, 1, 1, 0, 0, 1, 0x26, 1, 0xaf,
+        ];
+        let types: Vec<u8> = split_annex_b(&data).into_iter().map(nal_type).collect();
+        assert_eq!(types, [NAL_VPS, NAL_SPS, 19]);
+        assert_eq!(nal_type(&test_util::pps()), NAL_PPS);
+    }
+
+    #[test]
+    fn parses_cropped_main_sps() {
+        let sps = Sps::parse(&test_util::sps(1, 1920, 1080, 1)).unwrap();
+        assert_eq!((sps.profile_space, sps.tier, sps.profile_idc), (0, 0, 1));
+        assert_eq!(sps.profile_compatibility_flags, 0x6000_0000);
+        assert_eq!(sps.constraint_flags, 0x9000_0000_0000);
+        assert_eq!(sps.level_idc, 123);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (8, 8));
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.max_sub_layers, sps.temporal_id_nesting), (1, true));
+    }
+
+    #[test]
+    fn parses_main_10_sps_with_sub_layers() {
+        let sps = Sps::parse(&test_util::sps(2, 3840, 2160, 3)).unwrap();
+        assert_eq!(sps.profile_idc, 2);
+        assert_eq!(sps.profile_compatibility_flags, 0x3000_0000);
+        assert_eq!(sps.max_sub_layers, 3);
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (10, 10));
+        assert_eq!((sps.width, sps.height), (3840, 2160));
+        assert!(Sps::parse(&test_util::pps()).is_err());
+        assert!(Sps::parse(&test_util::vps()).is_err());
+    }
+
+    #[test]
+    fn builds_hvcc_with_every_parameter_set() {
+        let vps = test_util::vps();
+        let sps = test_util::sps(2, 1280, 720, 2);
+        let pps = test_util::pps();
+        let config = hevc_decoder_configuration(
+            std::slice::from_ref(&vps),
+            std::slice::from_ref(&sps),
+            std::slice::from_ref(&pps),
+        )
+        .unwrap();
+        assert_eq!(&config[..6], &[1, 2, 0x30, 0, 0, 0]);
+        assert_eq!(&config[6..12], &[0x90, 0, 0, 0, 0, 0]);
+        assert_eq!(config[12], 123);
+        assert_eq!(
+            &config[13..22],
+            &[0xf0, 0, 0xfc, 0xfd, 0xfa, 0xfa, 0, 0, 0x17]
+        );
+        assert_eq!(config[22], 3);
+        let mut rest = &config[23..];
+        for (nal_type, unit) in [(NAL_VPS, &vps), (NAL_SPS, &sps), (NAL_PPS, &pps)] {
+            assert_eq!(rest[0], 0x80 | nal_type);
+            assert_eq!(u16::from_be_bytes([rest[1], rest[2]]), 1);
+            let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            assert_eq!(&rest[5..5 + length], &unit[..]);
+            rest = &rest[5 + length..];
+        }
+        assert!(rest.is_empty());
+
+        assert!(hevc_decoder_configuration(
+            &[],
+            std::slice::from_ref(&sps),
+            std::slice::from_ref(&pps)
+        )
+        .is_err());
+        assert!(hevc_decoder_configuration(&[vps], &[sps], &[]).is_err());
+    }
+}
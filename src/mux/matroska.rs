@@ -0,0 +1,1197 @@
+//! Matroska and WebM output. Everything is written as it arrives: the
+//! headers first, then clusters of blocks, each given its size once the next
+//! one starts. A file that's cut off still plays up to where it ends, as the
+//! segment and the last cluster are left with an unknown size. Cue points
+//! are added to the cues as each cluster is finished, in space kept for
+//! them after the tracks, and the seek head is kept pointing at them, so a
+//! file that's cut off can still be seeked up to its last cluster. The
+//! chapters go in when the writer stops.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{flac, AudioCodec, Result, Sink, StreamFormat, VideoCodec},
+};
+
+use super::{
+    aac::AudioSpecificConfig,
+    ebml::{write_element, PutElements, UNKNOWN_SIZE, VOID},
+    mp4::{add_track, decoder_configuration, display_size, find_track, State, Track},
+    opus,
+};
+
+const EBML: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114d9b74;
+const SEEK: u32 = 0x4dbb;
+const SEEK_ID: u32 = 0x53ab;
+const SEEK_POSITION: u32 = 0x53ac;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_DEFAULT: u32 = 0x88;
+const FLAG_LACING: u32 = 0x9c;
+const NAME: u32 = 0x536e;
+const LANGUAGE: u32 = 0x22b59c;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const CODEC_DELAY: u32 = 0x56aa;
+const SEEK_PRE_ROLL: u32 = 0x56bb;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const AUDIO: u32 = 0xe1;
+const SAMPLING_FREQUENCY: u32 = 0xb5;
+const CHANNELS: u32 = 0x9f;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const CUES: u32 = 0x1c53bb6b;
+const CUE_POINT: u32 = 0xbb;
+const CUE_TIME: u32 = 0xb3;
+const CUE_TRACK_POSITIONS: u32 = 0xb7;
+const CUE_TRACK: u32 = 0xf7;
+const CUE_CLUSTER_POSITION: u32 = 0xf1;
+const CHAPTERS: u32 = 0x1043a770;
+const EDITION_ENTRY: u32 = 0x45b9;
+const EDITION_UID: u32 = 0x45bc;
+const CHAPTER_ATOM: u32 = 0xb6;
+const CHAPTER_UID: u32 = 0x73c4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const CHAP_LANGUAGE: u32 = 0x437c;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const UNDETERMINED_LANGUAGE: &str = "und";
+const APP: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Block timestamps are in milliseconds.
+const TICKS_PER_TIMESTAMP: i64 = HNS_PER_SECOND / 1000;
+const NANOSECONDS_PER_TIMESTAMP: u64 = 1_000_000;
+/// Recordings without video start a cluster this often, in milliseconds.
+const AUDIO_CLUSTER_DURATION: i64 = 5000;
+/// Room kept at the start of the segment for the seek head.
+const SEEK_HEAD_LEN: usize = 128;
+/// Room kept after the tracks for the cues. When they outgrow it they move
+/// to the end of the file, into twice as much.
+const CUE_SPACE_LEN: u64 = 4096;
+/// The cues' ID and 8 byte size.
+const CUES_HEADER_LEN: u64 = 12;
+/// How far back an Opus decoder has to start to be right by a given time.
+const OPUS_SEEK_PRE_ROLL: u64 = 80_000_000;
+
+/// Which flavour of Matroska to write.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DocType {
+    Matroska,
+    /// The subset browsers play, which only allows some codecs.
+    WebM,
+}
+
+impl DocType {
+    fn name(self) -> &'static str {
+        match self {
+            DocType::Matroska => "matroska",
+            DocType::WebM => "webm",
+        }
+    }
+}
+
+/// A sample on its way into a cluster.
+struct Block {
+    track: usize,
+    data: Vec<u8>,
+    timestamp: i64,
+    duration: i64,
+    keyframe: bool,
+}
+
+struct Cluster {
+    /// Where its size goes in the file.
+    size_offset: u64,
+    /// In milliseconds, which the blocks are relative to.
+    timestamp: i64,
+}
+
+struct CuePoint {
+    /// In milliseconds.
+    time: u64,
+    track: u64,
+    /// From the start of the segment body.
+    cluster_position: u64,
+}
+
+/// Space in the file the cues are written into, with a void after them
+/// filling the rest.
+#[derive(Copy, Clone)]
+struct CueSpace {
+    offset: u64,
+    len: u64,
+    /// How much of it the cues take, or 0 if it's all still void.
+    used: u64,
+}
+
+/// Writes a Matroska or WebM file with one track per stream.
+pub struct MatroskaWriter<W> {
+    out: W,
+    doc_type: DocType,
+    tracks: Vec<Track>,
+    /// The track number each track was written with, once the tracks have
+    /// been. A video track that never got a sample isn't written.
+    numbers: Option<Vec<Option<u64>>>,
+    /// Blocks that came before the tracks could be written, which needs a
+    /// sample from every video track for its parameter sets.
+    pending: Vec<Block>,
+    cluster: Option<Cluster>,
+    cues: Vec<CuePoint>,
+    /// How many of the cue points are in the file.
+    written_cues: usize,
+    cue_space: CueSpace,
+    chapters: Vec<(i64, String)>,
+    /// Where the segment's size is, and where its body starts. Positions in
+    /// the seek head and cues are from there.
+    segment_size_offset: u64,
+    segment_start: u64,
+    duration_offset: u64,
+    /// The top level elements the seek head points at, by ID and position.
+    seek_entries: Vec<(u32, u64)>,
+    end_time: i64,
+    position: u64,
+    state: State,
+}
+
+impl<W: Write + Seek + Send> MatroskaWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self::with_doc_type(out, DocType::Matroska)
+    }
+
+    pub fn webm(out: W) -> Self {
+        Self::with_doc_type(out, DocType::WebM)
+    }
+
+    fn with_doc_type(out: W, doc_type: DocType) -> Self {
+        Self {
+            out,
+            doc_type,
+            tracks: Vec::new(),
+            numbers: None,
+            pending: Vec::new(),
+            cluster: None,
+            cues: Vec::new(),
+            written_cues: 0,
+            cue_space: CueSpace {
+                offset: 0,
+                len: 0,
+                used: 0,
+            },
+            chapters: Vec::new(),
+            segment_size_offset: 0,
+            segment_start: 0,
+            duration_offset: 0,
+            seek_entries: Vec::new(),
+            end_time: 0,
+            position: 0,
+            state: State::Idle,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Writes at the end of what's been written so far.
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.out.seek(SeekFrom::Start(self.position))?;
+        self.out.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes over something written earlier.
+    fn patch(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.out.seek(SeekFrom::Start(offset))?;
+        self.out.write_all(data)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = Vec::new();
+        write_element(&mut header, EBML, |out| {
+            out.put_uint(EBML_VERSION, 1);
+            out.put_uint(EBML_READ_VERSION, 1);
+            out.put_uint(EBML_MAX_ID_LENGTH, 4);
+            out.put_uint(EBML_MAX_SIZE_LENGTH, 8);
+            out.put_string(DOC_TYPE, self.doc_type.name());
+            // Version 4 for the codec delay, and 2 to read simple blocks.
+            out.put_uint(DOC_TYPE_VERSION, 4);
+            out.put_uint(DOC_TYPE_READ_VERSION, 2);
+        });
+        header.put_id(SEGMENT);
+        self.segment_size_offset = header.len() as u64;
+        header.put_size_8(UNKNOWN_SIZE);
+        self.segment_start = header.len() as u64;
+        header.put_void(SEEK_HEAD_LEN);
+
+        self.seek_entries
+            .push((INFO, header.len() as u64 - self.segment_start));
+        write_element(&mut header, INFO, |out| {
+            out.put_uint(TIMESTAMP_SCALE, NANOSECONDS_PER_TIMESTAMP);
+            out.put_string(MUXING_APP, APP);
+            out.put_string(WRITING_APP, APP);
+            // Filled in when we finish.
+            out.put_float(DURATION, 0.0);
+        });
+        self.duration_offset = header.len() as u64 - 8;
+        self.append(&header)
+    }
+
+    /// Writes the tracks, leaving out any video track that hasn't had a
+    /// sample, and then the blocks that were waiting on them.
+    fn write_tracks(&mut self) -> Result<()> {
+        let mut numbers = Vec::new();
+        let mut entries = Vec::new();
+        let mut next_number = 1;
+        for (index, track) in self.tracks.iter().enumerate() {
+            let has_samples = self.pending.iter().any(|block| block.track == index);
+            if track.kind() == StreamKind::Video && !has_samples {
+                numbers.push(None);
+                continue;
+            }
+            // Only the first track of each kind plays unless another is
+            // picked, like the alternate groups of an MP4.
+            let is_default = self.tracks[..index]
+                .iter()
+                .all(|other| other.kind() != track.kind());
+            put_track_entry(&mut entries, track, next_number, is_default)?;
+            numbers.push(Some(next_number));
+            next_number += 1;
+        }
+        let mut tracks = Vec::new();
+        tracks.put_id(TRACKS);
+        tracks.put_size(entries.len() as u64);
+        tracks.extend_from_slice(&entries);
+        self.seek_entries
+            .push((TRACKS, self.position - self.segment_start));
+        self.append(&tracks)?;
+        self.numbers = Some(numbers);
+
+        let mut cue_space = Vec::new();
+        cue_space.put_void(CUE_SPACE_LEN as usize);
+        self.cue_space = CueSpace {
+            offset: self.position,
+            len: CUE_SPACE_LEN,
+            used: 0,
+        };
+        self.append(&cue_space)?;
+        self.write_seek_head()?;
+
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_by_key(|block| block.timestamp);
+        for block in pending {
+            self.write_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// The track clusters are started on: the first video track, or the
+    /// first audio track if there isn't one.
+    fn cut_track(&self) -> usize {
+        self.tracks
+            .iter()
+            .position(|track| track.kind() == StreamKind::Video)
+            .unwrap_or(0)
+    }
+
+    fn write_block(&mut self, block: Block) -> Result<()> {
+        let Some(number) = self
+            .numbers
+            .as_ref()
+            .and_then(|numbers| numbers[block.track])
+        else {
+            return Ok(());
+        };
+        let time = block.timestamp.div_euclid(TICKS_PER_TIMESTAMP);
+        let is_cut = block.keyframe && block.track == self.cut_track();
+        let has_video = self.tracks[self.cut_track()].kind() == StreamKind::Video;
+        let starts_cluster = match &self.cluster {
+            None => true,
+            Some(cluster) => {
+                let offset = time - cluster.timestamp;
+                // Block timestamps are 16-bit offsets from the cluster's.
+                !(i16::MIN as i64..=i16::MAX as i64).contains(&offset)
+                    || (is_cut && (has_video || offset >= AUDIO_CLUSTER_DURATION))
+            }
+        };
+        if starts_cluster {
+            self.start_cluster(time)?;
+            if is_cut {
+                self.cues.push(CuePoint {
+                    time: time.max(0) as u64,
+                    track: number,
+                    cluster_position: self.cluster.as_ref().unwrap().size_offset
+                        - 4
+                        - self.segment_start,
+                });
+            }
+        }
+
+        let offset = time - self.cluster.as_ref().unwrap().timestamp;
+        let mut header = Vec::new();
+        let mut body_header = Vec::new();
+        body_header.put_size(number);
+        body_header.extend_from_slice(&(offset as i16).to_be_bytes());
+        body_header.push(if block.keyframe { 0x80 } else { 0 });
+        header.put_id(SIMPLE_BLOCK);
+        header.put_size((body_header.len() + block.data.len()) as u64);
+        header.extend_from_slice(&body_header);
+        self.append(&header)?;
+        self.append(&block.data)?;
+        self.end_time = self.end_time.max(block.timestamp + block.duration);
+        Ok(())
+    }
+
+    /// Finishes the current cluster and starts one at `time`, or at the
+    /// current one's time if that's later, so they never go backwards.
+    fn start_cluster(&mut self, time: i64) -> Result<()> {
+        let time = match &self.cluster {
+            Some(cluster) => time.max(cluster.timestamp),
+            None => time.max(0),
+        };
+        self.finish_cluster()?;
+        self.write_cues()?;
+        let mut header = Vec::new();
+        header.put_id(CLUSTER);
+        header.put_size_8(UNKNOWN_SIZE);
+        header.put_uint(TIMESTAMP, time as u64);
+        self.cluster = Some(Cluster {
+            size_offset: self.position + 4,
+            timestamp: time,
+        });
+        self.append(&header)
+    }
+
+    fn finish_cluster(&mut self) -> Result<()> {
+        let Some(cluster) = self.cluster.take() else {
+            return Ok(());
+        };
+        let mut size = Vec::new();
+        size.put_size_8(self.position - cluster.size_offset - 8);
+        self.patch(cluster.size_offset, &size)
+    }
+
+    /// Adds the cue points that aren't in the file yet to the cues. If
+    /// they don't fit, the cues move to the end of the file, so this is only
+    /// called between clusters.
+    fn write_cues(&mut self) -> Result<()> {
+        if self.written_cues == self.cues.len() {
+            return Ok(());
+        }
+        let space = self.cue_space;
+        let used = space.used.max(CUES_HEADER_LEN);
+        let mut points = build_cue_points(&self.cues[self.written_cues..]);
+        self.written_cues = self.cues.len();
+
+        // A void takes at least 2 bytes, so 1 left over won't do.
+        match space.len.checked_sub(used + points.len() as u64) {
+            Some(left) if left != 1 => {
+                // The new points go where the void was, and the void shrinks
+                // to what's left.
+                if left > 0 {
+                    points.put_void(left as usize);
+                }
+                self.patch(space.offset + used, &points)?;
+                let mut header = Vec::new();
+                header.put_id(CUES);
+                header.put_size_8(space.len - left - CUES_HEADER_LEN);
+                self.patch(space.offset, &header)?;
+                self.cue_space.used = space.len - left;
+                if space.used == 0 {
+                    self.set_seek_entry(CUES, space.offset);
+                    self.write_seek_head()?;
+                }
+            }
+            _ => {
+                let points = build_cue_points(&self.cues);
+                let used = CUES_HEADER_LEN + points.len() as u64;
+                let len = (space.len * 2).max(used * 2);
+                let mut cues = Vec::new();
+                cues.put_id(CUES);
+                cues.put_size_8(points.len() as u64);
+                cues.extend_from_slice(&points);
+                cues.put_void((len - used) as usize);
+                self.cue_space = CueSpace {
+                    offset: self.position,
+                    len,
+                    used,
+                };
+                self.append(&cues)?;
+                self.set_seek_entry(CUES, self.cue_space.offset);
+                self.write_seek_head()?;
+
+                // Readers skip over the old cues once they're void.
+                let mut void = Vec::new();
+                void.put_id(VOID);
+                void.put_size_8(space.len - 9);
+                self.patch(space.offset, &void)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the seek head at the element with `id`, now at `offset` in
+    /// the file.
+    fn set_seek_entry(&mut self, id: u32, offset: u64) {
+        let position = offset - self.segment_start;
+        match self.seek_entries.iter_mut().find(|(entry, _)| *entry == id) {
+            Some(entry) => entry.1 = position,
+            None => self.seek_entries.push((id, position)),
+        }
+    }
+
+    fn write_seek_head(&mut self) -> Result<()> {
+        let mut seek_head = Vec::new();
+        write_element(&mut seek_head, SEEK_HEAD, |out| {
+            for &(id, position) in &self.seek_entries {
+                write_element(out, SEEK, |out| {
+                    let mut id_bytes = Vec::new();
+                    id_bytes.put_id(id);
+                    out.put_binary(SEEK_ID, &id_bytes);
+                    out.put_uint(SEEK_POSITION, position);
+                });
+            }
+        });
+        seek_head.put_void(SEEK_HEAD_LEN - seek_head.len());
+        self.patch(self.segment_start, &seek_head)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.numbers.is_none() {
+            self.write_tracks()?;
+        }
+        self.finish_cluster()?;
+        self.write_cues()?;
+
+        if !self.chapters.is_empty() {
+            let chapters = build_chapters(&mut self.chapters, self.end_time);
+            self.seek_entries
+                .push((CHAPTERS, self.position - self.segment_start));
+            self.append(&chapters)?;
+        }
+
+        let mut segment_size = Vec::new();
+        segment_size.put_size_8(self.position - self.segment_start);
+        self.patch(self.segment_size_offset, &segment_size)?;
+        let duration = self.end_time as f64 / TICKS_PER_TIMESTAMP as f64;
+        self.patch(self.duration_offset, &duration.to_be_bytes())?;
+        self.write_seek_head()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// The cue points' elements, without the cues around them.
+fn build_cue_points(cues: &[CuePoint]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for cue in cues {
+        write_element(&mut out, CUE_POINT, |out| {
+            out.put_uint(CUE_TIME, cue.time);
+            write_element(out, CUE_TRACK_POSITIONS, |out| {
+                out.put_uint(CUE_TRACK, cue.track);
+                out.put_uint(CUE_CLUSTER_POSITION, cue.cluster_position);
+            });
+        });
+    }
+    out
+}
+
+fn put_track_entry(out: &mut Vec<u8>, track: &Track, number: u64, is_default: bool) -> Result<()> {
+    let (codec_id, codec_private) = codec(track)?;
+    let (width, height) = display_size(track)?;
+    write_element(out, TRACK_ENTRY, |out| {
+        out.put_uint(TRACK_NUMBER, number);
+        out.put_uint(TRACK_UID, number);
+        match track.format() {
+            StreamFormat::Video(_) => out.put_uint(TRACK_TYPE, TRACK_TYPE_VIDEO),
+            StreamFormat::Audio(_) => out.put_uint(TRACK_TYPE, TRACK_TYPE_AUDIO),
+        }
+        out.put_uint(FLAG_DEFAULT, is_default as u64);
+        out.put_uint(FLAG_LACING, 0);
+        if let Some(name) = track.name() {
+            out.put_string(NAME, name);
+        }
+        // Matroska takes tracks to be in English unless they say otherwise
+        out.put_string(LANGUAGE, track.language().unwrap_or(UNDETERMINED_LANGUAGE));
+        out.put_string(CODEC_ID, codec_id);
+        if let Some(codec_private) = &codec_private {
+            out.put_binary(CODEC_PRIVATE, codec_private);
+        }
+        match track.format() {
+            StreamFormat::Video(_) => write_element(out, VIDEO, |out| {
+                out.put_uint(PIXEL_WIDTH, width as u64);
+                out.put_uint(PIXEL_HEIGHT, height as u64);
+            }),
+            StreamFormat::Audio(format) => {
+                let sample_rate = match format.codec {
+                    AudioCodec::Opus => {
+                        let pre_skip = opus::PRE_SKIP as u64 * 1_000_000_000;
+                        out.put_uint(CODEC_DELAY, pre_skip / opus::SAMPLE_RATE as u64);
+                        out.put_uint(SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL);
+                        opus::SAMPLE_RATE
+                    }
+                    _ => format.sample_rate,
+                };
+                write_element(out, AUDIO, |out| {
+                    out.put_float(SAMPLING_FREQUENCY, sample_rate as f64);
+                    out.put_uint(CHANNELS, format.channels as u64);
+                    if matches!(format.codec, AudioCodec::Flac | AudioCodec::Pcm) {
+                        out.put_uint(BIT_DEPTH, 16);
+                    }
+                });
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The codec ID and private data of a track.
+fn codec(track: &Track) -> Result<(&'static str, Option<Vec<u8>>)> {
+    Ok(match track.format() {
+        StreamFormat::Video(format) => {
+            let codec_id = match format.codec {
+                VideoCodec::Hevc => "V_MPEGH/ISO/HEVC",
+                VideoCodec::Av1 => "V_AV1",
+                _ => "V_MPEG4/ISO/AVC",
+            };
+            (codec_id, Some(decoder_configuration(track)?))
+        }
+        StreamFormat::Audio(format) => match format.codec {
+            AudioCodec::Aac => (
+                "A_AAC",
+                Some(AudioSpecificConfig::new(format.sample_rate, format.channels).to_bytes()?),
+            ),
+            AudioCodec::Opus => (
+                "A_OPUS",
+                Some(opus::opus_head(format.channels, format.sample_rate)?),
+            ),
+            AudioCodec::Flac => (
+                "A_FLAC",
+                Some(
+                    [
+                        &b"fLaC"[..],
+                        &flac::metadata(format.sample_rate, format.channels),
+                    ]
+                    .concat(),
+                ),
+            ),
+            AudioCodec::Pcm => ("A_PCM/INT/LIT", None),
+        },
+    })
+}
+
+/// Builds one edition with a chapter for each mark, each ending where the
+/// next one starts, and the last at `end_time`.
+fn build_chapters(chapters: &mut [(i64, String)], end_time: i64) -> Vec<u8> {
+    chapters.sort_by_key(|(timestamp, _)| *timestamp);
+    let to_nanoseconds = |timestamp: i64| timestamp.max(0) as u64 * 100;
+    let mut out = Vec::new();
+    write_element(&mut out, CHAPTERS, |out| {
+        write_element(out, EDITION_ENTRY, |out| {
+            out.put_uint(EDITION_UID, 1);
+            for (index, (start, title)) in chapters.iter().enumerate() {
+                let end = chapters
+                    .get(index + 1)
+                    .map_or(end_time.max(*start), |(next, _)| *next);
+                write_element(out, CHAPTER_ATOM, |out| {
+                    out.put_uint(CHAPTER_UID, index as u64 + 1);
+                    out.put_uint(CHAPTER_TIME_START, to_nanoseconds(*start));
+                    out.put_uint(CHAPTER_TIME_END, to_nanoseconds(end));
+                    write_element(out, CHAPTER_DISPLAY, |out| {
+                        out.put_string(CHAP_STRING, title);
+                        out.put_string(CHAP_LANGUAGE, UNDETERMINED_LANGUAGE);
+                    });
+                });
+            }
+        });
+    });
+    out
+}
+
+impl<W: Write + Seek + Send> Sink for MatroskaWriter<W> {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("Streams must be added before starting."));
+        }
+        let allowed = match &format {
+            _ if self.doc_type == DocType::Matroska => true,
+            StreamFormat::Video(format) => format.codec == VideoCodec::Av1,
+            StreamFormat::Audio(format) => format.codec == AudioCodec::Opus,
+        };
+        if !allowed {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "WebM files can only hold AV1 video and Opus audio.",
+            ));
+        }
+        add_track(&mut self.tracks, format)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("The Matroska writer was already started."));
+        }
+        self.write_header()?;
+        self.state = State::Writing;
+        if self
+            .tracks
+            .iter()
+            .all(|track| track.kind() == StreamKind::Audio)
+        {
+            self.write_tracks()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other(
+                "The Matroska writer isn't accepting packets.",
+            ));
+        }
+        let index = find_track(&self.tracks, &packet)?;
+        let data = self.tracks[index].sample_data(&packet)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let block = Block {
+            track: index,
+            data,
+            timestamp: packet.timestamp,
+            duration: packet.duration,
+            keyframe: packet.keyframe,
+        };
+        if self.numbers.is_some() {
+            return self.write_block(block);
+        }
+
+        self.pending.push(block);
+        let is_ready = self.tracks.iter().enumerate().all(|(index, track)| {
+            track.kind() == StreamKind::Audio
+                || self.pending.iter().any(|block| block.track == index)
+        });
+        if is_ready {
+            self.write_tracks()?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other("The Matroska writer isn't running."));
+        }
+        self.state = State::Finished;
+        self.finish()
+    }
+
+    fn add_chapter(&mut self, timestamp: i64, title: &str) -> Result<()> {
+        if self.state == State::Finished {
+            return Err(io::Error::other("The Matroska writer isn't running."));
+        }
+        self.chapters.push((timestamp, title.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{
+        MatroskaWriter, AUDIO, BIT_DEPTH, CHAPTERS, CHAPTER_ATOM, CHAPTER_DISPLAY,
+        CHAPTER_TIME_END, CHAPTER_TIME_START, CHAP_STRING, CLUSTER, CODEC_DELAY, CODEC_ID,
+        CODEC_PRIVATE, CUES, CUE_CLUSTER_POSITION, CUE_POINT, CUE_TIME, CUE_TRACK,
+        CUE_TRACK_POSITIONS, DOC_TYPE, DURATION, EBML, EDITION_ENTRY, FLAG_DEFAULT, INFO, LANGUAGE,
+        NAME, PIXEL_HEIGHT, PIXEL_WIDTH, SAMPLING_FREQUENCY, SEEK, SEEK_HEAD, SEEK_ID,
+        SEEK_POSITION, SEGMENT, SIMPLE_BLOCK, TIMESTAMP, TIMESTAMP_SCALE, TRACKS, TRACK_ENTRY,
+        TRACK_NUMBER, TRACK_TYPE, VIDEO,
+    };
+    use crate::{
+        mux::{
+            aac::AudioSpecificConfig,
+            ebml::{elements, find_element, read_float, read_size, read_uint, Element},
+            h264::{self, test_util},
+        },
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            AudioCodec, AudioStreamFormat, Sink, StreamFormat, VideoCodec, VideoStreamFormat,
+        },
+    };
+
+    const FRAME: i64 = 10_000_000 / 60;
+    const AAC_FRAME: i64 = 1024 * 10_000_000 / 48000;
+
+    fn video_format(codec: VideoCodec) -> StreamFormat {
+        StreamFormat::Video(VideoStreamFormat {
+            codec,
+            width: 1280,
+            height: 720,
+            frame_rate: 60,
+            bit_rate: 8_000_000,
+        })
+    }
+
+    fn audio_format(codec: AudioCodec, name: Option<&str>, language: Option<&str>) -> StreamFormat {
+        StreamFormat::Audio(AudioStreamFormat {
+            codec,
+            sample_rate: 48000,
+            channels: 2,
+            bit_rate: 128_000,
+            name: name.map(str::to_string),
+            language: language.map(str::to_string),
+        })
+    }
+
+    fn slice(index: usize, keyframe: bool) -> Vec<u8> {
+        let header = if keyframe { 0x65 } else { 0x41 };
+        let mut slice = vec![header];
+        slice.extend((0..20 + index).map(|byte| byte as u8 | 0x80));
+        slice
+    }
+
+    /// An Annex B access unit with parameter sets on keyframes, as hardware
+    /// encoders produce them.
+    fn video_packet(index: usize) -> EncodedPacket {
+        let keyframe = index.is_multiple_of(4);
+        let mut units = vec![vec![0x09, 0xf0]];
+        if keyframe {
+            units.push(test_util::sps(100, 1280, 720));
+            units.push(test_util::pps());
+        }
+        units.push(slice(index, keyframe));
+        let mut data = Vec::new();
+        for unit in units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend(unit);
+        }
+        EncodedPacket::new(
+            StreamKind::Video,
+            data,
+            index as i64 * FRAME,
+            FRAME,
+            keyframe,
+        )
+    }
+
+    /// What a video packet is stored as: its slice, with a length prefix.
+    fn video_block(index: usize) -> Vec<u8> {
+        let slice = slice(index, index.is_multiple_of(4));
+        [&(slice.len() as u32).to_be_bytes()[..], &slice].concat()
+    }
+
+    fn audio_packet(stream: usize, index: usize) -> EncodedPacket {
+        let mut data = vec![0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc];
+        data.extend((0..10).map(|byte| (byte + index + stream * 100) as u8));
+        let mut packet = EncodedPacket::new(
+            StreamKind::Audio,
+            data,
+            index as i64 * AAC_FRAME,
+            AAC_FRAME,
+            true,
+        );
+        packet.stream = stream;
+        packet
+    }
+
+    /// Video and audio in timestamp order, with the audio a few packets
+    /// ahead, the way it can be when it's encoded on another thread.
+    fn recording(frames: usize, audio_tracks: usize) -> Vec<EncodedPacket> {
+        let audio_packets = frames * FRAME as usize / AAC_FRAME as usize;
+        let mut packets: Vec<_> = (0..frames).map(video_packet).collect();
+        for stream in 0..audio_tracks {
+            packets.extend((0..audio_packets).map(|index| audio_packet(stream, index)));
+        }
+        packets.sort_by_key(|packet| match packet.kind {
+            StreamKind::Video => packet.timestamp + 4 * AAC_FRAME,
+            StreamKind::Audio => packet.timestamp,
+        });
+        packets
+    }
+
+    fn write_file(
+        writer: MatroskaWriter<Cursor<Vec<u8>>>,
+        formats: &[StreamFormat],
+        packets: Vec<EncodedPacket>,
+        chapters: &[(i64, &str)],
+    ) -> Vec<u8> {
+        let mut writer = writer;
+        for format in formats {
+            writer.add_stream(format.clone()).unwrap();
+        }
+        writer.start().unwrap();
+        for packet in packets {
+            writer.write(packet).unwrap();
+        }
+        for &(timestamp, title) in chapters {
+            writer.add_chapter(timestamp, title).unwrap();
+        }
+        writer.stop().unwrap();
+        writer.into_inner().into_inner()
+    }
+
+    fn new_writer() -> MatroskaWriter<Cursor<Vec<u8>>> {
+        MatroskaWriter::new(Cursor::new(Vec::new()))
+    }
+
+    fn segment(file: &[u8]) -> &[u8] {
+        let top: Vec<_> = elements(file).map(|found| found.id).collect();
+        assert_eq!(top, [EBML, SEGMENT]);
+        find_element(file, &[SEGMENT]).unwrap().body
+    }
+
+    fn children(data: &[u8], id: u32) -> Vec<Element<'_>> {
+        elements(data).filter(|found| found.id == id).collect()
+    }
+
+    fn uint(data: &[u8], path: &[u32]) -> u64 {
+        read_uint(find_element(data, path).unwrap().body)
+    }
+
+    fn string<'a>(data: &'a [u8], path: &[u32]) -> &'a str {
+        std::str::from_utf8(find_element(data, path).unwrap().body).unwrap()
+    }
+
+    struct Block {
+        track: u64,
+        /// In milliseconds.
+        time: i64,
+        keyframe: bool,
+        data: Vec<u8>,
+    }
+
+    fn cluster_blocks(cluster: &[u8]) -> Vec<Block> {
+        let cluster_time = uint(cluster, &[TIMESTAMP]) as i64;
+        children(cluster, SIMPLE_BLOCK)
+            .into_iter()
+            .map(|block| {
+                let (track, len) = read_size(block.body).unwrap();
+                let body = &block.body[len..];
+                Block {
+                    track: track.unwrap(),
+                    time: cluster_time + i16::from_be_bytes([body[0], body[1]]) as i64,
+                    keyframe: body[2] & 0x80 != 0,
+                    data: body[3..].to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    fn blocks(segment: &[u8]) -> Vec<Block> {
+        children(segment, CLUSTER)
+            .into_iter()
+            .flat_map(|cluster| cluster_blocks(cluster.body))
+            .collect()
+    }
+
+    /// Where the seek head says the element with `id` is.
+    fn seek_position(segment: &[u8], id: u32) -> usize {
+        let seek_head = find_element(segment, &[SEEK_HEAD]).unwrap();
+        let seek = children(seek_head.body, SEEK)
+            .into_iter()
+            .find(|seek| uint(seek.body, &[SEEK_ID]) == id as u64)
+            .unwrap();
+        uint(seek.body, &[SEEK_POSITION]) as usize
+    }
+
+    fn track_entries(segment: &[u8]) -> Vec<Element<'_>> {
+        children(find_element(segment, &[TRACKS]).unwrap().body, TRACK_ENTRY)
+    }
+
+    #[test]
+    fn describes_every_track() {
+        let formats = [
+            video_format(VideoCodec::H264),
+            audio_format(AudioCodec::Aac, Some("Mixed"), None),
+            audio_format(AudioCodec::Aac, Some("Microphone"), Some("eng")),
+        ];
+        let file = write_file(new_writer(), &formats, recording(8, 2), &[]);
+        assert_eq!(string(&file, &[EBML, DOC_TYPE]), "matroska");
+        let segment = segment(&file);
+        assert_eq!(uint(segment, &[INFO, TIMESTAMP_SCALE]), 1_000_000);
+
+        let entries = track_entries(segment);
+        assert_eq!(entries.len(), 3);
+        let video = entries[0].body;
+        assert_eq!(uint(video, &[TRACK_NUMBER]), 1);
+        assert_eq!(uint(video, &[TRACK_TYPE]), 1);
+        assert_eq!(string(video, &[CODEC_ID]), "V_MPEG4/ISO/AVC");
+        let avcc =
+            h264::avc_decoder_configuration(&[test_util::sps(100, 1280, 720)], &[test_util::pps()])
+                .unwrap();
+        assert_eq!(find_element(video, &[CODEC_PRIVATE]).unwrap().body, avcc);
+        assert_eq!(uint(video, &[VIDEO, PIXEL_WIDTH]), 1280);
+        assert_eq!(uint(video, &[VIDEO, PIXEL_HEIGHT]), 720);
+        assert_eq!(string(video, &[LANGUAGE]), "und");
+        assert!(find_element(video, &[NAME]).is_none());
+
+        let config = AudioSpecificConfig::new(48000, 2).to_bytes().unwrap();
+        let tracks = [(2, "Mixed", "und", 1), (3, "Microphone", "eng", 0)];
+        for (entry, (number, name, language, default)) in entries[1..].iter().zip(tracks) {
+            let audio = entry.body;
+            assert_eq!(uint(audio, &[TRACK_NUMBER]), number);
+            assert_eq!(uint(audio, &[TRACK_TYPE]), 2);
+            assert_eq!(string(audio, &[NAME]), name);
+            assert_eq!(string(audio, &[LANGUAGE]), language);
+            // Only the first audio track plays unless another is picked
+            assert_eq!(uint(audio, &[FLAG_DEFAULT]), default);
+            assert_eq!(string(audio, &[CODEC_ID]), "A_AAC");
+            assert_eq!(find_element(audio, &[CODEC_PRIVATE]).unwrap().body, config);
+            let rate = find_element(audio, &[AUDIO, SAMPLING_FREQUENCY]).unwrap();
+            assert_eq!(read_float(rate.body), 48000.0);
+        }
+    }
+
+    #[test]
+    fn blocks_read_back() {
+        let formats = [
+            video_format(VideoCodec::H264),
+            audio_format(AudioCodec::Aac, None, None),
+            audio_format(AudioCodec::Aac, None, None),
+        ];
+        let packets = recording(30, 2);
+        let file = write_file(new_writer(), &formats, packets.clone(), &[]);
+        let blocks = blocks(segment(&file));
+        assert_eq!(blocks.len(), packets.len());
+
+        let video: Vec<_> = blocks.iter().filter(|block| block.track == 1).collect();
+        assert_eq!(video.len(), 30);
+        for (index, block) in video.iter().enumerate() {
+            assert_eq!(block.data, video_block(index));
+            assert_eq!(block.time, index as i64 * FRAME / 10_000);
+            assert_eq!(block.keyframe, index.is_multiple_of(4));
+        }
+        for (track, stream) in [(2, 0), (3, 1)] {
+            let audio: Vec<_> = blocks.iter().filter(|block| block.track == track).collect();
+            assert_eq!(audio.len(), 23);
+            for (index, block) in audio.iter().enumerate() {
+                // Without the ADTS header
+                assert_eq!(block.data, audio_packet(stream, index).data[7..]);
+                assert_eq!(block.time, index as i64 * AAC_FRAME / 10_000);
+                assert!(block.keyframe);
+            }
+        }
+
+        let duration = find_element(segment(&file), &[INFO, DURATION]).unwrap();
+        assert_eq!(read_float(duration.body), 30.0 * FRAME as f64 / 10_000.0);
+    }
+
+    #[test]
+    fn clusters_start_on_keyframes_and_are_cued() {
+        let formats = [
+            video_format(VideoCodec::H264),
+            audio_format(AudioCodec::Aac, None, None),
+        ];
+        let file = write_file(new_writer(), &formats, recording(30, 1), &[]);
+        let segment = segment(&file);
+
+        let clusters = children(segment, CLUSTER);
+        // Audio that came before the first keyframe gets a cluster of its own
+        assert_eq!(clusters.len(), 9);
+        for cluster in &clusters[1..] {
+            let blocks = cluster_blocks(cluster.body);
+            let first_video = blocks.iter().find(|block| block.track == 1).unwrap();
+            assert!(first_video.keyframe);
+        }
+
+        let cues = children(find_element(segment, &[CUES]).unwrap().body, CUE_POINT);
+        assert_eq!(cues.len(), 8);
+        for (index, cue) in cues.iter().enumerate() {
+            assert_eq!(
+                uint(cue.body, &[CUE_TIME]),
+                (index * 4) as u64 * FRAME as u64 / 10_000
+            );
+            assert_eq!(uint(cue.body, &[CUE_TRACK_POSITIONS, CUE_TRACK]), 1);
+            let position = uint(cue.body, &[CUE_TRACK_POSITIONS, CUE_CLUSTER_POSITION]);
+            assert_eq!(position as usize, clusters[index + 1].offset);
+        }
+
+        // The seek head points at each of the other top level elements
+        let seek_head = find_element(segment, &[SEEK_HEAD]).unwrap();
+        assert_eq!(seek_head.offset, 0);
+        let seeks = children(seek_head.body, SEEK);
+        assert_eq!(seeks.len(), 3);
+        for seek in seeks {
+            let id = read_uint(find_element(seek.body, &[SEEK_ID]).unwrap().body) as u32;
+            let position = uint(seek.body, &[SEEK_POSITION]) as usize;
+            assert_eq!(elements(&segment[position..]).next().unwrap().id, id);
+        }
+    }
+
+    #[test]
+    fn writes_chapters() {
+        let formats = [
+            video_format(VideoCodec::H264),
+            audio_format(AudioCodec::Aac, None, None),
+        ];
+        let chapters = [(10 * FRAME, "Boss fight"), (0, "Lobby")];
+        let file = write_file(new_writer(), &formats, recording(20, 1), &chapters);
+        let segment = segment(&file);
+
+        let edition = find_element(segment, &[CHAPTERS, EDITION_ENTRY]).unwrap();
+        let atoms = children(edition.body, CHAPTER_ATOM);
+        let end = 20 * FRAME as u64 * 100;
+        let expected = [
+            (0, 10 * FRAME as u64 * 100, "Lobby"),
+            (10 * FRAME as u64 * 100, end, "Boss fight"),
+        ];
+        assert_eq!(atoms.len(), 2);
+        for (atom, (start, end, title)) in atoms.iter().zip(expected) {
+            assert_eq!(uint(atom.body, &[CHAPTER_TIME_START]), start);
+            assert_eq!(uint(atom.body, &[CHAPTER_TIME_END]), end);
+            assert_eq!(string(atom.body, &[CHAPTER_DISPLAY, CHAP_STRING]), title);
+        }
+    }
+
+    #[test]
+    fn cut_off_files_keep_what_was_written() {
+        let mut writer = new_writer();
+        writer.add_stream(video_format(VideoCodec::H264)).unwrap();
+        writer
+            .add_stream(audio_format(AudioCodec::Aac, None, None))
+            .unwrap();
+        writer.start().unwrap();
+        let packets = recording(30, 1);
+        for packet in packets.clone() {
+            writer.write(packet).unwrap();
+        }
+        // Cut off in the middle of the last block
+        let mut file = writer.into_inner().into_inner();
+        file.truncate(file.len() - 3);
+
+        let segment = segment(&file);
+        assert!(find_element(segment, &[TRACKS]).is_some());
+        let blocks = blocks(segment);
+        assert_eq!(blocks.len(), packets.len() - 1);
+        assert!(blocks.iter().all(|block| !block.data.is_empty()));
+
+        // Every cluster but the one being written is cued
+        let clusters = children(segment, CLUSTER);
+        let cues = find_element(segment, &[CUES]).unwrap();
+        assert_eq!(children(cues.body, CUE_POINT).len(), clusters.len() - 2);
+        assert_eq!(seek_position(segment, CUES), cues.offset);
+    }
+
+    #[test]
+    fn cues_move_when_they_outgrow_their_space() {
+        let mut writer = new_writer();
+        writer.add_stream(video_format(VideoCodec::H264)).unwrap();
+        writer.start().unwrap();
+        for packet in recording(2000, 0) {
+            writer.write(packet).unwrap();
+        }
+        let file = writer.into_inner().into_inner();
+        let segment = segment(&file);
+
+        // The space after the tracks went back to being void
+        let cues = children(segment, CUES);
+        assert_eq!(cues.len(), 1);
+        let clusters = children(segment, CLUSTER);
+        assert!(cues[0].offset > clusters[0].offset);
+        assert_eq!(seek_position(segment, CUES), cues[0].offset);
+
+        let points = children(cues[0].body, CUE_POINT);
+        assert_eq!(points.len(), clusters.len() - 1);
+        for (point, cluster) in points.iter().zip(&clusters) {
+            let position = uint(point.body, &[CUE_TRACK_POSITIONS, CUE_CLUSTER_POSITION]);
+            assert_eq!(position as usize, cluster.offset);
+        }
+    }
+
+    #[test]
+    fn webm_only_takes_av1_and_opus() {
+        let mut writer = MatroskaWriter::webm(Cursor::new(Vec::new()));
+        assert!(writer.add_stream(video_format(VideoCodec::H264)).is_err());
+        assert!(writer
+            .add_stream(audio_format(AudioCodec::Aac, None, None))
+            .is_err());
+        let opus = audio_format(AudioCodec::Opus, None, None);
+        let file = write_file(writer, &[opus], Vec::new(), &[]);
+
+        assert_eq!(string(&file, &[EBML, DOC_TYPE]), "webm");
+        let entries = track_entries(segment(&file));
+        assert_eq!(entries.len(), 1);
+        let opus = entries[0].body;
+        assert_eq!(string(opus, &[CODEC_ID]), "A_OPUS");
+        let head = find_element(opus, &[CODEC_PRIVATE]).unwrap().body;
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(uint(opus, &[CODEC_DELAY]), 6_500_000);
+    }
+
+    #[test]
+    fn lossless_audio_gets_its_codec_private() {
+        let formats = [
+            audio_format(AudioCodec::Flac, None, None),
+            audio_format(AudioCodec::Pcm, None, None),
+        ];
+        let packets: Vec<_> = (0..3)
+            .map(|index| {
+                let mut packet = audio_packet(index % 2, index);
+                packet.data = vec![index as u8; 40];
+                packet
+            })
+            .collect();
+        let file = write_file(new_writer(), &formats, packets, &[]);
+        let segment = segment(&file);
+        let entries = track_entries(segment);
+
+        let flac = entries[0].body;
+        assert_eq!(string(flac, &[CODEC_ID]), "A_FLAC");
+        let private = find_element(flac, &[CODEC_PRIVATE]).unwrap().body;
+        assert_eq!(&private[..8], [b'f', b'L', b'a', b'C', 0x80, 0, 0, 34]);
+        assert_eq!(private.len(), 42);
+        assert_eq!(uint(flac, &[AUDIO, BIT_DEPTH]), 16);
+
+        let pcm = entries[1].body;
+        assert_eq!(string(pcm, &[CODEC_ID]), "A_PCM/INT/LIT");
+        assert!(find_element(pcm, &[CODEC_PRIVATE]).is_none());
+
+        // Stored as they are
+        let blocks = blocks(segment);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[1].track, 2);
+        assert_eq!(blocks[1].data, [1; 40]);
+    }
+
+    #[test]
+    fn audio_only_recordings_cluster_every_few_seconds() {
+        let formats = [audio_format(AudioCodec::Aac, None, None)];
+        let packets: Vec<_> = (0..500).map(|index| audio_packet(0, index)).collect();
+        let file = write_file(new_writer(), &formats, packets, &[]);
+        let segment = segment(&file);
+
+        // 10.7 seconds of audio
+        assert_eq!(children(segment, CLUSTER).len(), 3);
+        let cues = children(find_element(segment, &[CUES]).unwrap().body, CUE_POINT);
+        let times: Vec<_> = cues.iter().map(|cue| uint(cue.body, &[CUE_TIME])).collect();
+        assert_eq!(times, [0, 5013, 10026]);
+        assert_eq!(blocks(segment).len(), 500);
+    }
+}
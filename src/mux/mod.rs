@@ -0,0 +1,98 @@
+//! Container writers that don't depend on Media Foundation.
+
+pub mod aac;
+mod av1;
+mod bmff;
+mod ebml;
+mod fragmented;
+pub mod h264;
+mod hevc;
+mod matroska;
+mod mp4;
+mod opus;
+
+use std::path::Path;
+#[cfg(windows)]
+use std::{fmt::Display, str::FromStr};
+
+#[cfg(windows)]
+pub use fragmented::{scan_fragments, FragmentedMp4Writer};
+#[cfg(windows)]
+pub use matroska::MatroskaWriter;
+
+/// The kind of file a recording is written to, picked by its extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+    /// Matroska limited to AV1 video and Opus audio, which browsers play.
+    WebM,
+}
+
+impl Container {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "mp4" => Some(Container::Mp4),
+            "mkv" => Some(Container::Matroska),
+            "webm" => Some(Container::WebM),
+            _ => None,
+        }
+    }
+}
+#[cfg(windows)]
+pub use mp4::Mp4Writer;
+
+/// Which muxer writes the output file.
+#[cfg(windows)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Muxer {
+    /// Media Foundation's sink writer.
+    MediaFoundation,
+    /// The MP4 writer in this crate.
+    Builtin,
+    /// The fragmented MP4 writer in this crate, which leaves a playable file
+    /// behind even if the recording is cut off.
+    Fragmented,
+}
+
+#[cfg(windows)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseMuxerError(&'static str);
+
+#[cfg(windows)]
+impl FromStr for Muxer {
+    type Err = ParseMuxerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mf" => Ok(Muxer::MediaFoundation),
+            "builtin" => Ok(Muxer::Builtin),
+            "fragmented" => Ok(Muxer::Fragmented),
+            _ => Err(ParseMuxerError(
+                "Invalid muxer value! Expecting: mf, builtin, or fragmented.",
+            )),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Display for Muxer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Muxer::MediaFoundation => "mf",
+            Muxer::Builtin => "builtin",
+            Muxer::Fragmented => "fragmented",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+#[cfg(windows)]
+impl Display for ParseMuxerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+#[cfg(windows)]
+impl std::error::Error for ParseMuxerError {}
@@ -0,0 +1,1530 @@
+//! An MP4 muxer that doesn't need Media Foundation. Samples are appended to
+//! the mdat as they arrive. When the writer stops, the sample tables are
+//! written in a moov box that's moved ahead of the media data, so players can
+//! start without reading the whole file.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{flac, AudioCodec, Result, Sink, StreamFormat, VideoCodec},
+};
+
+use super::{
+    aac::{self, AudioSpecificConfig},
+    av1::{self, SequenceHeader},
+    bmff::{write_box, write_full_box, PutBytes},
+    h264::{self, Sps},
+    hevc, opus,
+};
+
+const MOVIE_TIMESCALE: u32 = 1000;
+const VIDEO_TIMESCALE: u32 = 90000;
+const UNDETERMINED_LANGUAGE: u16 = 0x55c4;
+const IDENTITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(super) enum State {
+    Idle,
+    Writing,
+    Finished,
+}
+
+pub(super) struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub keyframe: bool,
+}
+
+pub(super) struct Track {
+    format: StreamFormat,
+    /// Which track of its kind this is, as packets number it.
+    number: usize,
+    // The parameter sets of H.264 and HEVC streams, and the sequence header
+    // of AV1 ones, for the sample entry
+    vps: Vec<Vec<u8>>,
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>,
+    sequence_header: Option<Vec<u8>>,
+    pub samples: Vec<Sample>,
+}
+
+impl Track {
+    pub fn new(format: StreamFormat, number: usize) -> Result<Self> {
+        let supported = match &format {
+            StreamFormat::Video(format) => format.codec != VideoCodec::Raw,
+            StreamFormat::Audio(_) => true,
+        };
+        if !supported {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The MP4 writer only supports H.264, HEVC and AV1 video.",
+            ));
+        }
+        Ok(Self {
+            format,
+            number,
+            vps: Vec::new(),
+            sps: Vec::new(),
+            pps: Vec::new(),
+            sequence_header: None,
+            samples: Vec::new(),
+        })
+    }
+
+    pub fn video_codec(&self) -> Option<VideoCodec> {
+        match &self.format {
+            StreamFormat::Video(format) => Some(format.codec),
+            StreamFormat::Audio(_) => None,
+        }
+    }
+
+    pub fn kind(&self) -> StreamKind {
+        self.format.kind()
+    }
+
+    pub fn format(&self) -> &StreamFormat {
+        &self.format
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match &self.format {
+            StreamFormat::Video(_) => None,
+            StreamFormat::Audio(format) => format.name.as_deref(),
+        }
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        match &self.format {
+            StreamFormat::Video(_) => None,
+            StreamFormat::Audio(format) => format.language.as_deref(),
+        }
+    }
+
+    pub fn timescale(&self) -> u32 {
+        match &self.format {
+            StreamFormat::Video(_) => VIDEO_TIMESCALE,
+            StreamFormat::Audio(format) if format.codec == AudioCodec::Opus => opus::SAMPLE_RATE,
+            StreamFormat::Audio(format) => format.sample_rate,
+        }
+    }
+
+    /// Turns a packet into the bytes of a sample. This is empty for packets
+    /// that only carried parameter sets.
+    pub fn sample_data(&mut self, packet: &EncodedPacket) -> Result<Vec<u8>> {
+        match self.video_codec() {
+            Some(VideoCodec::H264) => self.h264_sample(&packet.data),
+            Some(VideoCodec::Hevc) => self.hevc_sample(&packet.data),
+            Some(VideoCodec::Av1) => self.av1_sample(&packet.data),
+            Some(VideoCodec::Raw) => unreachable!("Raw video tracks aren't allowed"),
+            None => match &self.format {
+                StreamFormat::Audio(format) if format.codec == AudioCodec::Aac => {
+                    Ok(aac::strip_adts(&packet.data).to_vec())
+                }
+                // Opus packets, FLAC frames and PCM are stored as they are
+                _ => Ok(packet.data.clone()),
+            },
+        }
+    }
+
+    /// Turns an access unit into a sample with 4-byte length prefixes,
+    /// collecting the parameter sets for the avcC as we go.
+    fn h264_sample(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut sample = Vec::with_capacity(data.len());
+        for unit in h264::split_nal_units(data)? {
+            match h264::nal_type(unit) {
+                h264::NAL_SPS => keep_first(&mut self.sps, unit),
+                h264::NAL_PPS => keep_first(&mut self.pps, unit),
+                h264::NAL_AUD => {}
+                _ => {
+                    sample.put_u32(unit.len() as u32);
+                    sample.put_bytes(unit);
+                }
+            }
+        }
+        Ok(sample)
+    }
+
+    /// The same for HEVC, where the parameter sets go in the hvcC.
+    fn hevc_sample(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut sample = Vec::with_capacity(data.len());
+        for unit in h264::split_nal_units(data)? {
+            match hevc::nal_type(unit) {
+                hevc::NAL_VPS => keep_first(&mut self.vps, unit),
+                hevc::NAL_SPS => keep_first(&mut self.sps, unit),
+                hevc::NAL_PPS => keep_first(&mut self.pps, unit),
+                hevc::NAL_AUD => {}
+                _ => {
+                    sample.put_u32(unit.len() as u32);
+                    sample.put_bytes(unit);
+                }
+            }
+        }
+        Ok(sample)
+    }
+
+    /// Turns a temporal unit into a sample, leaving out the temporal
+    /// delimiters and padding and giving every OBU a size. The sequence
+    /// header stays in keyframes as well as going in the av1C.
+    fn av1_sample(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut sample = Vec::with_capacity(data.len());
+        for obu in av1::split_obus(data)? {
+            match obu.obu_type {
+                av1::OBU_TEMPORAL_DELIMITER | av1::OBU_PADDING => {}
+                obu_type => {
+                    let bytes = obu.to_bytes();
+                    if obu_type == av1::OBU_SEQUENCE_HEADER && self.sequence_header.is_none() {
+                        self.sequence_header = Some(bytes.clone());
+                    }
+                    sample.put_bytes(&bytes);
+                }
+            }
+        }
+        Ok(sample)
+    }
+}
+
+/// Holds on to the first of each kind of parameter set. Encoders repeat them
+/// on every keyframe, and they don't change in a recording.
+fn keep_first(units: &mut Vec<Vec<u8>>, unit: &[u8]) {
+    if units.is_empty() {
+        units.push(unit.to_vec());
+    }
+}
+
+/// Adds a track for a new stream, returning the number its packets carry.
+pub(super) fn add_track(tracks: &mut Vec<Track>, format: StreamFormat) -> Result<usize> {
+    let number = tracks
+        .iter()
+        .filter(|track| track.kind() == format.kind())
+        .count();
+    tracks.push(Track::new(format, number)?);
+    Ok(number)
+}
+
+/// Finds the index of the track a packet belongs to.
+pub(super) fn find_track(tracks: &[Track], packet: &EncodedPacket) -> Result<usize> {
+    tracks
+        .iter()
+        .position(|track| track.kind() == packet.kind && track.number == packet.stream)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No stream was added for this packet.",
+            )
+        })
+}
+
+/// The sample timing of a track in its own timescale.
+pub(super) struct Timing {
+    pub decode_deltas: Vec<u32>,
+    /// None when every sample is presented in decode order.
+    pub composition_offsets: Option<Vec<u32>>,
+    /// The composition time of the first presented sample.
+    pub composition_start: i64,
+    pub media_duration: i64,
+    /// The earliest presentation time, in 100ns units.
+    pub start_time: i64,
+}
+
+impl Timing {
+    /// `end_time` is when the last sample ends, if something after it is
+    /// already known. Otherwise the last packet's duration is used.
+    pub fn new(track: &Track, end_time: Option<i64>) -> Self {
+        let timescale = track.timescale() as i64;
+        let start_time = track
+            .samples
+            .iter()
+            .map(|sample| sample.timestamp)
+            .min()
+            .unwrap_or(0);
+        let origin = rescale(start_time, HNS_PER_SECOND, timescale);
+        let presentation: Vec<i64> = track
+            .samples
+            .iter()
+            .map(|sample| rescale(sample.timestamp, HNS_PER_SECOND, timescale) - origin)
+            .collect();
+
+        // Decode times are the presentation times in order, and samples
+        // that are shown before their decode time shift everything later.
+        let mut decode = presentation.clone();
+        decode.sort_unstable();
+        let composition_start = decode
+            .iter()
+            .zip(&presentation)
+            .map(|(decode, presentation)| decode - presentation)
+            .max()
+            .unwrap_or(0)
+            .max(0);
+
+        let mut decode_deltas: Vec<u32> = decode
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as u32)
+            .collect();
+        if let (Some(last), Some(last_decode)) = (track.samples.last(), decode.last()) {
+            let duration = match end_time {
+                Some(end_time) => {
+                    rescale(end_time, HNS_PER_SECOND, timescale) - origin - last_decode
+                }
+                None => rescale(last.duration, HNS_PER_SECOND, timescale),
+            };
+            decode_deltas.push(duration.max(1) as u32);
+        }
+        let composition_offsets = (decode != presentation).then(|| {
+            decode
+                .iter()
+                .zip(&presentation)
+                .map(|(decode, presentation)| (presentation + composition_start - decode) as u32)
+                .collect()
+        });
+        let media_duration = decode_deltas.iter().map(|&delta| delta as i64).sum();
+
+        Self {
+            decode_deltas,
+            composition_offsets,
+            composition_start,
+            media_duration,
+            start_time,
+        }
+    }
+
+    /// The edit list entries as (duration in the movie timescale, media
+    /// time), where a media time of -1 is an empty edit.
+    fn edits(&self, timescale: u32) -> Vec<(u64, i64)> {
+        let timescale = timescale as i64;
+        let to_movie = |units: i64| rescale(units, timescale, MOVIE_TIMESCALE as i64) as u64;
+        if self.start_time > 0 {
+            let delay = rescale(self.start_time, HNS_PER_SECOND, MOVIE_TIMESCALE as i64);
+            vec![
+                (delay as u64, -1),
+                (to_movie(self.media_duration), self.composition_start),
+            ]
+        } else if self.start_time < 0 {
+            let trim = rescale(-self.start_time, HNS_PER_SECOND, timescale);
+            vec![(
+                to_movie((self.media_duration - trim).max(0)),
+                self.composition_start + trim,
+            )]
+        } else if self.composition_start > 0 {
+            vec![(to_movie(self.media_duration), self.composition_start)]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Rescales `value` from one timescale to another, rounding to nearest.
+pub(super) fn rescale(value: i64, from: i64, to: i64) -> i64 {
+    let scaled = value as i128 * to as i128;
+    (scaled + from as i128 / 2).div_euclid(from as i128) as i64
+}
+
+/// Writes an MP4 file with one track per stream.
+pub struct Mp4Writer<W> {
+    out: W,
+    tracks: Vec<Track>,
+    mdat_start: u64,
+    position: u64,
+    state: State,
+}
+
+impl<W: Read + Write + Seek + Send> Mp4Writer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            tracks: Vec::new(),
+            mdat_start: 0,
+            position: 0,
+            state: State::Idle,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = Vec::new();
+        put_file_type(&mut header, b"iso2", &self.tracks);
+        self.mdat_start = header.len() as u64;
+        // The size is filled in when we finish.
+        header.put_u32(1);
+        header.put_bytes(b"mdat");
+        header.put_u64(0);
+
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.position = header.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out
+            .write_all(&(self.position - self.mdat_start).to_be_bytes())?;
+
+        // Chunk offsets move by the size of the moov, which can itself grow
+        // if the offsets no longer fit in 32 bits.
+        let mut moov_len = 0;
+        let moov = loop {
+            let moov = self.build_moov(moov_len)?;
+            if moov.len() as u64 == moov_len {
+                break moov;
+            }
+            moov_len = moov.len() as u64;
+        };
+
+        self.shift_media_data(moov_len)?;
+        self.out.seek(SeekFrom::Start(self.mdat_start))?;
+        self.out.write_all(&moov)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Moves everything from the mdat onwards `distance` bytes later,
+    /// working backwards so nothing is overwritten before it's copied.
+    fn shift_media_data(&mut self, distance: u64) -> Result<()> {
+        const BLOCK_LEN: u64 = 1 << 20;
+        let mut buffer = vec![0; BLOCK_LEN as usize];
+        let mut end = self.position;
+        while end > self.mdat_start {
+            let start = end.saturating_sub(BLOCK_LEN).max(self.mdat_start);
+            let block = &mut buffer[..(end - start) as usize];
+            self.out.seek(SeekFrom::Start(start))?;
+            self.out.read_exact(block)?;
+            self.out.seek(SeekFrom::Start(start + distance))?;
+            self.out.write_all(block)?;
+            end = start;
+        }
+        Ok(())
+    }
+
+    fn build_moov(&self, shift: u64) -> Result<Vec<u8>> {
+        let tracks: Vec<&Track> = self
+            .tracks
+            .iter()
+            .filter(|track| !track.samples.is_empty())
+            .collect();
+        let mut traks = Vec::new();
+        let mut movie_duration = 0;
+        for (index, track) in tracks.iter().enumerate() {
+            let (trak, duration) = build_trak(track, index as u32 + 1, Some(shift))?;
+            traks.extend_from_slice(&trak);
+            movie_duration = movie_duration.max(duration);
+        }
+
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |out| {
+            put_movie_header(out, movie_duration, tracks.len() as u32 + 1);
+            out.put_bytes(&traks);
+        });
+        Ok(moov)
+    }
+}
+
+/// Writes an ftyp box for a file that's compatible with `brand`, and with
+/// AV1 if any of the tracks are.
+pub(super) fn put_file_type(out: &mut Vec<u8>, brand: &[u8; 4], tracks: &[Track]) {
+    write_box(out, b"ftyp", |out| {
+        out.put_bytes(b"isom");
+        out.put_u32(0x200);
+        for brand in [b"isom", brand, b"avc1", b"mp41"] {
+            out.put_bytes(brand);
+        }
+        if tracks
+            .iter()
+            .any(|track| track.video_codec() == Some(VideoCodec::Av1))
+        {
+            out.put_bytes(b"av01");
+        }
+    });
+}
+
+pub(super) fn put_movie_header(out: &mut Vec<u8>, duration: u64, next_track_id: u32) {
+    let version = (duration > u32::MAX as u64) as u8;
+    write_full_box(out, b"mvhd", version, 0, |out| {
+        put_times(out, version);
+        out.put_u32(MOVIE_TIMESCALE);
+        put_duration(out, version, duration);
+        out.put_u32(0x10000);
+        out.put_u16(0x100);
+        out.put_zeros(10);
+        put_matrix(out);
+        out.put_zeros(24);
+        out.put_u32(next_track_id);
+    });
+}
+
+/// Builds a trak box, returning it with its duration in the movie timescale.
+/// Chunk offsets are moved by `shift`. Without one, the sample tables are
+/// left empty for a fragmented file to fill in.
+pub(super) fn build_trak(
+    track: &Track,
+    track_id: u32,
+    shift: Option<u64>,
+) -> Result<(Vec<u8>, u64)> {
+    let timescale = track.timescale();
+    let timing = shift.map(|_| Timing::new(track, None));
+    let edits = timing
+        .as_ref()
+        .map(|timing| timing.edits(timescale))
+        .unwrap_or_default();
+    let media_duration = timing
+        .as_ref()
+        .map(|timing| timing.media_duration)
+        .unwrap_or(0);
+    let duration = if edits.is_empty() {
+        rescale(media_duration, timescale as i64, MOVIE_TIMESCALE as i64) as u64
+    } else {
+        edits.iter().map(|(duration, _)| duration).sum()
+    };
+    let sample_entry = build_sample_entry(track)?;
+    let (width, height) = display_size(track)?;
+    let is_audio = track.kind() == StreamKind::Audio;
+    // Players pick one track out of an alternate group, and only the first
+    // of each kind is enabled, so extra audio tracks are left for editors
+    // instead of being played on top of each other.
+    let alternate_group = is_audio as u16;
+    let flags = if track.number == 0 { 3 } else { 2 };
+    let handler_name = match track.name() {
+        Some(name) => [name.as_bytes(), b"\0"].concat(),
+        None if is_audio => b"SoundHandler\0".to_vec(),
+        None => b"VideoHandler\0".to_vec(),
+    };
+
+    let mut trak = Vec::new();
+    write_box(&mut trak, b"trak", |out| {
+        let version = (duration > u32::MAX as u64) as u8;
+        // In the movie, and enabled unless another track plays instead.
+        write_full_box(out, b"tkhd", version, flags, |out| {
+            put_times(out, version);
+            out.put_u32(track_id);
+            out.put_u32(0);
+            put_duration(out, version, duration);
+            out.put_zeros(8);
+            out.put_u16(0);
+            out.put_u16(alternate_group);
+            out.put_u16(if is_audio { 0x100 } else { 0 });
+            out.put_u16(0);
+            put_matrix(out);
+            out.put_u32(width << 16);
+            out.put_u32(height << 16);
+        });
+        if !edits.is_empty() {
+            write_box(out, b"edts", |out| put_edit_list(out, &edits));
+        }
+        write_box(out, b"mdia", |out| {
+            let media_duration = media_duration as u64;
+            let version = (media_duration > u32::MAX as u64) as u8;
+            write_full_box(out, b"mdhd", version, 0, |out| {
+                put_times(out, version);
+                out.put_u32(timescale);
+                put_duration(out, version, media_duration);
+                out.put_u16(
+                    track
+                        .language()
+                        .map_or(UNDETERMINED_LANGUAGE, packed_language),
+                );
+                out.put_u16(0);
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                out.put_u32(0);
+                out.put_bytes(if is_audio { b"soun" } else { b"vide" });
+                out.put_zeros(12);
+                out.put_bytes(&handler_name);
+            });
+            write_box(out, b"minf", |out| {
+                if is_audio {
+                    write_full_box(out, b"smhd", 0, 0, |out| out.put_u32(0));
+                } else {
+                    write_full_box(out, b"vmhd", 0, 1, |out| out.put_zeros(8));
+                }
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        out.put_u32(1);
+                        // The media is in this file.
+                        write_full_box(out, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        out.put_u32(1);
+                        out.put_bytes(&sample_entry);
+                    });
+                    match (&timing, shift) {
+                        (Some(timing), Some(shift)) => put_sample_tables(out, track, timing, shift),
+                        _ => put_empty_sample_tables(out),
+                    }
+                });
+            });
+        });
+    });
+    Ok((trak, duration))
+}
+
+/// Packs an ISO 639-2 code into the 5 bits per letter of an mdhd.
+fn packed_language(language: &str) -> u16 {
+    language.bytes().take(3).fold(0, |packed, letter| {
+        packed << 5 | (letter - 0x60) as u16 & 0x1f
+    })
+}
+
+/// The body of a video track's avcC, hvcC or av1C.
+pub(super) fn decoder_configuration(track: &Track) -> Result<Vec<u8>> {
+    match track.video_codec() {
+        Some(VideoCodec::Hevc) => {
+            hevc::hevc_decoder_configuration(&track.vps, &track.sps, &track.pps)
+        }
+        Some(VideoCodec::Av1) => {
+            av1::av1_decoder_configuration(track.sequence_header.as_deref().unwrap_or_default())
+        }
+        _ => h264::avc_decoder_configuration(&track.sps, &track.pps),
+    }
+}
+
+pub(super) fn display_size(track: &Track) -> Result<(u32, u32)> {
+    let StreamFormat::Video(format) = &track.format else {
+        return Ok((0, 0));
+    };
+    Ok(match (format.codec, &track.sps, &track.sequence_header) {
+        (VideoCodec::H264, sps, _) if !sps.is_empty() => {
+            let sps = Sps::parse(&sps[0])?;
+            (sps.width, sps.height)
+        }
+        (VideoCodec::Hevc, sps, _) if !sps.is_empty() => {
+            let sps = hevc::Sps::parse(&sps[0])?;
+            (sps.width, sps.height)
+        }
+        (VideoCodec::Av1, _, Some(sequence_header)) => {
+            let obus = av1::split_obus(sequence_header)?;
+            let header = SequenceHeader::parse(obus[0].payload)?;
+            (header.max_width, header.max_height)
+        }
+        _ => (format.width, format.height),
+    })
+}
+
+fn build_sample_entry(track: &Track) -> Result<Vec<u8>> {
+    let mut entry = Vec::new();
+    match &track.format {
+        StreamFormat::Video(format) => {
+            let (kind, config_kind) = match format.codec {
+                VideoCodec::Hevc => (b"hvc1", b"hvcC"),
+                VideoCodec::Av1 => (b"av01", b"av1C"),
+                _ => (b"avc1", b"avcC"),
+            };
+            let config = decoder_configuration(track)?;
+            let (width, height) = display_size(track)?;
+            write_box(&mut entry, kind, |out| {
+                out.put_zeros(6);
+                out.put_u16(1);
+                out.put_zeros(16);
+                out.put_u16(width as u16);
+                out.put_u16(height as u16);
+                // 72 dpi.
+                out.put_u32(0x480000);
+                out.put_u32(0x480000);
+                out.put_u32(0);
+                out.put_u16(1);
+                out.put_zeros(32);
+                out.put_u16(0x18);
+                out.put_u16(0xffff);
+                write_box(out, config_kind, |out| out.put_bytes(&config));
+            });
+        }
+        StreamFormat::Audio(format) => {
+            let mut config = Vec::new();
+            let kind = match format.codec {
+                AudioCodec::Aac => {
+                    let specific =
+                        AudioSpecificConfig::new(format.sample_rate, format.channels).to_bytes()?;
+                    let descriptor = aac::elementary_stream_descriptor(&specific, format.bit_rate);
+                    write_full_box(&mut config, b"esds", 0, 0, |out| out.put_bytes(&descriptor));
+                    b"mp4a"
+                }
+                AudioCodec::Opus => {
+                    let specific = opus::opus_specific_box(format.channels, format.sample_rate)?;
+                    write_box(&mut config, b"dOps", |out| out.put_bytes(&specific));
+                    b"Opus"
+                }
+                AudioCodec::Flac => {
+                    let metadata = flac::metadata(format.sample_rate, format.channels);
+                    write_full_box(&mut config, b"dfLa", 0, 0, |out| out.put_bytes(&metadata));
+                    b"fLaC"
+                }
+                AudioCodec::Pcm => {
+                    write_full_box(&mut config, b"pcmC", 0, 0, |out| {
+                        // Little endian, 16-bit samples
+                        out.put_u8(1);
+                        out.put_u8(16);
+                    });
+                    b"ipcm"
+                }
+            };
+            let sample_rate = match format.codec {
+                AudioCodec::Opus => opus::SAMPLE_RATE,
+                _ => format.sample_rate,
+            };
+            write_box(&mut entry, kind, |out| {
+                out.put_zeros(6);
+                out.put_u16(1);
+                out.put_zeros(8);
+                out.put_u16(format.channels);
+                out.put_u16(16);
+                out.put_zeros(4);
+                // 16.16 fixed point, so rates above 65535 can't be stored here
+                // and decoders go by the codec configuration instead.
+                out.put_u32(sample_rate.min(0xffff) << 16);
+                out.put_bytes(&config);
+            });
+        }
+    }
+    Ok(entry)
+}
+
+fn put_sample_tables(out: &mut Vec<u8>, track: &Track, timing: &Timing, shift: u64) {
+    write_full_box(out, b"stts", 0, 0, |out| {
+        let runs = run_lengths(timing.decode_deltas.iter().copied());
+        out.put_u32(runs.len() as u32);
+        for (count, delta) in runs {
+            out.put_u32(count);
+            out.put_u32(delta);
+        }
+    });
+    if let Some(offsets) = &timing.composition_offsets {
+        write_full_box(out, b"ctts", 0, 0, |out| {
+            let runs = run_lengths(offsets.iter().copied());
+            out.put_u32(runs.len() as u32);
+            for (count, offset) in runs {
+                out.put_u32(count);
+                out.put_u32(offset);
+            }
+        });
+    }
+    if track.samples.iter().any(|sample| !sample.keyframe) {
+        write_full_box(out, b"stss", 0, 0, |out| {
+            let keyframes: Vec<u32> = (1..)
+                .zip(&track.samples)
+                .filter(|(_, sample)| sample.keyframe)
+                .map(|(number, _)| number)
+                .collect();
+            out.put_u32(keyframes.len() as u32);
+            for number in keyframes {
+                out.put_u32(number);
+            }
+        });
+    }
+
+    // A chunk is a run of samples that sit next to each other in the mdat.
+    let mut chunks: Vec<(u64, u32)> = Vec::new();
+    let mut next_offset = None;
+    for sample in &track.samples {
+        match chunks.last_mut() {
+            Some((_, count)) if next_offset == Some(sample.offset) => *count += 1,
+            _ => chunks.push((sample.offset + shift, 1)),
+        }
+        next_offset = Some(sample.offset + sample.size as u64);
+    }
+    write_full_box(out, b"stsc", 0, 0, |out| {
+        let mut entries = Vec::new();
+        for (number, &(_, count)) in (1u32..).zip(&chunks) {
+            if entries.last().map(|&(_, last)| last) != Some(count) {
+                entries.push((number, count));
+            }
+        }
+        out.put_u32(entries.len() as u32);
+        for (first_chunk, samples_per_chunk) in entries {
+            out.put_u32(first_chunk);
+            out.put_u32(samples_per_chunk);
+            out.put_u32(1);
+        }
+    });
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        out.put_u32(0);
+        out.put_u32(track.samples.len() as u32);
+        for sample in &track.samples {
+            out.put_u32(sample.size);
+        }
+    });
+    if chunks
+        .last()
+        .is_some_and(|&(offset, _)| offset > u32::MAX as u64)
+    {
+        write_full_box(out, b"co64", 0, 0, |out| {
+            out.put_u32(chunks.len() as u32);
+            for (offset, _) in &chunks {
+                out.put_u64(*offset);
+            }
+        });
+    } else {
+        write_full_box(out, b"stco", 0, 0, |out| {
+            out.put_u32(chunks.len() as u32);
+            for (offset, _) in &chunks {
+                out.put_u32(*offset as u32);
+            }
+        });
+    }
+}
+
+fn put_empty_sample_tables(out: &mut Vec<u8>) {
+    for kind in [b"stts", b"stsc", b"stco"] {
+        write_full_box(out, kind, 0, 0, |out| out.put_u32(0));
+    }
+    write_full_box(out, b"stsz", 0, 0, |out| out.put_u64(0));
+}
+
+fn put_edit_list(out: &mut Vec<u8>, edits: &[(u64, i64)]) {
+    let version = edits
+        .iter()
+        .any(|&(duration, time)| duration > u32::MAX as u64 || time > i32::MAX as i64)
+        as u8;
+    write_full_box(out, b"elst", version, 0, |out| {
+        out.put_u32(edits.len() as u32);
+        for &(duration, media_time) in edits {
+            if version == 1 {
+                out.put_u64(duration);
+                out.put_u64(media_time as u64);
+            } else {
+                out.put_u32(duration as u32);
+                out.put_u32(media_time as i32 as u32);
+            }
+            out.put_u16(1);
+            out.put_u16(0);
+        }
+    });
+}
+
+fn run_lengths(values: impl Iterator<Item = u32>) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    runs
+}
+
+fn put_times(out: &mut Vec<u8>, version: u8) {
+    if version == 1 {
+        out.put_zeros(16);
+    } else {
+        out.put_zeros(8);
+    }
+}
+
+fn put_duration(out: &mut Vec<u8>, version: u8, duration: u64) {
+    if version == 1 {
+        out.put_u64(duration);
+    } else {
+        out.put_u32(duration as u32);
+    }
+}
+
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in IDENTITY_MATRIX {
+        out.put_u32(value);
+    }
+}
+
+impl<W: Read + Write + Seek + Send> Sink for Mp4Writer<W> {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("Streams must be added before starting."));
+        }
+        add_track(&mut self.tracks, format)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if self.state != State::Idle {
+            return Err(io::Error::other("The MP4 writer was already started."));
+        }
+        self.write_header()?;
+        self.state = State::Writing;
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other("The MP4 writer isn't accepting packets."));
+        }
+        let index = find_track(&self.tracks, &packet)?;
+        let track = &mut self.tracks[index];
+        let data = track.sample_data(&packet)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.out.seek(SeekFrom::Start(self.position))?;
+        self.out.write_all(&data)?;
+        track.samples.push(Sample {
+            offset: self.position,
+            size: data.len() as u32,
+            timestamp: packet.timestamp,
+            duration: packet.duration,
+            keyframe: packet.keyframe,
+        });
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.state != State::Writing {
+            return Err(io::Error::other("The MP4 writer isn't running."));
+        }
+        self.state = State::Finished;
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::Mp4Writer;
+    use crate::{
+        mux::{
+            aac::AudioSpecificConfig,
+            av1,
+            bmff::{boxes, find_box, ByteReader, Mp4Box},
+            h264::{test_util, Sps},
+            hevc, opus,
+        },
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            flac::{self, test_util::decode_frame, FlacEncoder},
+            AudioBuffer, AudioCodec, AudioStreamFormat, Encoder, Sink, StreamFormat, VideoCodec,
+            VideoStreamFormat,
+        },
+    };
+
+    const FRAME: i64 = 10_000_000 / 60;
+    const AAC_FRAME: i64 = 1024 * 10_000_000 / 48000;
+
+    fn video_format() -> StreamFormat {
+        StreamFormat::Video(VideoStreamFormat {
+            codec: VideoCodec::H264,
+            width: 1280,
+            height: 720,
+            frame_rate: 60,
+            bit_rate: 8_000_000,
+        })
+    }
+
+    fn audio_format() -> StreamFormat {
+        StreamFormat::Audio(AudioStreamFormat {
+            codec: AudioCodec::Aac,
+            sample_rate: 48000,
+            channels: 2,
+            bit_rate: 192_000,
+            name: None,
+            language: None,
+        })
+    }
+
+    fn slice(index: usize, keyframe: bool) -> Vec<u8> {
+        let header = if keyframe { 0x65 } else { 0x41 };
+        let mut slice = vec![header];
+        slice.extend((0..20 + index).map(|byte| byte as u8 | 0x80));
+        slice
+    }
+
+    /// An Annex B access unit like the ones hardware encoders produce.
+    fn annex_b_frame(index: usize, keyframe: bool) -> Vec<u8> {
+        let mut units = vec![vec![0x09, 0xf0]];
+        if keyframe {
+            units.push(test_util::sps(100, 1280, 720));
+            units.push(test_util::pps());
+        }
+        units.push(slice(index, keyframe));
+        let mut data = Vec::new();
+        for unit in units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend(unit);
+        }
+        data
+    }
+
+    fn avcc_frame(index: usize, keyframe: bool) -> Vec<u8> {
+        let mut units = Vec::new();
+        if keyframe {
+            units.push(test_util::sps(100, 1280, 720));
+            units.push(test_util::pps());
+        }
+        units.push(slice(index, keyframe));
+        let mut data = Vec::new();
+        for unit in units {
+            data.extend_from_slice(&(unit.len() as u32).to_be_bytes());
+            data.extend(unit);
+        }
+        data
+    }
+
+    fn video_packets(frame: fn(usize, bool) -> Vec<u8>, count: usize) -> Vec<EncodedPacket> {
+        (0..count)
+            .map(|index| {
+                let keyframe = index % 4 == 0;
+                EncodedPacket::new(
+                    StreamKind::Video,
+                    frame(index, keyframe),
+                    index as i64 * FRAME,
+                    FRAME,
+                    keyframe,
+                )
+            })
+            .collect()
+    }
+
+    fn audio_packets(start: i64, count: usize) -> Vec<EncodedPacket> {
+        (0..count)
+            .map(|index| {
+                let mut data = vec![0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc];
+                data.extend((0..10).map(|byte| byte + index as u8));
+                EncodedPacket::new(
+                    StreamKind::Audio,
+                    data,
+                    start + rescaled(index as i64 * 1024),
+                    AAC_FRAME,
+                    true,
+                )
+            })
+            .collect()
+    }
+
+    fn rescaled(frames: i64) -> i64 {
+        (frames * 10_000_000 + 24000) / 48000
+    }
+
+    fn write_file(formats: &[StreamFormat], packets: Vec<EncodedPacket>) -> Vec<u8> {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()));
+        for format in formats {
+            writer.add_stream(format.clone()).unwrap();
+        }
+        writer.start().unwrap();
+        for packet in packets {
+            writer.write(packet).unwrap();
+        }
+        writer.stop().unwrap();
+        writer.into_inner().into_inner()
+    }
+
+    fn interleaved(video: Vec<EncodedPacket>, audio: Vec<EncodedPacket>) -> Vec<EncodedPacket> {
+        let mut packets: Vec<_> = video.into_iter().chain(audio).collect();
+        packets.sort_by_key(|packet| packet.timestamp);
+        packets
+    }
+
+    fn trak(file: &[u8], index: usize) -> Mp4Box<'_> {
+        let moov = find_box(file, &[b"moov"]).unwrap();
+        boxes(moov.body)
+            .filter(|found| &found.kind == b"trak")
+            .nth(index)
+            .unwrap()
+    }
+
+    fn stbl<'a>(trak: &Mp4Box<'a>, kind: &[u8; 4]) -> Option<ByteReader<'a>> {
+        let found = find_box(trak.body, &[b"mdia", b"minf", b"stbl", kind])?;
+        let mut reader = ByteReader::new(found.body);
+        reader.u32();
+        Some(reader)
+    }
+
+    fn table(reader: &mut ByteReader, columns: usize) -> Vec<Vec<u32>> {
+        let count = reader.u32();
+        (0..count)
+            .map(|_| (0..columns).map(|_| reader.u32()).collect())
+            .collect()
+    }
+
+    /// Reads the sample tables back into the bytes of each sample.
+    fn samples<'a>(file: &'a [u8], trak: &Mp4Box) -> Vec<&'a [u8]> {
+        let mut stsz = stbl(trak, b"stsz").unwrap();
+        assert_eq!(stsz.u32(), 0);
+        let sizes: Vec<_> = (0..stsz.u32()).map(|_| stsz.u32() as usize).collect();
+        let stsc = table(&mut stbl(trak, b"stsc").unwrap(), 3);
+        let offsets: Vec<u64> = match stbl(trak, b"stco") {
+            Some(mut stco) => (0..stco.u32()).map(|_| stco.u32() as u64).collect(),
+            None => {
+                let mut co64 = stbl(trak, b"co64").unwrap();
+                (0..co64.u32()).map(|_| co64.u64()).collect()
+            }
+        };
+
+        let mut samples = Vec::new();
+        let mut sizes = sizes.into_iter();
+        for (chunk, &offset) in offsets.iter().enumerate() {
+            let entry = stsc
+                .iter()
+                .rev()
+                .find(|entry| entry[0] as usize <= chunk + 1)
+                .unwrap();
+            let mut offset = offset as usize;
+            for _ in 0..entry[1] {
+                let size = sizes.next().unwrap();
+                samples.push(&file[offset..offset + size]);
+                offset += size;
+            }
+        }
+        assert!(sizes.next().is_none());
+        samples
+    }
+
+    #[test]
+    fn moov_is_moved_before_mdat() {
+        let file = write_file(
+            &[video_format(), audio_format()],
+            interleaved(video_packets(annex_b_frame, 8), audio_packets(0, 8)),
+        );
+        let kinds: Vec<_> = boxes(&file).map(|found| found.kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov", *b"mdat"]);
+        let mdat = boxes(&file).last().unwrap();
+        assert_eq!(mdat.offset + 16 + mdat.body.len(), file.len());
+    }
+
+    #[test]
+    fn sample_tables_point_at_samples() {
+        let video = video_packets(annex_b_frame, 10);
+        let audio = audio_packets(0, 12);
+        let file = write_file(
+            &[video_format(), audio_format()],
+            interleaved(video, audio.clone()),
+        );
+
+        let video_samples = samples(&file, &trak(&file, 0));
+        assert_eq!(video_samples.len(), 10);
+        for (index, sample) in video_samples.iter().enumerate() {
+            // Parameter sets and delimiters are stripped from the samples.
+            let slice = slice(index, index % 4 == 0);
+            assert_eq!(&sample[..4], &(slice.len() as u32).to_be_bytes());
+            assert_eq!(&sample[4..], &slice[..]);
+        }
+
+        let audio_samples = samples(&file, &trak(&file, 1));
+        assert_eq!(audio_samples.len(), 12);
+        for (sample, packet) in audio_samples.iter().zip(&audio) {
+            assert_eq!(*sample, &packet.data[7..]);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_input_gives_the_same_file() {
+        let annex_b = write_file(&[video_format()], video_packets(annex_b_frame, 6));
+        let avcc = write_file(&[video_format()], video_packets(avcc_frame, 6));
+        assert_eq!(annex_b, avcc);
+    }
+
+    #[test]
+    fn sample_entries_describe_the_streams() {
+        let file = write_file(
+            &[video_format(), audio_format()],
+            interleaved(video_packets(annex_b_frame, 4), audio_packets(0, 4)),
+        );
+
+        let stsd = |index| {
+            let trak = trak(&file, index);
+            let stsd = find_box(trak.body, &[b"mdia", b"minf", b"stbl", b"stsd"]).unwrap();
+            // Skip the version, flags and entry count.
+            boxes(&stsd.body[8..]).next().unwrap()
+        };
+
+        let avc1 = stsd(0);
+        assert_eq!(&avc1.kind, b"avc1");
+        let mut reader = ByteReader::new(avc1.body);
+        reader.bytes(24);
+        assert_eq!((reader.u16(), reader.u16()), (1280, 720));
+        let avcc = find_box(&avc1.body[78..], &[b"avcC"]).unwrap();
+        let mut reader = ByteReader::new(avcc.body);
+        assert_eq!(reader.bytes(4), [1, 100, 0, 40]);
+        reader.bytes(2);
+        let sps_len = reader.u16() as usize;
+        let sps = Sps::parse(reader.bytes(sps_len)).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+
+        let mp4a = stsd(1);
+        assert_eq!(&mp4a.kind, b"mp4a");
+        let mut reader = ByteReader::new(mp4a.body);
+        reader.bytes(16);
+        assert_eq!(reader.u16(), 2);
+        let esds = find_box(&mp4a.body[28..], &[b"esds"]).unwrap();
+        // The decoder specific info is the last descriptor before the SL
+        // config, and holds the AudioSpecificConfig.
+        let config = &esds.body[esds.body.len() - 8..esds.body.len() - 6];
+        assert_eq!(
+            AudioSpecificConfig::parse(config).unwrap(),
+            AudioSpecificConfig::new(48000, 2)
+        );
+    }
+
+    #[test]
+    fn timing_tables_match_timestamps() {
+        let file = write_file(
+            &[video_format(), audio_format()],
+            interleaved(video_packets(annex_b_frame, 9), audio_packets(0, 9)),
+        );
+
+        let video = trak(&file, 0);
+        let stts = table(&mut stbl(&video, b"stts").unwrap(), 2);
+        assert_eq!(stts, [vec![9, 1500]]);
+        let stss = table(&mut stbl(&video, b"stss").unwrap(), 1);
+        assert_eq!(stss, [vec![1], vec![5], vec![9]]);
+        assert!(stbl(&video, b"ctts").is_none());
+
+        let audio = trak(&file, 1);
+        let stts = table(&mut stbl(&audio, b"stts").unwrap(), 2);
+        assert_eq!(stts, [vec![9, 1024]]);
+        assert!(stbl(&audio, b"stss").is_none());
+
+        let mdhd = find_box(audio.body, &[b"mdia", b"mdhd"]).unwrap();
+        let mut reader = ByteReader::new(mdhd.body);
+        reader.bytes(12);
+        assert_eq!(reader.u32(), 48000);
+        assert_eq!(reader.u32(), 9 * 1024);
+    }
+
+    #[test]
+    fn late_tracks_start_with_an_empty_edit() {
+        let file = write_file(
+            &[video_format(), audio_format()],
+            interleaved(video_packets(annex_b_frame, 4), audio_packets(5_000_000, 4)),
+        );
+        assert!(find_box(trak(&file, 0).body, &[b"edts"]).is_none());
+
+        let elst = find_box(trak(&file, 1).body, &[b"edts", b"elst"]).unwrap();
+        let mut reader = ByteReader::new(elst.body);
+        reader.u32();
+        assert_eq!(reader.u32(), 2);
+        assert_eq!(reader.u32(), 500);
+        assert_eq!(reader.u32() as i32, -1);
+        reader.u32();
+        let duration = reader.u32();
+        assert_eq!(duration, (4 * 1024 * 1000 + 24000) / 48000);
+        assert_eq!(reader.u32(), 0);
+    }
+
+    #[test]
+    fn reordered_frames_get_composition_offsets() {
+        // I P B, presented as I B P.
+        let order = [0, 2, 1];
+        let packets = order
+            .iter()
+            .enumerate()
+            .map(|(index, &position)| {
+                EncodedPacket::new(
+                    StreamKind::Video,
+                    annex_b_frame(index, index == 0),
+                    position * FRAME,
+                    FRAME,
+                    index == 0,
+                )
+            })
+            .collect();
+        let file = write_file(&[video_format()], packets);
+
+        let video = trak(&file, 0);
+        let ctts = table(&mut stbl(&video, b"ctts").unwrap(), 2);
+        assert_eq!(ctts, [vec![1, 1500], vec![1, 3000], vec![1, 0]]);
+        let elst = find_box(video.body, &[b"edts", b"elst"]).unwrap();
+        let mut reader = ByteReader::new(elst.body);
+        reader.bytes(12);
+        assert_eq!(reader.u32(), 1500);
+    }
+
+    #[test]
+    fn writes_a_named_track_per_audio_stream() {
+        let named = |name: &str, language: Option<&str>| match audio_format() {
+            StreamFormat::Audio(format) => StreamFormat::Audio(AudioStreamFormat {
+                name: Some(name.to_string()),
+                language: language.map(str::to_string),
+                ..format
+            }),
+            StreamFormat::Video(_) => unreachable!(),
+        };
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()));
+        assert_eq!(writer.add_stream(video_format()).unwrap(), 0);
+        assert_eq!(writer.add_stream(named("Mixed", None)).unwrap(), 0);
+        assert_eq!(
+            writer.add_stream(named("Microphone", Some("eng"))).unwrap(),
+            1
+        );
+        writer.start().unwrap();
+        let microphone: Vec<_> = audio_packets(0, 6)
+            .into_iter()
+            .map(|mut packet| {
+                packet.stream = 1;
+                packet
+            })
+            .collect();
+        let packets = interleaved(video_packets(annex_b_frame, 4), audio_packets(0, 3));
+        for packet in interleaved(packets, microphone.clone()) {
+            writer.write(packet).unwrap();
+        }
+        let mut stray = microphone[0].clone();
+        stray.stream = 2;
+        assert!(writer.write(stray).is_err());
+        writer.stop().unwrap();
+        let file = writer.into_inner().into_inner();
+
+        assert_eq!(samples(&file, &trak(&file, 1)).len(), 3);
+        let microphone_samples = samples(&file, &trak(&file, 2));
+        assert_eq!(microphone_samples.len(), 6);
+        assert_eq!(microphone_samples[5], &microphone[5].data[7..]);
+
+        let tracks = [(1, "Mixed", 0x55c4, true), (2, "Microphone", 0x15c7, false)];
+        for (index, name, language, enabled) in tracks {
+            let trak = trak(&file, index);
+            let hdlr = find_box(trak.body, &[b"mdia", b"hdlr"]).unwrap();
+            assert_eq!(&hdlr.body[24..], [name.as_bytes(), b"\0"].concat());
+            // Packed ISO 639-2, "und" when it isn't known
+            let mdhd = find_box(trak.body, &[b"mdia", b"mdhd"]).unwrap();
+            assert_eq!(u16::from_be_bytes([mdhd.body[20], mdhd.body[21]]), language);
+            // Both audio tracks are alternatives, and only the first plays.
+            let tkhd = find_box(trak.body, &[b"tkhd"]).unwrap();
+            assert_eq!(tkhd.body[3] & 1 == 1, enabled);
+            assert_eq!(&tkhd.body[34..36], [0, 1]);
+        }
+        let video = trak(&file, 0);
+        let hdlr = find_box(video.body, &[b"mdia", b"hdlr"]).unwrap();
+        assert_eq!(&hdlr.body[24..], b"VideoHandler\0");
+    }
+
+    fn sample_entry(file: &[u8], index: usize) -> Mp4Box<'_> {
+        let trak = trak(file, index);
+        let stsd = find_box(trak.body, &[b"mdia", b"minf", b"stbl", b"stsd"]).unwrap();
+        boxes(&stsd.body[8..]).next().unwrap()
+    }
+
+    fn codec_format(codec: VideoCodec) -> StreamFormat {
+        StreamFormat::Video(VideoStreamFormat {
+            codec,
+            width: 1920,
+            height: 1080,
+            frame_rate: 60,
+            bit_rate: 20_000_000,
+        })
+    }
+
+    fn hevc_slice(index: usize, keyframe: bool) -> Vec<u8> {
+        // IDR_W_RADL or TRAIL_R.
+        let mut slice = vec![if keyframe { 19 << 1 } else { 1 << 1 }, 1];
+        slice.extend((0..20 + index).map(|byte| byte as u8 | 0x80));
+        slice
+    }
+
+    fn hevc_frame(index: usize, keyframe: bool) -> Vec<u8> {
+        let mut units = vec![vec![hevc::NAL_AUD << 1, 1, 0x50]];
+        if keyframe {
+            units.push(hevc::test_util::vps());
+            units.push(hevc::test_util::sps(1, 1920, 1080, 1));
+            units.push(hevc::test_util::pps());
+        }
+        units.push(hevc_slice(index, keyframe));
+        let mut data = Vec::new();
+        for unit in units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend(unit);
+        }
+        data
+    }
+
+    fn av1_frame_obu(index: usize) -> Vec<u8> {
+        // OBU_FRAME
+        av1::test_util::obu(6, &vec![index as u8; 20 + index])
+    }
+
+    fn av1_frame(index: usize, keyframe: bool) -> Vec<u8> {
+        let mut data = av1::test_util::obu(av1::OBU_TEMPORAL_DELIMITER, &[]);
+        if keyframe {
+            data.extend(av1::test_util::sequence_header(0, 8, 1920, 1080));
+        }
+        data.extend(av1_frame_obu(index));
+        data
+    }
+
+    #[test]
+    fn hevc_tracks_get_an_hvcc() {
+        let file = write_file(
+            &[codec_format(VideoCodec::Hevc)],
+            video_packets(hevc_frame, 5),
+        );
+
+        let hvc1 = sample_entry(&file, 0);
+        assert_eq!(&hvc1.kind, b"hvc1");
+        let mut reader = ByteReader::new(hvc1.body);
+        reader.bytes(24);
+        assert_eq!((reader.u16(), reader.u16()), (1920, 1080));
+        let hvcc = find_box(&hvc1.body[78..], &[b"hvcC"]).unwrap();
+        let mut reader = ByteReader::new(hvcc.body);
+        assert_eq!(reader.bytes(2), [1, 1]);
+        reader.bytes(20);
+        assert_eq!(reader.bytes(1), [3]);
+        for nal_type in [hevc::NAL_VPS, hevc::NAL_SPS, hevc::NAL_PPS] {
+            assert_eq!(reader.bytes(1), [0x80 | nal_type]);
+            assert_eq!(reader.u16(), 1);
+            let len = reader.u16() as usize;
+            let unit = reader.bytes(len);
+            if nal_type == hevc::NAL_SPS {
+                let sps = hevc::Sps::parse(unit).unwrap();
+                assert_eq!((sps.width, sps.height), (1920, 1080));
+            }
+        }
+
+        let samples = samples(&file, &trak(&file, 0));
+        assert_eq!(samples.len(), 5);
+        for (index, sample) in samples.iter().enumerate() {
+            let slice = hevc_slice(index, index % 4 == 0);
+            assert_eq!(&sample[..4], &(slice.len() as u32).to_be_bytes());
+            assert_eq!(&sample[4..], &slice[..]);
+        }
+    }
+
+    #[test]
+    fn av1_tracks_get_an_av1c() {
+        let file = write_file(
+            &[codec_format(VideoCodec::Av1)],
+            video_packets(av1_frame, 5),
+        );
+
+        let ftyp = boxes(&file).next().unwrap();
+        assert!(ftyp.body.chunks(4).any(|brand| brand == b"av01"));
+
+        let av01 = sample_entry(&file, 0);
+        assert_eq!(&av01.kind, b"av01");
+        let mut reader = ByteReader::new(av01.body);
+        reader.bytes(24);
+        assert_eq!((reader.u16(), reader.u16()), (1920, 1080));
+        let av1c = find_box(&av01.body[78..], &[b"av1C"]).unwrap();
+        assert_eq!(av1c.body[..2], [0x81, 8]);
+        let sequence_header = av1::test_util::sequence_header(0, 8, 1920, 1080);
+        assert_eq!(&av1c.body[4..], &sequence_header[..]);
+
+        // Temporal delimiters are left out, and keyframes keep their
+        // sequence header.
+        let samples = samples(&file, &trak(&file, 0));
+        assert_eq!(samples.len(), 5);
+        for (index, sample) in samples.iter().enumerate() {
+            let mut expected = Vec::new();
+            if index % 4 == 0 {
+                expected.extend_from_slice(&sequence_header);
+            }
+            expected.extend(av1_frame_obu(index));
+            assert_eq!(*sample, &expected[..]);
+        }
+    }
+
+    #[test]
+    fn flac_tracks_decode_back() {
+        let input: Vec<i16> = (0..5000 * 2)
+            .map(|index| ((index / 2) as f32 * 0.01).sin() * (8000.0 + index as f32))
+            .map(|sample| sample as i16)
+            .collect();
+        let mut encoder = FlacEncoder::new(48000, 2).unwrap();
+        let mut packets = encoder
+            .encode(AudioBuffer {
+                data: input
+                    .iter()
+                    .flat_map(|sample| sample.to_le_bytes())
+                    .collect(),
+                frames: 5000,
+                timestamp: 0,
+                duration: rescaled(5000),
+            })
+            .unwrap();
+        packets.extend(encoder.drain().unwrap());
+        let file = write_file(&[encoder.stream_format()], packets);
+
+        let entry = sample_entry(&file, 0);
+        assert_eq!(&entry.kind, b"fLaC");
+        let dfla = find_box(&entry.body[28..], &[b"dfLa"]).unwrap();
+        assert_eq!(dfla.body[4..8], [0x80, 0, 0, 34]);
+        assert_eq!(&dfla.body[8..], &flac::stream_info(48000, 2)[..]);
+
+        let track = trak(&file, 0);
+        let stts = table(&mut stbl(&track, b"stts").unwrap(), 2);
+        assert_eq!(stts, [vec![1, 4096], vec![1, 904]]);
+        let decoded: Vec<i16> = samples(&file, &track)
+            .iter()
+            .flat_map(|sample| decode_frame(sample, 2).samples)
+            .collect();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn other_audio_codecs_get_their_sample_entries() {
+        let entry = |codec, channels| {
+            let format = StreamFormat::Audio(AudioStreamFormat {
+                codec,
+                sample_rate: 48000,
+                channels,
+                bit_rate: 128_000,
+                name: None,
+                language: None,
+            });
+            let packets = (0..4)
+                .map(|index| {
+                    let data = vec![index as u8; 40];
+                    EncodedPacket::new(StreamKind::Audio, data, index * 200_000, 200_000, true)
+                })
+                .collect();
+            let file = write_file(&[format], packets);
+            let track = trak(&file, 0);
+            let stored: Vec<_> = samples(&file, &track)
+                .iter()
+                .map(|sample| sample[0])
+                .collect();
+            assert_eq!(stored, [0, 1, 2, 3]);
+            let entry = sample_entry(&file, 0);
+            let mut reader = ByteReader::new(entry.body);
+            reader.bytes(16);
+            assert_eq!(reader.u16(), channels);
+            reader.bytes(6);
+            assert_eq!(reader.u32(), 48000 << 16);
+            let config = boxes(&entry.body[28..]).next().unwrap();
+            (entry.kind, config.kind, config.body.to_vec())
+        };
+
+        let opus = opus::opus_specific_box(2, 48000).unwrap();
+        assert_eq!(entry(AudioCodec::Opus, 2), (*b"Opus", *b"dOps", opus));
+        let pcm = vec![0, 0, 0, 0, 1, 16];
+        assert_eq!(entry(AudioCodec::Pcm, 1), (*b"ipcm", *b"pcmC", pcm));
+
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()));
+        let surround = StreamFormat::Audio(AudioStreamFormat {
+            codec: AudioCodec::Opus,
+            sample_rate: 48000,
+            channels: 6,
+            bit_rate: 256_000,
+            name: None,
+            language: None,
+        });
+        writer.add_stream(surround).unwrap();
+        writer.start().unwrap();
+        let packet = EncodedPacket::new(StreamKind::Audio, vec![0; 10], 0, 200_000, true);
+        writer.write(packet).unwrap();
+        assert!(writer.stop().is_err());
+    }
+
+    #[test]
+    fn rejects_misuse() {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()));
+        let raw = StreamFormat::Video(VideoStreamFormat {
+            codec: VideoCodec::Raw,
+            width: 2,
+            height: 2,
+            frame_rate: 30,
+            bit_rate: 0,
+        });
+        assert!(writer.add_stream(raw).is_err());
+        let packet = EncodedPacket::new(StreamKind::Audio, vec![1], 0, 1, true);
+        assert!(writer.write(packet.clone()).is_err());
+        writer.add_stream(video_format()).unwrap();
+        writer.start().unwrap();
+        assert!(writer.add_stream(audio_format()).is_err());
+        assert!(writer.write(packet).is_err());
+        writer.stop().unwrap();
+        assert!(writer.stop().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Opus stream configuration. Opus always decodes at 48 kHz, and encoders
+//! start with some lookahead that players skip, whatever the input rate was.
+
+use std::io;
+
+use super::bmff::PutBytes;
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// The samples decoders drop from the start of the stream. Encoders built on
+/// libopus have 6.5 ms of lookahead, and Media Foundation doesn't say.
+pub const PRE_SKIP: u16 = 312;
+
+/// Builds the body of a dOps box. Only mono and stereo can use channel
+/// mapping family 0, which needs no mapping table.
+pub fn opus_specific_box(channels: u16, input_sample_rate: u32) -> io::Result<Vec<u8>> {
+    check_channels(channels)?;
+    let mut out = Vec::new();
+    // Version
+    out.put_u8(0);
+    out.put_u8(channels as u8);
+    out.put_u16(PRE_SKIP);
+    out.put_u32(input_sample_rate);
+    // No output gain, and mapping family 0
+    out.put_u16(0);
+    out.put_u8(0);
+    Ok(out)
+}
+
+/// Builds the OpusHead packet Matroska and Ogg streams start with. It holds
+/// the same as a dOps, but little endian and with a different version.
+pub fn opus_head(channels: u16, input_sample_rate: u32) -> io::Result<Vec<u8>> {
+    check_channels(channels)?;
+    let mut out = b"OpusHead".to_vec();
+    out.push(1);
+    out.push(channels as u8);
+    out.extend_from_slice(&PRE_SKIP.to_le_bytes());
+    out.extend_from_slice(&input_sample_rate.to_le_bytes());
+    // No output gain, and mapping family 0
+    out.extend_from_slice(&[0, 0, 0]);
+    Ok(out)
+}
+
+fn check_channels(channels: u16) -> io::Result<()> {
+    if !(1..=2).contains(&channels) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Opus streams can only have one or two channels.",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{opus_head, opus_specific_box};
+
+    #[test]
+    fn describes_mono_and_stereo() {
+        assert_eq!(
+            opus_specific_box(2, 48000).unwrap(),
+            [0, 2, 0x01, 0x38, 0, 0, 0xbb, 0x80, 0, 0, 0]
+        );
+        assert_eq!(opus_specific_box(1, 44100).unwrap()[1], 1);
+        assert!(opus_specific_box(6, 48000).is_err());
+    }
+
+    #[test]
+    fn heads_are_little_endian() {
+        let head = opus_head(2, 44100).unwrap();
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[8..], [1, 2, 0x38, 0x01, 0x44, 0xac, 0, 0, 0, 0, 0]);
+        assert!(opus_head(0, 48000).is_err());
+    }
+}
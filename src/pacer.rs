@@ -0,0 +1,344 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::clock::HNS_PER_SECOND;
+
+/// How captured frames are turned into the frames that get encoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pacing {
+    /// Caps the frame rate by dropping frames that arrive before the next
+    /// frame is due. Frames keep the time they were captured at.
+    Drop,
+    /// Constant frame rate. Frames are snapped to a fixed grid, and the last
+    /// frame is repeated when nothing new arrives in time.
+    Cfr,
+    /// Every captured frame is encoded at the time it was captured.
+    Vfr,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParsePacingError(&'static str);
+
+impl FromStr for Pacing {
+    type Err = ParsePacingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(Pacing::Drop),
+            "cfr" => Ok(Pacing::Cfr),
+            "vfr" => Ok(Pacing::Vfr),
+            _ => Err(ParsePacingError(
+                "Invalid pacing value! Expecting: drop, cfr, or vfr.",
+            )),
+        }
+    }
+}
+
+impl Display for Pacing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Pacing::Drop => "drop",
+            Pacing::Cfr => "cfr",
+            Pacing::Vfr => "vfr",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParsePacingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParsePacingError {}
+
+/// What to do with a captured frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PacedFrame {
+    /// Times at which the previous frame has to be encoded again, because
+    /// nothing new was captured for those slots. Always empty unless
+    /// pacing for a constant frame rate.
+    pub repeats: Vec<i64>,
+    /// The time to encode this frame at, or `None` if it is dropped.
+    pub timestamp: Option<i64>,
+}
+
+/// What the pacer has done so far.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PacerStats {
+    pub captured: u64,
+    /// Includes the repeated frames.
+    pub output: u64,
+    pub dropped: u64,
+    pub repeated: u64,
+    /// How far the last output frame was moved from the time it was
+    /// captured at, in 100ns units. Positive means later.
+    pub drift: i64,
+    /// The largest drift seen so far, either way.
+    pub max_drift: i64,
+}
+
+impl Display for PacerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} frames captured, {} encoded ({} dropped, {} repeated), max drift {:.1} ms",
+            self.captured,
+            self.output,
+            self.dropped,
+            self.repeated,
+            self.max_drift as f64 / 10_000.0
+        )
+    }
+}
+
+/// Decides which captured frames get encoded, and when, for a target frame
+/// rate. Timestamps are in 100ns units, relative to the start of the
+/// recording, and slot `n` is due at `n` frame periods. Slot times are
+/// worked out from the frame rate each time, so a period that doesn't divide
+/// evenly into 100ns units doesn't add up to drift over a long recording.
+///
+/// A frame belongs to the slot closest to its timestamp, so frames that
+/// arrive a little early or late with jitter still land in their own slot.
+/// If frames arrive several periods late, the pacer picks up at the slot
+/// the late frame belongs to instead of trying to fill the ones in between
+/// with whatever comes next, so the effective frame rate doesn't sag.
+pub struct FramePacer {
+    pacing: Pacing,
+    frame_rate: i64,
+    next_slot: Option<i64>,
+    last_timestamp: Option<i64>,
+    stats: PacerStats,
+}
+
+impl FramePacer {
+    pub fn new(pacing: Pacing, frame_rate: u32) -> Self {
+        Self {
+            pacing,
+            frame_rate: frame_rate as i64,
+            next_slot: None,
+            last_timestamp: None,
+            stats: PacerStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PacerStats {
+        self.stats
+    }
+
+    /// Paces the next captured frame. Timestamps must not go backwards.
+    pub fn pace(&mut self, timestamp: i64) -> PacedFrame {
+        self.stats.captured += 1;
+        let paced = match self.pacing {
+            Pacing::Vfr => {
+                // Encoders can't take two frames with the same time.
+                if self.last_timestamp.is_some_and(|last| timestamp <= last) {
+                    PacedFrame::default()
+                } else {
+                    PacedFrame {
+                        repeats: Vec::new(),
+                        timestamp: Some(timestamp),
+                    }
+                }
+            }
+            Pacing::Drop | Pacing::Cfr => self.pace_to_slots(timestamp),
+        };
+
+        match paced.timestamp {
+            Some(output_time) => {
+                self.stats.output += paced.repeats.len() as u64 + 1;
+                self.stats.repeated += paced.repeats.len() as u64;
+                self.stats.drift = output_time - timestamp;
+                if self.stats.drift.abs() > self.stats.max_drift.abs() {
+                    self.stats.max_drift = self.stats.drift;
+                }
+                self.last_timestamp = Some(output_time);
+            }
+            None => self.stats.dropped += 1,
+        }
+        paced
+    }
+
+    fn pace_to_slots(&mut self, timestamp: i64) -> PacedFrame {
+        let slot = (timestamp * self.frame_rate + HNS_PER_SECOND / 2).div_euclid(HNS_PER_SECOND);
+        let next_slot = self.next_slot.unwrap_or(slot);
+        if slot < next_slot {
+            // The slot already has a frame.
+            return PacedFrame::default();
+        }
+        self.next_slot = Some(slot + 1);
+
+        if self.pacing == Pacing::Drop {
+            // Only keep the frame's own time if that doesn't put it at or
+            // before the previous one, which can happen within a slot.
+            let timestamp = match self.last_timestamp {
+                Some(last) if timestamp <= last => last + 1,
+                _ => timestamp,
+            };
+            return PacedFrame {
+                repeats: Vec::new(),
+                timestamp: Some(timestamp),
+            };
+        }
+
+        // Nothing to repeat before the first frame.
+        let repeats = match self.last_timestamp {
+            Some(_) => (next_slot..slot).map(|slot| self.slot_time(slot)).collect(),
+            None => Vec::new(),
+        };
+        PacedFrame {
+            repeats,
+            timestamp: Some(self.slot_time(slot)),
+        }
+    }
+
+    fn slot_time(&self, slot: i64) -> i64 {
+        slot * HNS_PER_SECOND / self.frame_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FramePacer, Pacing};
+
+    const MILLISECOND: i64 = 10_000;
+    const SECOND: i64 = 10_000_000;
+    const FRAME: i64 = SECOND / 30;
+
+    fn slot_time(slot: usize) -> i64 {
+        slot as i64 * SECOND / 30
+    }
+
+    /// Runs timestamps through a pacer, returning every output time and the
+    /// number of the captured frame shown at that time.
+    fn run(pacer: &mut FramePacer, timestamps: &[i64]) -> Vec<(i64, usize)> {
+        let mut output = Vec::new();
+        for (index, &timestamp) in timestamps.iter().enumerate() {
+            let paced = pacer.pace(timestamp);
+            for repeat in paced.repeats {
+                // Repeats show the last frame that made it out.
+                let (_, shown) = *output.last().unwrap();
+                output.push((repeat, shown));
+            }
+            if let Some(timestamp) = paced.timestamp {
+                output.push((timestamp, index));
+            }
+        }
+        output
+    }
+
+    /// A 60fps source with up to 3ms of jitter either way.
+    fn jittery_60fps(seconds: i64) -> Vec<i64> {
+        let jitter = [0, 3, -2, 1, -3, 2, -1];
+        (0..seconds * 60)
+            .map(|index| {
+                (index * SECOND / 60 + jitter[index as usize % jitter.len()] * MILLISECOND).max(0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_caps_the_frame_rate() {
+        let mut pacer = FramePacer::new(Pacing::Drop, 30);
+        let output = run(&mut pacer, &jittery_60fps(2));
+        assert_eq!(output.len(), 60);
+        assert!(output.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let stats = pacer.stats();
+        assert_eq!(stats.captured, 120);
+        assert_eq!(stats.output, 60);
+        assert_eq!(stats.dropped, 60);
+        assert_eq!(stats.repeated, 0);
+        assert_eq!(stats.max_drift, 0);
+    }
+
+    #[test]
+    fn drop_recovers_from_a_stall() {
+        let mut pacer = FramePacer::new(Pacing::Drop, 30);
+        // Nothing gets captured for half a second in the middle.
+        let timestamps: Vec<i64> = (0..120)
+            .map(|index| index * SECOND / 60)
+            .filter(|&timestamp| !(SECOND / 2..SECOND).contains(&timestamp))
+            .collect();
+        let output = run(&mut pacer, &timestamps);
+        // Straight back to 30fps afterwards, rather than letting every frame
+        // through until the missed slots are made up.
+        let after: Vec<_> = output
+            .iter()
+            .filter(|&&(timestamp, _)| timestamp >= SECOND)
+            .collect();
+        assert_eq!(after.len(), 30);
+        assert_eq!(output.len(), 45);
+        assert_eq!(pacer.stats().repeated, 0);
+    }
+
+    #[test]
+    fn cfr_fills_gaps_with_repeats() {
+        let mut pacer = FramePacer::new(Pacing::Cfr, 30);
+        // A frame every 100ms, a third of the target rate.
+        let timestamps: Vec<i64> = (0..10).map(|index| index * SECOND / 10).collect();
+        let output = run(&mut pacer, &timestamps);
+
+        // Every slot up to the last frame is filled exactly once.
+        assert_eq!(output.len(), 28);
+        for (slot, &(timestamp, _)) in output.iter().enumerate() {
+            assert_eq!(timestamp, slot_time(slot));
+        }
+        // Repeats show the frame before them.
+        let shown: Vec<_> = output[..7].iter().map(|&(_, shown)| shown).collect();
+        assert_eq!(shown, [0, 0, 0, 1, 1, 1, 2]);
+
+        let stats = pacer.stats();
+        assert_eq!(stats.output, 28);
+        assert_eq!(stats.repeated, 18);
+        assert_eq!(stats.dropped, 0);
+        assert!(stats.max_drift.abs() <= FRAME / 2);
+    }
+
+    #[test]
+    fn cfr_stays_on_the_grid_with_jitter() {
+        let mut pacer = FramePacer::new(Pacing::Cfr, 30);
+        let output = run(&mut pacer, &jittery_60fps(10));
+        assert_eq!(output.len(), 300);
+        for (slot, &(timestamp, _)) in output.iter().enumerate() {
+            assert_eq!(timestamp, slot_time(slot));
+        }
+        let stats = pacer.stats();
+        assert_eq!(stats.repeated, 0);
+        assert_eq!(stats.dropped, 300);
+        assert!(stats.max_drift.abs() <= FRAME / 2);
+    }
+
+    #[test]
+    fn cfr_starts_at_the_first_frame() {
+        let mut pacer = FramePacer::new(Pacing::Cfr, 30);
+        let output = run(&mut pacer, &[SECOND, SECOND + FRAME]);
+        assert_eq!(output, [(slot_time(30), 0), (slot_time(31), 1)]);
+        assert_eq!(pacer.stats().repeated, 0);
+    }
+
+    #[test]
+    fn vfr_passes_frames_through() {
+        let mut pacer = FramePacer::new(Pacing::Vfr, 30);
+        let timestamps = jittery_60fps(1);
+        let output = run(&mut pacer, &timestamps);
+        assert_eq!(output.len(), 60);
+        assert!(output
+            .iter()
+            .all(|&(timestamp, index)| timestamp == timestamps[index]));
+
+        // Except for ones that would go backwards.
+        assert_eq!(pacer.pace(0).timestamp, None);
+        let stats = pacer.stats();
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.max_drift, 0);
+    }
+
+    #[test]
+    fn parses_pacing() {
+        for pacing in [Pacing::Drop, Pacing::Cfr, Pacing::Vfr] {
+            assert_eq!(pacing.to_string().parse(), Ok(pacing));
+        }
+        assert_eq!("CFR".parse(), Ok(Pacing::Cfr));
+        assert!("fast".parse::<Pacing>().is_err());
+    }
+}
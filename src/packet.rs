@@ -0,0 +1,46 @@
+/// Which elementary stream an encoded packet belongs to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+}
+
+/// A single compressed access unit as produced by one of the encoders.
+///
+/// Timestamps and durations are in 100ns units, relative to the start of
+/// the recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedPacket {
+    pub kind: StreamKind,
+    /// Which stream of its kind the packet belongs to, for recordings with
+    /// more than one audio track. Sinks number the streams of each kind from
+    /// 0 in the order they are added.
+    pub stream: usize,
+    pub data: Vec<u8>,
+    pub timestamp: i64,
+    pub duration: i64,
+    pub keyframe: bool,
+}
+
+impl EncodedPacket {
+    pub fn new(
+        kind: StreamKind,
+        data: Vec<u8>,
+        timestamp: i64,
+        duration: i64,
+        keyframe: bool,
+    ) -> Self {
+        Self {
+            kind,
+            stream: 0,
+            data,
+            timestamp,
+            duration,
+            keyframe,
+        }
+    }
+
+    pub fn end_time(&self) -> i64 {
+        self.timestamp + self.duration
+    }
+}
@@ -0,0 +1,843 @@
+//! A FLAC encoder in pure Rust, for lossless audio without any encoder
+//! installed. Blocks are a fixed 4096 frames. Each channel is predicted with
+//! whichever of FLAC's fixed polynomial predictors leaves the smallest
+//! residual, which is then Rice coded, and stereo also tries the left/side,
+//! side/right and mid/side decorrelations. It doesn't search for LPC
+//! coefficients like libFLAC does, so files come out somewhat larger, but
+//! they decode to exactly what went in all the same.
+
+use std::io;
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    mux::h264::BitWriter,
+    packet::{EncodedPacket, StreamKind},
+};
+
+use super::{AudioBuffer, AudioCodec, AudioStreamFormat, Encoder, Result, StreamFormat};
+
+/// The number of frames in every block but the last.
+pub const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+pub const MAX_CHANNELS: u16 = 8;
+// The STREAMINFO has 20 bits for it
+const MAX_SAMPLE_RATE: u32 = (1 << 20) - 1;
+
+// Block size codes in the frame header
+const BLOCK_SIZE_4096: u32 = 12;
+const BLOCK_SIZE_16_BIT: u32 = 7;
+// Bits per sample code for 16-bit samples
+const SAMPLE_SIZE_16: u32 = 4;
+
+// Channel assignments besides independent channels
+const LEFT_SIDE: u32 = 8;
+const SIDE_RIGHT: u32 = 9;
+const MID_SIDE: u32 = 10;
+
+const SUBFRAME_CONSTANT: u32 = 0;
+const SUBFRAME_VERBATIM: u32 = 1;
+// The predictor order goes in the low bits
+const SUBFRAME_FIXED: u32 = 8;
+const MAX_FIXED_ORDER: usize = 4;
+
+// Residual coding methods, with 4 and 5-bit Rice parameters
+const RICE: u32 = 0;
+const RICE2: u32 = 1;
+const MAX_RICE_PARAMETER: u32 = 14;
+const MAX_RICE2_PARAMETER: u32 = 30;
+const MAX_PARTITION_ORDER: u32 = 6;
+
+/// The sample rate codes a frame header can use instead of pointing back
+/// to the STREAMINFO.
+const SAMPLE_RATES: [(u32, u32); 11] = [
+    (88200, 1),
+    (176400, 2),
+    (192000, 3),
+    (8000, 4),
+    (16000, 5),
+    (22050, 6),
+    (24000, 7),
+    (32000, 8),
+    (44100, 9),
+    (48000, 10),
+    (96000, 11),
+];
+
+/// The STREAMINFO metadata block body, which decoders need before the
+/// first frame. Containers store it where MP4 stores the avcC.
+pub fn stream_info(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // Minimum and maximum block sizes. The last block can be shorter, which
+    // the minimum doesn't count.
+    writer.bits(BLOCK_SIZE as u32, 16);
+    writer.bits(BLOCK_SIZE as u32, 16);
+    // Minimum and maximum frame sizes aren't known up front.
+    writer.bits(0, 24);
+    writer.bits(0, 24);
+    writer.bits(sample_rate, 20);
+    writer.bits(channels as u32 - 1, 3);
+    writer.bits(BITS_PER_SAMPLE - 1, 5);
+    // Nor is the length, or the MD5 of the audio.
+    writer.bits(0, 4);
+    writer.bits(0, 32);
+    writer.bytes(&[0; 16]);
+    writer.data
+}
+
+/// The STREAMINFO as the only metadata block, header and all, which is how
+/// a dfLa box holds it and what follows "fLaC" at the start of a stream.
+pub fn metadata(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let stream_info = stream_info(sample_rate, channels);
+    // The last metadata block, of type STREAMINFO
+    let mut block = vec![0x80];
+    block.extend_from_slice(&(stream_info.len() as u32).to_be_bytes()[1..]);
+    block.extend(stream_info);
+    block
+}
+
+/// Encodes interleaved 16-bit PCM to FLAC frames, one packet per block.
+pub struct FlacEncoder {
+    format: AudioStreamFormat,
+    // Interleaved samples that don't make up a whole block yet
+    pending: Vec<i16>,
+    // When the first pending sample plays, as the timestamp of the buffer
+    // it came in and the number of frames since then
+    start: i64,
+    start_frames: u64,
+    frame_number: u32,
+}
+
+impl FlacEncoder {
+    pub fn new(sample_rate: u32, channels: u16) -> Result<Self> {
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC can't store {} channels.", channels),
+            ));
+        }
+        if !(1..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC can't store a sample rate of {} Hz.", sample_rate),
+            ));
+        }
+        Ok(Self {
+            format: AudioStreamFormat {
+                codec: AudioCodec::Flac,
+                sample_rate,
+                channels,
+                // The most it takes, since it never does worse than PCM by
+                // more than the headers
+                bit_rate: sample_rate * channels as u32 * BITS_PER_SAMPLE,
+                name: None,
+                language: None,
+            },
+            pending: Vec::new(),
+            start: 0,
+            start_frames: 0,
+            frame_number: 0,
+        })
+    }
+
+    fn ticks(&self, frames: u64) -> i64 {
+        (frames as i128 * HNS_PER_SECOND as i128 / self.format.sample_rate as i128) as i64
+    }
+
+    /// Encodes the first `frames` pending frames to a packet.
+    fn next_packet(&mut self, frames: usize) -> EncodedPacket {
+        let channels = self.format.channels as usize;
+        let samples: Vec<i16> = self.pending.drain(..frames * channels).collect();
+        let data = self.encode_frame(&samples);
+        let timestamp = self.start + self.ticks(self.start_frames);
+        self.start_frames += frames as u64;
+        let duration = self.start + self.ticks(self.start_frames) - timestamp;
+        EncodedPacket::new(StreamKind::Audio, data, timestamp, duration, true)
+    }
+
+    fn encode_frame(&mut self, samples: &[i16]) -> Vec<u8> {
+        let channels = self.format.channels as usize;
+        let block_size = samples.len() / channels;
+        let planes: Vec<Vec<i32>> = (0..channels)
+            .map(|channel| {
+                samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .map(|&sample| sample as i32)
+                    .collect()
+            })
+            .collect();
+        let (assignment, subframes) = if channels == 2 {
+            best_stereo(&planes[0], &planes[1])
+        } else {
+            let subframes = planes
+                .iter()
+                .map(|plane| (Subframe::choose(plane, BITS_PER_SAMPLE), BITS_PER_SAMPLE))
+                .collect();
+            (channels as u32 - 1, subframes)
+        };
+
+        let mut writer = BitWriter::default();
+        // Sync code, then a fixed block size stream
+        writer.bits(0b11111111111110, 14);
+        writer.bits(0, 2);
+        let block_size_code = if block_size == BLOCK_SIZE {
+            BLOCK_SIZE_4096
+        } else {
+            BLOCK_SIZE_16_BIT
+        };
+        writer.bits(block_size_code, 4);
+        let sample_rate_code = SAMPLE_RATES
+            .iter()
+            .find(|&&(rate, _)| rate == self.format.sample_rate)
+            .map_or(0, |&(_, code)| code);
+        writer.bits(sample_rate_code, 4);
+        writer.bits(assignment, 4);
+        writer.bits(SAMPLE_SIZE_16, 3);
+        writer.bit(false);
+        writer.bytes(&utf8_number(self.frame_number));
+        if block_size_code == BLOCK_SIZE_16_BIT {
+            writer.bits(block_size as u32 - 1, 16);
+        }
+        let crc = crc8(&writer.data);
+        writer.bits(crc as u32, 8);
+
+        for (subframe, bits_per_sample) in &subframes {
+            subframe.write(&mut writer, *bits_per_sample);
+        }
+        writer.align();
+        let crc = crc16(&writer.data);
+        writer.bits(crc as u32, 16);
+
+        self.frame_number += 1;
+        writer.data
+    }
+}
+
+impl Encoder for FlacEncoder {
+    type Input = AudioBuffer;
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::Audio(self.format.clone())
+    }
+
+    fn encode(&mut self, buffer: AudioBuffer) -> Result<Vec<EncodedPacket>> {
+        if self.pending.is_empty() {
+            self.start = buffer.timestamp;
+            self.start_frames = 0;
+        }
+        self.pending.extend(
+            buffer
+                .data
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])),
+        );
+        let channels = self.format.channels as usize;
+        let mut packets = Vec::new();
+        while self.pending.len() >= BLOCK_SIZE * channels {
+            packets.push(self.next_packet(BLOCK_SIZE));
+        }
+        Ok(packets)
+    }
+
+    fn drain(&mut self) -> Result<Vec<EncodedPacket>> {
+        let frames = self.pending.len() / self.format.channels as usize;
+        if frames == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.next_packet(frames)])
+    }
+}
+
+/// Picks whichever way of coding the two channels takes the fewest bits.
+/// Each subframe comes with its sample size, which is a bit more for the
+/// side channel.
+fn best_stereo(left: &[i32], right: &[i32]) -> (u32, Vec<(Subframe, u32)>) {
+    let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+    let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+    let left = (Subframe::choose(left, 16), 16);
+    let right = (Subframe::choose(right, 16), 16);
+    let side = (Subframe::choose(&side, 17), 17);
+    let mid = (Subframe::choose(&mid, 16), 16);
+
+    let size = |subframes: [&(Subframe, u32); 2]| -> u64 {
+        subframes
+            .iter()
+            .map(|(subframe, bits)| subframe.size(*bits))
+            .sum()
+    };
+    let options = [
+        (1, size([&left, &right])),
+        (LEFT_SIDE, size([&left, &side])),
+        (SIDE_RIGHT, size([&side, &right])),
+        (MID_SIDE, size([&mid, &side])),
+    ];
+    let (assignment, _) = options.into_iter().min_by_key(|&(_, size)| size).unwrap();
+    let subframes = match assignment {
+        LEFT_SIDE => vec![left, side],
+        SIDE_RIGHT => vec![side, right],
+        MID_SIDE => vec![mid, side],
+        _ => vec![left, right],
+    };
+    (assignment, subframes)
+}
+
+enum Subframe {
+    Constant(i32),
+    Verbatim(Vec<i32>),
+    Fixed {
+        warm_up: Vec<i32>,
+        residual: Residual,
+    },
+}
+
+impl Subframe {
+    fn choose(samples: &[i32], bits_per_sample: u32) -> Self {
+        if samples.iter().all(|&sample| sample == samples[0]) {
+            return Subframe::Constant(samples[0]);
+        }
+        let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+            .map(|order| Subframe::Fixed {
+                warm_up: samples[..order].to_vec(),
+                residual: Residual::choose(&fixed_residual(samples, order), order),
+            })
+            .min_by_key(|subframe| subframe.size(bits_per_sample));
+        let verbatim = Subframe::Verbatim(samples.to_vec());
+        match best {
+            Some(best) if best.size(bits_per_sample) < verbatim.size(bits_per_sample) => best,
+            _ => verbatim,
+        }
+    }
+
+    /// The size in bits, including the subframe header.
+    fn size(&self, bits_per_sample: u32) -> u64 {
+        8 + match self {
+            Subframe::Constant(_) => bits_per_sample as u64,
+            Subframe::Verbatim(samples) => samples.len() as u64 * bits_per_sample as u64,
+            Subframe::Fixed { warm_up, residual } => {
+                warm_up.len() as u64 * bits_per_sample as u64 + residual.size
+            }
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter, bits_per_sample: u32) {
+        // Zero padding bit, the type, then no wasted bits
+        writer.bit(false);
+        match self {
+            Subframe::Constant(value) => {
+                writer.bits(SUBFRAME_CONSTANT, 6);
+                writer.bit(false);
+                writer.bits(*value as u32, bits_per_sample);
+            }
+            Subframe::Verbatim(samples) => {
+                writer.bits(SUBFRAME_VERBATIM, 6);
+                writer.bit(false);
+                for &sample in samples {
+                    writer.bits(sample as u32, bits_per_sample);
+                }
+            }
+            Subframe::Fixed { warm_up, residual } => {
+                writer.bits(SUBFRAME_FIXED | warm_up.len() as u32, 6);
+                writer.bit(false);
+                for &sample in warm_up {
+                    writer.bits(sample as u32, bits_per_sample);
+                }
+                residual.write(writer);
+            }
+        }
+    }
+}
+
+/// What's left after predicting each sample from the ones before it with a
+/// fixed polynomial of the given order. The first `order` samples have
+/// nothing to go on, and are stored as they are.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    let mut residual: Vec<i32> = samples.to_vec();
+    // Each order is the difference of the one before
+    for _ in 0..order {
+        for index in (1..residual.len()).rev() {
+            residual[index] -= residual[index - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+/// A Rice coded residual, split into partitions that each get their own
+/// Rice parameter.
+struct Residual {
+    // Zigzag coded, so that small negative values stay small
+    values: Vec<u32>,
+    predictor_order: usize,
+    partition_order: u32,
+    parameters: Vec<u32>,
+    method: u32,
+    size: u64,
+}
+
+impl Residual {
+    /// Picks the partition order and parameters that take the fewest bits.
+    fn choose(residual: &[i32], predictor_order: usize) -> Self {
+        let values: Vec<u32> = residual
+            .iter()
+            .map(|&value| ((value << 1) ^ (value >> 31)) as u32)
+            .collect();
+        let block_size = values.len() + predictor_order;
+
+        let mut best: Option<Self> = None;
+        for partition_order in 0..=MAX_PARTITION_ORDER {
+            let partition_size = block_size >> partition_order;
+            if !block_size.is_multiple_of(1 << partition_order) || partition_size <= predictor_order
+            {
+                break;
+            }
+            let mut parameters = Vec::new();
+            let mut size = 0;
+            let mut start = 0;
+            for partition in 0..1 << partition_order {
+                let length = if partition == 0 {
+                    partition_size - predictor_order
+                } else {
+                    partition_size
+                };
+                let (parameter, bits) = best_parameter(&values[start..start + length]);
+                parameters.push(parameter);
+                size += bits;
+                start += length;
+            }
+            let max_parameter = *parameters.iter().max().unwrap();
+            let (method, parameter_bits) = if max_parameter > MAX_RICE_PARAMETER {
+                (RICE2, 5)
+            } else {
+                (RICE, 4)
+            };
+            // The method and partition order, then a parameter each
+            size += 6 + parameter_bits * parameters.len() as u64;
+            if best.as_ref().is_none_or(|best| size < best.size) {
+                best = Some(Self {
+                    values: Vec::new(),
+                    predictor_order,
+                    partition_order,
+                    parameters,
+                    method,
+                    size,
+                });
+            }
+        }
+        Self {
+            values,
+            ..best.unwrap()
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.bits(self.method, 2);
+        writer.bits(self.partition_order, 4);
+        let parameter_bits = if self.method == RICE2 { 5 } else { 4 };
+        let partition_size = (self.values.len() + self.predictor_order) >> self.partition_order;
+        let mut start = 0;
+        for (index, &parameter) in self.parameters.iter().enumerate() {
+            let length = if index == 0 {
+                partition_size - self.predictor_order
+            } else {
+                partition_size
+            };
+            writer.bits(parameter, parameter_bits);
+            for &value in &self.values[start..start + length] {
+                for _ in 0..value >> parameter {
+                    writer.bit(false);
+                }
+                writer.bit(true);
+                writer.bits(value, parameter);
+            }
+            start += length;
+        }
+    }
+}
+
+/// The Rice parameter that codes the values in the fewest bits, and how
+/// many that is. The mean gives a good guess, which the neighbours of might
+/// still beat.
+fn best_parameter(values: &[u32]) -> (u32, u64) {
+    let size = |parameter: u32| -> u64 {
+        values
+            .iter()
+            .map(|&value| (value >> parameter) as u64 + 1 + parameter as u64)
+            .sum()
+    };
+    let sum: u64 = values.iter().map(|&value| value as u64).sum();
+    let mean = sum / values.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(MAX_RICE2_PARAMETER);
+    (guess.saturating_sub(1)..=(guess + 1).min(MAX_RICE2_PARAMETER))
+        .map(|parameter| (parameter, size(parameter)))
+        .min_by_key(|&(_, size)| size)
+        .unwrap()
+}
+
+/// Frame numbers are coded like UTF-8 code points, extended to 36 bits.
+fn utf8_number(number: u32) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![number as u8];
+    }
+    let mut continuation = Vec::new();
+    let mut rest = number;
+    // The bits the first byte has room for shrink with every continuation
+    // byte
+    let mut first_bits = 6;
+    while rest >= 1 << first_bits {
+        continuation.push(0x80 | (rest & 0x3f) as u8);
+        rest >>= 6;
+        first_bits -= 1;
+    }
+    let length = continuation.len() as u32 + 1;
+    let prefix = (0xff00u32 >> length) as u8;
+    let mut bytes = vec![prefix | rest as u8];
+    bytes.extend(continuation.into_iter().rev());
+    bytes
+}
+
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Decodes the frames `FlacEncoder` writes, for tests.
+#[cfg(test)]
+pub mod test_util {
+    use super::{
+        crc16, crc8, BLOCK_SIZE, BLOCK_SIZE_16_BIT, BLOCK_SIZE_4096, LEFT_SIDE, MID_SIDE, RICE2,
+        SIDE_RIGHT, SUBFRAME_CONSTANT, SUBFRAME_FIXED, SUBFRAME_VERBATIM,
+    };
+    use crate::mux::h264::BitReader;
+
+    pub struct Frame {
+        pub number: u32,
+        pub sample_rate_code: u32,
+        /// Interleaved samples.
+        pub samples: Vec<i16>,
+    }
+
+    fn signed(reader: &mut BitReader, bits: u32) -> i32 {
+        let value = reader.bits(bits).unwrap();
+        ((value << (32 - bits)) as i32) >> (32 - bits)
+    }
+
+    fn utf8_number(reader: &mut BitReader) -> u32 {
+        let first = reader.bits(8).unwrap();
+        let length = (first as u8).leading_ones();
+        if length == 0 {
+            return first;
+        }
+        let mut number = first & (0x7f >> length);
+        for _ in 1..length {
+            let byte = reader.bits(8).unwrap();
+            assert_eq!(byte & 0xc0, 0x80);
+            number = (number << 6) | (byte & 0x3f);
+        }
+        number
+    }
+
+    fn subframe(reader: &mut BitReader, block_size: usize, bits: u32) -> Vec<i32> {
+        assert!(!reader.bit().unwrap());
+        let subframe_type = reader.bits(6).unwrap();
+        assert!(!reader.bit().unwrap(), "No wasted bits are used");
+        match subframe_type {
+            SUBFRAME_CONSTANT => vec![signed(reader, bits); block_size],
+            SUBFRAME_VERBATIM => (0..block_size).map(|_| signed(reader, bits)).collect(),
+            _ => {
+                assert_eq!(subframe_type & !7, SUBFRAME_FIXED);
+                let order = (subframe_type & 7) as usize;
+                let coefficients: &[i32] = match order {
+                    0 => &[],
+                    1 => &[1],
+                    2 => &[2, -1],
+                    3 => &[3, -3, 1],
+                    4 => &[4, -6, 4, -1],
+                    _ => panic!("Unexpected predictor order {}", order),
+                };
+                let mut samples: Vec<i32> = (0..order).map(|_| signed(reader, bits)).collect();
+                for residual in residual(reader, block_size, order) {
+                    let prediction: i32 = coefficients
+                        .iter()
+                        .zip(samples.iter().rev())
+                        .map(|(coefficient, sample)| coefficient * sample)
+                        .sum();
+                    samples.push(prediction + residual);
+                }
+                samples
+            }
+        }
+    }
+
+    fn residual(reader: &mut BitReader, block_size: usize, order: usize) -> Vec<i32> {
+        let method = reader.bits(2).unwrap();
+        let parameter_bits = if method == RICE2 { 5 } else { 4 };
+        let partition_order = reader.bits(4).unwrap();
+        let partitions = 1 << partition_order;
+        let mut residual = Vec::new();
+        for partition in 0..partitions {
+            let mut length = block_size >> partition_order;
+            if partition == 0 {
+                length -= order;
+            }
+            let parameter = reader.bits(parameter_bits).unwrap();
+            for _ in 0..length {
+                let mut quotient = 0;
+                while !reader.bit().unwrap() {
+                    quotient += 1;
+                }
+                let value = (quotient << parameter) | reader.bits(parameter).unwrap();
+                residual.push((value >> 1) as i32 ^ -((value & 1) as i32));
+            }
+        }
+        residual
+    }
+
+    /// Decodes a frame, checking both CRCs along the way.
+    pub fn decode_frame(data: &[u8], channels: usize) -> Frame {
+        assert_eq!(crc16(data), 0, "The frame CRC matches");
+        let mut reader = BitReader::new(data);
+        assert_eq!(reader.bits(14).unwrap(), 0b11111111111110);
+        assert_eq!(reader.bits(2).unwrap(), 0);
+        let block_size_code = reader.bits(4).unwrap();
+        let sample_rate_code = reader.bits(4).unwrap();
+        let assignment = reader.bits(4).unwrap();
+        assert_eq!(reader.bits(3).unwrap(), 4, "Samples are 16-bit");
+        assert!(!reader.bit().unwrap());
+        let number = utf8_number(&mut reader);
+        let block_size = match block_size_code {
+            BLOCK_SIZE_4096 => BLOCK_SIZE,
+            BLOCK_SIZE_16_BIT => reader.bits(16).unwrap() as usize + 1,
+            code => panic!("Unexpected block size code {}", code),
+        };
+        reader.bits(8).unwrap();
+        let header_length = data.len() - reader.bits_left() / 8;
+        assert_eq!(crc8(&data[..header_length]), 0, "The header CRC matches");
+
+        let planes: Vec<Vec<i32>> = match assignment {
+            LEFT_SIDE | SIDE_RIGHT | MID_SIDE => {
+                assert_eq!(channels, 2);
+                let first_bits = if assignment == SIDE_RIGHT { 17 } else { 16 };
+                let first = subframe(&mut reader, block_size, first_bits);
+                let second = subframe(&mut reader, block_size, 33 - first_bits);
+                let pairs = first.iter().zip(&second);
+                match assignment {
+                    LEFT_SIDE => {
+                        let right = pairs.map(|(left, side)| left - side).collect();
+                        vec![first, right]
+                    }
+                    SIDE_RIGHT => {
+                        let left = pairs.map(|(side, right)| side + right).collect();
+                        vec![left, second]
+                    }
+                    _ => {
+                        let (left, right) = pairs
+                            .map(|(&mid, &side)| {
+                                let mid = (mid << 1) | (side & 1);
+                                ((mid + side) >> 1, (mid - side) >> 1)
+                            })
+                            .unzip();
+                        vec![left, right]
+                    }
+                }
+            }
+            assignment => {
+                assert_eq!(assignment as usize + 1, channels);
+                (0..channels)
+                    .map(|_| subframe(&mut reader, block_size, 16))
+                    .collect()
+            }
+        };
+        reader.align();
+        assert_eq!(reader.bits_left(), 16, "Only the CRC follows the subframes");
+
+        let samples = (0..block_size)
+            .flat_map(|index| planes.iter().map(move |plane| plane[index] as i16))
+            .collect();
+        Frame {
+            number,
+            sample_rate_code,
+            samples,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{metadata, stream_info, test_util::decode_frame, FlacEncoder, BLOCK_SIZE};
+    use crate::{
+        mux::h264::BitReader,
+        packet::EncodedPacket,
+        pipeline::{AudioBuffer, Encoder},
+    };
+
+    /// A predictable stand-in for noise.
+    fn noise(seed: &mut u32) -> i16 {
+        *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (*seed >> 16) as i16
+    }
+
+    fn buffers(samples: &[i16], channels: usize, frames_per_buffer: usize) -> Vec<AudioBuffer> {
+        samples
+            .chunks(frames_per_buffer * channels)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let frames = chunk.len() / channels;
+                AudioBuffer {
+                    data: chunk
+                        .iter()
+                        .flat_map(|sample| sample.to_le_bytes())
+                        .collect(),
+                    frames: frames as u32,
+                    timestamp: (index * frames_per_buffer) as i64 * 10_000_000 / 48000,
+                    duration: frames as i64 * 10_000_000 / 48000,
+                }
+            })
+            .collect()
+    }
+
+    fn encode(samples: &[i16], channels: u16, frames_per_buffer: usize) -> Vec<EncodedPacket> {
+        let mut encoder = FlacEncoder::new(48000, channels).unwrap();
+        let mut packets = Vec::new();
+        for buffer in buffers(samples, channels as usize, frames_per_buffer) {
+            packets.extend(encoder.encode(buffer).unwrap());
+        }
+        packets.extend(encoder.drain().unwrap());
+        packets
+    }
+
+    fn decode(packets: &[EncodedPacket], channels: usize) -> Vec<i16> {
+        let mut samples = Vec::new();
+        for (number, packet) in packets.iter().enumerate() {
+            let frame = decode_frame(&packet.data, channels);
+            assert_eq!(frame.number, number as u32);
+            // 48 kHz has its own code
+            assert_eq!(frame.sample_rate_code, 10);
+            samples.extend(frame.samples);
+        }
+        samples
+    }
+
+    #[test]
+    fn decodes_back_to_the_input() {
+        // A tone that's louder on the left, with a little noise on top
+        let mut seed = 1;
+        let samples: Vec<i16> = (0..10_000)
+            .flat_map(|index| {
+                let tone = (index as f32 * 0.05).sin() * 12000.0;
+                [tone as i16, (tone * 0.5) as i16]
+            })
+            .map(|sample| sample.saturating_add(noise(&mut seed) >> 10))
+            .collect();
+
+        let packets = encode(&samples, 2, 480);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(decode(&packets, 2), samples);
+        // Predicting each sample makes it smaller than PCM
+        let size: usize = packets.iter().map(|packet| packet.data.len()).sum();
+        assert!(size < samples.len() * 2 * 3 / 4, "{} bytes", size);
+    }
+
+    #[test]
+    fn packets_cover_the_timeline() {
+        let samples = vec![0; 10_000 * 2];
+        let packets = encode(&samples, 2, 480);
+        let durations: Vec<_> = packets.iter().map(|packet| packet.duration).collect();
+        assert_eq!(durations, [853_333, 853_333, 376_667]);
+        let mut end = 0;
+        for packet in &packets {
+            assert_eq!(packet.timestamp, end);
+            assert!(packet.keyframe);
+            end += packet.duration;
+        }
+        assert_eq!(end, 10_000 * 10_000_000 / 48000);
+    }
+
+    #[test]
+    fn silence_takes_almost_nothing() {
+        let samples = vec![0; BLOCK_SIZE];
+        let packets = encode(&samples, 1, BLOCK_SIZE);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].data.len() < 16);
+        assert_eq!(decode(&packets, 1), samples);
+    }
+
+    #[test]
+    fn extremes_survive() {
+        // Full scale and opposite on each side, which needs the side
+        // channel's extra bit, and noise that can't be predicted
+        let mut seed = 7;
+        let mut samples = Vec::new();
+        for index in 0..BLOCK_SIZE {
+            let value = if index % 3 == 0 { i16::MAX } else { i16::MIN };
+            samples.extend([value, value.wrapping_neg().wrapping_sub(1)]);
+        }
+        samples.extend((0..BLOCK_SIZE * 2).map(|_| noise(&mut seed)));
+        let packets = encode(&samples, 2, 1000);
+        assert_eq!(decode(&packets, 2), samples);
+    }
+
+    #[test]
+    fn encodes_more_channels() {
+        let mut seed = 3;
+        let samples: Vec<i16> = (0..3000 * 6)
+            .map(|index| {
+                ((index / 6) as i16).wrapping_mul(index as i16 % 6 + 1) ^ (noise(&mut seed) & 0xf)
+            })
+            .collect();
+        let packets = encode(&samples, 6, 441);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(decode(&packets, 6), samples);
+    }
+
+    #[test]
+    fn stream_info_describes_the_stream() {
+        let info = stream_info(44100, 2);
+        assert_eq!(info.len(), 34);
+        let mut reader = BitReader::new(&info);
+        assert_eq!(reader.bits(16).unwrap(), BLOCK_SIZE as u32);
+        assert_eq!(reader.bits(16).unwrap(), BLOCK_SIZE as u32);
+        reader.bits(24).unwrap();
+        reader.bits(24).unwrap();
+        assert_eq!(reader.bits(20).unwrap(), 44100);
+        assert_eq!(reader.bits(3).unwrap(), 1);
+        assert_eq!(reader.bits(5).unwrap(), 15);
+
+        let block = metadata(44100, 2);
+        assert_eq!(block[..4], [0x80, 0, 0, 34]);
+        assert_eq!(block[4..], info);
+    }
+
+    #[test]
+    fn rejects_what_flac_cant_store() {
+        assert!(FlacEncoder::new(48000, 0).is_err());
+        assert!(FlacEncoder::new(48000, 9).is_err());
+        assert!(FlacEncoder::new(0, 2).is_err());
+        assert!(FlacEncoder::new(1 << 20, 2).is_err());
+        assert!(FlacEncoder::new(384000, 8).is_ok());
+    }
+}
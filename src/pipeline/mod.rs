@@ -0,0 +1,335 @@
+//! Platform-neutral building blocks of a recording: something that produces
+//! frames or audio, something that encodes them, and something that stores
+//! the encoded packets. The Windows capture and Media Foundation types
+//! implement these traits, and `synthetic` provides pure Rust versions for
+//! the tests, which run anywhere. `software` is a pure Rust H.264 encoder, for machines
+//! without a hardware one, and `flac` a pure Rust FLAC encoder.
+
+pub mod flac;
+pub mod software;
+mod stream;
+#[cfg(test)]
+pub mod synthetic;
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::packet::{EncodedPacket, StreamKind};
+
+pub use stream::StreamSession;
+
+/// Errors are reported as `std::io::Error` so that the Windows backends can
+/// bubble up their HRESULTs with `?`.
+pub type Result<T> = std::io::Result<T>;
+
+/// A sink shared between the video and audio streams of a session.
+pub type SharedSink = Arc<Mutex<dyn Sink>>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+    /// Uncompressed NV12 frames.
+    Raw,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseVideoCodecError(&'static str);
+
+/// Only the compressed codecs can be picked to record with.
+impl FromStr for VideoCodec {
+    type Err = ParseVideoCodecError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h264" => Ok(VideoCodec::H264),
+            "hevc" => Ok(VideoCodec::Hevc),
+            "av1" => Ok(VideoCodec::Av1),
+            _ => Err(ParseVideoCodecError(
+                "Invalid codec value! Expecting: h264, hevc, or av1.",
+            )),
+        }
+    }
+}
+
+impl Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Hevc => "hevc",
+            VideoCodec::Av1 => "av1",
+            VideoCodec::Raw => "raw",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseVideoCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseVideoCodecError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+    Flac,
+    /// Uncompressed 16-bit little endian PCM.
+    Pcm,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseAudioCodecError(&'static str);
+
+impl FromStr for AudioCodec {
+    type Err = ParseAudioCodecError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aac" => Ok(AudioCodec::Aac),
+            "opus" => Ok(AudioCodec::Opus),
+            "flac" => Ok(AudioCodec::Flac),
+            "pcm" => Ok(AudioCodec::Pcm),
+            _ => Err(ParseAudioCodecError(
+                "Invalid audio codec value! Expecting: aac, opus, flac, or pcm.",
+            )),
+        }
+    }
+}
+
+impl Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Flac => "flac",
+            AudioCodec::Pcm => "pcm",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseAudioCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseAudioCodecError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoStreamFormat {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: u32,
+    /// In bits per second.
+    pub bit_rate: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioStreamFormat {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// In bits per second.
+    pub bit_rate: u32,
+    /// Shown by players and editors to tell audio tracks apart.
+    pub name: Option<String>,
+    /// A three letter ISO 639-2 code, like "eng".
+    pub language: Option<String>,
+}
+
+/// Describes an encoded stream to a sink before any packets are written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamFormat {
+    Video(VideoStreamFormat),
+    Audio(AudioStreamFormat),
+}
+
+impl StreamFormat {
+    pub fn kind(&self) -> StreamKind {
+        match self {
+            StreamFormat::Video(_) => StreamKind::Video,
+            StreamFormat::Audio(_) => StreamKind::Audio,
+        }
+    }
+}
+
+/// The number the next stream of `kind` gets, given the streams added so far.
+pub fn next_stream_number(formats: &[StreamFormat], kind: StreamKind) -> usize {
+    formats
+        .iter()
+        .filter(|format| format.kind() == kind)
+        .count()
+}
+
+/// An uncompressed NV12 frame in system memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// In 100ns units.
+    pub timestamp: i64,
+}
+
+impl VideoFrame {
+    /// Takes a planar I420 frame, interleaving its chroma planes into NV12.
+    #[cfg(test)]
+    pub fn from_i420(width: u32, height: u32, data: &[u8], timestamp: i64) -> Self {
+        let luma_size = width as usize * height as usize;
+        let chroma_size = luma_size / 4;
+        let (luma, chroma) = data[..luma_size + chroma_size * 2].split_at(luma_size);
+        let (u, v) = chroma.split_at(chroma_size);
+        let mut nv12 = Vec::with_capacity(luma_size + chroma_size * 2);
+        nv12.extend_from_slice(luma);
+        for (&u, &v) in u.iter().zip(v) {
+            nv12.push(u);
+            nv12.push(v);
+        }
+        Self {
+            width,
+            height,
+            data: nv12,
+            timestamp,
+        }
+    }
+}
+
+/// Interleaved 16-bit little endian PCM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioBuffer {
+    pub data: Vec<u8>,
+    pub frames: u32,
+    /// In 100ns units.
+    pub timestamp: i64,
+    /// In 100ns units.
+    pub duration: i64,
+}
+
+/// Anything a source produces. Streams use this to move inputs onto the
+/// recording timeline before they are encoded.
+pub trait Timestamped {
+    fn timestamp(&self) -> i64;
+    fn set_timestamp(&mut self, timestamp: i64);
+}
+
+impl Timestamped for VideoFrame {
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = timestamp;
+    }
+}
+
+impl Timestamped for AudioBuffer {
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = timestamp;
+    }
+}
+
+/// Produces video frames for an encoder.
+///
+/// `next_frame` may block briefly, but should return `Ok(None)` regularly
+/// when nothing is available so that the stream can notice it is being
+/// stopped. Frames held back when it stops are returned by `drain`.
+pub trait FrameSource: Send + 'static {
+    type Frame: Timestamped;
+
+    /// `start_time` is the timeline origin the frame timestamps should be
+    /// relative to.
+    fn start(&mut self, start_time: i64) -> Result<()>;
+    fn next_frame(&mut self) -> Result<Option<Self::Frame>>;
+    fn stop(&mut self) -> Result<()>;
+
+    fn drain(&mut self) -> Result<Vec<Self::Frame>> {
+        Ok(Vec::new())
+    }
+
+    /// Whether the frames are already on the recording timeline, with
+    /// paused time taken out. Otherwise the stream maps them itself.
+    fn on_recording_timeline(&self) -> bool {
+        false
+    }
+}
+
+/// Produces audio for an encoder. Follows the same rules as `FrameSource`.
+pub trait SampleSource: Send + 'static {
+    type Samples: Timestamped;
+
+    fn start(&mut self, start_time: i64) -> Result<()>;
+    fn next_samples(&mut self) -> Result<Option<Self::Samples>>;
+    fn stop(&mut self) -> Result<()>;
+
+    fn drain(&mut self) -> Result<Vec<Self::Samples>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Turns frames or audio into encoded packets. An input doesn't have to
+/// produce output right away, anything held back is returned by `drain`.
+pub trait Encoder: Send + 'static {
+    type Input;
+
+    fn stream_format(&self) -> StreamFormat;
+    fn encode(&mut self, input: Self::Input) -> Result<Vec<EncodedPacket>>;
+    fn drain(&mut self) -> Result<Vec<EncodedPacket>>;
+}
+
+/// Stores encoded packets. All streams are added before `start` is called.
+pub trait Sink: Send {
+    /// Returns the number that packets for the new stream carry in
+    /// `EncodedPacket::stream`, see `next_stream_number`.
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize>;
+    fn start(&mut self) -> Result<()>;
+    fn write(&mut self, packet: EncodedPacket) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+
+    /// Starts a chapter at `timestamp`, on the same timeline as the packets.
+    /// Sinks that can't store chapters leave them out.
+    fn add_chapter(&mut self, _timestamp: i64, _title: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioCodec, VideoCodec};
+
+    #[test]
+    fn parses_video_codec() {
+        for codec in [VideoCodec::H264, VideoCodec::Hevc, VideoCodec::Av1] {
+            assert_eq!(codec.to_string().parse(), Ok(codec));
+        }
+        assert_eq!("HEVC".parse(), Ok(VideoCodec::Hevc));
+        assert!("raw".parse::<VideoCodec>().is_err());
+        assert!("vp9".parse::<VideoCodec>().is_err());
+    }
+
+    #[test]
+    fn parses_audio_codec() {
+        let codecs = [
+            AudioCodec::Aac,
+            AudioCodec::Opus,
+            AudioCodec::Flac,
+            AudioCodec::Pcm,
+        ];
+        for codec in codecs {
+            assert_eq!(codec.to_string().parse(), Ok(codec));
+        }
+        assert_eq!("FLAC".parse(), Ok(AudioCodec::Flac));
+        assert!("mp3".parse::<AudioCodec>().is_err());
+    }
+}
@@ -0,0 +1,587 @@
+//! A lossless H.264 encoder in pure Rust, for debugging on machines without
+//! a hardware encoder like VMs and build agents. It doesn't compress at all:
+//! keyframes store every macroblock uncompressed (I_PCM), and the frames in
+//! between skip the macroblocks that haven't changed since the last frame
+//! and store the rest uncompressed too. A still screen makes for a small
+//! file, but a moving one runs to more than a gigabit per second at 1080p60,
+//! so there's no bit rate to aim for and no rate control. The level is
+//! picked for the worst case, where everything changes in every frame. The
+//! stream is constrained baseline, so anything can play it.
+
+use std::io;
+
+use crate::{
+    clock::HNS_PER_SECOND,
+    encoder_settings::{Level, VideoEncoderSettings},
+    mux::h264::{escape_rbsp, BitWriter, NAL_PPS, NAL_SPS},
+    packet::{EncodedPacket, StreamKind},
+};
+
+use super::{Encoder, Result, StreamFormat, VideoCodec, VideoFrame, VideoStreamFormat};
+
+const NAL_SLICE: u8 = 1;
+const NAL_IDR_SLICE: u8 = 5;
+// Every NAL unit is kept as a reference
+const NAL_REF_IDC: u8 = 3;
+
+const PROFILE_BASELINE: u32 = 66;
+// constraint_set0_flag and constraint_set1_flag, making it constrained
+// baseline
+const CONSTRAINED_BASELINE: u32 = 0xc0;
+const LOG2_MAX_FRAME_NUM: u32 = 16;
+
+// slice_type, saying every slice of the picture has the same type
+const SLICE_TYPE_P: u32 = 5;
+const SLICE_TYPE_I: u32 = 7;
+// mb_type of I_PCM, which is offset by the P macroblock types in P slices
+const MB_TYPE_I_PCM: u32 = 25;
+const MB_TYPE_P_I_PCM: u32 = 30;
+
+const MB_SIZE: usize = 16;
+// 16x16 luma samples, then 8x8 of each chroma component
+const MB_BYTES: usize = MB_SIZE * MB_SIZE * 3 / 2;
+// The most a macroblock takes: its samples, plus the skip run in front of
+// it, its mb_type and the alignment before the samples
+const MB_MAX_BITS: u64 = MB_BYTES as u64 * 8 + 24;
+
+/// Encodes NV12 frames in system memory to an Annex B H.264 stream. Each
+/// keyframe starts with the parameter sets.
+pub struct SoftwareH264Encoder {
+    format: VideoStreamFormat,
+    level: Level,
+    keyframe_interval: u64,
+    width_in_mbs: usize,
+    height_in_mbs: usize,
+
+    // The last frame, in macroblock order, which is also what a decoder has
+    // since nothing is lost
+    reference: Vec<u8>,
+    count: u64,
+    frame_num: u32,
+    idr_pic_id: u32,
+}
+
+impl SoftwareH264Encoder {
+    /// Only the keyframe interval and level are taken from the settings.
+    /// The bit rate is ignored and rate control is refused, as nothing is
+    /// compressed. Without a level, the lowest one the worst case fits in
+    /// is used.
+    pub fn new(
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+        settings: &VideoEncoderSettings,
+    ) -> Result<Self> {
+        if !width.is_multiple_of(2) || !height.is_multiple_of(2) || width == 0 || height == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}x{} isn't a size NV12 frames can have.", width, height),
+            ));
+        }
+        if settings.codec != VideoCodec::H264 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The software encoder only encodes H.264.",
+            ));
+        }
+        if settings.rate_control.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The software encoder doesn't compress, so it has no rate control.",
+            ));
+        }
+        let width_in_mbs = width.div_ceil(MB_SIZE as u32) as usize;
+        let height_in_mbs = height.div_ceil(MB_SIZE as u32) as usize;
+        let bit_rate = (width_in_mbs * height_in_mbs) as u64 * frame_rate as u64 * MB_MAX_BITS;
+        let worst_case = VideoEncoderSettings {
+            bit_rate: bit_rate.min(u32::MAX as u64) as u32,
+            ..settings.clone()
+        };
+        worst_case
+            .check_level(width, height, frame_rate)
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+        let keyframe_interval = settings.keyframe_interval.unwrap_or(frame_rate * 2);
+        Ok(Self {
+            format: VideoStreamFormat {
+                codec: VideoCodec::H264,
+                width,
+                height,
+                frame_rate,
+                bit_rate: worst_case.bit_rate,
+            },
+            level: settings
+                .level
+                .unwrap_or_else(|| Level::fitting(width, height, frame_rate, bit_rate)),
+            keyframe_interval: keyframe_interval.max(1) as u64,
+            width_in_mbs,
+            height_in_mbs,
+            reference: Vec::new(),
+            count: 0,
+            frame_num: 0,
+            idr_pic_id: 0,
+        })
+    }
+
+    fn sps(&self) -> Vec<u8> {
+        let width = self.format.width;
+        let height = self.format.height;
+        let mut writer = BitWriter::default();
+        writer.bits(PROFILE_BASELINE, 8);
+        writer.bits(CONSTRAINED_BASELINE, 8);
+        writer.bits(self.level.value(), 8);
+        // seq_parameter_set_id
+        writer.ue(0);
+        writer.ue(LOG2_MAX_FRAME_NUM - 4);
+        // pic_order_cnt_type 2, where the order is the decoding order
+        writer.ue(2);
+        // max_num_ref_frames
+        writer.ue(1);
+        // gaps_in_frame_num_value_allowed_flag
+        writer.bit(false);
+        writer.ue(self.width_in_mbs as u32 - 1);
+        writer.ue(self.height_in_mbs as u32 - 1);
+        // frame_mbs_only_flag and direct_8x8_inference_flag
+        writer.bit(true);
+        writer.bit(true);
+        // Cropping is in pairs of pixels for 4:2:0
+        let crop_right = (self.width_in_mbs * MB_SIZE) as u32 - width;
+        let crop_bottom = (self.height_in_mbs * MB_SIZE) as u32 - height;
+        writer.bit(crop_right != 0 || crop_bottom != 0);
+        if crop_right != 0 || crop_bottom != 0 {
+            writer.ue(0);
+            writer.ue(crop_right / 2);
+            writer.ue(0);
+            writer.ue(crop_bottom / 2);
+        }
+        // vui_parameters_present_flag
+        writer.bit(false);
+        nal_unit(NAL_SPS, writer.finish())
+    }
+
+    fn pps(&self) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // pic_parameter_set_id and seq_parameter_set_id
+        writer.ue(0);
+        writer.ue(0);
+        // CAVLC, and no bottom_field_pic_order_in_frame_present_flag
+        writer.bit(false);
+        writer.bit(false);
+        // num_slice_groups_minus1
+        writer.ue(0);
+        // num_ref_idx_l0_default_active_minus1 and l1
+        writer.ue(0);
+        writer.ue(0);
+        // weighted_pred_flag and weighted_bipred_idc
+        writer.bit(false);
+        writer.bits(0, 2);
+        // pic_init_qp_minus26, pic_init_qs_minus26, chroma_qp_index_offset
+        writer.ue(0);
+        writer.ue(0);
+        writer.ue(0);
+        // deblocking_filter_control_present_flag, so it can be turned off
+        writer.bit(true);
+        // constrained_intra_pred_flag and redundant_pic_cnt_present_flag
+        writer.bit(false);
+        writer.bit(false);
+        nal_unit(NAL_PPS, writer.finish())
+    }
+
+    /// Copies the frame into macroblock order, repeating the last column
+    /// and row to fill out the ones on the edges.
+    fn macroblocks(&self, frame: &VideoFrame) -> Vec<u8> {
+        let width = frame.width as usize;
+        let height = frame.height as usize;
+        let (luma, chroma) = frame.data.split_at(width * height);
+        let mut macroblocks = Vec::with_capacity(self.width_in_mbs * self.height_in_mbs * MB_BYTES);
+        for mb_y in 0..self.height_in_mbs {
+            for mb_x in 0..self.width_in_mbs {
+                for y in 0..MB_SIZE {
+                    let row = (mb_y * MB_SIZE + y).min(height - 1) * width;
+                    for x in 0..MB_SIZE {
+                        macroblocks.push(luma[row + (mb_x * MB_SIZE + x).min(width - 1)]);
+                    }
+                }
+                for component in 0..2 {
+                    for y in 0..MB_SIZE / 2 {
+                        let row = (mb_y * MB_SIZE / 2 + y).min(height / 2 - 1) * width;
+                        for x in 0..MB_SIZE / 2 {
+                            let x = (mb_x * MB_SIZE / 2 + x).min(width / 2 - 1);
+                            macroblocks.push(chroma[row + x * 2 + component]);
+                        }
+                    }
+                }
+            }
+        }
+        macroblocks
+    }
+
+    fn idr_slice(&mut self, macroblocks: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // first_mb_in_slice, slice_type and pic_parameter_set_id
+        writer.ue(0);
+        writer.ue(SLICE_TYPE_I);
+        writer.ue(0);
+        writer.bits(0, LOG2_MAX_FRAME_NUM);
+        // Neighbouring IDR pictures need different ids
+        writer.ue(self.idr_pic_id);
+        self.idr_pic_id = (self.idr_pic_id + 1) % 2;
+        // no_output_of_prior_pics_flag and long_term_reference_flag
+        writer.bit(false);
+        writer.bit(false);
+        write_slice_qp_and_deblocking(&mut writer);
+        for macroblock in macroblocks.chunks_exact(MB_BYTES) {
+            write_pcm_macroblock(&mut writer, MB_TYPE_I_PCM, macroblock);
+        }
+        nal_unit(NAL_IDR_SLICE, writer.finish())
+    }
+
+    fn p_slice(&mut self, macroblocks: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // first_mb_in_slice, slice_type and pic_parameter_set_id
+        writer.ue(0);
+        writer.ue(SLICE_TYPE_P);
+        writer.ue(0);
+        writer.bits(self.frame_num, LOG2_MAX_FRAME_NUM);
+        // num_ref_idx_active_override_flag, ref_pic_list_modification_flag_l0
+        // and adaptive_ref_pic_marking_mode_flag
+        writer.bit(false);
+        writer.bit(false);
+        writer.bit(false);
+        write_slice_qp_and_deblocking(&mut writer);
+        // With every motion vector zero, a skipped macroblock is a copy of
+        // the one in the same place in the last frame
+        let mut skipped = 0;
+        for (macroblock, reference) in macroblocks
+            .chunks_exact(MB_BYTES)
+            .zip(self.reference.chunks_exact(MB_BYTES))
+        {
+            if macroblock == reference {
+                skipped += 1;
+                continue;
+            }
+            writer.ue(skipped);
+            skipped = 0;
+            write_pcm_macroblock(&mut writer, MB_TYPE_P_I_PCM, macroblock);
+        }
+        if skipped > 0 {
+            writer.ue(skipped);
+        }
+        nal_unit(NAL_SLICE, writer.finish())
+    }
+}
+
+impl Encoder for SoftwareH264Encoder {
+    type Input = VideoFrame;
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::Video(self.format.clone())
+    }
+
+    fn encode(&mut self, frame: VideoFrame) -> Result<Vec<EncodedPacket>> {
+        let expected_size = self.format.width as usize * self.format.height as usize * 3 / 2;
+        if frame.width != self.format.width
+            || frame.height != self.format.height
+            || frame.data.len() < expected_size
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected a {}x{} frame, but got {}x{}.",
+                    self.format.width, self.format.height, frame.width, frame.height
+                ),
+            ));
+        }
+        let macroblocks = self.macroblocks(&frame);
+        let keyframe = self.count.is_multiple_of(self.keyframe_interval);
+        let units = if keyframe {
+            self.frame_num = 0;
+            vec![self.sps(), self.pps(), self.idr_slice(&macroblocks)]
+        } else {
+            vec![self.p_slice(&macroblocks)]
+        };
+        self.frame_num = (self.frame_num + 1) % (1 << LOG2_MAX_FRAME_NUM);
+        self.reference = macroblocks;
+        self.count += 1;
+
+        let mut data = Vec::with_capacity(units.iter().map(|unit| unit.len() + 4).sum());
+        for unit in units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(&unit);
+        }
+        Ok(vec![EncodedPacket::new(
+            StreamKind::Video,
+            data,
+            frame.timestamp,
+            HNS_PER_SECOND / self.format.frame_rate as i64,
+            keyframe,
+        )])
+    }
+
+    fn drain(&mut self) -> Result<Vec<EncodedPacket>> {
+        Ok(Vec::new())
+    }
+}
+
+fn nal_unit(nal_type: u8, rbsp: Vec<u8>) -> Vec<u8> {
+    let mut nal = vec![(NAL_REF_IDC << 5) | nal_type];
+    nal.extend(escape_rbsp(&rbsp));
+    nal
+}
+
+/// Ends the slice header, with the quantizer left alone since nothing is
+/// quantized and the deblocking filter turned off so pictures come out
+/// exactly as they went in.
+fn write_slice_qp_and_deblocking(writer: &mut BitWriter) {
+    // slice_qp_delta
+    writer.ue(0);
+    // disable_deblocking_filter_idc
+    writer.ue(1);
+}
+
+fn write_pcm_macroblock(writer: &mut BitWriter, mb_type: u32, macroblock: &[u8]) {
+    writer.ue(mb_type);
+    writer.align();
+    writer.bytes(macroblock);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{SoftwareH264Encoder, MB_BYTES, MB_MAX_BITS, MB_SIZE};
+    use crate::{
+        clock::{PausableClock, SystemClock},
+        encoder_settings::{RateControl, VideoEncoderSettings},
+        mux::h264::{nal_type, split_annex_b, unescape_rbsp, BitReader, Sps, NAL_PPS, NAL_SPS},
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            synthetic::{MemorySink, TestPatternSource},
+            Encoder, Sink, StreamFormat, StreamSession, VideoFrame,
+        },
+    };
+
+    /// Decodes what the encoder produces, which is only ever I_PCM and
+    /// skipped macroblocks, back to NV12 frames.
+    fn decode(packets: &[EncodedPacket]) -> Vec<Vec<u8>> {
+        let mut sps = None;
+        let mut picture = Vec::new();
+        let mut frames = Vec::new();
+        for packet in packets {
+            for nal in split_annex_b(&packet.data) {
+                match nal_type(nal) {
+                    NAL_SPS => sps = Some(Sps::parse(nal).unwrap()),
+                    NAL_PPS => {}
+                    nal_type => {
+                        let sps = sps.expect("The stream starts with an SPS");
+                        let width_in_mbs = sps.width.div_ceil(16) as usize;
+                        let mb_count = width_in_mbs * sps.height.div_ceil(16) as usize;
+                        let rbsp = unescape_rbsp(&nal[1..]);
+                        let mut reader = BitReader::new(&rbsp);
+                        assert_eq!(reader.ue().unwrap(), 0);
+                        let slice_type = reader.ue().unwrap();
+                        assert_eq!(reader.ue().unwrap(), 0);
+                        reader.bits(16).unwrap();
+                        if nal_type == 5 {
+                            assert_eq!(slice_type, 7);
+                            reader.ue().unwrap();
+                            reader.bits(2).unwrap();
+                            picture = vec![0; mb_count * MB_BYTES];
+                        } else {
+                            assert_eq!((nal_type, slice_type), (1, 5));
+                            reader.bits(3).unwrap();
+                        }
+                        assert_eq!(reader.se().unwrap(), 0);
+                        assert_eq!(reader.ue().unwrap(), 1);
+
+                        let mut mb = 0;
+                        while mb < mb_count {
+                            if slice_type == 5 {
+                                mb += reader.ue().unwrap() as usize;
+                                if mb == mb_count {
+                                    break;
+                                }
+                            }
+                            assert_eq!(reader.ue().unwrap(), if slice_type == 5 { 30 } else { 25 });
+                            reader.align();
+                            for byte in &mut picture[mb * MB_BYTES..(mb + 1) * MB_BYTES] {
+                                *byte = reader.bits(8).unwrap() as u8;
+                            }
+                            mb += 1;
+                        }
+                        frames.push(to_nv12(&picture, width_in_mbs, sps.width, sps.height));
+                    }
+                }
+            }
+        }
+        frames
+    }
+
+    fn to_nv12(picture: &[u8], width_in_mbs: usize, width: u32, height: u32) -> Vec<u8> {
+        let width = width as usize;
+        let height = height as usize;
+        let mut nv12 = vec![0; width * height * 3 / 2];
+        let sample = |mb_x: usize, mb_y: usize, offset: usize| {
+            picture[(mb_y * width_in_mbs + mb_x) * MB_BYTES + offset]
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (y % MB_SIZE) * MB_SIZE + x % MB_SIZE;
+                nv12[y * width + x] = sample(x / MB_SIZE, y / MB_SIZE, offset);
+            }
+        }
+        for y in 0..height / 2 {
+            for x in 0..width / 2 {
+                let offset = MB_SIZE * MB_SIZE + (y % 8) * 8 + x % 8;
+                let chroma = width * height + y * width + x * 2;
+                nv12[chroma] = sample(x / 8, y / 8, offset);
+                nv12[chroma + 1] = sample(x / 8, y / 8, offset + 64);
+            }
+        }
+        nv12
+    }
+
+    fn frame(width: u32, height: u32, seed: u32, timestamp: i64) -> VideoFrame {
+        let data = (0..width * height * 3 / 2)
+            .map(|i| (i.wrapping_mul(seed) >> 3) as u8)
+            .collect();
+        VideoFrame {
+            width,
+            height,
+            data,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn round_trips_losslessly() {
+        let settings = VideoEncoderSettings::new(1_000_000);
+        let mut encoder = SoftwareH264Encoder::new(40, 22, 30, &settings).unwrap();
+        let frames = [
+            frame(40, 22, 7, 0),
+            frame(40, 22, 7, 1),
+            frame(40, 22, 13, 2),
+        ];
+        let mut packets = Vec::new();
+        for frame in frames.clone() {
+            packets.extend(encoder.encode(frame).unwrap());
+        }
+        let decoded = decode(&packets);
+        assert_eq!(decoded.len(), 3);
+        for (decoded, frame) in decoded.iter().zip(&frames) {
+            assert_eq!(decoded, &frame.data);
+        }
+    }
+
+    #[test]
+    fn skips_unchanged_macroblocks() {
+        let settings = VideoEncoderSettings::new(1_000_000);
+        let mut encoder = SoftwareH264Encoder::new(64, 64, 30, &settings).unwrap();
+        let mut frame = frame(64, 64, 3, 0);
+        encoder.encode(frame.clone()).unwrap();
+        // Nothing changed, so it's a slice header and one skip run
+        let unchanged = encoder.encode(frame.clone()).unwrap().remove(0);
+        assert!(unchanged.data.len() < 16);
+        // One pixel changed, so one macroblock is stored
+        frame.data[64 * 20 + 40] ^= 0xff;
+        let changed = encoder.encode(frame.clone()).unwrap().remove(0);
+        assert!(changed.data.len() > MB_BYTES && changed.data.len() < MB_BYTES + 32);
+        assert!(!changed.keyframe);
+    }
+
+    #[test]
+    fn keyframes_carry_parameter_sets() {
+        let settings = VideoEncoderSettings {
+            keyframe_interval: Some(3),
+            ..VideoEncoderSettings::new(1_000_000)
+        };
+        let mut encoder = SoftwareH264Encoder::new(1280, 720, 30, &settings).unwrap();
+        let mut keyframes = Vec::new();
+        for index in 0..4 {
+            let packet = encoder
+                .encode(frame(1280, 720, 1, index))
+                .unwrap()
+                .remove(0);
+            let units = split_annex_b(&packet.data);
+            if packet.keyframe {
+                assert_eq!(nal_type(units[0]), NAL_SPS);
+                let sps = Sps::parse(units[0]).unwrap();
+                assert_eq!((sps.profile_idc, sps.constraint_flags), (66, 0xc0));
+                // Enough for every macroblock changing in every frame
+                assert_eq!(sps.level_idc, 61);
+                assert_eq!((sps.width, sps.height), (1280, 720));
+                assert_eq!(nal_type(units[1]), NAL_PPS);
+                assert_eq!(nal_type(units[2]), 5);
+            } else {
+                assert_eq!(units.len(), 1);
+                assert_eq!(nal_type(units[0]), 1);
+            }
+            keyframes.push(packet.keyframe);
+        }
+        assert_eq!(keyframes, [true, false, false, true]);
+    }
+
+    #[test]
+    fn rejects_frames_of_another_size() {
+        let settings = VideoEncoderSettings::new(1_000_000);
+        assert!(SoftwareH264Encoder::new(33, 32, 30, &settings).is_err());
+        let level = VideoEncoderSettings {
+            level: Some("3.0".parse().unwrap()),
+            ..settings.clone()
+        };
+        assert!(SoftwareH264Encoder::new(1920, 1080, 30, &level).is_err());
+        let mut encoder = SoftwareH264Encoder::new(32, 32, 30, &settings).unwrap();
+        assert!(encoder.encode(frame(16, 16, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn has_no_rate_control() {
+        let settings = VideoEncoderSettings {
+            rate_control: Some(RateControl::Cbr),
+            ..VideoEncoderSettings::new(1_000_000)
+        };
+        assert!(SoftwareH264Encoder::new(64, 64, 30, &settings).is_err());
+        // The bit rate asked for is ignored, the worst case is reported
+        let encoder =
+            SoftwareH264Encoder::new(64, 64, 30, &VideoEncoderSettings::new(1_000_000)).unwrap();
+        let StreamFormat::Video(format) = encoder.stream_format() else {
+            panic!("Not a video format");
+        };
+        assert_eq!(format.bit_rate, 16 * 30 * MB_MAX_BITS as u32);
+    }
+
+    #[test]
+    fn takes_i420_frames() {
+        let settings = VideoEncoderSettings::new(1_000_000);
+        let mut encoder = SoftwareH264Encoder::new(4, 2, 30, &settings).unwrap();
+        let i420 = [1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 20, 21];
+        let frame = VideoFrame::from_i420(4, 2, &i420, 0);
+        assert_eq!(frame.data, [1, 2, 3, 4, 5, 6, 7, 8, 10, 20, 11, 21]);
+        let packets = encoder.encode(frame.clone()).unwrap();
+        assert_eq!(decode(&packets), [frame.data]);
+    }
+
+    #[test]
+    fn records_test_pattern() {
+        let sink = Arc::new(Mutex::new(MemorySink::new()));
+        let system_clock = Arc::new(SystemClock::new().unwrap());
+        let settings = VideoEncoderSettings::new(1_000_000);
+        let mut session = StreamSession::video(
+            TestPatternSource::new(system_clock.clone(), 64, 48, 60),
+            SoftwareH264Encoder::new(64, 48, 60, &settings).unwrap(),
+            sink.clone(),
+        )
+        .unwrap();
+        sink.lock().unwrap().start().unwrap();
+        let clock = PausableClock::new(system_clock);
+        clock.start();
+        session.start(clock).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        session.stop().unwrap();
+
+        let sink = sink.lock().unwrap();
+        let packets: Vec<_> = sink.packets_of(StreamKind::Video).cloned().collect();
+        assert!(!packets.is_empty());
+        assert!(packets[0].keyframe);
+        assert_eq!(decode(&packets).len(), packets.len());
+    }
+}
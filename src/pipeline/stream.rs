@@ -0,0 +1,225 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{clock::PausableClock, packet::EncodedPacket};
+
+use super::{Encoder, FrameSource, Result, SampleSource, SharedSink, Timestamped};
+
+/// Runs a source through an encoder and into a sink on its own thread.
+pub struct StreamSession {
+    pump: Option<Box<dyn Pump>>,
+    should_stop: Arc<AtomicBool>,
+    thread_handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl StreamSession {
+    pub fn video<S, E>(source: S, encoder: E, sink: SharedSink) -> Result<Self>
+    where
+        S: FrameSource,
+        E: Encoder<Input = S::Frame>,
+    {
+        Self::new(Frames(source), encoder, sink)
+    }
+
+    pub fn audio<S, E>(source: S, encoder: E, sink: SharedSink) -> Result<Self>
+    where
+        S: SampleSource,
+        E: Encoder<Input = S::Samples>,
+    {
+        Self::new(Samples(source), encoder, sink)
+    }
+
+    fn new<I, E>(input: I, encoder: E, sink: SharedSink) -> Result<Self>
+    where
+        I: Input,
+        E: Encoder<Input = I::Item>,
+    {
+        // The sink needs to know about every stream before it starts.
+        let stream = sink.lock().unwrap().add_stream(encoder.stream_format())?;
+        Ok(Self {
+            pump: Some(Box::new(Stream {
+                input,
+                encoder,
+                sink,
+                stream,
+            })),
+            should_stop: Arc::new(AtomicBool::new(false)),
+            thread_handle: None,
+        })
+    }
+
+    /// Starts pumping on a new thread. Timestamps will be relative to the
+    /// start time of `clock`, which must already be started, and anything
+    /// captured while it is paused is dropped. A session can only be
+    /// started once.
+    pub fn start(&mut self, clock: PausableClock) -> Result<()> {
+        if let Some(pump) = self.pump.take() {
+            let should_stop = self.should_stop.clone();
+            self.thread_handle = Some(std::thread::spawn(move || {
+                let result = pump.run(clock, &should_stop);
+                if result.is_err() {
+                    println!("Recording stopped unexpectedly!");
+                }
+                result
+            }));
+        }
+        Ok(())
+    }
+
+    /// Stops the source, drains the encoder into the sink and waits for the
+    /// thread to finish. Any error the thread ran into is returned here.
+    pub fn stop(&mut self) -> Result<()> {
+        self.should_stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.thread_handle.take() {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    }
+}
+
+/// Lets `StreamSession` treat frame and sample sources the same way.
+trait Input: Send + 'static {
+    type Item: Timestamped;
+
+    fn start(&mut self, start_time: i64) -> Result<()>;
+    fn next(&mut self) -> Result<Option<Self::Item>>;
+    fn stop(&mut self) -> Result<()>;
+    /// Whatever the input still has once it has stopped.
+    fn drain(&mut self) -> Result<Vec<Self::Item>>;
+    fn on_recording_timeline(&self) -> bool;
+}
+
+struct Frames<S>(S);
+
+impl<S: FrameSource> Input for Frames<S> {
+    type Item = S::Frame;
+
+    fn start(&mut self, start_time: i64) -> Result<()> {
+        self.0.start(start_time)
+    }
+
+    fn next(&mut self) -> Result<Option<Self::Item>> {
+        self.0.next_frame()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.0.stop()
+    }
+
+    fn drain(&mut self) -> Result<Vec<Self::Item>> {
+        self.0.drain()
+    }
+
+    fn on_recording_timeline(&self) -> bool {
+        self.0.on_recording_timeline()
+    }
+}
+
+struct Samples<S>(S);
+
+impl<S: SampleSource> Input for Samples<S> {
+    type Item = S::Samples;
+
+    fn start(&mut self, start_time: i64) -> Result<()> {
+        self.0.start(start_time)
+    }
+
+    fn next(&mut self) -> Result<Option<Self::Item>> {
+        self.0.next_samples()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.0.stop()
+    }
+
+    fn drain(&mut self) -> Result<Vec<Self::Item>> {
+        self.0.drain()
+    }
+
+    fn on_recording_timeline(&self) -> bool {
+        false
+    }
+}
+
+trait Pump: Send {
+    fn run(self: Box<Self>, clock: PausableClock, should_stop: &AtomicBool) -> Result<()>;
+}
+
+struct Stream<I, E> {
+    input: I,
+    encoder: E,
+    sink: SharedSink,
+    /// The number the sink gave the stream.
+    stream: usize,
+}
+
+impl<I, E> Pump for Stream<I, E>
+where
+    I: Input,
+    E: Encoder<Input = I::Item>,
+{
+    fn run(mut self: Box<Self>, clock: PausableClock, should_stop: &AtomicBool) -> Result<()> {
+        self.input.start(clock.start_time())?;
+        let result = self.pump(&clock, should_stop);
+        // Always give the source a chance to shut down, but report the
+        // first thing that went wrong.
+        let stop_result = self.input.stop();
+        result?;
+        stop_result?;
+        for item in self.input.drain()? {
+            self.encode(&clock, item)?;
+        }
+        let packets = self.encoder.drain()?;
+        self.write(packets)
+    }
+}
+
+impl<I, E> Stream<I, E>
+where
+    I: Input,
+    E: Encoder<Input = I::Item>,
+{
+    fn pump(&mut self, clock: &PausableClock, should_stop: &AtomicBool) -> Result<()> {
+        while !should_stop.load(Ordering::SeqCst) {
+            match self.input.next()? {
+                Some(item) => self.encode(clock, item)?,
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, clock: &PausableClock, mut item: I::Item) -> Result<()> {
+        // Anything captured while paused is dropped here, so the encoder
+        // only ever sees a continuous timeline.
+        let timestamp = if self.input.on_recording_timeline() {
+            Some(item.timestamp())
+        } else {
+            clock.recording_time(item.timestamp())
+        };
+        if let Some(timestamp) = timestamp {
+            item.set_timestamp(timestamp);
+            let packets = self.encoder.encode(item)?;
+            self.write(packets)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, packets: Vec<EncodedPacket>) -> Result<()> {
+        if packets.is_empty() {
+            return Ok(());
+        }
+        let mut sink = self.sink.lock().unwrap();
+        for mut packet in packets {
+            packet.stream = self.stream;
+            sink.write(packet)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+//! Pure Rust stand-ins for the capture, encoding and muxing stages. The
+//! sources are paced against a `Clock`, so a session built from them behaves
+//! like a real recording, just without any hardware. With a `ManualClock`
+//! they produce exactly what the clock has advanced past.
+
+use std::{f32::consts::PI, io, marker::PhantomData};
+
+use crate::{
+    clock::{SharedClock, HNS_PER_SECOND},
+    packet::{EncodedPacket, StreamKind},
+};
+
+use super::{
+    next_stream_number, AudioBuffer, AudioCodec, AudioStreamFormat, Encoder, FrameSource, Result,
+    SampleSource, Sink, StreamFormat, VideoCodec, VideoFrame, VideoStreamFormat,
+};
+
+/// How long `clock` has run since `start_time`, or `None` before starting.
+fn elapsed_ticks(clock: &SharedClock, start_time: Option<i64>) -> Option<i64> {
+    start_time.map(|start_time| clock.now() - start_time)
+}
+
+/// Produces NV12 frames of scrolling vertical bars at a constant rate. Each
+/// frame is handed out once the clock passes its time.
+pub struct TestPatternSource {
+    clock: SharedClock,
+    width: u32,
+    height: u32,
+    frame_period: i64,
+    frame_index: i64,
+    start_time: Option<i64>,
+}
+
+impl TestPatternSource {
+    pub fn new(clock: SharedClock, width: u32, height: u32, frame_rate: u32) -> Self {
+        assert!(
+            width.is_multiple_of(2) && height.is_multiple_of(2),
+            "NV12 needs even dimensions"
+        );
+        Self {
+            clock,
+            width,
+            height,
+            frame_period: HNS_PER_SECOND / frame_rate as i64,
+            frame_index: 0,
+            start_time: None,
+        }
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let offset = self.frame_index as usize * 4;
+        let mut data = vec![128u8; width * height * 3 / 2];
+        for row in data[..width * height].chunks_exact_mut(width) {
+            for (x, luma) in row.iter_mut().enumerate() {
+                *luma = (((x + offset) * 8 / width) * 32) as u8;
+            }
+        }
+        data
+    }
+}
+
+impl FrameSource for TestPatternSource {
+    type Frame = VideoFrame;
+
+    fn start(&mut self, start_time: i64) -> Result<()> {
+        self.start_time = Some(start_time);
+        self.frame_index = 0;
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>> {
+        let timestamp = self.frame_index * self.frame_period;
+        if elapsed_ticks(&self.clock, self.start_time).is_none_or(|elapsed| elapsed < timestamp) {
+            return Ok(None);
+        }
+        let frame = VideoFrame {
+            width: self.width,
+            height: self.height,
+            data: self.render(),
+            timestamp,
+        };
+        self.frame_index += 1;
+        Ok(Some(frame))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.start_time = None;
+        Ok(())
+    }
+}
+
+/// Produces a sine wave as 16-bit PCM, in buffers of a fixed size.
+pub struct SineWaveSource {
+    clock: SharedClock,
+    sample_rate: u32,
+    channels: u16,
+    frequency: f32,
+    frames_per_buffer: u32,
+    frames_produced: i64,
+    start_time: Option<i64>,
+}
+
+impl SineWaveSource {
+    pub fn new(
+        clock: SharedClock,
+        sample_rate: u32,
+        channels: u16,
+        frequency: f32,
+        frames_per_buffer: u32,
+    ) -> Self {
+        Self {
+            clock,
+            sample_rate,
+            channels,
+            frequency,
+            frames_per_buffer,
+            frames_produced: 0,
+            start_time: None,
+        }
+    }
+
+    fn frames_to_ticks(&self, frames: i64) -> i64 {
+        frames * HNS_PER_SECOND / self.sample_rate as i64
+    }
+}
+
+impl SampleSource for SineWaveSource {
+    type Samples = AudioBuffer;
+
+    fn start(&mut self, start_time: i64) -> Result<()> {
+        self.start_time = Some(start_time);
+        self.frames_produced = 0;
+        Ok(())
+    }
+
+    fn next_samples(&mut self) -> Result<Option<AudioBuffer>> {
+        let end = self.frames_produced + self.frames_per_buffer as i64;
+        let end_time = self.frames_to_ticks(end);
+        if elapsed_ticks(&self.clock, self.start_time).is_none_or(|elapsed| elapsed < end_time) {
+            return Ok(None);
+        }
+        let mut data =
+            Vec::with_capacity(self.frames_per_buffer as usize * self.channels as usize * 2);
+        for frame in self.frames_produced..end {
+            let phase = 2.0 * PI * self.frequency * frame as f32 / self.sample_rate as f32;
+            let value = (phase.sin() * i16::MAX as f32 * 0.5) as i16;
+            for _ in 0..self.channels {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let buffer = AudioBuffer {
+            data,
+            frames: self.frames_per_buffer,
+            timestamp: self.frames_to_ticks(self.frames_produced),
+            duration: self.frames_to_ticks(end) - self.frames_to_ticks(self.frames_produced),
+        };
+        self.frames_produced = end;
+        Ok(Some(buffer))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.start_time = None;
+        Ok(())
+    }
+}
+
+/// Wraps its input in packets unchanged. Video frames are marked as
+/// keyframes at a fixed interval so GOP handling downstream can be tested.
+pub struct PassthroughEncoder<I> {
+    format: StreamFormat,
+    keyframe_interval: u64,
+    count: u64,
+    _input: PhantomData<fn(I)>,
+}
+
+impl PassthroughEncoder<VideoFrame> {
+    pub fn video(width: u32, height: u32, frame_rate: u32, keyframe_interval: u64) -> Self {
+        Self {
+            format: StreamFormat::Video(VideoStreamFormat {
+                codec: VideoCodec::Raw,
+                width,
+                height,
+                frame_rate,
+                bit_rate: width * height * 12 * frame_rate,
+            }),
+            keyframe_interval: keyframe_interval.max(1),
+            count: 0,
+            _input: PhantomData,
+        }
+    }
+}
+
+impl PassthroughEncoder<AudioBuffer> {
+    pub fn audio(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            format: StreamFormat::Audio(AudioStreamFormat {
+                codec: AudioCodec::Pcm,
+                sample_rate,
+                channels,
+                bit_rate: sample_rate * channels as u32 * 16,
+                name: None,
+                language: None,
+            }),
+            keyframe_interval: 1,
+            count: 0,
+            _input: PhantomData,
+        }
+    }
+}
+
+impl Encoder for PassthroughEncoder<VideoFrame> {
+    type Input = VideoFrame;
+
+    fn stream_format(&self) -> StreamFormat {
+        self.format.clone()
+    }
+
+    fn encode(&mut self, frame: VideoFrame) -> Result<Vec<EncodedPacket>> {
+        let frame_rate = match &self.format {
+            StreamFormat::Video(format) => format.frame_rate,
+            StreamFormat::Audio(_) => unreachable!(),
+        };
+        let keyframe = self.count.is_multiple_of(self.keyframe_interval);
+        self.count += 1;
+        Ok(vec![EncodedPacket::new(
+            StreamKind::Video,
+            frame.data,
+            frame.timestamp,
+            HNS_PER_SECOND / frame_rate as i64,
+            keyframe,
+        )])
+    }
+
+    fn drain(&mut self) -> Result<Vec<EncodedPacket>> {
+        Ok(Vec::new())
+    }
+}
+
+impl Encoder for PassthroughEncoder<AudioBuffer> {
+    type Input = AudioBuffer;
+
+    fn stream_format(&self) -> StreamFormat {
+        self.format.clone()
+    }
+
+    fn encode(&mut self, buffer: AudioBuffer) -> Result<Vec<EncodedPacket>> {
+        self.count += 1;
+        Ok(vec![EncodedPacket::new(
+            StreamKind::Audio,
+            buffer.data,
+            buffer.timestamp,
+            buffer.duration,
+            true,
+        )])
+    }
+
+    fn drain(&mut self) -> Result<Vec<EncodedPacket>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Keeps everything it is given so tests can inspect it afterwards.
+#[derive(Default)]
+pub struct MemorySink {
+    pub formats: Vec<StreamFormat>,
+    pub packets: Vec<EncodedPacket>,
+    pub chapters: Vec<(i64, String)>,
+    pub started: bool,
+    pub stopped: bool,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn packets_of(&self, kind: StreamKind) -> impl Iterator<Item = &EncodedPacket> {
+        self.packets
+            .iter()
+            .filter(move |packet| packet.kind == kind)
+    }
+}
+
+impl Sink for MemorySink {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        if self.started {
+            return Err(io::Error::other("Streams must be added before starting."));
+        }
+        let number = next_stream_number(&self.formats, format.kind());
+        self.formats.push(format);
+        Ok(number)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.started = true;
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        if !self.started || self.stopped {
+            return Err(io::Error::other("The sink isn't accepting packets."));
+        }
+        self.packets.push(packet);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.stopped = true;
+        Ok(())
+    }
+
+    fn add_chapter(&mut self, timestamp: i64, title: &str) -> Result<()> {
+        self.chapters.push((timestamp, title.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{MemorySink, PassthroughEncoder, SineWaveSource, TestPatternSource};
+    use crate::{
+        clock::{Clock, ManualClock, PausableClock, SystemClock, HNS_PER_SECOND},
+        packet::StreamKind,
+        pipeline::{Encoder, FrameSource, SampleSource, Sink, StreamSession},
+    };
+
+    #[test]
+    fn test_pattern_is_nv12() {
+        let clock = Arc::new(ManualClock::new(HNS_PER_SECOND));
+        let mut source = TestPatternSource::new(clock.clone(), 64, 32, 1000);
+        assert!(source.next_frame().unwrap().is_none());
+        source.start(0).unwrap();
+        let frame = source.next_frame().unwrap().unwrap();
+        assert_eq!(frame.data.len(), 64 * 32 * 3 / 2);
+        assert_eq!(frame.timestamp, 0);
+    }
+
+    #[test]
+    fn test_pattern_follows_the_clock() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        clock.set_ticks(5_000_000);
+        let mut source = TestPatternSource::new(clock.clone(), 2, 2, 30);
+        source.start(clock.now()).unwrap();
+        let mut timestamps = Vec::new();
+        for _ in 0..10 {
+            clock.advance_hns(HNS_PER_SECOND / 20);
+            while let Some(frame) = source.next_frame().unwrap() {
+                timestamps.push(frame.timestamp);
+            }
+        }
+        // Every frame due in the first half second, including the one at 0.
+        assert_eq!(timestamps.len(), 16);
+        assert!(timestamps
+            .iter()
+            .enumerate()
+            .all(|(index, &timestamp)| timestamp == index as i64 * (HNS_PER_SECOND / 30)));
+    }
+
+    #[test]
+    fn sine_wave_buffers_are_contiguous() {
+        let clock = Arc::new(ManualClock::new(HNS_PER_SECOND));
+        let mut source = SineWaveSource::new(clock.clone(), 48000, 2, 440.0, 48);
+        source.start(0).unwrap();
+        assert!(source.next_samples().unwrap().is_none());
+        clock.advance_hns(HNS_PER_SECOND / 1000 * 3);
+        let mut buffers = Vec::new();
+        while let Some(buffer) = source.next_samples().unwrap() {
+            buffers.push(buffer);
+        }
+        assert_eq!(buffers.len(), 3);
+        for pair in buffers.windows(2) {
+            assert_eq!(pair[0].timestamp + pair[0].duration, pair[1].timestamp);
+        }
+        assert_eq!(buffers[0].data.len(), 48 * 2 * 2);
+    }
+
+    #[test]
+    fn passthrough_marks_keyframes() {
+        let clock = Arc::new(ManualClock::new(HNS_PER_SECOND));
+        let mut encoder = PassthroughEncoder::video(2, 2, 30, 3);
+        let mut source = TestPatternSource::new(clock.clone(), 2, 2, 30);
+        source.start(0).unwrap();
+        clock.advance_hns(HNS_PER_SECOND);
+        let mut keyframes = Vec::new();
+        while keyframes.len() < 6 {
+            let frame = source.next_frame().unwrap().unwrap();
+            let packets = encoder.encode(frame).unwrap();
+            keyframes.push(packets[0].keyframe);
+        }
+        assert_eq!(keyframes, [true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn memory_sink_enforces_ordering() {
+        let mut sink = MemorySink::new();
+        let encoder = PassthroughEncoder::audio(48000, 2);
+        assert!(sink
+            .write(crate::packet::EncodedPacket::new(
+                StreamKind::Audio,
+                Vec::new(),
+                0,
+                0,
+                true
+            ))
+            .is_err());
+        sink.start().unwrap();
+        assert!(sink.add_stream(encoder.stream_format()).is_err());
+    }
+
+    #[test]
+    fn stream_session_pumps_into_sink() {
+        let sink = Arc::new(Mutex::new(MemorySink::new()));
+        let system_clock = Arc::new(SystemClock::new().unwrap());
+        let mut session = StreamSession::audio(
+            SineWaveSource::new(system_clock.clone(), 48000, 2, 440.0, 480),
+            PassthroughEncoder::audio(48000, 2),
+            sink.clone(),
+        )
+        .unwrap();
+        sink.lock().unwrap().start().unwrap();
+        let clock = PausableClock::new(system_clock);
+        clock.start();
+        session.start(clock).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        session.stop().unwrap();
+
+        let sink = sink.lock().unwrap();
+        assert_eq!(sink.formats.len(), 1);
+        assert!(sink.packets_of(StreamKind::Audio).count() > 0);
+    }
+}
@@ -0,0 +1,399 @@
+//! Keeps private windows, like password managers and chat apps, out of a
+//! recording. While one is in the foreground its frames are replaced with a
+//! card, and the audio can be muted too.
+//!
+//! Focus changes are reported a little after they happen, so frames and
+//! audio are held back for `PRIVACY_DELAY` before the timeline is checked.
+//! That way a frame captured just after a private window came up is hidden
+//! even if the capture got to it before the focus change was reported.
+
+#[cfg(windows)]
+use std::sync::Arc;
+use std::{collections::VecDeque, fmt::Display, str::FromStr, sync::Mutex};
+
+use crate::{
+    auto_record::{parse_lines, FocusedWindow, Matcher},
+    clock::HNS_PER_SECOND,
+    mixer::MixedBlock,
+};
+
+/// How long frames and audio are held back, in 100ns units. Much longer
+/// than focus changes take to be reported.
+pub const PRIVACY_DELAY: i64 = HNS_PER_SECOND / 10;
+
+/// How long focus changes are kept after the delay, in 100ns units. Frames
+/// and audio blocks are let go of as soon as the delay has passed, so this
+/// only has to cover the length of one, with plenty to spare.
+const HELD_MARGIN: i64 = HNS_PER_SECOND;
+
+/// The windows that are kept out of the recording.
+#[derive(Clone, Debug, Default)]
+pub struct PrivacyList {
+    matchers: Vec<Matcher>,
+}
+
+impl PrivacyList {
+    /// Parses a window on each line: `title`, `process` or `class`, then
+    /// what to match, as in `process KeePass.exe`. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Ok(Self {
+            matchers: parse_lines(text, Matcher::parse)?,
+        })
+    }
+
+    pub fn is_private(&self, window: &FocusedWindow) -> bool {
+        self.matchers.iter().any(|matcher| matcher.matches(window))
+    }
+}
+
+/// What private windows are replaced with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Card {
+    /// A solid color, as red, green and blue.
+    Color([u8; 3]),
+    /// The frame scaled down so far nothing can be read, and back up.
+    Blur,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseCardError(&'static str);
+
+impl FromStr for Card {
+    type Err = ParseCardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = ParseCardError(
+            "Invalid privacy-card value! Expecting: blur, black, or a color like #202020.",
+        );
+        match s.to_lowercase().as_str() {
+            "blur" => Ok(Card::Blur),
+            "black" => Ok(Card::Color([0, 0, 0])),
+            color => {
+                let hex = color.strip_prefix('#').ok_or(error)?;
+                if hex.len() != 6 || !hex.is_ascii() {
+                    return Err(error);
+                }
+                let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16);
+                match (channel(0), channel(2), channel(4)) {
+                    (Ok(red), Ok(green), Ok(blue)) => Ok(Card::Color([red, green, blue])),
+                    _ => Err(error),
+                }
+            }
+        }
+    }
+}
+
+impl Display for Card {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Card::Color([0, 0, 0]) => write!(f, "black"),
+            Card::Color([red, green, blue]) => write!(f, "#{:02x}{:02x}{:02x}", red, green, blue),
+            Card::Blur => write!(f, "blur"),
+        }
+    }
+}
+
+impl Display for ParseCardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseCardError {}
+
+/// How private windows are kept out of a recording.
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct Privacy {
+    pub timeline: Arc<FocusTimeline>,
+    pub card: Card,
+    /// Whether the audio is muted too.
+    pub mute_audio: bool,
+}
+
+/// When private windows were in the foreground, as reported by the focus
+/// hook. Times are on the system clock, in 100ns units, so streams add
+/// their start time to look things up.
+#[derive(Debug, Default)]
+pub struct FocusTimeline {
+    // When focus moved, and whether it moved to a private window, oldest
+    // first
+    changes: Mutex<Vec<(i64, bool)>>,
+}
+
+impl FocusTimeline {
+    pub fn record(&self, timestamp: i64, private: bool) {
+        let mut changes = self.changes.lock().unwrap();
+        // Reports can cross each other on the way in
+        let index = changes.partition_point(|&(time, _)| time <= timestamp);
+        changes.insert(index, (timestamp, private));
+
+        // Nothing from before the horizon is held back any more, so only
+        // the last change before it still matters
+        let horizon = timestamp - PRIVACY_DELAY - HELD_MARGIN;
+        let stale = changes.partition_point(|&(time, _)| time <= horizon);
+        if stale > 1 {
+            changes.drain(..stale - 1);
+        }
+    }
+
+    /// Whether a private window was in the foreground at `timestamp`.
+    /// Nothing is private before the first report.
+    pub fn is_private(&self, timestamp: i64) -> bool {
+        is_private_at(&self.changes.lock().unwrap(), timestamp)
+    }
+
+    /// The parts of `start..end` a private window was in the foreground
+    /// for.
+    pub fn private_spans(&self, start: i64, end: i64) -> Vec<(i64, i64)> {
+        let changes = self.changes.lock().unwrap();
+        let mut spans = Vec::new();
+        let mut private_since = is_private_at(&changes, start).then_some(start);
+        for &(time, private) in changes
+            .iter()
+            .filter(|(time, _)| *time > start && *time < end)
+        {
+            match (private_since, private) {
+                (None, true) => private_since = Some(time),
+                (Some(since), false) => {
+                    spans.push((since, time));
+                    private_since = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(since) = private_since {
+            spans.push((since, end));
+        }
+        spans
+    }
+
+    /// Silences the parts of a block a private window was in the foreground
+    /// for. `origin` is the time the block's timestamp is relative to.
+    pub fn mute(&self, block: &mut MixedBlock, origin: i64) {
+        let start = origin + block.timestamp;
+        let spans = self.private_spans(start, start + block.duration);
+        if spans.is_empty() || block.frames == 0 {
+            return;
+        }
+        let channels = block.samples.len() / block.frames as usize;
+        let frame_at = |time: i64| {
+            let frame = (time - start) as i128 * block.frames as i128 / block.duration as i128;
+            frame.clamp(0, block.frames as i128) as usize
+        };
+        for (span_start, span_end) in spans {
+            let range = frame_at(span_start) * channels..frame_at(span_end) * channels;
+            block.samples[range].fill(0.0);
+        }
+    }
+}
+
+fn is_private_at(changes: &[(i64, bool)], timestamp: i64) -> bool {
+    let index = changes.partition_point(|&(time, _)| time <= timestamp);
+    index > 0 && changes[index - 1].1
+}
+
+/// Holds things back until the focus changes that could affect them have
+/// been reported.
+pub struct PrivacyQueue<T> {
+    // By timestamp, on the system clock
+    items: VecDeque<(i64, T)>,
+}
+
+impl<T> PrivacyQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            items: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, timestamp: i64, item: T) {
+        self.items.push_back((timestamp, item));
+    }
+
+    /// The oldest item, once it's been held for `PRIVACY_DELAY` by `now`,
+    /// with whether a private window was in the foreground when it was
+    /// captured.
+    pub fn pop(&mut self, timeline: &FocusTimeline, now: i64) -> Option<(T, bool)> {
+        let &(timestamp, _) = self.items.front()?;
+        if now - timestamp < PRIVACY_DELAY {
+            return None;
+        }
+        let (timestamp, item) = self.items.pop_front()?;
+        Some((item, timeline.is_private(timestamp)))
+    }
+
+    /// Everything that's left, once nothing more is coming.
+    pub fn drain(&mut self, timeline: &FocusTimeline) -> Vec<(T, bool)> {
+        self.items
+            .drain(..)
+            .map(|(timestamp, item)| (item, timeline.is_private(timestamp)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Card, FocusTimeline, PrivacyList, PrivacyQueue, PRIVACY_DELAY};
+    use crate::{auto_record::FocusedWindow, mixer::MixedBlock};
+
+    const MS: i64 = 10_000;
+    // A frame every 16ms or so
+    const FRAME: i64 = 166_667;
+
+    fn window(title: &str, process: &str) -> FocusedWindow {
+        FocusedWindow {
+            title: title.to_owned(),
+            process: process.to_owned(),
+            class: String::new(),
+        }
+    }
+
+    #[test]
+    fn matches_private_windows() {
+        let list = PrivacyList::parse(
+            "
+            # Passwords and chat
+            process KeePass
+            title - Discord$
+            ",
+        )
+        .unwrap();
+        assert!(list.is_private(&window("Database.kdbx - KeePass", "KeePass.exe")));
+        assert!(list.is_private(&window("#general - Discord", "Discord.exe")));
+        assert!(!list.is_private(&window("Discord - Wikipedia", "firefox.exe")));
+        assert!(!PrivacyList::default().is_private(&window("", "KeePass.exe")));
+        assert!(PrivacyList::parse("process")
+            .unwrap_err()
+            .starts_with("line 1"));
+    }
+
+    /// A scripted run: frames are captured every `FRAME`, focus changes
+    /// happen at the times given but are only reported `lag` later, and
+    /// frames are handed out as soon as the queue lets them go.
+    fn run(changes: &[(i64, bool)], lag: i64, duration: i64) -> Vec<(i64, bool)> {
+        let timeline = FocusTimeline::default();
+        let mut queue = PrivacyQueue::new();
+        let mut reported = 0;
+        let mut captured = 0;
+        let mut released = Vec::new();
+        let mut now = 0;
+        while now < duration {
+            while reported < changes.len() && changes[reported].0 + lag <= now {
+                timeline.record(changes[reported].0, changes[reported].1);
+                reported += 1;
+            }
+            if captured * FRAME <= now {
+                queue.push(captured * FRAME, captured * FRAME);
+                captured += 1;
+            }
+            while let Some(frame) = queue.pop(&timeline, now) {
+                released.push(frame);
+            }
+            now += MS / 10;
+        }
+        released.extend(queue.drain(&timeline));
+        released
+    }
+
+    #[test]
+    fn hides_frames_from_when_focus_changed() {
+        let changes = [(50 * FRAME + 3 * MS, true), (80 * FRAME - MS, false)];
+        // Reported straight away, or most of the way through the delay
+        for lag in [0, 5 * MS, PRIVACY_DELAY - MS] {
+            let frames = run(&changes, lag, 100 * FRAME);
+            assert_eq!(frames.len(), 100);
+            for (index, (timestamp, private)) in frames.into_iter().enumerate() {
+                assert_eq!(timestamp, index as i64 * FRAME);
+                assert_eq!(
+                    private,
+                    (51..80).contains(&index),
+                    "frame {index}, lag {lag}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn holds_frames_back_for_the_delay() {
+        let timeline = FocusTimeline::default();
+        let mut queue = PrivacyQueue::new();
+        queue.push(0, "first");
+        queue.push(FRAME, "second");
+        assert_eq!(queue.pop(&timeline, PRIVACY_DELAY - 1), None);
+        assert_eq!(queue.pop(&timeline, PRIVACY_DELAY), Some(("first", false)));
+        assert_eq!(queue.pop(&timeline, PRIVACY_DELAY), None);
+        timeline.record(FRAME, true);
+        assert_eq!(queue.drain(&timeline), [("second", true)]);
+    }
+
+    #[test]
+    fn keeps_changes_in_order() {
+        let timeline = FocusTimeline::default();
+        assert!(!timeline.is_private(0));
+        timeline.record(100, true);
+        timeline.record(300, true);
+        timeline.record(200, false);
+        assert!(!timeline.is_private(99));
+        assert!(timeline.is_private(100));
+        assert!(!timeline.is_private(250));
+        assert!(timeline.is_private(1000));
+        assert_eq!(timeline.private_spans(0, 1000), [(100, 200), (300, 1000)]);
+        assert_eq!(timeline.private_spans(150, 250), [(150, 200)]);
+        assert_eq!(timeline.private_spans(200, 300), []);
+    }
+
+    #[test]
+    fn forgets_changes_nothing_is_held_for() {
+        let timeline = FocusTimeline::default();
+        for index in 0..1000 {
+            timeline.record(index * 100 * MS, index % 2 == 0);
+        }
+        // Only the changes from 1.1 seconds before the last one on are kept
+        assert_eq!(timeline.changes.lock().unwrap().len(), 12);
+        let last = 999 * 100 * MS;
+        for time in [last - PRIVACY_DELAY - FRAME, last - 5 * MS, last + MS] {
+            let index = time / (100 * MS);
+            assert_eq!(timeline.is_private(time), index % 2 == 0, "at {time}");
+        }
+    }
+
+    #[test]
+    fn mutes_audio_from_when_focus_changed() {
+        let timeline = FocusTimeline::default();
+        let origin = 5_000 * MS;
+        // Private from 2.5ms into a 10ms block, until 7.5ms into it
+        timeline.record(origin + 1000 * MS + 25_000, true);
+        timeline.record(origin + 1000 * MS + 75_000, false);
+        let mut block = MixedBlock {
+            samples: vec![1.0; 480 * 2],
+            frames: 480,
+            timestamp: 1000 * MS,
+            duration: 10 * MS,
+        };
+        timeline.mute(&mut block, origin);
+        for (frame, samples) in block.samples.chunks(2).enumerate() {
+            let expected = if (120..360).contains(&frame) {
+                0.0
+            } else {
+                1.0
+            };
+            assert_eq!(samples, [expected; 2], "frame {frame}");
+        }
+    }
+
+    #[test]
+    fn parses_card() {
+        for card in [
+            Card::Blur,
+            Card::Color([0, 0, 0]),
+            Card::Color([32, 0, 255]),
+        ] {
+            assert_eq!(card.to_string().parse(), Ok(card));
+        }
+        assert_eq!("#FF8000".parse(), Ok(Card::Color([255, 128, 0])));
+        for invalid in ["", "red", "#fff", "ff8000", "#ff80zz", "#ff80é"] {
+            assert!(invalid.parse::<Card>().is_err(), "{invalid}");
+        }
+    }
+}
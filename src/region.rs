@@ -0,0 +1,236 @@
+//! Picks the part of a display that gets recorded. Regions are in physical
+//! pixels: the process is made DPI aware on startup, so window bounds and
+//! display bounds both come back unscaled, and match the captured frames
+//! pixel for pixel.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::resolution::{Rect, Size};
+
+/// A rectangle that may lie partly or entirely off a display, like the
+/// bounds of a window that has been dragged halfway off screen.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// A region from the edges of a Win32 `RECT`, which can be inside out.
+    pub fn from_edges(left: i32, top: i32, right: i32, bottom: i32) -> Self {
+        Self::new(
+            left,
+            top,
+            (right as i64 - left as i64).clamp(0, u32::MAX as i64) as u32,
+            (bottom as i64 - top as i64).clamp(0, u32::MAX as i64) as u32,
+        )
+    }
+
+    /// The same region, relative to a display whose top left corner is at
+    /// `origin`, in desktop coordinates.
+    pub fn relative_to(&self, origin: (i32, i32)) -> Self {
+        Self::new(
+            self.x.saturating_sub(origin.0),
+            self.y.saturating_sub(origin.1),
+            self.width,
+            self.height,
+        )
+    }
+
+    /// The part of the region that is on a display `bounds` big, or `None`
+    /// if less than `MIN_SIDE` of it is on the display either way.
+    pub fn clamp(&self, bounds: Size) -> Option<Rect> {
+        let left = (self.x as i64).clamp(0, bounds.width as i64);
+        let top = (self.y as i64).clamp(0, bounds.height as i64);
+        let right = (self.x as i64 + self.width as i64).clamp(0, bounds.width as i64);
+        let bottom = (self.y as i64 + self.height as i64).clamp(0, bounds.height as i64);
+        if right - left < MIN_SIDE as i64 || bottom - top < MIN_SIDE as i64 {
+            return None;
+        }
+        Some(Rect::new(
+            left as u32,
+            top as u32,
+            (right - left) as u32,
+            (bottom - top) as u32,
+        ))
+    }
+}
+
+/// The smallest width or height a recorded region can have.
+pub const MIN_SIDE: u32 = 16;
+
+/// Parses `x,y,width,height`.
+fn parse_region(s: &str) -> Option<Region> {
+    let values: Vec<&str> = s.split(',').map(str::trim).collect();
+    let [x, y, width, height] = values[..] else {
+        return None;
+    };
+    let region = Region::new(
+        x.parse().ok()?,
+        y.parse().ok()?,
+        width.parse().ok()?,
+        height.parse().ok()?,
+    );
+    (region.width >= MIN_SIDE && region.height >= MIN_SIDE).then_some(region)
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// What part of the display gets recorded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Crop {
+    /// A fixed part of the display, relative to its top left corner.
+    Region(Region),
+    /// Whatever window is in the foreground, following it as it moves,
+    /// changes size, or another window takes over.
+    Window,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseCropError(&'static str);
+
+impl FromStr for Crop {
+    type Err = ParseCropError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "window" => Ok(Crop::Window),
+            region => parse_region(region).map(Crop::Region).ok_or(ParseCropError(
+                "Invalid crop value! Expecting: x,y,width,height (at least 16 by 16), or window.",
+            )),
+        }
+    }
+}
+
+impl Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Crop::Region(region) => write!(f, "{}", region),
+            Crop::Window => write!(f, "window"),
+        }
+    }
+}
+
+impl Display for ParseCropError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseCropError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crop, Region};
+    use crate::resolution::{Rect, Size};
+
+    const DISPLAY: Size = Size::new(3840, 2160);
+
+    #[test]
+    fn keeps_regions_on_the_display() {
+        let inside = Region::new(100, 200, 1280, 720);
+        assert_eq!(inside.clamp(DISPLAY), Some(Rect::new(100, 200, 1280, 720)));
+        let whole = Region::new(0, 0, 3840, 2160);
+        assert_eq!(whole.clamp(DISPLAY), Some(Rect::of(DISPLAY)));
+    }
+
+    #[test]
+    fn clamps_regions_hanging_off_each_edge() {
+        let cases = [
+            (
+                Region::new(-100, 500, 1280, 720),
+                Rect::new(0, 500, 1180, 720),
+            ),
+            (
+                Region::new(500, -100, 1280, 720),
+                Rect::new(500, 0, 1280, 620),
+            ),
+            (
+                Region::new(3000, 500, 1280, 720),
+                Rect::new(3000, 500, 840, 720),
+            ),
+            (
+                Region::new(500, 1800, 1280, 720),
+                Rect::new(500, 1800, 1280, 360),
+            ),
+            (Region::new(-10, -10, 4000, 3000), Rect::of(DISPLAY)),
+        ];
+        for (region, clamped) in cases {
+            assert_eq!(region.clamp(DISPLAY), Some(clamped), "{region}");
+        }
+    }
+
+    #[test]
+    fn drops_regions_that_are_off_the_display() {
+        let cases = [
+            Region::new(-1280, 0, 1280, 720),
+            Region::new(3840, 0, 1280, 720),
+            Region::new(0, 2160, 1280, 720),
+            Region::new(0, -720, 1280, 720),
+            // Only a sliver left on screen
+            Region::new(3830, 0, 1280, 720),
+            Region::new(i32::MIN, i32::MIN, u32::MAX, 10),
+        ];
+        for region in cases {
+            assert_eq!(region.clamp(DISPLAY), None, "{region}");
+        }
+        // Doesn't overflow at the far end either
+        let huge = Region::new(i32::MAX, i32::MAX, u32::MAX, u32::MAX);
+        assert_eq!(huge.clamp(DISPLAY), None);
+        let everything = Region::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX);
+        assert_eq!(everything.clamp(DISPLAY), Some(Rect::of(DISPLAY)));
+    }
+
+    #[test]
+    fn moves_window_bounds_onto_the_display() {
+        // A second display to the left of the primary one, and one above.
+        let window = Region::from_edges(-2000, 100, -720, 820);
+        assert_eq!(window, Region::new(-2000, 100, 1280, 720));
+        assert_eq!(
+            window.relative_to((-2560, 0)).clamp(Size::new(2560, 1440)),
+            Some(Rect::new(560, 100, 1280, 720))
+        );
+        let above = Region::from_edges(100, -1000, 500, -700);
+        assert_eq!(
+            above.relative_to((0, -1080)).clamp(Size::new(1920, 1080)),
+            Some(Rect::new(100, 80, 400, 300))
+        );
+        // Edges the wrong way round make an empty region.
+        assert_eq!(Region::from_edges(10, 10, 0, 0).clamp(DISPLAY), None);
+    }
+
+    #[test]
+    fn parses_crop() {
+        for crop in [Crop::Region(Region::new(-5, 10, 1280, 720)), Crop::Window] {
+            assert_eq!(crop.to_string().parse(), Ok(crop));
+        }
+        assert_eq!(
+            "0, 0, 640, 480".parse(),
+            Ok(Crop::Region(Region::new(0, 0, 640, 480)))
+        );
+        assert_eq!("Window".parse(), Ok(Crop::Window));
+        for invalid in [
+            "0,0,640",
+            "0,0,640,480,1",
+            "0,0,8,8",
+            "a,0,640,480",
+            "0,0,-640,480",
+        ] {
+            assert!(invalid.parse::<Crop>().is_err(), "{invalid}");
+        }
+    }
+}
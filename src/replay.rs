@@ -0,0 +1,363 @@
+use std::{collections::VecDeque, io};
+
+use crate::{
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{next_stream_number, Result, Sink, StreamFormat},
+};
+
+/// Keeps the most recent encoded audio and video packets in memory so the
+/// last N seconds of a recording can be saved on demand.
+///
+/// Video is only ever evicted a whole GOP at a time, so the retained window
+/// always starts on a keyframe and may be up to one GOP longer than
+/// requested. Audio older than the first retained keyframe is dropped along
+/// with it.
+pub struct ReplayBuffer {
+    max_duration: i64,
+    max_bytes: Option<usize>,
+    video: VecDeque<EncodedPacket>,
+    audio: VecDeque<EncodedPacket>,
+    bytes: usize,
+}
+
+impl ReplayBuffer {
+    /// `max_duration` is in 100ns units. `max_bytes` optionally caps the
+    /// amount of encoded data held, at the cost of a shorter replay.
+    pub fn new(max_duration: i64, max_bytes: Option<usize>) -> Self {
+        Self {
+            max_duration,
+            max_bytes,
+            video: VecDeque::new(),
+            audio: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, packet: EncodedPacket) {
+        let size = packet.data.len();
+        match packet.kind {
+            StreamKind::Video => {
+                // A clip can't start without a keyframe, so anything before
+                // the first one is useless to us.
+                if self.video.is_empty() && !packet.keyframe {
+                    return;
+                }
+                self.video.push_back(packet);
+            }
+            StreamKind::Audio => self.audio.push_back(packet),
+        }
+        self.bytes += size;
+        self.trim();
+    }
+
+    /// Returns the buffered packets as a clip that starts on a keyframe,
+    /// interleaved in timestamp order and rebased so that the keyframe is at
+    /// time zero. Audio that precedes the keyframe is left out.
+    pub fn snapshot(&self) -> Option<Vec<EncodedPacket>> {
+        let start = self.video.front()?.timestamp;
+        let mut packets: Vec<EncodedPacket> = self
+            .video
+            .iter()
+            .chain(self.audio.iter().filter(|packet| packet.timestamp >= start))
+            .cloned()
+            .map(|mut packet| {
+                packet.timestamp -= start;
+                packet
+            })
+            .collect();
+        // Stable, so packets with equal timestamps keep video first.
+        packets.sort_by_key(|packet| packet.timestamp);
+        Some(packets)
+    }
+
+    fn trim(&mut self) {
+        let newest = self
+            .video
+            .back()
+            .map(|packet| packet.end_time())
+            .into_iter()
+            .chain(self.audio.back().map(|packet| packet.end_time()))
+            .max()
+            .unwrap_or(0);
+        let cutoff = newest - self.max_duration;
+
+        // Drop whole GOPs while the next keyframe still leaves us with at
+        // least the requested duration.
+        while let Some(next_keyframe) = self.next_keyframe_index() {
+            let over_duration = self.video[next_keyframe].timestamp <= cutoff;
+            let over_size = self
+                .max_bytes
+                .map(|max_bytes| self.bytes > max_bytes)
+                .unwrap_or(false);
+            if !over_duration && !over_size {
+                break;
+            }
+            for packet in self.video.drain(..next_keyframe) {
+                self.bytes -= packet.data.len();
+            }
+        }
+
+        // Audio is cut to line up with the first keyframe. Without any video
+        // we still need to bound it by time.
+        let audio_start = self
+            .video
+            .front()
+            .map(|packet| packet.timestamp)
+            .unwrap_or(cutoff);
+        while let Some(packet) = self.audio.front() {
+            if packet.timestamp >= audio_start {
+                break;
+            }
+            self.bytes -= packet.data.len();
+            self.audio.pop_front();
+        }
+    }
+
+    fn next_keyframe_index(&self) -> Option<usize> {
+        self.video
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, packet)| packet.keyframe)
+            .map(|(index, _)| index)
+    }
+}
+
+/// A sink that holds on to the most recent part of a recording until it is
+/// saved into another sink.
+pub struct ReplaySink {
+    buffer: ReplayBuffer,
+    formats: Vec<StreamFormat>,
+}
+
+impl ReplaySink {
+    pub fn new(max_duration: i64, max_bytes: Option<usize>) -> Self {
+        Self {
+            buffer: ReplayBuffer::new(max_duration, max_bytes),
+            formats: Vec::new(),
+        }
+    }
+
+    /// Copies out the buffered clip, if there is one. Saving it can take a
+    /// while, and the encoders keep writing to this sink in the meantime, so
+    /// it's saved from the copy rather than while holding on to the sink.
+    pub fn snapshot(&self) -> Option<Replay> {
+        Some(Replay {
+            formats: self.formats.clone(),
+            packets: self.buffer.snapshot()?,
+        })
+    }
+}
+
+/// A clip taken from a `ReplaySink`, ready to be saved.
+pub struct Replay {
+    formats: Vec<StreamFormat>,
+    packets: Vec<EncodedPacket>,
+}
+
+impl Replay {
+    /// Writes the clip to `sink` as a standalone recording.
+    pub fn save(self, sink: &mut dyn Sink) -> Result<()> {
+        for format in self.formats {
+            sink.add_stream(format)?;
+        }
+        sink.start()?;
+        for packet in self.packets {
+            sink.write(packet)?;
+        }
+        sink.stop()
+    }
+}
+
+impl Sink for ReplaySink {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        let number = next_stream_number(&self.formats, format.kind());
+        self.formats.push(format);
+        Ok(number)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        // The clip can only be saved with streams it knows the format of
+        if packet.stream >= next_stream_number(&self.formats, packet.kind) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No stream was added for this packet.",
+            ));
+        }
+        self.buffer.push(packet);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayBuffer, ReplaySink};
+    use crate::{
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            synthetic::MemorySink, AudioCodec, AudioStreamFormat, Sink, StreamFormat, VideoCodec,
+            VideoStreamFormat,
+        },
+    };
+
+    const SECOND: i64 = 10_000_000;
+    const FRAME: i64 = SECOND / 30;
+    const AUDIO_FRAME: i64 = 1024 * SECOND / 48000;
+
+    fn video(index: i64, gop: i64) -> EncodedPacket {
+        EncodedPacket::new(
+            StreamKind::Video,
+            vec![0; 100],
+            index * FRAME,
+            FRAME,
+            index % gop == 0,
+        )
+    }
+
+    fn audio(index: i64) -> EncodedPacket {
+        EncodedPacket::new(
+            StreamKind::Audio,
+            vec![0; 10],
+            index * AUDIO_FRAME,
+            AUDIO_FRAME,
+            true,
+        )
+    }
+
+    fn duration(buffer: &ReplayBuffer) -> i64 {
+        buffer.video.back().unwrap().end_time() - buffer.video.front().unwrap().timestamp
+    }
+
+    /// Feeds `seconds` worth of interleaved audio and video.
+    fn fill(buffer: &mut ReplayBuffer, seconds: i64, gop: i64) {
+        let mut audio_index = 0;
+        for index in 0..seconds * 30 {
+            let packet = video(index, gop);
+            let end = packet.end_time();
+            buffer.push(packet);
+            while (audio_index + 1) * AUDIO_FRAME <= end {
+                buffer.push(audio(audio_index));
+                audio_index += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn clip_starts_on_keyframe() {
+        let mut buffer = ReplayBuffer::new(5 * SECOND, None);
+        fill(&mut buffer, 20, 60);
+
+        let clip = buffer.snapshot().unwrap();
+        let first = &clip[0];
+        assert_eq!(first.kind, StreamKind::Video);
+        assert!(first.keyframe);
+        assert_eq!(first.timestamp, 0);
+    }
+
+    #[test]
+    fn keeps_at_least_requested_duration() {
+        let mut buffer = ReplayBuffer::new(5 * SECOND, None);
+        fill(&mut buffer, 20, 60);
+
+        // Eviction happens per GOP, so we hold between 5 and 7 seconds.
+        assert!(duration(&buffer) >= 5 * SECOND);
+        assert!(duration(&buffer) <= 7 * SECOND);
+    }
+
+    #[test]
+    fn audio_is_aligned_to_video() {
+        let mut buffer = ReplayBuffer::new(3 * SECOND, None);
+        fill(&mut buffer, 10, 45);
+
+        let clip = buffer.snapshot().unwrap();
+        let first_audio = clip
+            .iter()
+            .find(|packet| packet.kind == StreamKind::Audio)
+            .unwrap();
+        assert!(first_audio.timestamp >= 0);
+        assert!(first_audio.timestamp < AUDIO_FRAME);
+        assert!(clip
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    }
+
+    #[test]
+    fn leading_delta_frames_are_ignored() {
+        let mut buffer = ReplayBuffer::new(5 * SECOND, None);
+        buffer.push(video(1, 30));
+        buffer.push(video(2, 30));
+        assert!(buffer.snapshot().is_none());
+        assert_eq!(buffer.bytes, 0);
+
+        buffer.push(video(30, 30));
+        assert!(buffer.snapshot().is_some());
+    }
+
+    #[test]
+    fn byte_cap_evicts_whole_gops() {
+        let mut buffer = ReplayBuffer::new(60 * SECOND, Some(100 * 30 * 2));
+        fill(&mut buffer, 10, 30);
+
+        assert!(buffer.bytes <= 100 * 30 * 3);
+        let clip = buffer.snapshot().unwrap();
+        assert!(clip[0].keyframe);
+        assert!(duration(&buffer) <= 2 * SECOND);
+    }
+
+    #[test]
+    fn audio_without_video_is_bounded() {
+        let mut buffer = ReplayBuffer::new(SECOND, None);
+        for index in 0..200 {
+            buffer.push(audio(index));
+        }
+        assert!(buffer.bytes <= 10 * (SECOND / AUDIO_FRAME + 2) as usize);
+        assert!(buffer.snapshot().is_none());
+    }
+
+    #[test]
+    fn sink_saves_clip_with_its_streams() {
+        let video_format = StreamFormat::Video(VideoStreamFormat {
+            codec: VideoCodec::H264,
+            width: 1280,
+            height: 720,
+            frame_rate: 30,
+            bit_rate: 8_000_000,
+        });
+        let audio_format = StreamFormat::Audio(AudioStreamFormat {
+            codec: AudioCodec::Aac,
+            sample_rate: 48000,
+            channels: 2,
+            bit_rate: 192000,
+            name: None,
+            language: None,
+        });
+        let mut replay = ReplaySink::new(5 * SECOND, None);
+        replay.add_stream(video_format.clone()).unwrap();
+        replay.add_stream(audio_format.clone()).unwrap();
+        replay.start().unwrap();
+        assert!(replay.snapshot().is_none());
+
+        for index in 0..60 {
+            replay.write(video(index, 30)).unwrap();
+        }
+        // Only one stream of each kind was added
+        let mut unknown = audio(0);
+        unknown.stream = 1;
+        assert!(replay.write(unknown).is_err());
+        replay.stop().unwrap();
+        let mut clip = MemorySink::new();
+        replay.snapshot().unwrap().save(&mut clip).unwrap();
+        assert_eq!(clip.formats, [video_format, audio_format]);
+        assert!(clip.stopped);
+        assert_eq!(clip.packets.len(), 60);
+        assert_eq!(clip.packets[0].timestamp, 0);
+    }
+}
@@ -0,0 +1,1037 @@
+//! Converts PCM audio between sample encodings, speaker layouts and sample
+//! rates without going through Media Foundation. Samples are decoded to
+//! floats, mapped onto the output speakers using the channel masks of both
+//! formats, resampled with a windowed sinc filter, and encoded again.
+
+use std::{f64::consts::PI, fmt::Display, io, str::FromStr};
+
+/// How the samples in a buffer are stored. Everything is little endian.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleEncoding {
+    I16,
+    /// Packed into 3 bytes.
+    I24,
+    I32,
+    F32,
+}
+
+impl SampleEncoding {
+    /// The encoding for integer or float samples of a given size, if it's
+    /// one of the supported ones.
+    pub fn from_bits(bits_per_sample: u16, float: bool) -> Option<Self> {
+        match (bits_per_sample, float) {
+            (16, false) => Some(SampleEncoding::I16),
+            (24, false) => Some(SampleEncoding::I24),
+            (32, false) => Some(SampleEncoding::I32),
+            (32, true) => Some(SampleEncoding::F32),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleEncoding::I16 => 2,
+            SampleEncoding::I24 => 3,
+            SampleEncoding::I32 | SampleEncoding::F32 => 4,
+        }
+    }
+}
+
+/// The layout of interleaved PCM audio.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub encoding: SampleEncoding,
+    /// Which speaker each channel is for, as in `WAVEFORMATEXTENSIBLE`.
+    /// Channels are ordered by their bit, lowest first. Without a mask the
+    /// usual layout for the number of channels is assumed.
+    pub channel_mask: Option<u32>,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// The part of the `KSDATAFORMAT_SUBTYPE_*` GUIDs after the format tag.
+const SUBTYPE_SUFFIX: [u8; 12] = [
+    0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+impl PcmFormat {
+    /// Reads a `WAVEFORMATEX`, or a `WAVEFORMATEXTENSIBLE`, as the audio
+    /// engine hands them out. Fails for anything that isn't PCM or float
+    /// in one of the supported sample encodings.
+    pub fn from_wave_format(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        if bytes.len() < 16 {
+            return Err(invalid(format!(
+                "A wave format is at least 16 bytes, not {}.",
+                bytes.len()
+            )));
+        }
+
+        let mut tag = u16_at(0);
+        let channels = u16_at(2);
+        let sample_rate = u32_at(4);
+        let block_align = u16_at(12);
+        let bits_per_sample = u16_at(14);
+        let mut channel_mask = None;
+        if tag == WAVE_FORMAT_EXTENSIBLE {
+            if bytes.len() < 40 {
+                return Err(invalid(format!(
+                    "An extensible wave format is 40 bytes, not {}.",
+                    bytes.len()
+                )));
+            }
+            // Samples narrower than their container are left aligned in it,
+            // so they decode like full size ones.
+            channel_mask = Some(u32_at(20));
+            if bytes[28..40] != SUBTYPE_SUFFIX || u16_at(26) != 0 {
+                return Err(invalid("Unknown wave format subtype.".to_string()));
+            }
+            tag = u16_at(24);
+        }
+
+        let float = match tag {
+            WAVE_FORMAT_PCM => false,
+            WAVE_FORMAT_IEEE_FLOAT => true,
+            tag => return Err(invalid(format!("Unsupported wave format tag {tag:#x}."))),
+        };
+        let encoding = SampleEncoding::from_bits(bits_per_sample, float).ok_or_else(|| {
+            invalid(format!(
+                "Unsupported sample size: {bits_per_sample} bits{}.",
+                if float { " float" } else { "" }
+            ))
+        })?;
+        if channels == 0
+            || sample_rate == 0
+            || block_align as usize != channels as usize * encoding.bytes_per_sample()
+        {
+            return Err(invalid(format!(
+                "Inconsistent wave format: {channels} channels at {sample_rate}Hz, {block_align} bytes per frame."
+            )));
+        }
+        Ok(Self {
+            sample_rate,
+            channels,
+            encoding,
+            channel_mask,
+        })
+    }
+
+    pub fn bits_per_sample(&self) -> u16 {
+        self.encoding.bytes_per_sample() as u16 * 8
+    }
+}
+
+pub const SPEAKER_FRONT_LEFT: u32 = 0x1;
+pub const SPEAKER_FRONT_RIGHT: u32 = 0x2;
+pub const SPEAKER_FRONT_CENTER: u32 = 0x4;
+pub const SPEAKER_LOW_FREQUENCY: u32 = 0x8;
+pub const SPEAKER_BACK_LEFT: u32 = 0x10;
+pub const SPEAKER_BACK_RIGHT: u32 = 0x20;
+pub const SPEAKER_FRONT_LEFT_OF_CENTER: u32 = 0x40;
+pub const SPEAKER_FRONT_RIGHT_OF_CENTER: u32 = 0x80;
+pub const SPEAKER_BACK_CENTER: u32 = 0x100;
+pub const SPEAKER_SIDE_LEFT: u32 = 0x200;
+pub const SPEAKER_SIDE_RIGHT: u32 = 0x400;
+pub const SPEAKER_TOP_CENTER: u32 = 0x800;
+pub const SPEAKER_TOP_FRONT_LEFT: u32 = 0x1000;
+pub const SPEAKER_TOP_FRONT_CENTER: u32 = 0x2000;
+pub const SPEAKER_TOP_FRONT_RIGHT: u32 = 0x4000;
+pub const SPEAKER_TOP_BACK_LEFT: u32 = 0x8000;
+pub const SPEAKER_TOP_BACK_CENTER: u32 = 0x10000;
+pub const SPEAKER_TOP_BACK_RIGHT: u32 = 0x20000;
+
+/// The layout Windows assumes for a number of channels when there is no
+/// channel mask.
+pub fn default_channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => SPEAKER_FRONT_CENTER,
+        2 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
+        3 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT | SPEAKER_FRONT_CENTER,
+        // Quad
+        4 => 0x33,
+        5 => 0x37,
+        // 5.1
+        6 => 0x3f,
+        // 6.1
+        7 => 0x13f,
+        // 7.1 surround
+        8 => 0x63f,
+        channels => (1u64 << channels.min(32)).wrapping_sub(1) as u32,
+    }
+}
+
+/// What converts audio between formats.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProcessorBackend {
+    /// The converter in this module.
+    Builtin,
+    /// The Windows resampler DSP.
+    MediaFoundation,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseProcessorBackendError(&'static str);
+
+impl FromStr for ProcessorBackend {
+    type Err = ParseProcessorBackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "builtin" => Ok(ProcessorBackend::Builtin),
+            "mf" => Ok(ProcessorBackend::MediaFoundation),
+            _ => Err(ParseProcessorBackendError(
+                "Invalid audio processor value! Expecting: builtin or mf.",
+            )),
+        }
+    }
+}
+
+impl Display for ProcessorBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            ProcessorBackend::Builtin => "builtin",
+            ProcessorBackend::MediaFoundation => "mf",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseProcessorBackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseProcessorBackendError {}
+
+/// Decodes samples to floats between -1 and 1. A partial sample at the end
+/// is ignored.
+pub fn decode(data: &[u8], encoding: SampleEncoding) -> Vec<f32> {
+    let size = encoding.bytes_per_sample();
+    let samples = data.chunks_exact(size);
+    match encoding {
+        SampleEncoding::I16 => samples
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+            .collect(),
+        SampleEncoding::I24 => samples
+            .map(|bytes| {
+                // Sign extend by filling the top byte and shifting back down
+                let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+                value as f32 / 8_388_608.0
+            })
+            .collect(),
+        SampleEncoding::I32 => samples
+            .map(|bytes| {
+                let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                (value as f64 / 2_147_483_648.0) as f32
+            })
+            .collect(),
+        SampleEncoding::F32 => samples
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect(),
+    }
+}
+
+/// Encodes floats, clipping integer encodings at full scale.
+pub fn encode(samples: &[f32], encoding: SampleEncoding) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * encoding.bytes_per_sample());
+    for &sample in samples {
+        let clamped = sample.clamp(-1.0, 1.0) as f64;
+        match encoding {
+            SampleEncoding::I16 => {
+                let value = (clamped * 32768.0).round().min(i16::MAX as f64) as i16;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            SampleEncoding::I24 => {
+                let value = (clamped * 8_388_608.0).round().min(8_388_607.0) as i32;
+                bytes.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+            SampleEncoding::I32 => {
+                let value = (clamped * 2_147_483_648.0).round().min(i32::MAX as f64) as i32;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            SampleEncoding::F32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Converts packets of audio from one format to another, keeping state
+/// between packets so a stream can be converted a packet at a time.
+pub struct FormatConverter {
+    input: PcmFormat,
+    output: PcmFormat,
+    channel_map: ChannelMap,
+    resampler: Option<Resampler>,
+}
+
+impl FormatConverter {
+    pub fn new(input: PcmFormat, output: PcmFormat) -> Self {
+        let resampler = (input.sample_rate != output.sample_rate)
+            .then(|| Resampler::new(input.sample_rate, output.sample_rate, output.channels));
+        Self {
+            input,
+            output,
+            channel_map: ChannelMap::new(&input, &output),
+            resampler,
+        }
+    }
+
+    /// Converts a packet to interleaved floats at the output rate and with
+    /// the output channels, leaving out the output encoding. The resampler
+    /// holds on to the last few input frames until it has what comes after
+    /// them, so the output runs a little behind the input.
+    pub fn convert_to_f32(&mut self, data: &[u8]) -> Vec<f32> {
+        let samples = decode(data, self.input.encoding);
+        let samples = self.channel_map.apply(samples);
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&samples),
+            None => samples,
+        }
+    }
+
+    /// Converts a packet to the output format.
+    // The capture path mixes in floats, so only needs `convert_to_f32`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn convert(&mut self, data: &[u8]) -> Vec<u8> {
+        let samples = self.convert_to_f32(data);
+        encode(&samples, self.output.encoding)
+    }
+}
+
+/// Gain for a speaker folded into two others, keeping its power the same.
+const FOLD_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Where a speaker goes when the output doesn't have it, in order of
+/// preference. Centre speakers are split between a pair instead.
+fn fallbacks(speaker: u32) -> &'static [u32] {
+    match speaker {
+        SPEAKER_BACK_LEFT => &[SPEAKER_SIDE_LEFT, SPEAKER_FRONT_LEFT],
+        SPEAKER_BACK_RIGHT => &[SPEAKER_SIDE_RIGHT, SPEAKER_FRONT_RIGHT],
+        SPEAKER_SIDE_LEFT => &[SPEAKER_BACK_LEFT, SPEAKER_FRONT_LEFT],
+        SPEAKER_SIDE_RIGHT => &[SPEAKER_BACK_RIGHT, SPEAKER_FRONT_RIGHT],
+        SPEAKER_FRONT_LEFT_OF_CENTER | SPEAKER_TOP_FRONT_LEFT => &[SPEAKER_FRONT_LEFT],
+        SPEAKER_FRONT_RIGHT_OF_CENTER | SPEAKER_TOP_FRONT_RIGHT => &[SPEAKER_FRONT_RIGHT],
+        SPEAKER_TOP_BACK_LEFT => &[SPEAKER_BACK_LEFT, SPEAKER_SIDE_LEFT, SPEAKER_FRONT_LEFT],
+        SPEAKER_TOP_BACK_RIGHT => &[SPEAKER_BACK_RIGHT, SPEAKER_SIDE_RIGHT, SPEAKER_FRONT_RIGHT],
+        SPEAKER_TOP_CENTER | SPEAKER_TOP_FRONT_CENTER => &[SPEAKER_FRONT_CENTER],
+        SPEAKER_TOP_BACK_CENTER => &[SPEAKER_BACK_CENTER],
+        _ => &[],
+    }
+}
+
+/// Pairs a centre speaker can be split between, in order of preference.
+fn split_pairs(speaker: u32) -> &'static [(u32, u32)] {
+    const FRONT: (u32, u32) = (SPEAKER_FRONT_LEFT, SPEAKER_FRONT_RIGHT);
+    const BACK: (u32, u32) = (SPEAKER_BACK_LEFT, SPEAKER_BACK_RIGHT);
+    const SIDE: (u32, u32) = (SPEAKER_SIDE_LEFT, SPEAKER_SIDE_RIGHT);
+    match speaker {
+        SPEAKER_FRONT_CENTER | SPEAKER_TOP_CENTER | SPEAKER_TOP_FRONT_CENTER => &[FRONT],
+        SPEAKER_BACK_CENTER | SPEAKER_TOP_BACK_CENTER => &[BACK, SIDE, FRONT],
+        _ => &[],
+    }
+}
+
+/// The speaker of each channel of a format, or `None` for channels the mask
+/// doesn't cover.
+fn speakers(format: &PcmFormat) -> Vec<Option<u32>> {
+    let mask = format
+        .channel_mask
+        .filter(|&mask| mask != 0)
+        .unwrap_or_else(|| default_channel_mask(format.channels));
+    let mut bits = (0..32).map(|bit| 1u32 << bit).filter(|bit| mask & bit != 0);
+    (0..format.channels).map(|_| bits.next()).collect()
+}
+
+/// Mixes each frame of one speaker layout into another with a matrix.
+///
+/// Speakers both layouts have are copied straight across. The others are
+/// folded into the nearest speakers the output has: surrounds into the
+/// fronts on the same side, and centre speakers split between a pair, both
+/// at -3dB. Anything left of a stereo pair going into a single centre
+/// speaker is halved, so stereo averages to mono, and a mono input goes to
+/// the centre if there is one, or to both fronts at full level. The low
+/// frequency channel is dropped if the output has none. If that leaves an
+/// output speaker with more than full scale going into it, every speaker is
+/// turned down by the same amount so it can't clip.
+struct ChannelMap {
+    output_channels: usize,
+    /// `matrix[output][input]` is the gain of an input channel in an output
+    /// channel. Empty if the layouts are the same.
+    matrix: Vec<Vec<f32>>,
+}
+
+impl ChannelMap {
+    fn new(input: &PcmFormat, output: &PcmFormat) -> Self {
+        let input_speakers = speakers(input);
+        let output_speakers = speakers(output);
+        let output_channels = output_speakers.len();
+        if input_speakers == output_speakers {
+            return Self {
+                output_channels,
+                matrix: Vec::new(),
+            };
+        }
+
+        let find = |speaker: u32| {
+            output_speakers
+                .iter()
+                .position(|&output| output == Some(speaker))
+        };
+        let mut matrix = vec![vec![0.0; input_speakers.len()]; output_channels];
+        for (input_channel, speaker) in input_speakers.iter().enumerate() {
+            let Some(speaker) = *speaker else {
+                continue;
+            };
+            let mut route = |output: usize, gain: f32| matrix[output][input_channel] += gain;
+
+            if input_speakers.len() == 1 {
+                match (
+                    find(SPEAKER_FRONT_CENTER),
+                    find(SPEAKER_FRONT_LEFT),
+                    find(SPEAKER_FRONT_RIGHT),
+                ) {
+                    (Some(center), _, _) => route(center, 1.0),
+                    (None, None, None) => route(0, 1.0),
+                    (None, left, right) => {
+                        left.into_iter()
+                            .chain(right)
+                            .for_each(|output| route(output, 1.0));
+                    }
+                }
+                continue;
+            }
+            if let Some(output) = find(speaker) {
+                route(output, 1.0);
+                continue;
+            }
+            if speaker == SPEAKER_LOW_FREQUENCY {
+                continue;
+            }
+            if let Some(output) = fallbacks(speaker).iter().find_map(|&other| find(other)) {
+                route(output, FOLD_GAIN);
+                continue;
+            }
+            let pair = split_pairs(speaker)
+                .iter()
+                .find_map(|&(left, right)| Some((find(left)?, find(right)?)));
+            if let Some((left, right)) = pair {
+                route(left, FOLD_GAIN);
+                route(right, FOLD_GAIN);
+                continue;
+            }
+            // Nothing close, so into the centre, or whatever the output
+            // has at the front.
+            let target = [
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_RIGHT,
+            ]
+            .iter()
+            .find_map(|&other| find(other));
+            if let Some(output) = target {
+                let gain = if output_speakers[output] == Some(SPEAKER_FRONT_CENTER) {
+                    0.5
+                } else {
+                    FOLD_GAIN
+                };
+                route(output, gain);
+            }
+        }
+
+        let loudest = matrix
+            .iter()
+            .map(|row| row.iter().sum::<f32>())
+            .fold(1.0f32, f32::max);
+        for gain in matrix.iter_mut().flatten() {
+            *gain /= loudest;
+        }
+        Self {
+            output_channels,
+            matrix,
+        }
+    }
+
+    fn apply(&self, samples: Vec<f32>) -> Vec<f32> {
+        if self.matrix.is_empty() {
+            return samples;
+        }
+        let input_channels = self.matrix[0].len();
+        let frames = samples.len() / input_channels;
+        let mut output = Vec::with_capacity(frames * self.output_channels);
+        for frame in samples.chunks_exact(input_channels) {
+            for row in &self.matrix {
+                output.push(
+                    row.iter()
+                        .zip(frame)
+                        .map(|(gain, sample)| gain * sample)
+                        .sum(),
+                );
+            }
+        }
+        output
+    }
+}
+
+/// Taps on either side of the filter's centre, when the rate goes up.
+const HALF_TAPS: usize = 64;
+/// How much of the band below the lower of the two Nyquist frequencies
+/// passes. The filter rolls off across the rest, and is down by more than
+/// 90dB by the time it gets to the Nyquist frequency.
+const PASSBAND: f64 = 0.93;
+/// How finely the filter is worked out between input frames. In between,
+/// the coefficients are interpolated.
+const PHASES: usize = 256;
+
+/// Changes the sample rate of interleaved floats by any ratio, with a
+/// Blackman-Harris windowed sinc filter.
+///
+/// Output frame `n` is at input time `n * input_rate / output_rate`, with
+/// the position between input frames kept as an exact fraction, so the
+/// output never drifts from the input however long it runs. The filter
+/// needs `HALF_TAPS` input frames after each output frame (more when the
+/// rate goes down) before it can work that frame out.
+pub struct Resampler {
+    channels: usize,
+    half_taps: usize,
+    /// `coefficients[phase * taps + tap]` for `PHASES + 1` phases, so the
+    /// last phase can be interpolated towards.
+    coefficients: Vec<f32>,
+    /// Input frames per output frame, as whole frames plus a fraction of
+    /// `denominator`.
+    step: usize,
+    step_fraction: u64,
+    denominator: u64,
+    /// Input frames that are still needed, interleaved.
+    history: Vec<f32>,
+    /// Where the next output frame falls, in frames into `history` plus a
+    /// fraction of `denominator`.
+    position: usize,
+    position_fraction: u64,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32, channels: u16) -> Self {
+        let divisor = gcd(input_rate as u64, output_rate as u64);
+        let numerator = input_rate as u64 / divisor;
+        let denominator = output_rate as u64 / divisor;
+
+        // Going down, the cut off moves down to the new Nyquist frequency
+        // and the filter is stretched to keep the transition as sharp.
+        let scale = (output_rate as f64 / input_rate as f64).min(1.0);
+        let cutoff = PASSBAND * scale;
+        let half_taps = (HALF_TAPS as f64 / scale).ceil() as usize;
+        let taps = 2 * half_taps;
+
+        let mut coefficients = Vec::with_capacity((PHASES + 1) * taps);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let start = coefficients.len();
+            for tap in 0..taps {
+                // How far the input frame is from the output frame.
+                let distance = tap as f64 + 1.0 - half_taps as f64 - fraction;
+                let window = blackman_harris(distance / half_taps as f64);
+                coefficients.push((cutoff * sinc(cutoff * distance) * window) as f32);
+            }
+            // Normalise, so a constant signal comes through unchanged.
+            let sum: f32 = coefficients[start..].iter().sum();
+            for coefficient in &mut coefficients[start..] {
+                *coefficient /= sum;
+            }
+        }
+
+        let channels = channels as usize;
+        Self {
+            channels,
+            half_taps,
+            coefficients,
+            step: (numerator / denominator) as usize,
+            step_fraction: numerator % denominator,
+            denominator,
+            // Silence before the first frame, so it can be the first output.
+            history: vec![0.0; (half_taps - 1) * channels],
+            position: half_taps - 1,
+            position_fraction: 0,
+        }
+    }
+
+    /// Takes interleaved input frames, and returns every output frame they
+    /// complete.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.history.extend_from_slice(samples);
+        let channels = self.channels;
+        let taps = 2 * self.half_taps;
+        let frames = self.history.len() / channels;
+
+        let mut output = Vec::new();
+        let mut weights = vec![0.0f32; taps];
+        while self.position + self.half_taps < frames {
+            let phase = self.position_fraction as f64 / self.denominator as f64 * PHASES as f64;
+            let index = phase as usize;
+            let blend = (phase - index as f64) as f32;
+            let before = &self.coefficients[index * taps..][..taps];
+            let after = &self.coefficients[(index + 1) * taps..][..taps];
+            for ((weight, before), after) in weights.iter_mut().zip(before).zip(after) {
+                *weight = before + (after - before) * blend;
+            }
+
+            let first = (self.position + 1 - self.half_taps) * channels;
+            let window = &self.history[first..first + taps * channels];
+            for channel in 0..channels {
+                output.push(
+                    window
+                        .iter()
+                        .skip(channel)
+                        .step_by(channels)
+                        .zip(&weights)
+                        .map(|(sample, weight)| sample * weight)
+                        .sum(),
+                );
+            }
+
+            self.position += self.step;
+            self.position_fraction += self.step_fraction;
+            if self.position_fraction >= self.denominator {
+                self.position_fraction -= self.denominator;
+                self.position += 1;
+            }
+        }
+
+        // Drop what the next output frame no longer reaches back to.
+        let unused = (self.position + 1)
+            .saturating_sub(self.half_taps)
+            .min(frames);
+        self.history.drain(..unused * channels);
+        self.position -= unused;
+        output
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The four term Blackman-Harris window, over -1 to 1.
+fn blackman_harris(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let x = PI * x;
+    0.35875 + 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() + 0.01168 * (3.0 * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::{
+        decode, encode, FormatConverter, PcmFormat, ProcessorBackend, Resampler, SampleEncoding,
+        HALF_TAPS, SPEAKER_BACK_CENTER, SPEAKER_FRONT_CENTER, SPEAKER_LOW_FREQUENCY,
+    };
+
+    /// A sine tone as floats.
+    fn tone(sample_rate: u32, frequency: f64, amplitude: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                let phase = 2.0 * PI * frequency * frame as f64 / sample_rate as f64;
+                (phase.sin() * amplitude) as f32
+            })
+            .collect()
+    }
+
+    /// Resamples mono audio in packets of varying size.
+    fn resample(input_rate: u32, output_rate: u32, samples: &[f32]) -> Vec<f32> {
+        let mut resampler = Resampler::new(input_rate, output_rate, 1);
+        let mut output = Vec::new();
+        let mut rest = samples;
+        for size in [480, 441, 1, 1000, 7].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (packet, remaining) = rest.split_at((*size).min(rest.len()));
+            output.extend(resampler.process(packet));
+            rest = remaining;
+        }
+        output
+    }
+
+    /// The level of a tone in a signal, leaving out the edges where the
+    /// filter is still filling up. A Hann window keeps everything else
+    /// from leaking in.
+    fn level(samples: &[f32], sample_rate: u32, frequency: f64) -> f64 {
+        let samples = &samples[1000..samples.len() - 1000];
+        let count = samples.len() as f64;
+        let (mut real, mut imaginary, mut weights) = (0.0, 0.0, 0.0);
+        for (index, &sample) in samples.iter().enumerate() {
+            let weight = 0.5 - 0.5 * (2.0 * PI * index as f64 / count).cos();
+            let phase = 2.0 * PI * frequency * index as f64 / sample_rate as f64;
+            real += weight * sample as f64 * phase.cos();
+            imaginary += weight * sample as f64 * phase.sin();
+            weights += weight;
+        }
+        2.0 * (real * real + imaginary * imaginary).sqrt() / weights
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let samples = &samples[1000..samples.len() - 1000];
+        let sum: f64 = samples.iter().map(|&sample| (sample as f64).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    fn decibels(level: f64) -> f64 {
+        20.0 * level.log10()
+    }
+
+    #[test]
+    fn passband_is_flat() {
+        for (input_rate, output_rate) in [
+            (44100, 48000),
+            (48000, 44100),
+            (48000, 16000),
+            (22050, 48000),
+            (48000, 96000),
+        ] {
+            let nyquist = input_rate.min(output_rate) as f64 / 2.0;
+            for frequency in [50.0, 1000.0, 0.5 * nyquist, 0.85 * nyquist] {
+                let input = tone(input_rate, frequency, 0.5, input_rate as usize / 4);
+                let output = resample(input_rate, output_rate, &input);
+                let gain = decibels(level(&output, output_rate, frequency) / 0.5);
+                assert!(
+                    gain.abs() < 0.05,
+                    "{input_rate} -> {output_rate}: {frequency}Hz at {gain:.3}dB"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn filters_out_what_would_alias() {
+        for (input_rate, output_rate) in [(48000, 44100), (48000, 16000), (96000, 44100)] {
+            let nyquist = output_rate as f64 / 2.0;
+            // Just past the new Nyquist frequency, and well past it.
+            let input_nyquist = input_rate as f64 / 2.0;
+            for frequency in [1.05 * nyquist, 1.5 * nyquist, 0.95 * input_nyquist] {
+                if frequency >= input_nyquist {
+                    continue;
+                }
+                let input = tone(input_rate, frequency, 1.0, input_rate as usize / 4);
+                let output = resample(input_rate, output_rate, &input);
+                let level = decibels(rms(&output) * 2f64.sqrt());
+                assert!(
+                    level < -85.0,
+                    "{input_rate} -> {output_rate}: {frequency}Hz at {level:.1}dB"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_time_with_any_ratio() {
+        // A click every 100ms stays on the same grid after resampling.
+        for (input_rate, output_rate) in [
+            (44100, 48000),
+            (48000, 44100),
+            (8000, 48000),
+            (32000, 22050),
+        ] {
+            let mut input = vec![0.0f32; input_rate as usize];
+            for click in (0..10).map(|index| index * input_rate as usize / 10) {
+                input[click] = 1.0;
+            }
+            let output = resample(input_rate, output_rate, &input);
+
+            // Everything but the last few frames the filter is waiting on.
+            let missing = output_rate as usize - output.len();
+            let lag = HALF_TAPS as f64 * (output_rate as f64 / input_rate as f64).max(1.0);
+            assert!(missing as f64 <= lag + 1.0, "{missing} frames behind");
+
+            for click in 1..9 {
+                let at = click * output_rate as usize / 10;
+                let peak = (at - 5..=at + 5)
+                    .max_by(|&a, &b| output[a].total_cmp(&output[b]))
+                    .unwrap();
+                assert_eq!(peak, at, "{input_rate} -> {output_rate}: click {click}");
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        let samples = [0.0, 0.5, -0.5, 0.25, -1.0, 0.999];
+        for encoding in [
+            SampleEncoding::I16,
+            SampleEncoding::I24,
+            SampleEncoding::I32,
+            SampleEncoding::F32,
+        ] {
+            let bytes = encode(&samples, encoding);
+            assert_eq!(bytes.len(), samples.len() * encoding.bytes_per_sample());
+            let decoded = decode(&bytes, encoding);
+            for (&sample, decoded) in samples.iter().zip(decoded) {
+                assert!((sample - decoded).abs() <= 1.0 / 32768.0, "{encoding:?}");
+            }
+        }
+
+        // Full scale clips instead of wrapping around.
+        assert_eq!(encode(&[1.5], SampleEncoding::I16), i16::MAX.to_le_bytes());
+        assert_eq!(encode(&[-1.0], SampleEncoding::I24), [0x00, 0x00, 0x80]);
+        assert_eq!(
+            decode(&[0xff, 0xff, 0xff], SampleEncoding::I24),
+            [-1.0 / 8_388_608.0]
+        );
+        assert_eq!(decode(&i32::MIN.to_le_bytes(), SampleEncoding::I32), [-1.0]);
+    }
+
+    fn format(channels: u16, channel_mask: Option<u32>) -> PcmFormat {
+        PcmFormat {
+            sample_rate: 48000,
+            channels,
+            encoding: SampleEncoding::F32,
+            channel_mask,
+        }
+    }
+
+    /// Converts one frame between layouts.
+    fn remix(input: PcmFormat, output: PcmFormat, frame: &[f32]) -> Vec<f32> {
+        let mut converter = FormatConverter::new(input, output);
+        decode(
+            &converter.convert(&encode(frame, SampleEncoding::F32)),
+            SampleEncoding::F32,
+        )
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (actual_value, expected_value) in actual.iter().zip(expected) {
+            assert!(
+                (actual_value - expected_value).abs() < 1e-4,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn maps_channels_by_speaker() {
+        let mono = format(1, None);
+        let stereo = format(2, None);
+        let surround = format(6, None);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+
+        assert_close(&remix(mono, stereo, &[0.5]), &[0.5, 0.5]);
+        assert_close(&remix(stereo, mono, &[0.5, 0.1]), &[0.3]);
+        assert_close(
+            &remix(mono, surround, &[0.5]),
+            &[0.0, 0.0, 0.5, 0.0, 0.0, 0.0],
+        );
+        assert_close(
+            &remix(stereo, surround, &[0.5, 0.1]),
+            &[0.5, 0.1, 0.0, 0.0, 0.0, 0.0],
+        );
+
+        // 5.1 folds down with the centre and surrounds at -3dB and no LFE,
+        // turned down so it can't clip.
+        let scale = 1.0 + 2.0 * half;
+        let frame = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        assert_close(
+            &remix(surround, stereo, &frame),
+            &[
+                (0.1 + half * (0.3 + 0.5)) / scale,
+                (0.2 + half * (0.3 + 0.6)) / scale,
+            ],
+        );
+
+        // The mask says which channel is which, whatever the count.
+        let centre_and_bass = format(2, Some(SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY));
+        assert_close(&remix(centre_and_bass, mono, &[0.4, 0.9]), &[0.4]);
+        assert_close(
+            &remix(centre_and_bass, stereo, &[0.4, 0.9]),
+            &[0.4 * half, 0.4 * half],
+        );
+        let back_centre = format(3, Some(0x3 | SPEAKER_BACK_CENTER));
+        assert_close(
+            &remix(back_centre, format(4, None), &[0.1, 0.2, 0.4]),
+            &[0.1, 0.2, 0.4 * half, 0.4 * half],
+        );
+    }
+
+    #[test]
+    fn converts_whole_formats() {
+        let input = PcmFormat {
+            sample_rate: 44100,
+            channels: 1,
+            encoding: SampleEncoding::I24,
+            channel_mask: None,
+        };
+        let output = PcmFormat {
+            sample_rate: 48000,
+            channels: 2,
+            encoding: SampleEncoding::I16,
+            channel_mask: Some(0x3),
+        };
+        let mut converter = FormatConverter::new(input, output);
+        let samples = tone(44100, 440.0, 0.5, 44100);
+        let mut converted = Vec::new();
+        for packet in encode(&samples, SampleEncoding::I24).chunks(441 * 3) {
+            converted.extend(converter.convert(packet));
+        }
+        let converted = decode(&converted, SampleEncoding::I16);
+        assert!(converted.chunks(2).all(|frame| frame[0] == frame[1]));
+        let left: Vec<f32> = converted.iter().step_by(2).copied().collect();
+        assert!((level(&left, 48000, 440.0) - 0.5).abs() < 0.001);
+    }
+
+    /// A `WAVEFORMATEXTENSIBLE` as the audio engine lays it out.
+    fn wave_format_extensible(
+        tag: u16,
+        channels: u16,
+        bits_per_sample: u16,
+        valid_bits: u16,
+        channel_mask: u32,
+    ) -> Vec<u8> {
+        let mut bytes = wave_format(0xfffe, channels, bits_per_sample);
+        bytes.extend_from_slice(&valid_bits.to_le_bytes());
+        bytes.extend_from_slice(&channel_mask.to_le_bytes());
+        bytes.extend_from_slice(&(tag as u32).to_le_bytes());
+        bytes.extend_from_slice(&[
+            0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+        ]);
+        bytes[16..18].copy_from_slice(&22u16.to_le_bytes());
+        bytes
+    }
+
+    /// A plain `WAVEFORMATEX` at 48kHz.
+    fn wave_format(tag: u16, channels: u16, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = channels * bits_per_sample / 8;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&48000u32.to_le_bytes());
+        bytes.extend_from_slice(&(48000 * block_align as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_align.to_le_bytes());
+        bytes.extend_from_slice(&bits_per_sample.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_device_formats() {
+        let stereo = PcmFormat::from_wave_format(&wave_format(1, 2, 16)).unwrap();
+        assert_eq!(
+            stereo,
+            PcmFormat {
+                sample_rate: 48000,
+                channels: 2,
+                encoding: SampleEncoding::I16,
+                channel_mask: None,
+            }
+        );
+        let float = PcmFormat::from_wave_format(&wave_format(3, 1, 32)).unwrap();
+        assert_eq!(float.encoding, SampleEncoding::F32);
+
+        // The usual shared mode format, here for 7.1.
+        let surround = wave_format_extensible(3, 8, 32, 32, 0x63f);
+        let surround = PcmFormat::from_wave_format(&surround).unwrap();
+        assert_eq!(surround.channels, 8);
+        assert_eq!(surround.encoding, SampleEncoding::F32);
+        assert_eq!(surround.channel_mask, Some(0x63f));
+        assert_eq!(surround.bits_per_sample(), 32);
+
+        // 24-bit samples in 32-bit containers read as 32-bit.
+        let padded = wave_format_extensible(1, 6, 32, 24, 0x3f);
+        let padded = PcmFormat::from_wave_format(&padded).unwrap();
+        assert_eq!(padded.encoding, SampleEncoding::I32);
+        let packed = wave_format_extensible(1, 2, 24, 24, 0x3);
+        let packed = PcmFormat::from_wave_format(&packed).unwrap();
+        assert_eq!(packed.encoding, SampleEncoding::I24);
+
+        for unsupported in [
+            // 8-bit unsigned, ADPCM, 64-bit float
+            wave_format(1, 2, 8),
+            wave_format(2, 2, 16),
+            wave_format(3, 2, 64),
+            wave_format_extensible(2, 2, 16, 16, 0x3),
+            // Cut short
+            wave_format(1, 2, 16)[..14].to_vec(),
+            wave_format_extensible(3, 2, 32, 32, 0x3)[..30].to_vec(),
+        ] {
+            assert!(PcmFormat::from_wave_format(&unsupported).is_err());
+        }
+        let mut wrong_alignment = wave_format(1, 2, 16);
+        wrong_alignment[12] = 2;
+        assert!(PcmFormat::from_wave_format(&wrong_alignment).is_err());
+    }
+
+    #[test]
+    fn converts_device_formats_to_the_encoder_format() {
+        // Float 7.1 at 44.1kHz, as a home theatre setup might mix in, with a
+        // tone on the front left and another on the back right.
+        let device = PcmFormat::from_wave_format(&{
+            let mut bytes = wave_format_extensible(3, 8, 32, 32, 0x63f);
+            bytes[4..8].copy_from_slice(&44100u32.to_le_bytes());
+            bytes
+        })
+        .unwrap();
+        let encoder = PcmFormat {
+            sample_rate: 48000,
+            channels: 2,
+            encoding: SampleEncoding::I16,
+            channel_mask: None,
+        };
+        let left = tone(44100, 440.0, 0.5, 22050);
+        let back_right = tone(44100, 1000.0, 0.5, 22050);
+        let mut samples = vec![0.0; 22050 * 8];
+        for (frame, (left, back_right)) in samples.chunks_mut(8).zip(left.iter().zip(&back_right)) {
+            frame[0] = *left;
+            frame[5] = *back_right;
+        }
+
+        let mut converter = FormatConverter::new(device, encoder);
+        let converted = converter.convert(&encode(&samples, SampleEncoding::F32));
+        let converted = decode(&converted, SampleEncoding::I16);
+        let channel = |index: usize| -> Vec<f32> {
+            converted.iter().skip(index).step_by(2).copied().collect()
+        };
+
+        // Surrounds fold into the front on their own side at -3dB, and
+        // everything is turned down by the same amount to leave room for
+        // the front, centre, side and back all going into one side.
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        let scale = 1.0 + 3.0 * half;
+        let (left, right) = (channel(0), channel(1));
+        assert!((level(&left, 48000, 440.0) - 0.5 / scale).abs() < 0.001);
+        assert!(level(&left, 48000, 1000.0) < 0.001);
+        assert!((level(&right, 48000, 1000.0) - 0.5 * half / scale).abs() < 0.001);
+        assert!(level(&right, 48000, 440.0) < 0.001);
+    }
+
+    #[test]
+    fn parses_processor_backend() {
+        for backend in [ProcessorBackend::Builtin, ProcessorBackend::MediaFoundation] {
+            assert_eq!(backend.to_string().parse(), Ok(backend));
+        }
+        assert!("sox".parse::<ProcessorBackend>().is_err());
+    }
+}
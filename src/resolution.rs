@@ -1,15 +1,84 @@
 use std::{fmt::Display, str::FromStr};
 
-use windows::Graphics::SizeInt32;
+/// A size in pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Size {
+    pub const fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+
+    /// Rounds both sides up to an even number, which NV12 needs.
+    pub fn even(self) -> Self {
+        Self::new(self.width + self.width % 2, self.height + self.height % 2)
+    }
+}
+
+#[cfg(windows)]
+impl From<Size> for windows::Graphics::SizeInt32 {
+    fn from(size: Size) -> Self {
+        Self {
+            Width: size.width as i32,
+            Height: size.height as i32,
+        }
+    }
+}
+
+/// A rectangle in pixels, from its top left corner.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The whole of something `size` big.
+    pub const fn of(size: Size) -> Self {
+        Self::new(0, 0, size.width, size.height)
+    }
+
+    #[cfg(windows)]
+    pub fn size(&self) -> Size {
+        Size::new(self.width, self.height)
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Resolution {
+    /// The size of whatever is captured.
     Native,
     _720p,
     _1080p,
     _1440p,
     _2160p,
     _4320p,
+    Custom(Size),
+    /// This many pixels high, and as wide as keeps the captured aspect
+    /// ratio. Set with `--height` rather than parsed.
+    Height(u32),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -26,24 +95,32 @@ impl FromStr for Resolution {
             "1440p" => Ok(Resolution::_1440p),
             "2160p" => Ok(Resolution::_2160p),
             "4320p" => Ok(Resolution::_4320p),
-            _ => Err(ParseResolutionError(
-                "Invalid resolution value! Expecting: native, 720p, 1080p, 1440p, 2160p, or 4320p.",
+            custom => parse_size(custom).map(Resolution::Custom).ok_or(ParseResolutionError(
+                "Invalid resolution value! Expecting: native, 720p, 1080p, 1440p, 2160p, 4320p, or WIDTHxHEIGHT.",
             )),
         }
     }
 }
 
+/// Parses `WIDTHxHEIGHT`, where neither side is zero.
+fn parse_size(s: &str) -> Option<Size> {
+    let (width, height) = s.split_once('x')?;
+    let size = Size::new(width.parse().ok()?, height.parse().ok()?);
+    (size.width > 0 && size.height > 0).then_some(size)
+}
+
 impl Display for Resolution {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let string = match self {
-            Resolution::Native => "native",
-            Resolution::_720p => "720p",
-            Resolution::_1080p => "1080p",
-            Resolution::_1440p => "1440p",
-            Resolution::_2160p => "2160p",
-            Resolution::_4320p => "4320p",
-        };
-        write!(f, "{}", string)
+        match self {
+            Resolution::Native => write!(f, "native"),
+            Resolution::_720p => write!(f, "720p"),
+            Resolution::_1080p => write!(f, "1080p"),
+            Resolution::_1440p => write!(f, "1440p"),
+            Resolution::_2160p => write!(f, "2160p"),
+            Resolution::_4320p => write!(f, "4320p"),
+            Resolution::Custom(size) => write!(f, "{}x{}", size.width, size.height),
+            Resolution::Height(height) => write!(f, "{} high", height),
+        }
     }
 }
 
@@ -55,29 +132,367 @@ impl Display for ParseResolutionError {
 impl std::error::Error for ParseResolutionError {}
 
 impl Resolution {
-    pub fn get_size(&self) -> Option<SizeInt32> {
-        match self {
-            Resolution::Native => None,
-            Resolution::_720p => Some(SizeInt32 {
-                Width: 1280,
-                Height: 720,
-            }),
-            Resolution::_1080p => Some(SizeInt32 {
-                Width: 1920,
-                Height: 1080,
-            }),
-            Resolution::_1440p => Some(SizeInt32 {
-                Width: 2560,
-                Height: 1440,
-            }),
-            Resolution::_2160p => Some(SizeInt32 {
-                Width: 3840,
-                Height: 2160,
-            }),
-            Resolution::_4320p => Some(SizeInt32 {
-                Width: 7680,
-                Height: 4320,
-            }),
+    /// The size to encode at when capturing something `native` big, rounded
+    /// up to even sides.
+    pub fn resolve(&self, native: Size) -> Size {
+        let size = match *self {
+            Resolution::Native => native,
+            Resolution::_720p => Size::new(1280, 720),
+            Resolution::_1080p => Size::new(1920, 1080),
+            Resolution::_1440p => Size::new(2560, 1440),
+            Resolution::_2160p => Size::new(3840, 2160),
+            Resolution::_4320p => Size::new(7680, 4320),
+            Resolution::Custom(size) => size,
+            Resolution::Height(height) => {
+                Size::new(scale(native.width, height, native.height).max(1), height)
+            }
+        };
+        size.even()
+    }
+}
+
+/// How the captured image is fitted to the output when their sizes differ.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScaleMode {
+    /// Scales to fit inside the output, keeping the aspect ratio, with black
+    /// bars on the sides that are left over.
+    Letterbox,
+    /// Scales to fill the output, whatever that does to the aspect ratio.
+    Stretch,
+    /// Scales to fill the output, keeping the aspect ratio, and cuts off
+    /// whatever doesn't fit.
+    Crop,
+    /// Scales by a whole number, so that every captured pixel covers the
+    /// same number of output pixels, or every output pixel the same number
+    /// of captured ones. Centred, with black bars around it.
+    Integer,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseScaleModeError(&'static str);
+
+impl FromStr for ScaleMode {
+    type Err = ParseScaleModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "letterbox" => Ok(ScaleMode::Letterbox),
+            "stretch" => Ok(ScaleMode::Stretch),
+            "crop" => Ok(ScaleMode::Crop),
+            "integer" => Ok(ScaleMode::Integer),
+            _ => Err(ParseScaleModeError(
+                "Invalid scale mode value! Expecting: letterbox, stretch, crop, or integer.",
+            )),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Display for ScaleMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            ScaleMode::Letterbox => "letterbox",
+            ScaleMode::Stretch => "stretch",
+            ScaleMode::Crop => "crop",
+            ScaleMode::Integer => "integer",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseScaleModeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseScaleModeError {}
+
+/// Which part of the captured image ends up where in the output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// The part of the captured image that is shown.
+    pub source: Rect,
+    /// Where it goes in the output. Always lands on even pixels, so it lines
+    /// up with NV12's chroma.
+    pub dest: Rect,
+}
+
+impl Placement {
+    /// Works out how an image `input` big is fitted to an `output` with
+    /// even sides.
+    pub fn new(input: Size, output: Size, mode: ScaleMode) -> Self {
+        let whole_input = Rect::of(input);
+        match mode {
+            ScaleMode::Letterbox => {
+                // Whichever side is tighter decides the scale.
+                let fitted = if output.width as u64 * input.height as u64
+                    <= output.height as u64 * input.width as u64
+                {
+                    Size::new(output.width, scale(input.height, output.width, input.width))
+                } else {
+                    Size::new(
+                        scale(input.width, output.height, input.height),
+                        output.height,
+                    )
+                };
+                Self {
+                    source: whole_input,
+                    dest: centre(fitted, output),
+                }
+            }
+            ScaleMode::Stretch => Self {
+                source: whole_input,
+                dest: Rect::of(output),
+            },
+            ScaleMode::Crop => {
+                // The largest part of the input with the output's shape.
+                let shown = if input.width as u64 * output.height as u64
+                    > input.height as u64 * output.width as u64
+                {
+                    Size::new(
+                        scale(output.width, input.height, output.height),
+                        input.height,
+                    )
+                } else {
+                    Size::new(input.width, scale(output.height, input.width, output.width))
+                };
+                let shown = Size::new(
+                    shown.width.clamp(1, input.width),
+                    shown.height.clamp(1, input.height),
+                );
+                Self {
+                    source: Rect::new(
+                        (input.width - shown.width) / 2,
+                        (input.height - shown.height) / 2,
+                        shown.width,
+                        shown.height,
+                    ),
+                    dest: Rect::of(output),
+                }
+            }
+            ScaleMode::Integer => {
+                let fitted = if input.width <= output.width && input.height <= output.height {
+                    let factor = (output.width / input.width).min(output.height / input.height);
+                    Size::new(input.width * factor, input.height * factor)
+                } else {
+                    let factor = input
+                        .width
+                        .div_ceil(output.width)
+                        .max(input.height.div_ceil(output.height));
+                    Size::new(input.width / factor, input.height / factor)
+                };
+                Self {
+                    source: whole_input,
+                    dest: centre(fitted, output),
+                }
+            }
+        }
+    }
+}
+
+/// `value * numerator / denominator`, rounded to the nearest pixel.
+fn scale(value: u32, numerator: u32, denominator: u32) -> u32 {
+    ((value as u64 * numerator as u64 * 2 + denominator as u64) / (denominator as u64 * 2)) as u32
+}
+
+/// Places something `size` big in the middle of `output`, on even pixels.
+/// Odd sides lose a pixel rather than grow past what was asked for.
+fn centre(size: Size, output: Size) -> Rect {
+    let width = (size.width.min(output.width) & !1).max(2);
+    let height = (size.height.min(output.height) & !1).max(2);
+    Rect::new(
+        ((output.width - width) / 2) & !1,
+        ((output.height - height) / 2) & !1,
+        width,
+        height,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Placement, Rect, Resolution, ScaleMode, Size};
+
+    const MODES: [ScaleMode; 4] = [
+        ScaleMode::Letterbox,
+        ScaleMode::Stretch,
+        ScaleMode::Crop,
+        ScaleMode::Integer,
+    ];
+
+    fn place(input: (u32, u32), output: (u32, u32), mode: ScaleMode) -> Placement {
+        Placement::new(
+            Size::new(input.0, input.1),
+            Size::new(output.0, output.1),
+            mode,
+        )
+    }
+
+    #[test]
+    fn letterboxes() {
+        let same_shape = place((1920, 1080), (1280, 720), ScaleMode::Letterbox);
+        assert_eq!(same_shape.dest, Rect::new(0, 0, 1280, 720));
+        assert_eq!(same_shape.source, Rect::new(0, 0, 1920, 1080));
+        // Bars top and bottom
+        let square = place((1920, 1080), (1080, 1080), ScaleMode::Letterbox);
+        assert_eq!(square.dest, Rect::new(0, 236, 1080, 608));
+        // Bars on the sides
+        let ultrawide = place((1920, 1080), (3440, 1440), ScaleMode::Letterbox);
+        assert_eq!(ultrawide.dest, Rect::new(440, 0, 2560, 1440));
+    }
+
+    #[test]
+    fn stretches() {
+        let placement = place((1920, 1080), (1080, 1080), ScaleMode::Stretch);
+        assert_eq!(placement.source, Rect::new(0, 0, 1920, 1080));
+        assert_eq!(placement.dest, Rect::new(0, 0, 1080, 1080));
+    }
+
+    #[test]
+    fn crops_to_fill() {
+        let square = place((1920, 1080), (1080, 1080), ScaleMode::Crop);
+        assert_eq!(square.source, Rect::new(420, 0, 1080, 1080));
+        assert_eq!(square.dest, Rect::new(0, 0, 1080, 1080));
+        let tall = place((1280, 1024), (1920, 1080), ScaleMode::Crop);
+        assert_eq!(tall.source, Rect::new(0, 152, 1280, 720));
+    }
+
+    #[test]
+    fn scales_by_whole_numbers() {
+        assert_eq!(
+            place((1280, 720), (3840, 2160), ScaleMode::Integer).dest,
+            Rect::new(0, 0, 3840, 2160)
+        );
+        assert_eq!(
+            place((800, 600), (1920, 1080), ScaleMode::Integer).dest,
+            Rect::new(560, 240, 800, 600)
+        );
+        assert_eq!(
+            place((320, 240), (1920, 1080), ScaleMode::Integer).dest,
+            Rect::new(320, 60, 1280, 960)
+        );
+        // Down by a whole number too
+        assert_eq!(
+            place((2560, 1440), (1920, 1080), ScaleMode::Integer).dest,
+            Rect::new(320, 180, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn placements_stay_in_bounds_and_on_even_pixels() {
+        let sizes = [
+            (1, 1),
+            (2, 2),
+            (3, 7),
+            (17, 5),
+            (640, 480),
+            (853, 480),
+            (1366, 767),
+            (1920, 1080),
+            (1080, 1920),
+            (2560, 1080),
+            (3441, 1439),
+            (7680, 4320),
+        ];
+        for input in sizes {
+            for output in sizes {
+                let output_size = Size::new(output.0, output.1).even();
+                let input_size = Size::new(input.0, input.1);
+                for mode in MODES {
+                    let placement = Placement::new(input_size, output_size, mode);
+                    let (source, dest) = (placement.source, placement.dest);
+                    let context = format!("{input:?} into {output_size:?} with {mode}");
+
+                    assert!(source.width > 0 && source.height > 0, "{context}");
+                    assert!(source.right() <= input.0, "{context}");
+                    assert!(source.bottom() <= input.1, "{context}");
+                    assert!(dest.width > 0 && dest.height > 0, "{context}");
+                    assert!(dest.right() <= output_size.width, "{context}");
+                    assert!(dest.bottom() <= output_size.height, "{context}");
+                    for value in [dest.x, dest.y, dest.width, dest.height] {
+                        assert_eq!(value % 2, 0, "{context}: {dest:?}");
+                    }
+
+                    // Centred, give or take the even rounding
+                    let left = dest.x as i64;
+                    let right = (output_size.width - dest.right()) as i64;
+                    assert!((left - right).abs() <= 2, "{context}: {dest:?}");
+
+                    match mode {
+                        ScaleMode::Stretch => assert_eq!(dest, Rect::of(output_size)),
+                        ScaleMode::Crop => assert_eq!(dest, Rect::of(output_size)),
+                        ScaleMode::Letterbox | ScaleMode::Integer => {
+                            assert_eq!(source, Rect::of(input_size));
+                            // Fills the output one way or the other
+                            if mode == ScaleMode::Letterbox {
+                                assert!(
+                                    dest.width + 2 >= output_size.width
+                                        || dest.height + 2 >= output_size.height,
+                                    "{context}: {dest:?}"
+                                );
+                            }
+                        }
+                    }
+
+                    // Nothing gets squashed, once there are enough pixels
+                    // for rounding not to matter.
+                    let sides = [source.width, source.height, dest.width, dest.height];
+                    if mode != ScaleMode::Stretch && sides.iter().all(|&side| side >= 100) {
+                        let shown = source.width as f64 / source.height as f64;
+                        let placed = dest.width as f64 / dest.height as f64;
+                        assert!((shown / placed - 1.0).abs() < 0.02, "{context}: {dest:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_resolutions() {
+        let native = Size::new(2560, 1440);
+        assert_eq!(Resolution::Native.resolve(native), native);
+        assert_eq!(Resolution::_1080p.resolve(native), Size::new(1920, 1080));
+        assert_eq!(
+            Resolution::Height(900).resolve(native),
+            Size::new(1600, 900)
+        );
+        assert_eq!(
+            Resolution::Custom(Size::new(853, 480)).resolve(native),
+            Size::new(854, 480)
+        );
+        // Odd sizes get rounded up
+        let odd = Size::new(1366, 767);
+        assert_eq!(Resolution::Native.resolve(odd), Size::new(1366, 768));
+        assert_eq!(Resolution::Height(719).resolve(odd), Size::new(1282, 720));
+        assert_eq!(Resolution::Height(1).resolve(odd), Size::new(2, 2));
+    }
+
+    #[test]
+    fn parses_resolution() {
+        for resolution in [
+            Resolution::Native,
+            Resolution::_720p,
+            Resolution::_1080p,
+            Resolution::_1440p,
+            Resolution::_2160p,
+            Resolution::_4320p,
+            Resolution::Custom(Size::new(1280, 1024)),
+        ] {
+            assert_eq!(resolution.to_string().parse(), Ok(resolution));
+        }
+        assert_eq!(
+            "853X480".parse(),
+            Ok(Resolution::Custom(Size::new(853, 480)))
+        );
+        for invalid in ["0x720", "1280x", "x720", "1280x-720", "720", "wide"] {
+            assert!(invalid.parse::<Resolution>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_scale_mode() {
+        for mode in MODES {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert_eq!("Crop".parse(), Ok(ScaleMode::Crop));
+        assert!("zoom".parse::<ScaleMode>().is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::io;
+
+use windows::{
+    core::{Result, HSTRING},
+    Graphics::SizeInt32,
+    Storage::Streams::IRandomAccessStream,
+    Win32::Media::MediaFoundation::{
+        IMFSinkWriter, MFAudioFormat_AAC, MFCreateAttributes, MFCreateMFByteStreamOnStreamEx,
+        MFCreateSinkWriterFromURL, MF_SINK_WRITER_DISABLE_THROTTLING,
+    },
+};
+
+use crate::{
+    audio::{encoder::create_aac_output_media_type, processor::AudioFormat},
+    media::packet_to_sample,
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{self, AudioCodec, Sink, StreamFormat, VideoCodec},
+    video::encoder::create_video_output_type,
+};
+
+/// Writes encoded packets to an mp4 file using the Media Foundation sink writer.
+///
+/// The sink writer has no way to name a track, so audio track names are
+/// only written by the muxers in this crate.
+pub struct SampleWriter {
+    _stream: IRandomAccessStream,
+    sink_writer: IMFSinkWriter,
+    video_stream_index: Option<u32>,
+    /// Sink writer stream indices, by audio stream number.
+    audio_stream_indices: Vec<u32>,
+}
+
+unsafe impl Send for SampleWriter {}
+unsafe impl Sync for SampleWriter {}
+impl SampleWriter {
+    pub fn new(stream: IRandomAccessStream) -> Result<Self> {
+        let attributes = unsafe {
+            let mut attributes = None;
+            MFCreateAttributes(&mut attributes, 1)?;
+            let attributes = attributes.unwrap();
+
+            // Set the disable throttling attribute to TRUE
+            attributes.SetUINT32(&MF_SINK_WRITER_DISABLE_THROTTLING, 1)?;
+
+            attributes
+        };
+
+        let sink_writer = unsafe {
+            let byte_stream = MFCreateMFByteStreamOnStreamEx(&stream)?;
+            MFCreateSinkWriterFromURL(&HSTRING::from(".mp4"), &byte_stream, &attributes)?
+        };
+
+        Ok(Self {
+            _stream: stream,
+            sink_writer,
+            video_stream_index: None,
+            audio_stream_indices: Vec::new(),
+        })
+    }
+}
+
+impl Sink for SampleWriter {
+    fn add_stream(&mut self, format: StreamFormat) -> pipeline::Result<usize> {
+        let media_type = match &format {
+            StreamFormat::Video(format) if format.codec != VideoCodec::Raw => {
+                create_video_output_type(
+                    format.codec,
+                    SizeInt32 {
+                        Width: format.width as i32,
+                        Height: format.height as i32,
+                    },
+                    format.bit_rate,
+                    format.frame_rate,
+                )?
+            }
+            StreamFormat::Audio(format) if format.codec == AudioCodec::Aac => {
+                create_aac_output_media_type(
+                    &AudioFormat {
+                        sample_rate: format.sample_rate,
+                        channels: format.channels,
+                        bits_per_sample: 16,
+                        channel_mask: None,
+                        format: MFAudioFormat_AAC,
+                    },
+                    Some(format.bit_rate),
+                )?
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("The mp4 sink writer can't store {:?}.", format),
+                ))
+            }
+        };
+        let stream_index = unsafe { self.sink_writer.AddStream(&media_type)? };
+        match format {
+            StreamFormat::Video(_) => {
+                self.video_stream_index = Some(stream_index);
+                Ok(0)
+            }
+            StreamFormat::Audio(_) => {
+                self.audio_stream_indices.push(stream_index);
+                Ok(self.audio_stream_indices.len() - 1)
+            }
+        }
+    }
+
+    fn start(&mut self) -> pipeline::Result<()> {
+        unsafe { self.sink_writer.BeginWriting()? };
+        Ok(())
+    }
+
+    /// Writes the packet to the sink writer and releases the sample's buffers
+    /// to avoid leaks.
+    fn write(&mut self, packet: EncodedPacket) -> pipeline::Result<()> {
+        let stream_index = match packet.kind {
+            StreamKind::Video => self.video_stream_index,
+            StreamKind::Audio => self.audio_stream_indices.get(packet.stream).copied(),
+        }
+        .ok_or_else(|| io::Error::other(format!("No {:?} stream was added.", packet.kind)))?;
+        let sample = packet_to_sample(&packet)?;
+        unsafe {
+            self.sink_writer.WriteSample(stream_index, &sample)?;
+            sample.RemoveAllBuffers()?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> pipeline::Result<()> {
+        unsafe { self.sink_writer.Finalize()? };
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{next_stream_number, Result, SharedSink, Sink, StreamFormat},
+};
+
+/// When a segmented recording moves on to its next file. Rollover happens on
+/// the first keyframe after either limit is reached.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SegmentLimits {
+    /// In 100ns units.
+    pub max_duration: Option<i64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Creates the sink for a segment, given its number (starting at 1).
+pub type SegmentSinkFactory = Box<dyn FnMut(usize) -> Result<SharedSink> + Send>;
+
+struct Segment {
+    sink: SharedSink,
+    start_time: i64,
+    bytes: u64,
+}
+
+impl Segment {
+    /// Writes a packet with its timestamp made relative to the segment.
+    fn write(&mut self, mut packet: EncodedPacket) -> Result<()> {
+        self.bytes += packet.data.len() as u64;
+        packet.timestamp -= self.start_time;
+        self.sink.lock().unwrap().write(packet)
+    }
+
+    fn stop(self) -> Result<()> {
+        self.sink.lock().unwrap().stop()
+    }
+}
+
+/// Splits a recording across a series of sinks, switching to the next one on
+/// a video keyframe once the current segment is long or large enough.
+///
+/// Every packet ends up in exactly one segment. Audio belongs to the segment
+/// its timestamp falls in, so the previous segment stays open until the audio
+/// has caught up with the keyframe the new one starts on.
+pub struct SegmentedSink {
+    limits: SegmentLimits,
+    create_sink: SegmentSinkFactory,
+    formats: Vec<StreamFormat>,
+    current: Option<Segment>,
+    closing: Option<Segment>,
+    /// The streams the closing segment is still waiting on, by kind and
+    /// number.
+    closing_streams: Vec<(StreamKind, usize)>,
+    segment_count: usize,
+    split_request: Arc<AtomicBool>,
+}
+
+impl SegmentedSink {
+    pub fn new(limits: SegmentLimits, create_sink: SegmentSinkFactory) -> Self {
+        Self {
+            limits,
+            create_sink,
+            formats: Vec::new(),
+            current: None,
+            closing: None,
+            closing_streams: Vec::new(),
+            segment_count: 0,
+            split_request: Arc::default(),
+        }
+    }
+
+    /// A flag that, once set, moves on to the next file on the next
+    /// keyframe whatever the limits say. It's cleared when that happens.
+    pub fn split_request(&self) -> Arc<AtomicBool> {
+        self.split_request.clone()
+    }
+
+    /// The stream segments are cut on: video if there is any, so every
+    /// segment starts with a keyframe.
+    fn cut_stream(&self) -> (StreamKind, usize) {
+        if self
+            .formats
+            .iter()
+            .any(|format| matches!(format, StreamFormat::Video(_)))
+        {
+            (StreamKind::Video, 0)
+        } else {
+            (StreamKind::Audio, 0)
+        }
+    }
+
+    fn is_full(&self, segment: &Segment, timestamp: i64) -> bool {
+        // A split only makes sense once something has gone in the segment
+        (segment.bytes > 0 && self.split_request.load(Ordering::SeqCst))
+            || self
+                .limits
+                .max_duration
+                .is_some_and(|max_duration| timestamp - segment.start_time >= max_duration)
+            || self
+                .limits
+                .max_bytes
+                .is_some_and(|max_bytes| segment.bytes >= max_bytes)
+    }
+
+    fn open_segment(&mut self, start_time: i64) -> Result<Segment> {
+        self.segment_count += 1;
+        let sink = (self.create_sink)(self.segment_count)?;
+        {
+            let mut sink = sink.lock().unwrap();
+            for format in &self.formats {
+                sink.add_stream(format.clone())?;
+            }
+            sink.start()?;
+        }
+        Ok(Segment {
+            sink,
+            start_time,
+            bytes: 0,
+        })
+    }
+
+    fn finish_closing(&mut self) -> Result<()> {
+        self.closing_streams.clear();
+        match self.closing.take() {
+            Some(segment) => segment.stop(),
+            None => Ok(()),
+        }
+    }
+
+    fn roll_over(&mut self, start_time: i64) -> Result<()> {
+        // Anything that still hasn't caught up after a whole segment never
+        // will, so don't hold more than one old segment open.
+        self.finish_closing()?;
+        self.split_request.store(false, Ordering::SeqCst);
+        let next = self.open_segment(start_time)?;
+        self.closing = self.current.replace(next);
+        let cut_stream = self.cut_stream();
+        self.closing_streams = self
+            .formats
+            .iter()
+            .enumerate()
+            .map(|(index, format)| {
+                let kind = format.kind();
+                (kind, next_stream_number(&self.formats[..index], kind))
+            })
+            .filter(|&stream| stream != cut_stream)
+            .collect();
+        if self.closing_streams.is_empty() {
+            self.finish_closing()?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for SegmentedSink {
+    fn add_stream(&mut self, format: StreamFormat) -> Result<usize> {
+        if self.current.is_some() {
+            return Err(io::Error::other("Streams must be added before starting."));
+        }
+        let number = next_stream_number(&self.formats, format.kind());
+        self.formats.push(format);
+        Ok(number)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let segment = self.open_segment(0)?;
+        self.current = Some(segment);
+        Ok(())
+    }
+
+    fn write(&mut self, packet: EncodedPacket) -> Result<()> {
+        let Some(current) = &self.current else {
+            return Err(io::Error::other(
+                "The segmented sink isn't accepting packets.",
+            ));
+        };
+
+        let stream = (packet.kind, packet.stream);
+        if stream == self.cut_stream() && packet.keyframe && self.is_full(current, packet.timestamp)
+        {
+            self.roll_over(packet.timestamp)?;
+        }
+
+        let current = self.current.as_mut().unwrap();
+        if self.closing_streams.contains(&stream) {
+            if packet.timestamp < current.start_time {
+                return self.closing.as_mut().unwrap().write(packet);
+            }
+            self.closing_streams.retain(|&closing| closing != stream);
+            if self.closing_streams.is_empty() {
+                self.finish_closing()?;
+            }
+        }
+        self.current.as_mut().unwrap().write(packet)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.finish_closing()?;
+        match self.current.take() {
+            Some(segment) => segment.stop(),
+            None => Err(io::Error::other("The segmented sink isn't running.")),
+        }
+    }
+
+    /// Chapters go in the segment being written, relative to its start.
+    fn add_chapter(&mut self, timestamp: i64, title: &str) -> Result<()> {
+        match &self.current {
+            Some(segment) => segment
+                .sink
+                .lock()
+                .unwrap()
+                .add_chapter(timestamp - segment.start_time, title),
+            None => Err(io::Error::other("The segmented sink isn't running.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc, Mutex};
+
+    use super::{SegmentLimits, SegmentedSink};
+    use crate::{
+        packet::{EncodedPacket, StreamKind},
+        pipeline::{
+            synthetic::MemorySink, AudioCodec, AudioStreamFormat, Sink, StreamFormat, VideoCodec,
+            VideoStreamFormat,
+        },
+    };
+
+    const SECOND: i64 = 10_000_000;
+    const FRAME: i64 = SECOND / 30;
+    const AUDIO_FRAME: i64 = 1024 * SECOND / 48000;
+
+    type Segments = Arc<Mutex<Vec<Arc<Mutex<MemorySink>>>>>;
+
+    fn segmented(limits: SegmentLimits, with_video: bool) -> (SegmentedSink, Segments) {
+        let segments: Segments = Arc::default();
+        let created = segments.clone();
+        let mut sink = SegmentedSink::new(
+            limits,
+            Box::new(move |number| {
+                let mut created = created.lock().unwrap();
+                assert_eq!(number, created.len() + 1);
+                let sink = Arc::new(Mutex::new(MemorySink::new()));
+                created.push(sink.clone());
+                Ok(sink)
+            }),
+        );
+        if with_video {
+            sink.add_stream(StreamFormat::Video(VideoStreamFormat {
+                codec: VideoCodec::H264,
+                width: 64,
+                height: 64,
+                frame_rate: 30,
+                bit_rate: 1_000_000,
+            }))
+            .unwrap();
+        }
+        sink.add_stream(StreamFormat::Audio(AudioStreamFormat {
+            codec: AudioCodec::Aac,
+            sample_rate: 48000,
+            channels: 2,
+            bit_rate: 128_000,
+            name: None,
+            language: None,
+        }))
+        .unwrap();
+        sink.start().unwrap();
+        (sink, segments)
+    }
+
+    fn video(index: i64) -> EncodedPacket {
+        EncodedPacket::new(
+            StreamKind::Video,
+            vec![index as u8; 100],
+            index * FRAME,
+            FRAME,
+            index % 15 == 0,
+        )
+    }
+
+    fn audio(index: i64) -> EncodedPacket {
+        EncodedPacket::new(
+            StreamKind::Audio,
+            vec![index as u8; 10],
+            index * AUDIO_FRAME,
+            AUDIO_FRAME,
+            true,
+        )
+    }
+
+    /// Audio and video in timestamp order, except that audio shows up
+    /// `audio_delay` late, the way it does when it's encoded on another
+    /// thread.
+    fn packets(seconds: i64, audio_delay: i64) -> Vec<EncodedPacket> {
+        let mut packets: Vec<(i64, EncodedPacket)> = (0..seconds * 30)
+            .map(|index| (index * FRAME, video(index)))
+            .chain(
+                (0..seconds * SECOND / AUDIO_FRAME)
+                    .map(|index| (index * AUDIO_FRAME + audio_delay, audio(index))),
+            )
+            .collect();
+        packets.sort_by_key(|(arrival, _)| *arrival);
+        packets.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    fn record(sink: &mut SegmentedSink, packets: &[EncodedPacket]) {
+        for packet in packets {
+            sink.write(packet.clone()).unwrap();
+        }
+        sink.stop().unwrap();
+    }
+
+    #[test]
+    fn rolls_over_on_keyframes_after_the_duration() {
+        let limits = SegmentLimits {
+            max_duration: Some(30 * FRAME),
+            max_bytes: None,
+        };
+        let (mut sink, segments) = segmented(limits, true);
+        record(&mut sink, &packets(3, 0));
+
+        let segments = segments.lock().unwrap();
+        assert_eq!(segments.len(), 3);
+        for segment in segments.iter() {
+            let segment = segment.lock().unwrap();
+            assert!(segment.started && segment.stopped);
+            assert_eq!(segment.formats.len(), 2);
+            let video: Vec<_> = segment.packets_of(StreamKind::Video).collect();
+            assert_eq!(video.len(), 30);
+            assert!(video[0].keyframe);
+            assert_eq!(video[0].timestamp, 0);
+        }
+    }
+
+    #[test]
+    fn every_packet_lands_in_one_segment() {
+        let limits = SegmentLimits {
+            max_duration: Some(SECOND / 2),
+            max_bytes: None,
+        };
+        let (mut sink, segments) = segmented(limits, true);
+        let input = packets(3, FRAME * 4);
+        record(&mut sink, &input);
+
+        let segments = segments.lock().unwrap();
+        let mut starts = Vec::new();
+        let mut written = Vec::new();
+        for segment in segments.iter() {
+            let segment = segment.lock().unwrap();
+            let start = segment.packets_of(StreamKind::Video).next().unwrap();
+            assert_eq!(start.timestamp, 0);
+            let start = start.data[0] as i64 * FRAME;
+            starts.push(start);
+            for packet in &segment.packets {
+                let mut packet = packet.clone();
+                packet.timestamp += start;
+                written.push(packet);
+            }
+        }
+        assert_eq!(written.len(), input.len());
+        for packet in &input {
+            assert_eq!(written.iter().filter(|&other| other == packet).count(), 1);
+        }
+
+        // Audio goes in the segment its timestamp falls in, even though it
+        // arrived after the rollover.
+        for (index, segment) in segments.iter().enumerate() {
+            let end = starts.get(index + 1).copied().unwrap_or(i64::MAX);
+            let segment = segment.lock().unwrap();
+            for packet in segment.packets_of(StreamKind::Audio) {
+                let timestamp = packet.timestamp + starts[index];
+                assert!(timestamp >= starts[index] && timestamp < end);
+            }
+        }
+    }
+
+    #[test]
+    fn rolls_over_on_size() {
+        let limits = SegmentLimits {
+            max_duration: None,
+            max_bytes: Some(1000),
+        };
+        let (mut sink, segments) = segmented(limits, true);
+        record(&mut sink, &packets(2, 0));
+
+        let segments = segments.lock().unwrap();
+        // 15 frames of 100 bytes already pass the limit, so every keyframe
+        // starts a new segment.
+        assert_eq!(segments.len(), 4);
+        for segment in segments.iter() {
+            let segment = segment.lock().unwrap();
+            assert_eq!(segment.packets_of(StreamKind::Video).count(), 15);
+        }
+    }
+
+    #[test]
+    fn splits_when_asked() {
+        let (mut sink, segments) = segmented(SegmentLimits::default(), true);
+        let split_request = sink.split_request();
+        // Asking before anything is written doesn't leave an empty file,
+        // the split waits for the keyframe after
+        split_request.store(true, Ordering::SeqCst);
+        for (index, packet) in packets(2, 0).into_iter().enumerate() {
+            // Asked for between keyframes, so the split waits for the next
+            if index == 100 {
+                split_request.store(true, Ordering::SeqCst);
+            }
+            sink.write(packet).unwrap();
+        }
+        sink.stop().unwrap();
+        assert!(!split_request.load(Ordering::SeqCst));
+
+        let segments = segments.lock().unwrap();
+        assert_eq!(segments.len(), 3);
+        let video_counts: Vec<_> = segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .lock()
+                    .unwrap()
+                    .packets_of(StreamKind::Video)
+                    .count()
+            })
+            .collect();
+        assert_eq!(video_counts.iter().sum::<usize>(), 60);
+        assert_eq!(video_counts[0], 15);
+        assert_eq!(video_counts[1] % 15, 0);
+    }
+
+    #[test]
+    fn chapters_go_in_the_current_segment() {
+        let limits = SegmentLimits {
+            max_duration: Some(30 * FRAME),
+            max_bytes: None,
+        };
+        let (mut sink, segments) = segmented(limits, true);
+        for packet in packets(2, 0) {
+            let is_marked = packet.kind == StreamKind::Video && packet.timestamp == 45 * FRAME;
+            sink.write(packet).unwrap();
+            if is_marked {
+                sink.add_chapter(45 * FRAME, "Boss fight").unwrap();
+            }
+        }
+        sink.stop().unwrap();
+
+        let segments = segments.lock().unwrap();
+        assert!(segments[0].lock().unwrap().chapters.is_empty());
+        assert_eq!(
+            segments[1].lock().unwrap().chapters,
+            [(15 * FRAME, "Boss fight".to_string())]
+        );
+    }
+
+    #[test]
+    fn audio_only_recordings_roll_over_on_any_packet() {
+        let limits = SegmentLimits {
+            max_duration: Some(SECOND),
+            max_bytes: None,
+        };
+        let (mut sink, segments) = segmented(limits, false);
+        let input: Vec<_> = (0..100).map(audio).collect();
+        record(&mut sink, &input);
+
+        let segments = segments.lock().unwrap();
+        assert_eq!(segments.len(), 3);
+        let total: usize = segments
+            .iter()
+            .map(|segment| segment.lock().unwrap().packets.len())
+            .sum();
+        assert_eq!(total, 100);
+        assert!(segments
+            .iter()
+            .all(|segment| segment.lock().unwrap().stopped));
+    }
+}
@@ -1,12 +1,11 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicI64, Ordering};
 
 use windows::core::Error;
 use windows::Foundation::TimeSpan;
-use windows::Win32::System::Performance::QueryPerformanceCounter;
 use windows::{
     core::{Interface, Result},
     Win32::{
@@ -23,6 +22,12 @@ use windows::{
     },
 };
 
+use crate::{clock::SharedClock, resolution::Size};
+
+// How long to wait for the capture thread before giving the caller a chance
+// to do something else.
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
 // Helper function to get IDXGIOutput1 from HMONITOR
 fn get_dxgi_output_from_hmonitor(
     d3d_device: &ID3D11Device,
@@ -75,9 +80,11 @@ impl CustomGraphicsCaptureSession {
         }
     }
 
-    pub fn StartCapture(&mut self, start_qpc: i64) -> Result<()> {
+    pub fn StartCapture(&mut self, start_time: i64) -> Result<()> {
         if !self.running {
-            self.sender.send((true, start_qpc)).map_err(|_| windows::core::Error::from(E_FAIL))?;
+            self.sender
+                .send((true, start_time))
+                .map_err(|_| windows::core::Error::from(E_FAIL))?;
             self.running = true;
         }
         Ok(())
@@ -106,11 +113,14 @@ pub struct CaptureFrameGenerator {
     sender: Sender<Option<AcquiredFrame>>,
     receiver: Receiver<Option<AcquiredFrame>>,
     session: CustomGraphicsCaptureSession,
-    start_qpc: Arc<AtomicI64>,  // Added to store the reference QPC value
+    start_time: Arc<AtomicI64>, // The clock time (in 100ns units) frame times are relative to
+    size: Size,
+    origin: (i32, i32),
 }
 
 impl CaptureFrameGenerator {
     pub fn new(
+        clock: SharedClock,
         d3d_device: ID3D11Device,
         monitor_handle: HMONITOR,
     ) -> Result<Self> {
@@ -120,9 +130,9 @@ impl CaptureFrameGenerator {
 
         let frame_sender_for_struct = frame_sender.clone();
         
-        // Create atomic for storing the start QPC timestamp
-        let start_qpc = Arc::new(AtomicI64::new(0));
-        let thread_start_qpc = start_qpc.clone();
+        // Create atomic for storing the start time
+        let start_time = Arc::new(AtomicI64::new(0));
+        let thread_start_time = start_time.clone();
         
         // Create session
         let session = CustomGraphicsCaptureSession::new(control_sender.clone());
@@ -130,10 +140,13 @@ impl CaptureFrameGenerator {
         // Get output and create duplication
         let output = get_dxgi_output_from_hmonitor(&d3d_device, monitor_handle)?;
         let duplication = unsafe { output.DuplicateOutput(&d3d_device)? };
+        // Frames come in the display mode's own orientation
+        let mode = unsafe { duplication.GetDesc() }.ModeDesc;
+        let size = Size::new(mode.Width, mode.Height);
         
-        // Get output dimensions
         // Get output dimensions
         let desc = unsafe { output.GetDesc()? };
+        let origin = (desc.DesktopCoordinates.left, desc.DesktopCoordinates.top);
         // Clone necessary values for the capture thread
         let d3d_device_clone = d3d_device.clone();
         
@@ -146,13 +159,13 @@ impl CaptureFrameGenerator {
             'outer: loop {
                 // Check for control messages first
                 match control_receiver.try_recv() {
-                    Ok((start_signal, new_qpc)) => {
+                    Ok((start_signal, new_start_time)) => {
                         running = start_signal;
                         
                         if running {
-                            // Update the start QPC value when starting capture
-                            thread_start_qpc.store(new_qpc, Ordering::SeqCst);
-                            println!("Video capture: Updated start_qpc to: {}", new_qpc);
+                            // Update the start time when starting capture
+                            thread_start_time.store(new_start_time, Ordering::SeqCst);
+                            println!("Video capture: Updated start time to: {}", new_start_time);
                         }
                         
                         if !running {
@@ -252,20 +265,9 @@ impl CaptureFrameGenerator {
                             continue;
                         }
                         
-                        // Get our own QPC timestamp
-                        let qpc_timestamp = match get_raw_qpc_timestamp() {
-                            Ok(timestamp) => timestamp,
-                            Err(e) => {
-                                eprintln!("Failed to get QPC timestamp: {:?}", e);
-                                continue;
-                            }
-                        };
-                        
-                        // Get the current reference QPC value 
-                        let current_start_qpc = thread_start_qpc.load(Ordering::SeqCst);
-                        
-                        // Create a relative timestamp based on the start_qpc
-                        let relative_timestamp = qpc_timestamp - current_start_qpc;
+                        // Create a timestamp relative to the start time
+                        let current_start_time = thread_start_time.load(Ordering::SeqCst);
+                        let relative_timestamp = clock.now() - current_start_time;
                         let present_time = TimeSpan { Duration: relative_timestamp };
                         
                         // Create and send the frame
@@ -285,19 +287,9 @@ impl CaptureFrameGenerator {
                     Err(err) if err.code() == DXGI_ERROR_WAIT_TIMEOUT => {
                         // No new frame available - use last frame with a new timestamp if we have one
                         if let Some(last_tex) = &last_texture {
-                            // Get a new QPC timestamp
-                            let qpc_timestamp = match get_raw_qpc_timestamp() {
-                                Ok(timestamp) => timestamp,
-                                Err(e) => {
-                                    eprintln!("Failed to get QPC timestamp for duplicate frame: {:?}", e);
-                                    thread::sleep(Duration::from_millis(1));
-                                    continue;
-                                }
-                            };
-                            
                             // Calculate relative timestamp
-                            let current_start_qpc = thread_start_qpc.load(Ordering::SeqCst);
-                            let relative_timestamp = qpc_timestamp - current_start_qpc;
+                            let current_start_time = thread_start_time.load(Ordering::SeqCst);
+                            let relative_timestamp = clock.now() - current_start_time;
                             let present_time = TimeSpan { Duration: relative_timestamp };
                             
                             // Create a duplicate frame with the new timestamp
@@ -336,26 +328,38 @@ impl CaptureFrameGenerator {
             sender: frame_sender_for_struct,
             receiver: frame_receiver,
             session,
-            start_qpc,
+            start_time,
+            size,
+            origin,
         })
     }
 
-    pub fn session(&self) -> &CustomGraphicsCaptureSession {
-        &self.session
+    /// The size of the frames that get captured.
+    pub fn size(&self) -> Size {
+        self.size
     }
 
-    // Convenience method to start capture with the given QPC
-    pub fn start_capture(&mut self, start_qpc: i64) -> Result<()> {
-        self.session.StartCapture(start_qpc)
+    /// Where the display's top left corner is on the desktop.
+    pub fn origin(&self) -> (i32, i32) {
+        self.origin
     }
 
-    // Simplified function that just receives from the channel
+    // Convenience method to start capture with the given start time
+    pub fn start_capture(&mut self, start_time: i64) -> Result<()> {
+        self.session.StartCapture(start_time)
+    }
+
+    // Waits briefly for a frame, returning None if none arrived. Once the
+    // capture has ended an error is returned instead.
     pub fn try_get_next_frame(&mut self) -> Result<Option<AcquiredFrame>> {
+        let capture_ended = || Error::new(E_FAIL, "The capture has ended.");
+
         // First wait for at least one frame (or end signal)
-        let mut latest_frame = match self.receiver.recv() {
-            Ok(Some(frame)) => Some(frame),
-            Ok(None) => return Ok(None), // End of capture signal
-            Err(_) => return Ok(None),   // Channel closed
+        let mut latest_frame = match self.receiver.recv_timeout(FRAME_TIMEOUT) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Err(capture_ended()), // End of capture signal
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err(capture_ended()),
         };
         
         // Now drain any additional frames that arrived
@@ -363,11 +367,11 @@ impl CaptureFrameGenerator {
             match self.receiver.try_recv() {
                 Ok(Some(frame)) => {
                     // Keep updating with newer frames
-                    latest_frame = Some(frame);
+                    latest_frame = frame;
                 },
                 Ok(None) => {
-                    // End of capture signal - return None regardless of what we've seen before
-                    return Ok(None);
+                    // End of capture signal - fail regardless of what we've seen before
+                    return Err(capture_ended());
                 },
                 Err(std::sync::mpsc::TryRecvError::Empty) => {
                     // No more frames in the channel, break the loop
@@ -375,42 +379,21 @@ impl CaptureFrameGenerator {
                 },
                 Err(std::sync::mpsc::TryRecvError::Disconnected) => {
                     // Channel closed, end of capture
-                    return Ok(None);
+                    return Err(capture_ended());
                 }
             }
         }
         
         // Return the latest frame we found
-        Ok(latest_frame)
+        Ok(Some(latest_frame))
     }
 
     pub fn stop_capture(&mut self) -> Result<()> {
         self.session.Close()
     }
     
-    // Getter for the current start_qpc value
-    pub fn get_start_qpc(&self) -> i64 {
-        self.start_qpc.load(Ordering::SeqCst)
+    // Getter for the current start time
+    pub fn get_start_time(&self) -> i64 {
+        self.start_time.load(Ordering::SeqCst)
     }
 }
-
-// Modified to return raw QPC value without creating TimeSpan
-fn get_raw_qpc_timestamp() -> Result<i64> {
-    let mut qpc_timestamp: i64 = 0;
-    unsafe {
-        QueryPerformanceCounter(&mut qpc_timestamp)?;
-    }
-    Ok(qpc_timestamp)
-}
-
-// Original function kept for backward compatibility
-fn get_qpc_timestamp() -> Result<TimeSpan> {
-    let qpc_timestamp = get_raw_qpc_timestamp()?;
-    
-    // Create a TimeSpan using the same pattern as your example
-    let timestamp = TimeSpan {
-        Duration: qpc_timestamp,
-    };
-    
-    Ok(timestamp)
-}
\ No newline at end of file
@@ -1,36 +1,46 @@
-use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    thread::JoinHandle, time::{SystemTime, UNIX_EPOCH},
-};
-
 use windows::{
-    core::{Interface, Error, Result},
+    core::{Error, Interface, Result, GUID},
     Foundation::TimeSpan,
     Graphics::SizeInt32,
     Win32::{
-        Foundation::E_NOTIMPL,
-        Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D},
+        Foundation::{E_INVALIDARG, E_NOTIMPL},
+        Graphics::Direct3D11::{
+            ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_CPU_ACCESS_READ,
+            D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ, D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+        },
         Media::MediaFoundation::{
-            IMFAttributes, IMFDXGIDeviceManager, IMFMediaEventGenerator, IMFMediaType, IMFSample,
-            IMFTransform, METransformHaveOutput, METransformNeedInput, MFCreateDXGIDeviceManager,
-            MFCreateDXGISurfaceBuffer, MFCreateMediaType, MFCreateSample, MFMediaType_Video,
-            MFStartup, MFVideoFormat_H264, MFVideoFormat_NV12, MFVideoInterlace_Progressive,
-            MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS, MFSTARTUP_FULL, MFT_MESSAGE_COMMAND_FLUSH,
-            MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, MFT_MESSAGE_NOTIFY_END_OF_STREAM,
-            MFT_MESSAGE_NOTIFY_END_STREAMING, MFT_MESSAGE_NOTIFY_START_OF_STREAM,
-            MFT_MESSAGE_SET_D3D_MANAGER, MFT_OUTPUT_DATA_BUFFER, MFT_SET_TYPE_TEST_ONLY,
-            MF_EVENT_TYPE, MF_E_INVALIDMEDIATYPE, MF_E_NO_MORE_TYPES, MF_E_TRANSFORM_TYPE_NOT_SET,
-            MF_MT_ALL_SAMPLES_INDEPENDENT, MF_MT_AVG_BITRATE, MF_MT_FRAME_RATE, MF_MT_FRAME_SIZE,
-            MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE, MF_MT_PIXEL_ASPECT_RATIO, MF_MT_SUBTYPE,
+            CODECAPI_AVEncCommonMeanBitRate, CODECAPI_AVEncCommonQuality,
+            CODECAPI_AVEncCommonRateControlMode, CODECAPI_AVEncMPVDefaultBPictureCount,
+            CODECAPI_AVEncMPVGOPSize, CODECAPI_AVEncVideoEncodeQP, CODECAPI_AVLowLatencyMode,
+            ICodecAPI, IMFAttributes, IMFDXGIDeviceManager, IMFMediaEventGenerator, IMFMediaType,
+            IMFTransform, METransformDrainComplete, METransformHaveOutput, METransformNeedInput,
+            MFCreateDXGIDeviceManager, MFCreateDXGISurfaceBuffer, MFCreateMediaType,
+            MFCreateSample, MFMediaType_Video, MFStartup, MFVideoFormat_AV1, MFVideoFormat_H264,
+            MFVideoFormat_HEVC, MFVideoFormat_NV12, MFVideoInterlace_Progressive,
+            MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS, MFSTARTUP_FULL, MFT_MESSAGE_COMMAND_DRAIN,
+            MFT_MESSAGE_COMMAND_FLUSH, MFT_MESSAGE_NOTIFY_BEGIN_STREAMING,
+            MFT_MESSAGE_NOTIFY_END_OF_STREAM, MFT_MESSAGE_NOTIFY_END_STREAMING,
+            MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_MESSAGE_SET_D3D_MANAGER,
+            MFT_OUTPUT_DATA_BUFFER, MFT_SET_TYPE_TEST_ONLY, MF_EVENT_FLAG_NO_WAIT, MF_EVENT_TYPE,
+            MF_E_INVALIDMEDIATYPE, MF_E_NO_EVENTS_AVAILABLE, MF_E_NO_MORE_TYPES,
+            MF_E_TRANSFORM_TYPE_NOT_SET, MF_MT_ALL_SAMPLES_INDEPENDENT, MF_MT_AVG_BITRATE,
+            MF_MT_FRAME_RATE, MF_MT_FRAME_SIZE, MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE,
+            MF_MT_MPEG2_LEVEL, MF_MT_MPEG2_PROFILE, MF_MT_PIXEL_ASPECT_RATIO, MF_MT_SUBTYPE,
             MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, MF_TRANSFORM_ASYNC_UNLOCK,
         },
+        System::Variant::VARIANT,
     },
 };
 
-use crate::media::{MFSetAttributeRatio, MFSetAttributeSize, MF_VERSION};
+use crate::{
+    encoder_settings::{CodecProperty, VideoEncoderSettings},
+    media::{sample_to_packet, MFSetAttributeRatio, MFSetAttributeSize, MF_VERSION},
+    packet::{EncodedPacket, StreamKind},
+    pipeline::{
+        self, software::SoftwareH264Encoder, Encoder, StreamFormat, Timestamped, VideoCodec,
+        VideoFrame, VideoStreamFormat,
+    },
+};
 
 use super::encoder_device::VideoEncoderDevice;
 
@@ -44,27 +54,27 @@ impl VideoEncoderInputSample {
     pub fn new(timestamp: TimeSpan, texture: ID3D11Texture2D) -> Self {
         Self { timestamp, texture }
     }
-}
 
-pub struct VideoEncoderOutputSample {
-    sample: IMFSample,
+    pub fn texture(&self) -> &ID3D11Texture2D {
+        &self.texture
+    }
 }
 
-impl VideoEncoderOutputSample {
-    pub fn sample(&self) -> &IMFSample {
-        &self.sample
+impl Timestamped for VideoEncoderInputSample {
+    fn timestamp(&self) -> i64 {
+        self.timestamp.Duration
     }
-}
 
-pub struct VideoEncoder {
-    inner: Option<VideoEncoderInner>,
-    output_type: IMFMediaType,
-    started: AtomicBool,
-    should_stop: Arc<AtomicBool>,
-    encoder_thread_handle: Option<JoinHandle<Result<()>>>,
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp.Duration = timestamp;
+    }
 }
 
-struct VideoEncoderInner {
+/// Encodes NV12 textures to H264, HEVC or AV1 using a hardware (async)
+/// transform, whichever the settings ask for. Input
+/// is pushed one frame at a time, and whatever the transform has finished by
+/// then is handed back.
+pub struct VideoEncoder {
     _d3d_device: ID3D11Device,
     _media_device_manager: IMFDXGIDeviceManager,
     _device_manager_reset_token: u32,
@@ -74,11 +84,10 @@ struct VideoEncoderInner {
     input_stream_id: u32,
     output_stream_id: u32,
 
-    sample_requested_callback:
-        Option<Box<dyn Send + FnMut() -> Result<Option<VideoEncoderInputSample>>>>,
-    sample_rendered_callback: Option<Box<dyn Send + FnMut(VideoEncoderOutputSample) -> Result<()>>>,
-
-    should_stop: Arc<AtomicBool>,
+    format: VideoStreamFormat,
+    streaming: bool,
+    // The number of METransformNeedInput events we haven't answered yet.
+    pending_input_requests: u32,
 }
 
 impl VideoEncoder {
@@ -87,9 +96,16 @@ impl VideoEncoder {
         d3d_device: ID3D11Device,
         input_resolution: SizeInt32,
         output_resolution: SizeInt32,
-        bit_rate: u32,
+        settings: &VideoEncoderSettings,
         frame_rate: u32,
     ) -> Result<Self> {
+        settings
+            .check_level(
+                output_resolution.Width as u32,
+                output_resolution.Height as u32,
+                frame_rate,
+            )
+            .map_err(|message| Error::new(E_INVALIDARG, message))?;
         let transform = encoder_device.create_transform()?;
 
         // Create MF device manager
@@ -157,24 +173,23 @@ impl VideoEncoder {
             transform.ProcessMessage(MFT_MESSAGE_SET_D3D_MANAGER, std::mem::transmute(temp))?;
         };
 
-        let output_type = unsafe {
-            let output_type = MFCreateMediaType()?;
-            let attributes: IMFAttributes = output_type.cast()?;
-            output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
-            output_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_H264)?;
-            output_type.SetUINT32(&MF_MT_AVG_BITRATE, bit_rate)?;
-            MFSetAttributeSize(
-                &attributes,
-                &MF_MT_FRAME_SIZE,
-                output_resolution.Width as u32,
-                output_resolution.Height as u32,
-            )?;
-            MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, frame_rate, 1)?;
-            MFSetAttributeRatio(&attributes, &MF_MT_PIXEL_ASPECT_RATIO, 1, 1)?;
-            output_type.SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
-            output_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
-            transform.SetOutputType(output_stream_id, &output_type, 0)?;
-            output_type
+        // Encoders want these before the media types are set
+        apply_settings(&transform, settings)?;
+
+        let output_type = create_video_output_type(
+            settings.codec,
+            output_resolution,
+            settings.bit_rate,
+            frame_rate,
+        )?;
+        unsafe {
+            if let Some(profile) = settings.profile {
+                output_type.SetUINT32(&MF_MT_MPEG2_PROFILE, profile.value())?;
+            }
+            if let Some(level) = settings.level {
+                output_type.SetUINT32(&MF_MT_MPEG2_LEVEL, level.value())?;
+            }
+            transform.SetOutputType(output_stream_id, &output_type, 0)?
         };
         let input_type: Option<IMFMediaType> = unsafe {
             let mut count = 0;
@@ -221,8 +236,7 @@ impl VideoEncoder {
             ));
         }
 
-        let should_stop = Arc::new(AtomicBool::new(false));
-        let inner = VideoEncoderInner {
+        Ok(Self {
             _d3d_device: d3d_device,
             _media_device_manager: media_device_manager,
             _device_manager_reset_token: device_manager_reset_token,
@@ -232,180 +246,339 @@ impl VideoEncoder {
             input_stream_id,
             output_stream_id,
 
-            sample_requested_callback: None,
-            sample_rendered_callback: None,
-
-            should_stop: should_stop.clone(),
-        };
-
-        Ok(Self {
-            inner: Some(inner),
-            output_type,
-            started: AtomicBool::new(false),
-            should_stop,
-            encoder_thread_handle: None,
+            format: VideoStreamFormat {
+                codec: settings.codec,
+                width: output_resolution.Width as u32,
+                height: output_resolution.Height as u32,
+                frame_rate,
+                bit_rate: settings.bit_rate,
+            },
+            streaming: false,
+            pending_input_requests: 0,
         })
     }
 
-    pub fn try_start(&mut self) -> Result<bool> {
-        let mut result = false;
-        if self
-            .started
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .is_ok()
-        {
-            let mut inner = self.inner.take().unwrap();
-
-            // Callbacks must both be set
-            if inner.sample_rendered_callback.is_none() || inner.sample_requested_callback.is_none()
-            {
-                panic!("Sample requested and rendered callbacks must be set before starting");
+    fn begin_streaming(&mut self) -> Result<()> {
+        if !self.streaming {
+            unsafe {
+                MFStartup(MF_VERSION, MFSTARTUP_FULL)?;
+                self.transform
+                    .ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
+                self.transform
+                    .ProcessMessage(MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, 0)?;
+                self.transform
+                    .ProcessMessage(MFT_MESSAGE_NOTIFY_START_OF_STREAM, 0)?;
             }
-
-            // Start a seperate thread to drive the transform
-            self.encoder_thread_handle = Some(std::thread::spawn(move || -> Result<()> {
-                unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
-                let result = inner.encode();
-                if result.is_err() {
-                    println!("Recording stopped unexpectedly!");
-                }
-                result
-            }));
-            result = true;
+            self.streaming = true;
         }
-        Ok(result)
+        Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<()> {
-        if self.started.load(Ordering::SeqCst) {
-            assert!(self
-                .should_stop
-                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-                .is_ok());
-            self.wait_for_completion()?;
+    /// Handles the next event from the transform, collecting any output into
+    /// `packets`. Returns the event type, or None if `wait` is false and no
+    /// event was ready.
+    fn handle_event(
+        &mut self,
+        wait: bool,
+        packets: &mut Vec<EncodedPacket>,
+    ) -> Result<Option<MF_EVENT_TYPE>> {
+        let flags = if wait {
+            MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS(0)
+        } else {
+            MF_EVENT_FLAG_NO_WAIT
+        };
+        let event = match unsafe { self.event_generator.GetEvent(flags) } {
+            Ok(event) => event,
+            Err(error) if error.code() == MF_E_NO_EVENTS_AVAILABLE => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let event_type = MF_EVENT_TYPE(unsafe { event.GetType()? } as i32);
+        match event_type {
+            MEDIA_ENGINE_TRANFORM_NEED_INPUT => {
+                self.pending_input_requests += 1;
+            }
+            MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT => {
+                packets.push(self.process_output()?);
+            }
+            MEDIA_ENGINE_TRANFORM_DRAIN_COMPLETE => {}
+            _ => {
+                panic!("Unknown media event type: {}", event_type.0);
+            }
         }
-        Ok(())
+        Ok(Some(event_type))
     }
 
-    fn wait_for_completion(&mut self) -> Result<()> {
-        let handle = self.encoder_thread_handle.take().unwrap();
-        handle.join().unwrap()
+    fn process_input(&mut self, sample: &VideoEncoderInputSample) -> Result<()> {
+        let input_buffer =
+            unsafe { MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &sample.texture, 0, false)? };
+        let mf_sample = unsafe { MFCreateSample()? };
+        unsafe {
+            // Add the DXGI surface buffer to the sample
+            mf_sample.AddBuffer(&input_buffer)?;
+            // Set the sample timestamp
+            mf_sample.SetSampleTime(sample.timestamp.Duration)?;
+            // Submit the sample to the transform
+            self.transform
+                .ProcessInput(self.input_stream_id, &mf_sample, 0)?;
+            // Release all buffers from the sample to free associated memory
+            mf_sample.RemoveAllBuffers()?;
+        };
+        Ok(())
     }
 
-    pub fn set_sample_requested_callback<
-        F: 'static + Send + FnMut() -> Result<Option<VideoEncoderInputSample>>,
-    >(
-        &mut self,
-        callback: F,
-    ) {
-        self.inner.as_mut().unwrap().sample_requested_callback = Some(Box::new(callback));
+    fn process_output(&mut self) -> Result<EncodedPacket> {
+        let mut status = 0;
+        let output_buffer = MFT_OUTPUT_DATA_BUFFER {
+            dwStreamID: self.output_stream_id,
+            ..Default::default()
+        };
+
+        let sample = unsafe {
+            let mut output_buffers = [output_buffer];
+            self.transform
+                .ProcessOutput(0, &mut output_buffers, &mut status)?;
+            output_buffers[0].pSample.as_ref().unwrap().clone()
+        };
+
+        sample_to_packet(&sample, StreamKind::Video)
     }
+}
 
-    pub fn set_sample_rendered_callback<
-        F: 'static + Send + FnMut(VideoEncoderOutputSample) -> Result<()>,
-    >(
-        &mut self,
-        callback: F,
-    ) {
-        self.inner.as_mut().unwrap().sample_rendered_callback = Some(Box::new(callback));
+unsafe impl Send for VideoEncoder {}
+impl Encoder for VideoEncoder {
+    type Input = VideoEncoderInputSample;
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::Video(self.format.clone())
     }
 
-    pub fn output_type(&self) -> &IMFMediaType {
-        &self.output_type
+    fn encode(&mut self, sample: VideoEncoderInputSample) -> pipeline::Result<Vec<EncodedPacket>> {
+        self.begin_streaming()?;
+
+        // The transform tells us when it can take another frame, and keeps
+        // producing output in the meantime.
+        let mut packets = Vec::new();
+        while self.pending_input_requests == 0 {
+            self.handle_event(true, &mut packets)?;
+        }
+        self.pending_input_requests -= 1;
+        self.process_input(&sample)?;
+
+        // Pick up anything else that's ready without waiting for it.
+        while self.handle_event(false, &mut packets)?.is_some() {}
+        Ok(packets)
     }
-}
 
-unsafe impl Send for VideoEncoderInner {}
-// Workaround for:
-//    warning: constant in pattern `METransformNeedInput` should have an upper case name
-//       --> src\video\encoder.rs:XXX:YY
-//        |
-//    XXX |                     METransformNeedInput => {
-//        |                     ^^^^^^^^^^^^^^^^^^^^ help: convert the identifier to upper case: `METRANSFORM_NEED_INPUT`
-//        |
-//        = note: `#[warn(non_upper_case_globals)]` on by default
-const MEDIA_ENGINE_TRANFORM_NEED_INPUT: MF_EVENT_TYPE = METransformNeedInput;
-const MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT: MF_EVENT_TYPE = METransformHaveOutput;
-impl VideoEncoderInner {
-    fn encode(&mut self) -> Result<()> {
+    fn drain(&mut self) -> pipeline::Result<Vec<EncodedPacket>> {
+        let mut packets = Vec::new();
+        if !self.streaming {
+            return Ok(packets);
+        }
         unsafe {
-            self.transform
-                .ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
-            self.transform
-                .ProcessMessage(MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, 0)?;
-            self.transform
-                .ProcessMessage(MFT_MESSAGE_NOTIFY_START_OF_STREAM, 0)?;
-
-                let mut should_exit = false;
-                while !should_exit {
-                    let event = self
-                        .event_generator
-                        .GetEvent(MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS(0))?;
-        
-                    let event_type = MF_EVENT_TYPE(event.GetType()? as i32);
-                    match event_type {
-                        MEDIA_ENGINE_TRANFORM_NEED_INPUT => {
-                            should_exit = self.on_transform_input_requested()?;
-                        }
-                        MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT => {
-                            self.on_transform_output_ready()?;
-                        }
-                    _ => {
-                        panic!("Unknown media event type: {}", event_type.0);
-                    }
-                }
-            }
-
             self.transform
                 .ProcessMessage(MFT_MESSAGE_NOTIFY_END_OF_STREAM, 0)?;
             self.transform
-                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0)?;
+                .ProcessMessage(MFT_MESSAGE_COMMAND_DRAIN, 0)?;
+        }
+        while self.handle_event(true, &mut packets)? != Some(MEDIA_ENGINE_TRANFORM_DRAIN_COMPLETE) {
+        }
+        unsafe {
             self.transform
-                .ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
+                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0)?;
         }
-        Ok(())
+        self.streaming = false;
+        self.pending_input_requests = 0;
+        Ok(packets)
+    }
+}
+
+/// Encodes NV12 textures with the software encoder, for when there's no
+/// hardware one. Each frame is read back from the GPU first.
+pub struct SoftwareVideoEncoder {
+    d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    // Frames are copied here to be read, it's made for the first one
+    staging_texture: Option<ID3D11Texture2D>,
+    encoder: SoftwareH264Encoder,
+}
+
+impl SoftwareVideoEncoder {
+    pub fn new(
+        d3d_device: ID3D11Device,
+        resolution: SizeInt32,
+        settings: &VideoEncoderSettings,
+        frame_rate: u32,
+    ) -> Result<Self> {
+        let encoder = SoftwareH264Encoder::new(
+            resolution.Width as u32,
+            resolution.Height as u32,
+            frame_rate,
+            settings,
+        )
+        .map_err(|error| Error::new(E_INVALIDARG, error.to_string()))?;
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+        Ok(Self {
+            d3d_device,
+            d3d_context,
+            staging_texture: None,
+            encoder,
+        })
     }
 
-    fn on_transform_input_requested(&mut self) -> Result<bool> {
-        let mut should_exit = true;
-        if !self.should_stop.load(Ordering::SeqCst) {
-            if let Some(sample) = self.sample_requested_callback.as_mut().unwrap()()? {
-                let input_buffer = unsafe {
-                    MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &sample.texture, 0, false)?
-                };
-                let mf_sample = unsafe { MFCreateSample()? };
-                unsafe {
-                    // Add the DXGI surface buffer to the sample
-                    mf_sample.AddBuffer(&input_buffer)?;
-                    // Set the sample timestamp
-                    mf_sample.SetSampleTime(sample.timestamp.Duration)?;
-                    // Submit the sample to the transform
-                    self.transform.ProcessInput(self.input_stream_id, &mf_sample, 0)?;
-                    // Release all buffers from the sample to free associated memory
-                    mf_sample.RemoveAllBuffers()?;
-                };
-                should_exit = false;
-            }
+    fn staging_texture(&mut self, texture: &ID3D11Texture2D) -> Result<ID3D11Texture2D> {
+        if let Some(staging_texture) = &self.staging_texture {
+            return Ok(staging_texture.clone());
         }
-        Ok(should_exit)
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+        desc.Usage = D3D11_USAGE_STAGING;
+        desc.BindFlags = 0;
+        desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ.0 as u32;
+        desc.MiscFlags = 0;
+        let staging_texture = unsafe {
+            let mut texture = None;
+            self.d3d_device
+                .CreateTexture2D(&desc, None, Some(&mut texture))?;
+            texture.unwrap()
+        };
+        self.staging_texture = Some(staging_texture.clone());
+        Ok(staging_texture)
     }
 
-    fn on_transform_output_ready(&mut self) -> Result<()> {
-        let mut status = 0;
-        let output_buffer = MFT_OUTPUT_DATA_BUFFER {
-            dwStreamID: self.output_stream_id,
-            ..Default::default()
+    fn read_frame(&mut self, sample: &VideoEncoderInputSample) -> Result<VideoFrame> {
+        let StreamFormat::Video(format) = self.encoder.stream_format() else {
+            unreachable!()
         };
+        let staging_texture = self.staging_texture(sample.texture())?;
+        let width = format.width as usize;
+        let height = format.height as usize;
+        let mut data = Vec::with_capacity(width * height * 3 / 2);
+        unsafe {
+            self.d3d_context
+                .CopyResource(&staging_texture, sample.texture());
+            let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+            self.d3d_context
+                .Map(&staging_texture, 0, D3D11_MAP_READ, 0, Some(&mut mapped))?;
+            // The chroma rows follow the luma ones, with the same pitch
+            let pitch = mapped.RowPitch as usize;
+            let mapped_data =
+                std::slice::from_raw_parts(mapped.pData as *const u8, pitch * height * 3 / 2);
+            for row in mapped_data.chunks_exact(pitch) {
+                data.extend_from_slice(&row[..width]);
+            }
+            self.d3d_context.Unmap(&staging_texture, 0);
+        }
+        Ok(VideoFrame {
+            width: format.width,
+            height: format.height,
+            data,
+            timestamp: sample.timestamp(),
+        })
+    }
+}
 
-        let sample = unsafe {
-            let mut output_buffers = [output_buffer];
-            self.transform
-                .ProcessOutput(0, &mut output_buffers, &mut status)?;
-            output_buffers[0].pSample.as_ref().unwrap().clone()
+unsafe impl Send for SoftwareVideoEncoder {}
+impl Encoder for SoftwareVideoEncoder {
+    type Input = VideoEncoderInputSample;
+
+    fn stream_format(&self) -> StreamFormat {
+        self.encoder.stream_format()
+    }
+
+    fn encode(&mut self, sample: VideoEncoderInputSample) -> pipeline::Result<Vec<EncodedPacket>> {
+        let frame = self.read_frame(&sample)?;
+        self.encoder.encode(frame)
+    }
+
+    fn drain(&mut self) -> pipeline::Result<Vec<EncodedPacket>> {
+        self.encoder.drain()
+    }
+}
+
+/// Sets the codec API properties the settings ask for. An encoder that
+/// doesn't support one fails, rather than quietly ignoring it.
+fn apply_settings(transform: &IMFTransform, settings: &VideoEncoderSettings) -> Result<()> {
+    let values = settings.codec_values();
+    if values.is_empty() {
+        return Ok(());
+    }
+    let codec_api: ICodecAPI = transform.cast()?;
+    for (property, value) in values {
+        let api = match property {
+            CodecProperty::RateControlMode => CODECAPI_AVEncCommonRateControlMode,
+            CodecProperty::MeanBitRate => CODECAPI_AVEncCommonMeanBitRate,
+            CodecProperty::Quality => CODECAPI_AVEncCommonQuality,
+            CodecProperty::Qp => CODECAPI_AVEncVideoEncodeQP,
+            CodecProperty::GopSize => CODECAPI_AVEncMPVGOPSize,
+            CodecProperty::BFrames => CODECAPI_AVEncMPVDefaultBPictureCount,
+            CodecProperty::LowLatency => CODECAPI_AVLowLatencyMode,
         };
+        // Most are a VT_UI4, but the QP is a VT_UI8 and low latency a VT_BOOL
+        let value = match property {
+            CodecProperty::Qp => VARIANT::from(value as u64),
+            CodecProperty::LowLatency => VARIANT::from(value != 0),
+            _ => VARIANT::from(value),
+        };
+        unsafe { codec_api.SetValue(&api, &value) }.map_err(|error| {
+            Error::new(
+                error.code(),
+                format!("The encoder couldn't set the {}.", property),
+            )
+        })?;
+    }
+    Ok(())
+}
 
-        let output_sample = VideoEncoderOutputSample { sample };
-        self.sample_rendered_callback.as_mut().unwrap()(output_sample)?;
-        Ok(())
+/// The Media Foundation subtype for an encoded video codec.
+pub fn video_subtype(codec: VideoCodec) -> Result<GUID> {
+    match codec {
+        VideoCodec::H264 => Ok(MFVideoFormat_H264),
+        VideoCodec::Hevc => Ok(MFVideoFormat_HEVC),
+        VideoCodec::Av1 => Ok(MFVideoFormat_AV1),
+        VideoCodec::Raw => Err(Error::new(
+            E_INVALIDARG,
+            "Raw video has no encoded subtype.",
+        )),
     }
 }
+
+/// Creates the media type the encoder produces, which is also what the
+/// sink writer expects to be given.
+pub fn create_video_output_type(
+    codec: VideoCodec,
+    resolution: SizeInt32,
+    bit_rate: u32,
+    frame_rate: u32,
+) -> Result<IMFMediaType> {
+    unsafe {
+        let output_type = MFCreateMediaType()?;
+        let attributes: IMFAttributes = output_type.cast()?;
+        output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+        output_type.SetGUID(&MF_MT_SUBTYPE, &video_subtype(codec)?)?;
+        output_type.SetUINT32(&MF_MT_AVG_BITRATE, bit_rate)?;
+        MFSetAttributeSize(
+            &attributes,
+            &MF_MT_FRAME_SIZE,
+            resolution.Width as u32,
+            resolution.Height as u32,
+        )?;
+        MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, frame_rate, 1)?;
+        MFSetAttributeRatio(&attributes, &MF_MT_PIXEL_ASPECT_RATIO, 1, 1)?;
+        output_type.SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
+        output_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
+        Ok(output_type)
+    }
+}
+
+// Workaround for:
+//    warning: constant in pattern `METransformNeedInput` should have an upper case name
+//       --> src\video\encoder.rs:XXX:YY
+//        |
+//    XXX |                     METransformNeedInput => {
+//        |                     ^^^^^^^^^^^^^^^^^^^^ help: convert the identifier to upper case: `METRANSFORM_NEED_INPUT`
+//        |
+//        = note: `#[warn(non_upper_case_globals)]` on by default
+const MEDIA_ENGINE_TRANFORM_NEED_INPUT: MF_EVENT_TYPE = METransformNeedInput;
+const MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT: MF_EVENT_TYPE = METransformHaveOutput;
+const MEDIA_ENGINE_TRANFORM_DRAIN_COMPLETE: MF_EVENT_TYPE = METransformDrainComplete;
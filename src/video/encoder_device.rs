@@ -1,25 +1,35 @@
 use windows::{
-    core::{Interface, Result},
-    Win32::Media::MediaFoundation::{
-        IMFActivate, IMFTransform, MFMediaType_Video, MFT_FRIENDLY_NAME_Attribute,
-        MFVideoFormat_H264, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG_HARDWARE,
-        MFT_ENUM_FLAG_SORTANDFILTER, MFT_ENUM_FLAG_TRANSCODE_ONLY, MFT_REGISTER_TYPE_INFO,
+    core::{Error, Interface, Result},
+    Win32::{
+        Foundation::E_NOTIMPL,
+        Media::MediaFoundation::{
+            IMFActivate, IMFTransform, MFMediaType_Video, MFT_FRIENDLY_NAME_Attribute,
+            MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG_HARDWARE, MFT_ENUM_FLAG_SORTANDFILTER,
+            MFT_ENUM_FLAG_TRANSCODE_ONLY, MFT_REGISTER_TYPE_INFO,
+        },
     },
 };
 
-use crate::media::{enumerate_mfts, get_string_attribute};
+use crate::{
+    media::{enumerate_mfts, get_string_attribute},
+    pipeline::VideoCodec,
+};
+
+use super::encoder::video_subtype;
 
 #[derive(Clone)]
 pub struct VideoEncoderDevice {
-    source: IMFActivate,
+    // None for the software encoder in this crate
+    source: Option<IMFActivate>,
     display_name: String,
 }
 
 impl VideoEncoderDevice {
-    pub fn enumerate() -> Result<Vec<VideoEncoderDevice>> {
+    /// Lists the hardware encoders that produce the given codec.
+    pub fn enumerate(codec: VideoCodec) -> Result<Vec<VideoEncoderDevice>> {
         let output_info = MFT_REGISTER_TYPE_INFO {
             guidMajorType: MFMediaType_Video,
-            guidSubtype: MFVideoFormat_H264,
+            guidSubtype: video_subtype(codec)?,
         };
         let encoders = enumerate_mfts(
             &MFT_CATEGORY_VIDEO_ENCODER,
@@ -37,7 +47,7 @@ impl VideoEncoderDevice {
                 "Unknown".to_owned()
             };
             let encoder_device = VideoEncoderDevice {
-                source: encoder,
+                source: Some(encoder),
                 display_name,
             };
             encoder_devices.push(encoder_device);
@@ -45,11 +55,29 @@ impl VideoEncoderDevice {
         Ok(encoder_devices)
     }
 
+    /// The encoder to fall back on when there are no hardware ones.
+    pub fn software() -> Self {
+        VideoEncoderDevice {
+            source: None,
+            display_name: "Software H264 Encoder (builtin)".to_owned(),
+        }
+    }
+
+    pub fn is_software(&self) -> bool {
+        self.source.is_none()
+    }
+
     pub fn display_name(&self) -> &str {
         &self.display_name
     }
 
     pub fn create_transform(&self) -> Result<IMFTransform> {
-        unsafe { self.source.ActivateObject() }
+        match &self.source {
+            Some(source) => unsafe { source.ActivateObject() },
+            None => Err(Error::new(
+                E_NOTIMPL,
+                "The software encoder isn't a Media Foundation transform.",
+            )),
+        }
     }
 }
@@ -1,41 +1,51 @@
-use std::{sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use windows::{
-    core::{Result, HSTRING},
+    core::{Error, Result},
     Foundation::TimeSpan,
     Graphics::SizeInt32,
-    Storage::Streams::IRandomAccessStream,
     Win32::{
+        Foundation::E_INVALIDARG,
         Graphics::{
             Direct3D11::{
-                ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D, 
-                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX, 
-                D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT
+                ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D,
+                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX,
+                D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
             },
             Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC},
             Gdi::HMONITOR,
         },
-        Media::MediaFoundation::{
-            IMFMediaType, IMFSample, IMFSinkWriter, MFCreateAttributes, 
-            MFCreateMFByteStreamOnStreamEx, MFCreateSinkWriterFromURL
-        },
-        System::Performance::QueryPerformanceFrequency
     },
 };
 
-use crate::{encoding_session::SampleWriter, video::capture::{AcquiredFrame, CaptureFrameGenerator, CustomGraphicsCaptureSession}};
+use crate::{
+    clock::{PausableClock, SharedClock},
+    displays::get_display_handle_from_region,
+    encoder_settings::VideoEncoderSettings,
+    pacer::{FramePacer, Pacing},
+    pipeline::{self, FrameSource, SharedSink, StreamSession, Timestamped},
+    privacy::{Card, FocusTimeline, Privacy, PrivacyQueue},
+    region::{Crop, Region},
+    resolution::{Placement, Rect, Resolution, ScaleMode, Size},
+    video::{
+        capture::{AcquiredFrame, CaptureFrameGenerator},
+        window::{foreground_window_bounds, DesktopWindows},
+    },
+    window_target::{Tracked, WhenMinimized, WindowCapture, WindowTracker},
+};
 
 use super::{
-    encoder::{VideoEncoder, VideoEncoderInputSample},
+    encoder::{SoftwareVideoEncoder, VideoEncoder, VideoEncoderInputSample},
     encoder_device::VideoEncoderDevice,
     processor::VideoProcessor,
 };
 
-pub struct VideoEncodingSession {
-    video_encoder: VideoEncoder,
-    capture_session: CustomGraphicsCaptureSession,
-}
-
 struct SampleGenerator {
     d3d_device: ID3D11Device,
     d3d_context: ID3D11DeviceContext,
@@ -44,141 +54,171 @@ struct SampleGenerator {
     compose_texture: ID3D11Texture2D,
     render_target_view: ID3D11RenderTargetView,
 
+    clock: SharedClock,
     frame_generator: CaptureFrameGenerator,
-
-    seen_first_time_stamp: bool,
-    first_timestamp: TimeSpan,
-
-    frame_period: i64,
-    next_frame_time: TimeSpan,
+    monitor_handle: HMONITOR,
+    output_size: SizeInt32,
+
+    crop: Option<Crop>,
+    scale_mode: ScaleMode,
+    // The part of the display being recorded
+    region: Rect,
+
+    // The window being recorded, if it was picked by title, process or
+    // handle rather than being whatever is in the foreground
+    tracker: Option<WindowTracker>,
+    when_minimized: WhenMinimized,
+    recording_clock: PausableClock,
+    // Whether the window is out of sight, so frames are left black
+    hidden: bool,
+    // Cleared while the foreground window isn't one to record
+    recording_window: Arc<AtomicBool>,
+    // Whether the recording was paused because of that
+    paused_for_window: bool,
+    // A display the window went to that couldn't be captured
+    unreachable_display: Option<HMONITOR>,
+
+    // Keeps private windows out of the recording
+    private_frames: Option<PrivateFrames>,
+
+    pacer: FramePacer,
+    // Samples that are ready, when pacing produced more than one at once,
+    // with when the frame they show was captured
+    pending_samples: VecDeque<(i64, VideoEncoderInputSample)>,
+    // The last sample handed out, for repeating
+    last_sample: Option<(i64, VideoEncoderInputSample)>,
+    // Whether to report how pacing went
+    verbose: bool,
 }
 
-impl VideoEncodingSession {
-    pub fn new(
-        d3d_device: ID3D11Device,
-        monitor_handle: HMONITOR,
-        encoder_device: &VideoEncoderDevice,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        sample_writer: Arc<Mutex<SampleWriter>>,
-    ) -> Result<Self> {
-        let input_size = ensure_even_size(resolution);
-        let output_size = ensure_even_size(resolution);
-
-        let mut video_encoder = VideoEncoder::new(
-            encoder_device,
-            d3d_device.clone(),
-            output_size,
-            output_size,
-            bit_rate,
-            frame_rate,
-        )?;
-        let output_type = video_encoder.output_type().clone();
-
-        let mut sample_generator = SampleGenerator::new(
-            d3d_device, 
-            monitor_handle,
-            input_size, 
-            output_size,
-            frame_rate,
-        )?;
-        let capture_session = sample_generator.capture_session().clone();
-        video_encoder.set_sample_requested_callback(
-            move || -> Result<Option<VideoEncoderInputSample>> { sample_generator.generate() },
-        );
-
-        // set output type
-        sample_writer.lock().unwrap().add_video_stream(&output_type);
-        video_encoder.set_sample_rendered_callback({
-            let sample_writer = sample_writer.clone();
-            move |mut output_sample| -> Result<()> {
-                // Write the sample and remove its buffers
-                {
-                    let writer = sample_writer.lock().unwrap();
-                    writer.write_video_sample(output_sample.sample())?;
-                }
-                // Explicitly drop the sample to force COM Release
-                drop(output_sample);
-                Ok(())
-            }
-        });
-
-        Ok(Self {
-            video_encoder,
-            capture_session,
-        })
-    }
-
-    pub fn start(&mut self, start_qpc: i64) -> Result<()> {
-        self.capture_session.StartCapture(start_qpc)?;
-        assert!(self.video_encoder.try_start()?);
-        Ok(())
-    }
-
-    pub fn stop(&mut self) -> Result<()> {
-        self.video_encoder.stop()?;
-        Ok(())
+/// Creates a session that captures the given monitor and encodes it to H264.
+/// When recording a window, `monitor_handle` is the display it starts out
+/// on, and `recording_clock` is paused while it's out of sight if asked to.
+/// Frames are left black while `recording_window` is cleared, and replaced
+/// with the card `privacy` asks for while a private window is in the
+/// foreground. The software encoder device reads frames back from the GPU to
+/// encode them.
+pub fn new_video_session(
+    clock: SharedClock,
+    recording_clock: PausableClock,
+    d3d_device: ID3D11Device,
+    monitor_handle: HMONITOR,
+    encoder_device: &VideoEncoderDevice,
+    crop: Option<Crop>,
+    window: Option<WindowCapture>,
+    recording_window: Arc<AtomicBool>,
+    privacy: Option<Privacy>,
+    resolution: Resolution,
+    scale_mode: ScaleMode,
+    settings: &VideoEncoderSettings,
+    frame_rate: u32,
+    pacing: Pacing,
+    verbose: bool,
+    sink: SharedSink,
+) -> Result<StreamSession> {
+    let sample_generator = SampleGenerator::new(
+        clock,
+        recording_clock,
+        d3d_device.clone(),
+        monitor_handle,
+        crop,
+        window,
+        recording_window,
+        privacy,
+        resolution,
+        scale_mode,
+        frame_rate,
+        pacing,
+        verbose,
+    )?;
+
+    let output_size = sample_generator.output_size;
+    if encoder_device.is_software() {
+        let video_encoder =
+            SoftwareVideoEncoder::new(d3d_device, output_size, settings, frame_rate)?;
+        return Ok(StreamSession::video(sample_generator, video_encoder, sink)?);
     }
+    let video_encoder = VideoEncoder::new(
+        encoder_device,
+        d3d_device,
+        output_size,
+        output_size,
+        settings,
+        frame_rate,
+    )?;
+
+    Ok(StreamSession::video(sample_generator, video_encoder, sink)?)
 }
 
 unsafe impl Send for SampleGenerator {}
 impl SampleGenerator {
     pub fn new(
+        clock: SharedClock,
+        recording_clock: PausableClock,
         d3d_device: ID3D11Device,
         monitor_handle: HMONITOR,
-        input_size: SizeInt32,
-        output_size: SizeInt32,
+        crop: Option<Crop>,
+        window: Option<WindowCapture>,
+        recording_window: Arc<AtomicBool>,
+        privacy: Option<Privacy>,
+        resolution: Resolution,
+        scale_mode: ScaleMode,
         frame_rate: u32,
+        pacing: Pacing,
+        verbose: bool,
     ) -> Result<Self> {
         let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
 
+        // Create frame generator
+        let frame_generator =
+            CaptureFrameGenerator::new(clock.clone(), d3d_device.clone(), monitor_handle)?;
+
+        // Native is whatever size the cropped region is. A window can
+        // change size while it's recorded, so that starts off at the size
+        // of the display, whichever way the window was picked.
+        let display_size = frame_generator.size();
+        let region = match crop {
+            Some(Crop::Region(region)) => {
+                let Some(clamped) = region.clamp(display_size) else {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        "The crop region isn't on the display.",
+                    ));
+                };
+                if clamped.size() != Size::new(region.width, region.height) {
+                    println!(
+                        "Cropping to the part of the region that's on the display: {:?}",
+                        clamped
+                    );
+                }
+                clamped
+            }
+            Some(Crop::Window) | None => Rect::of(display_size),
+        };
+        let output = resolution.resolve(region.size());
+        let placement = Placement::new(region.size(), output, scale_mode);
+        let input_size = SizeInt32::from(display_size);
+        let output_size = SizeInt32::from(output);
+
         let video_processor = VideoProcessor::new(
             d3d_device.clone(),
             DXGI_FORMAT_B8G8R8A8_UNORM,
             input_size,
             DXGI_FORMAT_NV12,
             output_size,
+            placement,
         )?;
 
-        let texture_desc = D3D11_TEXTURE2D_DESC {
-            Width: input_size.Width as u32,
-            Height: input_size.Height as u32,
-            ArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                ..Default::default()
-            },
-            Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
-            ..Default::default()
+        let (compose_texture, render_target_view) =
+            create_compose_texture(&d3d_device, input_size)?;
+        let when_minimized = window
+            .as_ref()
+            .map_or(WhenMinimized::Blank, |window| window.when_minimized);
+        let private_frames = match privacy {
+            Some(privacy) => Some(PrivateFrames::new(&d3d_device, privacy, output_size)?),
+            None => None,
         };
 
-        let mut qpc_frequency: i64 = 0;
-        unsafe {
-            QueryPerformanceFrequency(&mut qpc_frequency)?;
-        }
-        
-        // Calculate frame period in QPC units (performance counter ticks)
-        let frame_period = qpc_frequency / (frame_rate as i64);
-        
-        let compose_texture = unsafe {
-            let mut texture = None;
-            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
-            texture.unwrap()
-        };
-        
-        let render_target_view = unsafe {
-            let mut rtv = None;
-            d3d_device.CreateRenderTargetView(&compose_texture, None, Some(&mut rtv))?;
-            rtv.unwrap()
-        };
-
-        // Create frame generator
-        let frame_generator = CaptureFrameGenerator::new(d3d_device.clone(), monitor_handle)?;
-
         Ok(Self {
             d3d_device,
             d3d_context,
@@ -187,143 +227,513 @@ impl SampleGenerator {
             compose_texture,
             render_target_view,
 
+            clock,
             frame_generator,
+            monitor_handle,
+            output_size,
+
+            crop,
+            scale_mode,
+            region,
+
+            tracker: window.map(|window| WindowTracker::new(window.target)),
+            when_minimized,
+            recording_clock,
+            hidden: false,
+            recording_window,
+            paused_for_window: false,
+            unreachable_display: None,
 
-            seen_first_time_stamp: false,
-            first_timestamp: TimeSpan::default(),
+            private_frames,
 
-            frame_period,
-            next_frame_time: TimeSpan::default(),
+            pacer: FramePacer::new(pacing, frame_rate),
+            pending_samples: VecDeque::new(),
+            last_sample: None,
+            verbose,
         })
     }
 
-    pub fn capture_session(&self) -> &CustomGraphicsCaptureSession {
-        self.frame_generator.session()
+    pub fn generate(&mut self) -> Result<Option<VideoEncoderInputSample>> {
+        if self.private_frames.is_none() {
+            return Ok(self.next_sample()?.map(|(_, sample)| sample));
+        }
+        // Hold samples back until it's known whether they showed a private
+        // window. Repeats and paced frames are judged by when what they show
+        // was captured, not when they're shown.
+        let start_time = self.frame_generator.get_start_time();
+        while let Some((capture_time, sample)) = self.next_sample()? {
+            if let Some(private_frames) = &mut self.private_frames {
+                private_frames.hold(start_time + capture_time, sample);
+            }
+        }
+        let now = self.clock.now();
+        match &mut self.private_frames {
+            Some(private_frames) => private_frames.release(now),
+            None => Ok(None),
+        }
     }
 
-    pub fn generate(&mut self) -> Result<Option<VideoEncoderInputSample>> {
+    fn next_sample(&mut self) -> Result<Option<(i64, VideoEncoderInputSample)>> {
+        if let Some(sample) = self.pending_samples.pop_front() {
+            return Ok(Some(sample));
+        }
+        if self.tracker.is_some() {
+            self.follow_target()?;
+        }
+
         while let Some(frame) = self.frame_generator.try_get_next_frame()? {
-            // Initialize timing on first frame
-            if !self.seen_first_time_stamp {
-                self.first_timestamp = frame.present_time;
-                self.seen_first_time_stamp = true;
-                self.next_frame_time = TimeSpan {
-                    Duration: self.first_timestamp.Duration + self.frame_period,
-                };
-                
-                return self.generate_from_frame(&frame).map(Some);
-            }
-            
-            // Calculate timing relative to first frame
-            let relative_time = frame.present_time.Duration - self.first_timestamp.Duration;
-            let expected_time = self.next_frame_time.Duration - self.first_timestamp.Duration;
-            
-            // Check if this frame meets our timing requirements
-            if relative_time >= expected_time {
-
-                // Update next expected frame time
-                self.next_frame_time.Duration += self.frame_period;
-                
-                return self.generate_from_frame(&frame).map(Some);
+            // Pace on the recording timeline, so the frame grid carries on
+            // where it left off after a pause
+            let capture_time = frame.present_time.Duration;
+            let Some(recording_time) = self.recording_clock.recording_time(capture_time) else {
+                continue;
+            };
+            let paced = self.pacer.pace(recording_time);
+            let Some(timestamp) = paced.timestamp else {
+                // Too soon after the last frame, skip it and continue loop
+                continue;
+            };
+
+            // Fill any slots nothing new arrived for with the last frame
+            if let Some((capture_time, last_sample)) = &self.last_sample {
+                for repeat_time in paced.repeats {
+                    let mut repeat = last_sample.clone();
+                    repeat.set_timestamp(repeat_time);
+                    self.pending_samples.push_back((*capture_time, repeat));
+                }
             }
-            
-            // Frame is too early, skip it and continue loop
+
+            let sample = self.generate_from_frame(&frame, timestamp)?;
+            self.last_sample = Some((capture_time, sample.clone()));
+            self.pending_samples.push_back((capture_time, sample));
+            return Ok(self.pending_samples.pop_front());
         }
-        
-        // No more frames, end capture
-        self.stop_capture()?;
+
+        // Nothing new yet
         Ok(None)
     }
 
-    fn stop_capture(&mut self) -> Result<()> {
-        self.frame_generator.stop_capture()
+    /// Moves the region to wherever the foreground window is now. If it
+    /// isn't on the display, the region stays where it was.
+    fn follow_window(&mut self) {
+        if let Some(bounds) = foreground_window_bounds() {
+            self.move_region(bounds);
+        }
+    }
+
+    /// Keeps up with the window being recorded, moving the region along
+    /// with it and switching displays when it moves to another one. While
+    /// it's minimized or closed, frames are left black or the recording is
+    /// paused.
+    fn follow_target(&mut self) -> Result<()> {
+        let Some(tracker) = &mut self.tracker else {
+            return Ok(());
+        };
+        let previous = tracker.window().map(|window| window.handle);
+        let tracked = tracker.update(&DesktopWindows, self.clock.now());
+        if let Some(window) = tracker.window() {
+            if Some(window.handle) != previous {
+                println!(
+                    "Recording window \"{}\" ({}).",
+                    window.title, window.process
+                );
+            }
+        } else if previous.is_some() {
+            println!("The window was closed, waiting for another one...");
+        }
+
+        let Tracked::Shown(bounds) = tracked else {
+            self.set_hidden(true);
+            return Ok(());
+        };
+        self.set_hidden(false);
+        let monitor_handle = get_display_handle_from_region(bounds);
+        if monitor_handle != self.monitor_handle && Some(monitor_handle) != self.unreachable_display
+        {
+            self.switch_display(monitor_handle)?;
+        }
+        self.move_region(bounds);
+        Ok(())
+    }
+
+    fn set_hidden(&mut self, hidden: bool) {
+        if hidden == self.hidden {
+            return;
+        }
+        self.hidden = hidden;
+        if self.when_minimized != WhenMinimized::Pause {
+            return;
+        }
+        // Leave pauses the user made alone
+        if hidden && !self.recording_clock.is_paused() {
+            self.paused_for_window = self.recording_clock.pause().is_ok();
+            println!("Paused recording while the window is out of sight.");
+        } else if !hidden && self.paused_for_window {
+            self.paused_for_window = false;
+            if self.recording_clock.is_paused() && self.recording_clock.resume().is_ok() {
+                println!("Resumed recording.");
+            }
+        }
+    }
+
+    /// Starts capturing another display, with timestamps carrying on from
+    /// the one before. If it can't be captured, the old one is kept.
+    fn switch_display(&mut self, monitor_handle: HMONITOR) -> Result<()> {
+        let frame_generator =
+            CaptureFrameGenerator::new(self.clock.clone(), self.d3d_device.clone(), monitor_handle);
+        let mut frame_generator = match frame_generator {
+            Ok(frame_generator) => frame_generator,
+            Err(error) => {
+                // Most likely a display on another graphics adapter
+                println!(
+                    "Can't follow the window onto that display: {}",
+                    error.message()
+                );
+                self.unreachable_display = Some(monitor_handle);
+                return Ok(());
+            }
+        };
+        frame_generator.start_capture(self.frame_generator.get_start_time())?;
+        self.frame_generator.stop_capture()?;
+        self.frame_generator = frame_generator;
+        self.monitor_handle = monitor_handle;
+
+        // The new display can be another size
+        let display_size = self.frame_generator.size();
+        let input_size = SizeInt32::from(display_size);
+        self.region = Rect::of(display_size);
+        self.video_processor = VideoProcessor::new(
+            self.d3d_device.clone(),
+            DXGI_FORMAT_B8G8R8A8_UNORM,
+            input_size,
+            DXGI_FORMAT_NV12,
+            self.output_size,
+            Placement::new(display_size, self.output(), self.scale_mode),
+        )?;
+        (self.compose_texture, self.render_target_view) =
+            create_compose_texture(&self.d3d_device, input_size)?;
+        Ok(())
+    }
+
+    /// Moves the region to a window's bounds on the desktop. If the window
+    /// isn't on the display, the region stays where it was.
+    fn move_region(&mut self, bounds: Region) {
+        let display = bounds.relative_to(self.frame_generator.origin());
+        let Some(region) = display.clamp(self.frame_generator.size()) else {
+            return;
+        };
+        if region != self.region {
+            self.region = region;
+            self.video_processor.set_placement(Placement::new(
+                region.size(),
+                self.output(),
+                self.scale_mode,
+            ));
+        }
+    }
+
+    fn output(&self) -> Size {
+        Size::new(
+            self.output_size.Width as u32,
+            self.output_size.Height as u32,
+        )
     }
-    
+
     fn generate_from_frame(
         &mut self,
         frame: &AcquiredFrame,
+        timestamp: i64,
     ) -> Result<VideoEncoderInputSample> {
         let frame_texture = &frame.texture;
-        let frame_time = frame.present_time;
-
-        if !self.seen_first_time_stamp {
-            self.first_timestamp = frame_time;
-            self.seen_first_time_stamp = true;
-        }
-
         let timestamp = TimeSpan {
-            Duration: frame_time.Duration - self.first_timestamp.Duration,
+            Duration: timestamp,
         };
-    
+
         // Determine region to copy
         let desc = unsafe {
             let mut desc = D3D11_TEXTURE2D_DESC::default();
             frame_texture.GetDesc(&mut desc);
             desc
         };
+        if self.crop == Some(Crop::Window) {
+            self.follow_window();
+        }
+
+        // Only copy the part being recorded, to the corner of the
+        // composition texture. The display can change size under us, so
+        // never copy past the edge.
         let region = D3D11_BOX {
-            left: 0, right: desc.Width, top: 0, bottom: desc.Height, back: 1, front: 0,
+            left: self.region.x.min(desc.Width),
+            right: self.region.right().min(desc.Width),
+            top: self.region.y.min(desc.Height),
+            bottom: self.region.bottom().min(desc.Height),
+            back: 1,
+            front: 0,
         };
-    
+
         // GPU Processing
         unsafe {
             // Clear render target
-            self.d3d_context.ClearRenderTargetView(&self.render_target_view, &CLEAR_COLOR);
-    
-            // Copy the captured frame to composition texture
-            self.d3d_context.CopySubresourceRegion(
-                &self.compose_texture,
-                0, 0, 0, 0,
-                frame_texture,
-                0, Some(&region),
-            );
-    
+            self.d3d_context
+                .ClearRenderTargetView(&self.render_target_view, &CLEAR_COLOR);
+
+            // Copy the captured frame to composition texture, unless the
+            // window being recorded is out of sight
+            if !self.hidden && self.recording_window.load(Ordering::SeqCst) {
+                self.d3d_context.CopySubresourceRegion(
+                    &self.compose_texture,
+                    0,
+                    0,
+                    0,
+                    0,
+                    frame_texture,
+                    0,
+                    Some(&region),
+                );
+            }
+
             // Process BGRA -> NV12
-            // Fix: Call the function directly and use ? afterward
-            self.video_processor.process_texture(&self.compose_texture)?;
-    
-            // Get the resulting NV12 texture
+            self.video_processor
+                .process_texture(&self.compose_texture)?;
             let video_output_texture = self.video_processor.output_texture();
-    
+
             // Create a new texture for the sample
-            let sample_texture = {
-                 let output_desc = {
-                     let mut desc = D3D11_TEXTURE2D_DESC::default();
-                     video_output_texture.GetDesc(&mut desc);
-                     desc
-                 };
-                 
-                 // Fix: Call the function directly and use ? afterward
-                 let mut texture = None;
-                 self.d3d_device.CreateTexture2D(&output_desc, None, Some(&mut texture))?;
-                 texture.unwrap()
-            };
-            
-            // Copy the processed texture to the sample texture
-            self.d3d_context.CopyResource(&sample_texture, video_output_texture);
-    
+            let sample_texture =
+                copy_texture(&self.d3d_device, &self.d3d_context, video_output_texture)?;
+
             // Create and return the input sample
-            Ok(VideoEncoderInputSample::new(
-                timestamp,
-                sample_texture,
-            ))
+            Ok(VideoEncoderInputSample::new(timestamp, sample_texture))
         }
     }
 }
 
-const CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+impl FrameSource for SampleGenerator {
+    type Frame = VideoEncoderInputSample;
+
+    fn start(&mut self, start_time: i64) -> pipeline::Result<()> {
+        self.frame_generator.start_capture(start_time)?;
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> pipeline::Result<Option<VideoEncoderInputSample>> {
+        Ok(self.generate()?)
+    }
+
+    fn stop(&mut self) -> pipeline::Result<()> {
+        self.frame_generator.stop_capture()?;
+        if self.verbose {
+            println!("Video pacing: {}", self.pacer.stats());
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> pipeline::Result<Vec<VideoEncoderInputSample>> {
+        match &mut self.private_frames {
+            Some(private_frames) => Ok(private_frames.drain()?),
+            None => Ok(Vec::new()),
+        }
+    }
 
-fn ensure_even(value: i32) -> i32 {
-    if value % 2 == 0 {
-        value
-    } else {
-        value + 1
+    fn on_recording_timeline(&self) -> bool {
+        true
     }
 }
 
-fn ensure_even_size(size: SizeInt32) -> SizeInt32 {
-    SizeInt32 {
-        Width: ensure_even(size.Width),
-        Height: ensure_even(size.Height),
+/// Replaces frames captured while a private window was in the foreground.
+/// Frames are held back for a moment, as focus changes are reported after
+/// they happen.
+struct PrivateFrames {
+    d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    timeline: Arc<FocusTimeline>,
+    // Samples by when they were captured, on the system clock
+    queue: PrivacyQueue<VideoEncoderInputSample>,
+    card: PrivateCard,
+}
+
+enum PrivateCard {
+    Color(ID3D11Texture2D),
+    // Scales frames down until nothing can be read, and back up
+    Blur {
+        down: VideoProcessor,
+        up: VideoProcessor,
+    },
+}
+
+impl PrivateFrames {
+    fn new(d3d_device: &ID3D11Device, privacy: Privacy, output_size: SizeInt32) -> Result<Self> {
+        let card = match privacy.card {
+            Card::Color(color) => PrivateCard::Color(create_card(d3d_device, color, output_size)?),
+            Card::Blur => {
+                let output = Size::new(output_size.Width as u32, output_size.Height as u32);
+                let small = Size::new(
+                    (output.width / BLUR_SCALE).max(2),
+                    (output.height / BLUR_SCALE).max(2),
+                );
+                PrivateCard::Blur {
+                    down: VideoProcessor::new(
+                        d3d_device.clone(),
+                        DXGI_FORMAT_NV12,
+                        output_size,
+                        DXGI_FORMAT_B8G8R8A8_UNORM,
+                        SizeInt32::from(small),
+                        Placement {
+                            source: Rect::of(output),
+                            dest: Rect::of(small),
+                        },
+                    )?,
+                    up: VideoProcessor::new(
+                        d3d_device.clone(),
+                        DXGI_FORMAT_B8G8R8A8_UNORM,
+                        SizeInt32::from(small),
+                        DXGI_FORMAT_NV12,
+                        output_size,
+                        Placement {
+                            source: Rect::of(small),
+                            dest: Rect::of(output),
+                        },
+                    )?,
+                }
+            }
+        };
+        Ok(Self {
+            d3d_device: d3d_device.clone(),
+            d3d_context: unsafe { d3d_device.GetImmediateContext()? },
+            timeline: privacy.timeline,
+            queue: PrivacyQueue::new(),
+            card,
+        })
+    }
+
+    /// Queues a sample by when the frame it shows was captured, on the
+    /// system clock.
+    fn hold(&mut self, capture_time: i64, sample: VideoEncoderInputSample) {
+        self.queue.push(capture_time, sample);
+    }
+
+    fn release(&mut self, now: i64) -> Result<Option<VideoEncoderInputSample>> {
+        match self.queue.pop(&self.timeline, now) {
+            Some((sample, private)) => Ok(Some(self.hide(sample, private)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn drain(&mut self) -> Result<Vec<VideoEncoderInputSample>> {
+        let samples = self.queue.drain(&self.timeline);
+        samples
+            .into_iter()
+            .map(|(sample, private)| self.hide(sample, private))
+            .collect()
+    }
+
+    fn hide(
+        &mut self,
+        sample: VideoEncoderInputSample,
+        private: bool,
+    ) -> Result<VideoEncoderInputSample> {
+        if !private {
+            return Ok(sample);
+        }
+        let texture = match &mut self.card {
+            PrivateCard::Color(card) => card.clone(),
+            PrivateCard::Blur { down, up } => {
+                down.process_texture(sample.texture())?;
+                up.process_texture(down.output_texture())?;
+                copy_texture(&self.d3d_device, &self.d3d_context, up.output_texture())?
+            }
+        };
+        let timestamp = TimeSpan {
+            Duration: sample.timestamp(),
+        };
+        Ok(VideoEncoderInputSample::new(timestamp, texture))
+    }
+}
+
+// How much smaller than the output frames are scaled to blur them
+const BLUR_SCALE: u32 = 32;
+
+/// Creates an NV12 frame of the output size, filled with a color.
+fn create_card(
+    d3d_device: &ID3D11Device,
+    color: [u8; 3],
+    output_size: SizeInt32,
+) -> Result<ID3D11Texture2D> {
+    let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+    let size = Size::new(16, 16);
+    let (texture, render_target_view) = create_compose_texture(d3d_device, SizeInt32::from(size))?;
+    let mut video_processor = VideoProcessor::new(
+        d3d_device.clone(),
+        DXGI_FORMAT_B8G8R8A8_UNORM,
+        SizeInt32::from(size),
+        DXGI_FORMAT_NV12,
+        output_size,
+        Placement {
+            source: Rect::of(size),
+            dest: Rect::of(Size::new(
+                output_size.Width as u32,
+                output_size.Height as u32,
+            )),
+        },
+    )?;
+    let [red, green, blue] = color.map(|channel| channel as f32 / 255.0);
+    unsafe {
+        d3d_context.ClearRenderTargetView(&render_target_view, &[red, green, blue, 1.0]);
     }
-}
\ No newline at end of file
+    video_processor.process_texture(&texture)?;
+    copy_texture(d3d_device, &d3d_context, video_processor.output_texture())
+}
+
+/// Copies a texture to a new one, so it can be held on to while the
+/// original is reused.
+fn copy_texture(
+    d3d_device: &ID3D11Device,
+    d3d_context: &ID3D11DeviceContext,
+    texture: &ID3D11Texture2D,
+) -> Result<ID3D11Texture2D> {
+    unsafe {
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        texture.GetDesc(&mut desc);
+        let mut copy = None;
+        d3d_device.CreateTexture2D(&desc, None, Some(&mut copy))?;
+        let copy = copy.unwrap();
+        d3d_context.CopyResource(&copy, texture);
+        Ok(copy)
+    }
+}
+
+/// Creates the texture frames are composed on before processing, and a view
+/// to clear it with.
+fn create_compose_texture(
+    d3d_device: &ID3D11Device,
+    size: SizeInt32,
+) -> Result<(ID3D11Texture2D, ID3D11RenderTargetView)> {
+    let texture_desc = D3D11_TEXTURE2D_DESC {
+        Width: size.Width as u32,
+        Height: size.Height as u32,
+        ArraySize: 1,
+        MipLevels: 1,
+        Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            ..Default::default()
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
+        ..Default::default()
+    };
+
+    let compose_texture = unsafe {
+        let mut texture = None;
+        d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+        texture.unwrap()
+    };
+
+    let render_target_view = unsafe {
+        let mut rtv = None;
+        d3d_device.CreateRenderTargetView(&compose_texture, None, Some(&mut rtv))?;
+        rtv.unwrap()
+    };
+    Ok((compose_texture, render_target_view))
+}
+
+const CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
@@ -1,5 +1,6 @@
+mod capture;
 pub mod encoder;
 pub mod encoder_device;
 pub mod encoding_session;
-mod capture;
-mod processor;
\ No newline at end of file
+mod processor;
+pub mod window;
@@ -1,6 +1,6 @@
 use windows::{
     core::{Interface, Result},
-    Graphics::{RectInt32, SizeInt32},
+    Graphics::SizeInt32,
     Win32::{
         Foundation::RECT,
         Graphics::{
@@ -17,11 +17,12 @@ use windows::{
                 D3D11_VIDEO_USAGE_OPTIMAL_QUALITY, D3D11_VPIV_DIMENSION_TEXTURE2D,
                 D3D11_VPOV_DIMENSION_TEXTURE2D,
             },
-            Dxgi::Common::{DXGI_FORMAT, DXGI_RATIONAL, DXGI_SAMPLE_DESC},
+            Dxgi::Common::{DXGI_FORMAT, DXGI_FORMAT_NV12, DXGI_RATIONAL, DXGI_SAMPLE_DESC},
         },
     },
 };
-use windows_numerics::Vector2;
+
+use crate::resolution::{Placement, Rect};
 
 pub struct VideoProcessor {
     _d3d_device: ID3D11Device,
@@ -43,6 +44,7 @@ impl VideoProcessor {
         input_size: SizeInt32,
         output_format: DXGI_FORMAT,
         output_size: SizeInt32,
+        placement: Placement,
     ) -> Result<Self> {
         let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
 
@@ -62,8 +64,8 @@ impl VideoProcessor {
                 Numerator: 60,
                 Denominator: 1,
             },
-            OutputWidth: input_size.Width as u32,
-            OutputHeight: input_size.Height as u32,
+            OutputWidth: output_size.Width as u32,
+            OutputHeight: output_size.Height as u32,
             Usage: D3D11_VIDEO_USAGE_OPTIMAL_QUALITY,
         };
         let video_enum = unsafe { video_device.CreateVideoProcessorEnumerator(&video_desc)? };
@@ -74,31 +76,19 @@ impl VideoProcessor {
             _bitfield: 17, // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_16_235
         };
         unsafe { video_context.VideoProcessorSetOutputColorSpace(&video_processor, &color_space) };
-        color_space._bitfield = 33; // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_0_255
+        // NV12 input is what this outputs, which is limited range
+        if input_format != DXGI_FORMAT_NV12 {
+            color_space._bitfield = 33; // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_0_255
+        }
         unsafe {
             video_context.VideoProcessorSetStreamColorSpace(&video_processor, 0, &color_space)
         };
 
-        // If the input and output resolutions don't match, setup the
-        // video processor to preserve the aspect ratio when scaling.
-        if input_size.Width != output_size.Width || input_size.Height != output_size.Height {
-            let dest_rect = compute_dest_rect(&output_size, &input_size);
-            let rect = RECT {
-                left: dest_rect.X,
-                top: dest_rect.Y,
-                right: dest_rect.X + dest_rect.Width,
-                bottom: dest_rect.Y + dest_rect.Height,
-            };
-            unsafe {
-                video_context.VideoProcessorSetStreamDestRect(
-                    &video_processor,
-                    0,
-                    true,
-                    Some(&rect),
-                )
-            };
+        // Only NV12 output goes to the encoder
+        let mut bind_flags = D3D11_BIND_RENDER_TARGET.0;
+        if output_format == DXGI_FORMAT_NV12 {
+            bind_flags |= D3D11_BIND_VIDEO_ENCODER.0;
         }
-
         let mut texture_desc = D3D11_TEXTURE2D_DESC {
             Width: output_size.Width as u32,
             Height: output_size.Height as u32,
@@ -110,7 +100,7 @@ impl VideoProcessor {
                 ..Default::default()
             },
             Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_VIDEO_ENCODER.0) as u32,
+            BindFlags: bind_flags as u32,
             ..Default::default()
         };
         let video_output_texture = unsafe {
@@ -167,7 +157,7 @@ impl VideoProcessor {
             input.unwrap()
         };
 
-        Ok(Self {
+        let mut processor = Self {
             _d3d_device: d3d_device,
             d3d_context,
 
@@ -178,7 +168,28 @@ impl VideoProcessor {
             video_output,
             video_input_texture,
             video_input,
-        })
+        };
+        processor.set_placement(placement);
+        Ok(processor)
+    }
+
+    /// Changes which part of the input is shown, and where it goes in the
+    /// output. Anything left over is the background color, which is black.
+    pub fn set_placement(&mut self, placement: Placement) {
+        unsafe {
+            self.video_context.VideoProcessorSetStreamSourceRect(
+                &self.video_processor,
+                0,
+                true,
+                Some(&to_rect(placement.source)),
+            );
+            self.video_context.VideoProcessorSetStreamDestRect(
+                &self.video_processor,
+                0,
+                true,
+                Some(&to_rect(placement.dest)),
+            );
+        }
     }
 
     pub fn output_texture(&self) -> &ID3D11Texture2D {
@@ -212,45 +223,11 @@ impl VideoProcessor {
     }
 }
 
-fn compute_scale_factor(output_size: Vector2, input_size: Vector2) -> f32 {
-    let output_ratio = output_size.X / output_size.Y;
-    let input_ratio = input_size.X / input_size.Y;
-
-    let mut scale_factor = output_size.X / input_size.X;
-    if output_ratio > input_ratio {
-        scale_factor = output_size.Y / input_size.Y;
-    }
-
-    scale_factor
-}
-
-fn compute_dest_rect(output_size: &SizeInt32, input_size: &SizeInt32) -> RectInt32 {
-    let scale = compute_scale_factor(
-        Vector2 {
-            X: output_size.Width as f32,
-            Y: output_size.Height as f32,
-        },
-        Vector2 {
-            X: input_size.Width as f32,
-            Y: input_size.Height as f32,
-        },
-    );
-    let new_size = SizeInt32 {
-        Width: (input_size.Width as f32 * scale) as i32,
-        Height: (input_size.Height as f32 * scale) as i32,
-    };
-    let mut offset_x = 0;
-    let mut offset_y = 0;
-    if new_size.Width != output_size.Width {
-        offset_x = (output_size.Width - new_size.Width) / 2;
-    }
-    if new_size.Height != output_size.Height {
-        offset_y = (output_size.Height - new_size.Height) / 2;
-    }
-    RectInt32 {
-        X: offset_x,
-        Y: offset_y,
-        Width: new_size.Width,
-        Height: new_size.Height,
+fn to_rect(rect: Rect) -> RECT {
+    RECT {
+        left: rect.x as i32,
+        top: rect.y as i32,
+        right: rect.right() as i32,
+        bottom: rect.bottom() as i32,
     }
 }
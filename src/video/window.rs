@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use windows::{
+    core::{BOOL, PWSTR},
+    Win32::{
+        Foundation::{CloseHandle, HWND, LPARAM, RECT},
+        Graphics::Dwm::{DwmGetWindowAttribute, DWMWA_CLOAKED, DWMWA_EXTENDED_FRAME_BOUNDS},
+        System::Threading::{
+            OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+            PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+        UI::WindowsAndMessaging::{
+            EnumWindows, GetClassNameW, GetForegroundWindow, GetWindowLongW, GetWindowRect,
+            GetWindowTextW, GetWindowThreadProcessId, IsIconic, IsWindow, IsWindowVisible,
+            GWL_EXSTYLE, WS_EX_TOOLWINDOW,
+        },
+    },
+};
+
+use crate::{
+    region::Region,
+    window_target::{WindowEnumerator, WindowInfo},
+};
+
+/// The bounds of the foreground window on the desktop, or `None` if there
+/// isn't one or it's minimized.
+pub fn foreground_window_bounds() -> Option<Region> {
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.is_invalid() || IsIconic(window).as_bool() {
+            return None;
+        }
+        window_bounds(window)
+    }
+}
+
+fn window_bounds(window: HWND) -> Option<Region> {
+    unsafe {
+        // The frame bounds leave out the invisible resize borders, and
+        // aren't scaled for DPI
+        let mut rect = RECT::default();
+        let frame_bounds = DwmGetWindowAttribute(
+            window,
+            DWMWA_EXTENDED_FRAME_BOUNDS,
+            &mut rect as *mut _ as *mut _,
+            std::mem::size_of::<RECT>() as u32,
+        );
+        if frame_bounds.is_err() {
+            GetWindowRect(window, &mut rect).ok()?;
+        }
+        Some(Region::from_edges(
+            rect.left,
+            rect.top,
+            rect.right,
+            rect.bottom,
+        ))
+    }
+}
+
+/// The top-level windows on the desktop.
+pub struct DesktopWindows;
+
+impl WindowEnumerator for DesktopWindows {
+    fn windows(&self) -> Vec<WindowInfo> {
+        let mut windows: Vec<HWND> = Vec::new();
+        unsafe {
+            let _ = EnumWindows(Some(enum_window), LPARAM(&mut windows as *mut _ as isize));
+        }
+        windows
+            .into_iter()
+            .filter(|&window| is_app_window(window))
+            .filter_map(window_info)
+            .collect()
+    }
+
+    fn window(&self, handle: isize) -> Option<WindowInfo> {
+        let window = HWND(handle as *mut _);
+        if !unsafe { IsWindow(Some(window)) }.as_bool() {
+            return None;
+        }
+        window_info(window)
+    }
+}
+
+extern "system" fn enum_window(window: HWND, state: LPARAM) -> BOOL {
+    unsafe {
+        let windows = &mut *(state.0 as *mut Vec<HWND>);
+        windows.push(window);
+    }
+    true.into()
+}
+
+/// Whether a window shows up on the taskbar, more or less. Leaves out
+/// hidden windows, tool windows, and the windows of suspended apps, which
+/// are visible but cloaked.
+fn is_app_window(window: HWND) -> bool {
+    unsafe {
+        if !IsWindowVisible(window).as_bool() {
+            return false;
+        }
+        if GetWindowLongW(window, GWL_EXSTYLE) as u32 & WS_EX_TOOLWINDOW.0 != 0 {
+            return false;
+        }
+        let mut cloaked = 0u32;
+        let cloaked_result = DwmGetWindowAttribute(
+            window,
+            DWMWA_CLOAKED,
+            &mut cloaked as *mut _ as *mut _,
+            std::mem::size_of::<u32>() as u32,
+        );
+        cloaked_result.is_err() || cloaked == 0
+    }
+}
+
+fn window_info(window: HWND) -> Option<WindowInfo> {
+    Some(WindowInfo {
+        handle: window.0 as isize,
+        title: window_title(window),
+        process: window_process(window).unwrap_or_default(),
+        bounds: window_bounds(window)?,
+        minimized: unsafe { IsIconic(window) }.as_bool(),
+    })
+}
+
+pub fn window_title(window: HWND) -> String {
+    let mut buffer = [0u16; 512];
+    let length = unsafe { GetWindowTextW(window, &mut buffer) };
+    String::from_utf16_lossy(&buffer[..length.max(0) as usize])
+}
+
+pub fn window_class(window: HWND) -> String {
+    let mut buffer = [0u16; 256];
+    let length = unsafe { GetClassNameW(window, &mut buffer) };
+    String::from_utf16_lossy(&buffer[..length.max(0) as usize])
+}
+
+/// The file name of the executable that owns the window.
+pub fn window_process(window: HWND) -> Option<String> {
+    unsafe {
+        let mut process_id = 0;
+        GetWindowThreadProcessId(window, Some(&mut process_id));
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id).ok()?;
+        let mut buffer = [0u16; 1024];
+        let mut length = buffer.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut length,
+        );
+        let _ = CloseHandle(process);
+        result.ok()?;
+        let path = String::from_utf16_lossy(&buffer[..length as usize]);
+        let file_name = Path::new(&path).file_name()?.to_string_lossy().into_owned();
+        Some(file_name)
+    }
+}
@@ -1,76 +1,117 @@
-use windows::Win32::UI::Accessibility::{
-    SetWinEventHook, HWINEVENTHOOK
-};
-use windows::Win32::Foundation::HWND;
+use windows::Win32::Foundation::{HWND, LPARAM, WPARAM};
+use windows::Win32::System::SystemInformation::GetTickCount;
+use windows::Win32::System::Threading::GetCurrentThreadId;
+use windows::Win32::UI::Accessibility::{SetWinEventHook, UnhookWinEvent, HWINEVENTHOOK};
 use windows::Win32::UI::WindowsAndMessaging::{
-    GetWindowTextW, GetWindowTextLengthW, WINEVENT_OUTOFCONTEXT, EVENT_SYSTEM_FOREGROUND
+    GetForegroundWindow, GetMessageW, PostThreadMessageW, EVENT_SYSTEM_FOREGROUND, MSG,
+    WINEVENT_OUTOFCONTEXT, WM_QUIT,
 };
 
-use std::ffi::OsString;
-use std::os::windows::ffi::OsStringExt;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use std::sync::mpsc::channel;
+use std::thread::JoinHandle;
+
+use crate::auto_record::FocusedWindow;
+use crate::clock::{Clock, SystemClock};
+use crate::video::window::{window_class, window_process, window_title};
 
-static mut RECORDING_FLAG: Option<*const Arc<AtomicBool>> = None;
+type FocusCallback = Box<dyn FnMut(FocusedWindow, i64)>;
+
+thread_local! {
+    // The hook can't be handed any context, so the callback and the clock
+    // live with the thread that set it
+    static ON_FOCUS: RefCell<Option<(FocusCallback, SystemClock)>> = RefCell::new(None);
+}
 
-fn should_record_window() -> bool {
-    // Define your logic to determine if the window should be recorded
-    // For example, check if the window title contains a specific keyword
-    return true;
+/// Reports every window that comes to the foreground, along with the time
+/// on the system clock it got there, until it's dropped.
+pub struct WindowChangeDetector {
+    thread_id: u32,
+    thread: Option<JoinHandle<()>>,
 }
 
-pub fn start_window_change_detector(is_recording_window: Arc<AtomicBool>) -> HWINEVENTHOOK {
+/// Starts watching the foreground window on a thread of its own, so focus
+/// changes come through whether or not the caller pumps messages. The
+/// window in the foreground to begin with is reported straight away.
+pub fn start_window_change_detector<F>(on_focus: F) -> WindowChangeDetector
+where
+    F: FnMut(FocusedWindow, i64) + Send + 'static,
+{
+    let (sender, receiver) = channel();
+    let thread = std::thread::spawn(move || {
+        // Can't fail on anything newer than Windows XP
+        let clock = SystemClock::new().unwrap();
+        ON_FOCUS.with(|callback| *callback.borrow_mut() = Some((Box::new(on_focus), clock)));
+
+        // Out of context hooks are called from this thread's message loop
+        let hook = unsafe {
+            SetWinEventHook(
+                EVENT_SYSTEM_FOREGROUND,
+                EVENT_SYSTEM_FOREGROUND,
+                None,
+                Some(foreground_change_hook),
+                0,
+                0,
+                WINEVENT_OUTOFCONTEXT,
+            )
+        };
+        sender.send(unsafe { GetCurrentThreadId() }).unwrap();
+
+        report_focus(unsafe { GetForegroundWindow() }, 0);
+        let mut message = MSG::default();
+        while unsafe { GetMessageW(&mut message, None, 0, 0) }.0 > 0 {}
+        unsafe {
+            let _ = UnhookWinEvent(hook);
+        }
+    });
 
-    // TODO: memory leak?
-    unsafe {
-        let leaked = Box::leak(Box::new(is_recording_window));
-        RECORDING_FLAG = Some(leaked);
+    WindowChangeDetector {
+        thread_id: receiver.recv().unwrap(),
+        thread: Some(thread),
     }
+}
 
-    unsafe extern "system" fn foreground_change_hook(
-        _hook: HWINEVENTHOOK,
-        _event: u32,
-        hwnd: HWND,
-        _id_object: i32,
-        _id_child: i32,
-        _id_event_thread: u32,
-        _event_time: u32,
-    ) {
-        // Get our atomic from the context
-        if let Some(ptr) = RECORDING_FLAG {
-                let is_recording_window = &*ptr;
-            
-            // Get window title
-            let length = GetWindowTextLengthW(hwnd);
-            if length > 0 {
-                let mut buffer = vec![0u16; (length + 1) as usize];
-                if GetWindowTextW(hwnd, &mut buffer) > 0 {
-                    if let Some(pos) = buffer.iter().position(|&c| c == 0) {
-                        buffer.truncate(pos);
-                    }
-                    let window_title = OsString::from_wide(&buffer).to_string_lossy().into_owned();
-                    
-                    // Update the atomic based on window title
-                    let should_record = should_record_window();
-                    is_recording_window.store(should_record, Ordering::SeqCst);
-                    
-                    // Print for debugging
-                    println!("Window changed to: {}, recording: {}", window_title, should_record);
-                }
-            }
+impl Drop for WindowChangeDetector {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
         }
     }
+}
 
-    // TODO: resource leak? I think we need to unhook on recording stop()
-    unsafe {
-        SetWinEventHook(
-            EVENT_SYSTEM_FOREGROUND,
-            EVENT_SYSTEM_FOREGROUND,
-            None,
-            Some(foreground_change_hook),
-            0,
-            0,
-            WINEVENT_OUTOFCONTEXT,
-        )
+unsafe extern "system" fn foreground_change_hook(
+    _hook: HWINEVENTHOOK,
+    _event: u32,
+    hwnd: HWND,
+    _id_object: i32,
+    _id_child: i32,
+    _id_event_thread: u32,
+    event_time: u32,
+) {
+    // The event time is in milliseconds on the tick count, and events can
+    // take a while to get through the message loop
+    let age = GetTickCount().wrapping_sub(event_time);
+    report_focus(hwnd, age as i64 * 10_000);
+}
+
+/// Reports the window that came to the foreground `age` ago, in 100ns
+/// units.
+fn report_focus(hwnd: HWND, age: i64) {
+    if hwnd.is_invalid() {
+        return;
     }
-}
\ No newline at end of file
+    let window = FocusedWindow {
+        title: window_title(hwnd),
+        process: window_process(hwnd).unwrap_or_default(),
+        class: window_class(hwnd),
+    };
+    ON_FOCUS.with(|callback| {
+        if let Some((callback, clock)) = callback.borrow_mut().as_mut() {
+            let time = clock.now() - age;
+            callback(window, time);
+        }
+    });
+}
@@ -0,0 +1,399 @@
+//! Picks the window to record, by title, process or handle, and keeps track
+//! of it while it's recorded. Windows are listed through `WindowEnumerator`,
+//! so the rules work the same against the desktop and a made-up list.
+
+use std::{fmt::Display, str::FromStr};
+
+use regex_lite::{Regex, RegexBuilder};
+
+use crate::region::Region;
+
+/// A top-level window, as far as picking one to record goes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowInfo {
+    pub handle: isize,
+    pub title: String,
+    /// The file name of the executable that owns the window.
+    pub process: String,
+    /// Where the window is on the desktop.
+    pub bounds: Region,
+    pub minimized: bool,
+}
+
+/// Lists the windows that could be recorded.
+pub trait WindowEnumerator {
+    /// Every window that could be recorded, front to back.
+    fn windows(&self) -> Vec<WindowInfo>;
+
+    /// The window with the given handle, or `None` once it has closed. It
+    /// doesn't have to be one `windows` would list.
+    fn window(&self, handle: isize) -> Option<WindowInfo> {
+        self.windows()
+            .into_iter()
+            .find(|window| window.handle == handle)
+    }
+}
+
+/// Which window gets recorded.
+#[derive(Clone, Debug)]
+pub enum WindowTarget {
+    /// The first window whose title matches, ignoring case.
+    Title(Regex),
+    /// The first window of a process, by executable name. The `.exe` can
+    /// be left off.
+    Process(String),
+    /// Exactly this window.
+    Handle(isize),
+}
+
+impl WindowTarget {
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        match self {
+            WindowTarget::Title(pattern) => pattern.is_match(&window.title),
+            WindowTarget::Process(name) => is_process(&window.process, name),
+            WindowTarget::Handle(handle) => window.handle == *handle,
+        }
+    }
+
+    /// Finds the window to record. Of the windows that match, the front
+    /// most one that isn't minimized wins, so a game is picked over its
+    /// launcher sitting in the taskbar.
+    pub fn find(&self, windows: &impl WindowEnumerator) -> Option<WindowInfo> {
+        if let WindowTarget::Handle(handle) = self {
+            return windows.window(*handle);
+        }
+        let mut matches: Vec<_> = windows
+            .windows()
+            .into_iter()
+            .filter(|window| self.matches(window))
+            .collect();
+        // Stable, so front to back order is kept otherwise
+        matches.sort_by_key(|window| window.minimized);
+        matches.into_iter().next()
+    }
+}
+
+impl Display for WindowTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowTarget::Title(pattern) => {
+                write!(f, "a window with a title matching \"{}\"", pattern.as_str())
+            }
+            WindowTarget::Process(name) => write!(f, "a window of \"{}\"", name),
+            WindowTarget::Handle(handle) => write!(f, "window {:#x}", handle),
+        }
+    }
+}
+
+/// Whether `process`, an executable's file name, is the one `name` asks
+/// for. Case doesn't matter, and `name` can leave off the `.exe` or be a
+/// whole path.
+pub fn is_process(process: &str, name: &str) -> bool {
+    let name = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let process = process.to_lowercase();
+    let name = name.to_lowercase();
+    process == name || process.strip_suffix(".exe") == Some(name.as_str())
+}
+
+/// Parses a window title pattern, which ignores case.
+pub fn parse_title_pattern(value: &str) -> Result<Regex, String> {
+    RegexBuilder::new(value)
+        .case_insensitive(true)
+        .build()
+        .map_err(|error| error.to_string())
+}
+
+/// Parses a window handle, in decimal or hex (with a leading `0x`).
+pub fn parse_handle(value: &str) -> Result<isize, String> {
+    let handle = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => isize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    match handle {
+        Ok(handle) if handle != 0 => Ok(handle),
+        _ => Err(format!("`{}` isn't a window handle", value)),
+    }
+}
+
+/// What happens to the recording while the window is minimized or closed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WhenMinimized {
+    /// Keeps recording black frames.
+    Blank,
+    /// Pauses the recording, leaving the time out of it.
+    Pause,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseWhenMinimizedError(&'static str);
+
+impl FromStr for WhenMinimized {
+    type Err = ParseWhenMinimizedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "blank" => Ok(WhenMinimized::Blank),
+            "pause" => Ok(WhenMinimized::Pause),
+            _ => Err(ParseWhenMinimizedError(
+                "Invalid when-minimized value! Expecting: blank, or pause.",
+            )),
+        }
+    }
+}
+
+impl Display for WhenMinimized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhenMinimized::Blank => write!(f, "blank"),
+            WhenMinimized::Pause => write!(f, "pause"),
+        }
+    }
+}
+
+impl Display for ParseWhenMinimizedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseWhenMinimizedError {}
+
+/// Recording a window rather than a whole display.
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct WindowCapture {
+    pub target: WindowTarget,
+    pub when_minimized: WhenMinimized,
+}
+
+/// Where the window being recorded is now.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tracked {
+    Shown(Region),
+    Minimized,
+    /// It has closed, and nothing else matches yet.
+    Lost,
+}
+
+// How often to look for a window to record once the last one has closed
+const SEARCH_INTERVAL: i64 = 5_000_000;
+
+/// Sticks with the window it found, even if its title changes or another
+/// window comes to match too. Once it closes, the next window to match is
+/// picked up instead.
+pub struct WindowTracker {
+    target: WindowTarget,
+    window: Option<WindowInfo>,
+    // When to look for a window again, in 100ns units
+    next_search: i64,
+}
+
+impl WindowTracker {
+    pub fn new(target: WindowTarget) -> Self {
+        Self {
+            target,
+            window: None,
+            next_search: i64::MIN,
+        }
+    }
+
+    /// The window being recorded, as it was when last updated.
+    pub fn window(&self) -> Option<&WindowInfo> {
+        self.window.as_ref()
+    }
+
+    /// Checks on the window at `now`, in 100ns units. Listing every window
+    /// is slow, so while there's nothing to record that's only done every
+    /// so often.
+    pub fn update(&mut self, windows: &impl WindowEnumerator, now: i64) -> Tracked {
+        let mut window = self
+            .window
+            .as_ref()
+            .and_then(|window| windows.window(window.handle));
+        if window.is_none() && now >= self.next_search {
+            window = self.target.find(windows);
+            if window.is_none() {
+                self.next_search = now + SEARCH_INTERVAL;
+            }
+        }
+        self.window = window;
+        match &self.window {
+            Some(window) if window.minimized => Tracked::Minimized,
+            Some(window) => Tracked::Shown(window.bounds),
+            None => Tracked::Lost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::{
+        parse_handle, parse_title_pattern, Tracked, WhenMinimized, WindowEnumerator, WindowInfo,
+        WindowTarget, WindowTracker,
+    };
+    use crate::region::Region;
+
+    /// Windows listed front to back, that can be changed between updates.
+    #[derive(Default)]
+    struct FakeWindows {
+        windows: RefCell<Vec<WindowInfo>>,
+        // How many times every window was listed
+        listed: RefCell<usize>,
+    }
+
+    impl FakeWindows {
+        fn new(windows: Vec<WindowInfo>) -> Self {
+            Self {
+                windows: RefCell::new(windows),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl WindowEnumerator for FakeWindows {
+        fn windows(&self) -> Vec<WindowInfo> {
+            *self.listed.borrow_mut() += 1;
+            self.windows.borrow().clone()
+        }
+
+        fn window(&self, handle: isize) -> Option<WindowInfo> {
+            let windows = self.windows.borrow();
+            windows
+                .iter()
+                .find(|window| window.handle == handle)
+                .cloned()
+        }
+    }
+
+    fn window(handle: isize, title: &str, process: &str) -> WindowInfo {
+        WindowInfo {
+            handle,
+            title: title.to_owned(),
+            process: process.to_owned(),
+            bounds: Region::new(100, 100, 1280, 720),
+            minimized: false,
+        }
+    }
+
+    fn desktop() -> FakeWindows {
+        FakeWindows::new(vec![
+            window(0x10, "Untitled - Notepad", "notepad.exe"),
+            window(0x20, "Game Launcher", "Launcher.exe"),
+            window(0x30, "My Game", "Game.exe"),
+            window(0x40, "Game Settings", "Game.exe"),
+        ])
+    }
+
+    fn title(pattern: &str) -> WindowTarget {
+        WindowTarget::Title(parse_title_pattern(pattern).unwrap())
+    }
+
+    fn found(target: WindowTarget, windows: &FakeWindows) -> Option<isize> {
+        target.find(windows).map(|window| window.handle)
+    }
+
+    #[test]
+    fn finds_windows_by_title() {
+        let windows = desktop();
+        assert_eq!(found(title("notepad"), &windows), Some(0x10));
+        assert_eq!(found(title("^my game$"), &windows), Some(0x30));
+        // The front most match wins
+        assert_eq!(found(title("game"), &windows), Some(0x20));
+        assert_eq!(found(title("^Game"), &windows), Some(0x20));
+        assert_eq!(found(title("word"), &windows), None);
+        assert!(parse_title_pattern("(unclosed").is_err());
+    }
+
+    #[test]
+    fn finds_windows_by_process() {
+        let windows = desktop();
+        let process = |name: &str| WindowTarget::Process(name.to_owned());
+        assert_eq!(found(process("game.exe"), &windows), Some(0x30));
+        assert_eq!(found(process("GAME"), &windows), Some(0x30));
+        assert_eq!(found(process(r"C:\Games\Game.exe"), &windows), Some(0x30));
+        assert_eq!(found(process("notepad"), &windows), Some(0x10));
+        assert_eq!(found(process("note"), &windows), None);
+        assert_eq!(found(process("game.ex"), &windows), None);
+    }
+
+    #[test]
+    fn finds_windows_by_handle() {
+        let windows = desktop();
+        assert_eq!(found(WindowTarget::Handle(0x40), &windows), Some(0x40));
+        assert_eq!(found(WindowTarget::Handle(0x50), &windows), None);
+        assert_eq!(parse_handle("0x1A2b"), Ok(0x1a2b));
+        assert_eq!(parse_handle("6700"), Ok(6700));
+        for invalid in ["", "0", "0x", "hwnd", "0xzz"] {
+            assert!(parse_handle(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn prefers_windows_that_are_showing() {
+        let windows = desktop();
+        windows.windows.borrow_mut()[1].minimized = true;
+        assert_eq!(found(title("game"), &windows), Some(0x30));
+        // Still picks a minimized window if that's all there is
+        assert_eq!(found(title("launcher"), &windows), Some(0x20));
+    }
+
+    #[test]
+    fn follows_the_window_it_found() {
+        let windows = desktop();
+        let mut tracker = WindowTracker::new(WindowTarget::Process("game".to_owned()));
+        let bounds = Region::new(100, 100, 1280, 720);
+        assert_eq!(tracker.update(&windows, 0), Tracked::Shown(bounds));
+        assert_eq!(tracker.window().unwrap().handle, 0x30);
+
+        // It moves to another display
+        let moved = Region::new(-1920, 0, 1920, 1080);
+        windows.windows.borrow_mut()[2].bounds = moved;
+        assert_eq!(tracker.update(&windows, 1), Tracked::Shown(moved));
+
+        // Minimizing it doesn't switch to the settings window
+        windows.windows.borrow_mut()[2].minimized = true;
+        assert_eq!(tracker.update(&windows, 2), Tracked::Minimized);
+        windows.windows.borrow_mut()[2].minimized = false;
+        windows.windows.borrow_mut()[2].title = "My Game - Level 2".to_owned();
+        assert_eq!(tracker.update(&windows, 3), Tracked::Shown(moved));
+        assert_eq!(tracker.window().unwrap().title, "My Game - Level 2");
+        // Found once, then only checked on
+        assert_eq!(*windows.listed.borrow(), 1);
+    }
+
+    #[test]
+    fn picks_up_the_next_window_once_it_closes() {
+        let windows = desktop();
+        let mut tracker = WindowTracker::new(title("my game"));
+        tracker.update(&windows, 0);
+        assert_eq!(tracker.window().unwrap().handle, 0x30);
+
+        windows.windows.borrow_mut().remove(2);
+        assert_eq!(tracker.update(&windows, 1), Tracked::Lost);
+        // Doesn't keep listing every window while there's nothing to record
+        for now in 2..100 {
+            assert_eq!(tracker.update(&windows, now), Tracked::Lost);
+        }
+        assert_eq!(*windows.listed.borrow(), 2);
+
+        // The game is started again
+        windows
+            .windows
+            .borrow_mut()
+            .insert(0, window(0x50, "My Game", "Game.exe"));
+        assert_eq!(tracker.update(&windows, 100), Tracked::Lost);
+        let bounds = Region::new(100, 100, 1280, 720);
+        assert_eq!(tracker.update(&windows, 5_000_001), Tracked::Shown(bounds));
+        assert_eq!(tracker.window().unwrap().handle, 0x50);
+    }
+
+    #[test]
+    fn parses_when_minimized() {
+        for when_minimized in [WhenMinimized::Blank, WhenMinimized::Pause] {
+            assert_eq!(when_minimized.to_string().parse(), Ok(when_minimized));
+        }
+        assert_eq!("Pause".parse(), Ok(WhenMinimized::Pause));
+        assert!("hide".parse::<WhenMinimized>().is_err());
+    }
+}